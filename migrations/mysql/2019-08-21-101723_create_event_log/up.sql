@@ -0,0 +1,11 @@
+CREATE TABLE event (
+  uuid        CHAR(36) NOT NULL PRIMARY KEY,
+  event_type  INTEGER  NOT NULL,
+  user_uuid   CHAR(36),
+  org_uuid    CHAR(36),
+  cipher_uuid CHAR(36),
+  device_uuid CHAR(36),
+  device_type INTEGER,
+  ip_address  TEXT,
+  event_date  DATETIME NOT NULL
+);
@@ -0,0 +1,3 @@
+ALTER TABLE users
+    ADD COLUMN
+    twofactor_enroll_deadline DATETIME;
@@ -0,0 +1,10 @@
+CREATE TABLE cipher_transfers (
+  uuid           CHAR(36) NOT NULL PRIMARY KEY,
+  cipher_uuid    CHAR(36) NOT NULL REFERENCES ciphers (uuid),
+  sender_uuid    CHAR(36) NOT NULL REFERENCES users (uuid),
+  recipient_uuid CHAR(36) NOT NULL REFERENCES users (uuid),
+  data           TEXT     NOT NULL,
+  created_at     DATETIME NOT NULL,
+
+  UNIQUE (cipher_uuid)
+);
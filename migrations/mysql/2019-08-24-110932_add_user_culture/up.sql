@@ -0,0 +1,3 @@
+ALTER TABLE users
+    ADD COLUMN
+    culture VARCHAR(16);
@@ -0,0 +1,7 @@
+ALTER TABLE users
+    ADD COLUMN
+    magic_link_nonce TEXT;
+
+ALTER TABLE users
+    ADD COLUMN
+    magic_link_sent_at DATETIME;
@@ -0,0 +1,11 @@
+ALTER TABLE devices
+    ADD COLUMN
+    encrypted_user_key TEXT;
+
+ALTER TABLE devices
+    ADD COLUMN
+    encrypted_public_key TEXT;
+
+ALTER TABLE devices
+    ADD COLUMN
+    encrypted_private_key TEXT;
@@ -0,0 +1,13 @@
+CREATE TABLE emergency_access (
+  uuid                  CHAR(36) NOT NULL PRIMARY KEY,
+  grantor_uuid          CHAR(36) NOT NULL REFERENCES users (uuid),
+  grantee_uuid          CHAR(36) NOT NULL REFERENCES users (uuid),
+  key_encrypted         TEXT,
+  atype                 INTEGER  NOT NULL,
+  status                INTEGER  NOT NULL,
+  wait_time_days        INTEGER  NOT NULL,
+  recovery_initiated_at DATETIME,
+  created_at            DATETIME NOT NULL,
+  updated_at            DATETIME NOT NULL,
+  UNIQUE (grantor_uuid, grantee_uuid)
+);
@@ -0,0 +1,8 @@
+CREATE TABLE security_notices (
+  uuid            CHAR(36) NOT NULL PRIMARY KEY,
+  user_uuid       CHAR(36) NOT NULL REFERENCES users (uuid),
+  atype           INTEGER  NOT NULL,
+  data            TEXT     NOT NULL,
+  created_at      DATETIME NOT NULL,
+  acknowledged_at DATETIME
+);
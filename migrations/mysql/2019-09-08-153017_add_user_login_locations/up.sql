@@ -0,0 +1,3 @@
+ALTER TABLE users
+    ADD COLUMN
+    login_locations TEXT;
@@ -0,0 +1,11 @@
+ALTER TABLE users
+    ADD COLUMN
+    enabled BOOLEAN NOT NULL DEFAULT 1; -- True
+
+ALTER TABLE users
+    ADD COLUMN
+    inactivity_warned_at DATETIME;
+
+ALTER TABLE users
+    ADD COLUMN
+    enabled_at DATETIME;
@@ -0,0 +1,3 @@
+ALTER TABLE users
+    ADD COLUMN
+    password_history TEXT;
@@ -0,0 +1,8 @@
+CREATE TABLE device_approval_requests (
+  uuid        CHAR(36)     NOT NULL PRIMARY KEY,
+  user_uuid   CHAR(36)     NOT NULL REFERENCES users (uuid),
+  org_uuid    CHAR(36)     NOT NULL REFERENCES organizations (uuid),
+  device_uuid CHAR(36)     NOT NULL,
+  ip_address  VARCHAR(45)  NOT NULL,
+  created_at  DATETIME     NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE ciphers ADD COLUMN totp_params TEXT;
@@ -0,0 +1 @@
+ALTER TABLE event ADD COLUMN act_user_uuid CHAR(36);
@@ -0,0 +1 @@
+ALTER TABLE devices ADD COLUMN approval_token TEXT;
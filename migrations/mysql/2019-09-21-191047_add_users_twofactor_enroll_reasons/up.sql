@@ -0,0 +1,3 @@
+ALTER TABLE users
+    ADD COLUMN
+    twofactor_enroll_reasons INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+ALTER TABLE devices
+    ADD COLUMN
+    refresh_ip TEXT;
@@ -0,0 +1,10 @@
+CREATE TABLE cipher_transfers (
+  uuid           TEXT     NOT NULL PRIMARY KEY,
+  cipher_uuid    TEXT     NOT NULL REFERENCES ciphers (uuid),
+  sender_uuid    TEXT     NOT NULL REFERENCES users (uuid),
+  recipient_uuid TEXT     NOT NULL REFERENCES users (uuid),
+  data           TEXT     NOT NULL,
+  created_at     DATETIME NOT NULL,
+
+  UNIQUE (cipher_uuid)
+);
@@ -0,0 +1,3 @@
+ALTER TABLE users_collections
+    ADD COLUMN
+    can_delete BOOLEAN NOT NULL DEFAULT 1; -- True
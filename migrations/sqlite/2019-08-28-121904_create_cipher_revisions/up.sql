@@ -0,0 +1,13 @@
+CREATE TABLE cipher_revisions (
+  uuid              TEXT     NOT NULL PRIMARY KEY,
+  cipher_uuid       TEXT     NOT NULL REFERENCES ciphers (uuid),
+  organization_uuid TEXT,
+  atype             INTEGER  NOT NULL,
+  name              TEXT     NOT NULL,
+  notes             TEXT,
+  fields            TEXT,
+  data              TEXT     NOT NULL,
+  password_history  TEXT,
+  revision_date     DATETIME NOT NULL,
+  created_at        DATETIME NOT NULL
+);
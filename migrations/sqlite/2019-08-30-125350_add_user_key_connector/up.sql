@@ -0,0 +1,3 @@
+ALTER TABLE users
+    ADD COLUMN
+    uses_key_connector BOOLEAN NOT NULL DEFAULT 0;
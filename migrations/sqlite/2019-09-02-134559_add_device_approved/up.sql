@@ -0,0 +1,3 @@
+ALTER TABLE devices
+    ADD COLUMN
+    approved BOOLEAN NOT NULL DEFAULT 1; -- True
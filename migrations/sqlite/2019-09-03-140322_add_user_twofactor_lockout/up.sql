@@ -0,0 +1,7 @@
+ALTER TABLE users
+    ADD COLUMN
+    twofactor_failures INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE users
+    ADD COLUMN
+    twofactor_locked_until DATETIME;
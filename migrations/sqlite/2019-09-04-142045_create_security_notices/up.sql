@@ -0,0 +1,8 @@
+CREATE TABLE security_notices (
+  uuid            TEXT     NOT NULL PRIMARY KEY,
+  user_uuid       TEXT     NOT NULL REFERENCES users (uuid),
+  atype           INTEGER  NOT NULL,
+  data            TEXT     NOT NULL,
+  created_at      DATETIME NOT NULL,
+  acknowledged_at DATETIME
+);
@@ -0,0 +1,3 @@
+ALTER TABLE users
+    ADD COLUMN
+    api_key TEXT;
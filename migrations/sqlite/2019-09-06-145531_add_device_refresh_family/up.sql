@@ -0,0 +1,7 @@
+ALTER TABLE devices
+    ADD COLUMN
+    refresh_family TEXT;
+
+ALTER TABLE devices
+    ADD COLUMN
+    refresh_generation INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+ALTER TABLE devices
+    ADD COLUMN
+    previous_refresh_token TEXT;
+
+ALTER TABLE devices
+    ADD COLUMN
+    refresh_rotated_at DATETIME;
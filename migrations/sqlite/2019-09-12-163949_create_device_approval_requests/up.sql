@@ -0,0 +1,8 @@
+CREATE TABLE device_approval_requests (
+  uuid        TEXT     NOT NULL PRIMARY KEY,
+  user_uuid   TEXT     NOT NULL REFERENCES users (uuid),
+  org_uuid    TEXT     NOT NULL REFERENCES organizations (uuid),
+  device_uuid TEXT     NOT NULL,
+  ip_address  TEXT     NOT NULL,
+  created_at  DATETIME NOT NULL
+);
@@ -0,0 +1,7 @@
+ALTER TABLE users
+    ADD COLUMN
+    password_changed_at DATETIME;
+
+ALTER TABLE org_policies
+    ADD COLUMN
+    updated_at DATETIME;
@@ -0,0 +1,2 @@
+-- SQLite can't drop the column, the TOTP secrets the server stored are cleared instead
+UPDATE ciphers SET totp_params = NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE twofactor
+    ADD COLUMN
+    created_at DATETIME;
@@ -1,18 +1,23 @@
+use chrono::Utc;
 use serde_json::Value;
 use std::process::Command;
 
 use rocket::http::{Cookie, Cookies, SameSite};
 use rocket::request::{self, FlashMessage, Form, FromRequest, Request};
-use rocket::response::{content::Html, Flash, Redirect};
+use rocket::response::content::{Content, Html};
+use rocket::response::{Flash, Redirect, Stream};
 use rocket::{Outcome, Route};
 use rocket_contrib::json::Json;
 
+use crate::api::core::events::{EventExport, EventExportData};
 use crate::api::{ApiResult, EmptyResult, JsonResult};
 use crate::auth::{decode_admin, encode_jwt, generate_admin_claims, ClientIp};
 use crate::config::ConfigBuilder;
 use crate::db::{backup_database, models::*, DbConn};
 use crate::error::Error;
+use crate::lockout::{self, LockoutKind};
 use crate::mail;
+use crate::webhook;
 use crate::CONFIG;
 
 pub fn routes() -> Vec<Route> {
@@ -26,10 +31,19 @@ pub fn routes() -> Vec<Route> {
         post_admin_login,
         admin_page,
         invite_user,
+        test_smtp,
+        test_webhook,
         delete_user,
         deauth_user,
+        disable_user,
+        enable_user,
         remove_2fa,
+        get_devices,
+        delete_device,
         update_revision_users,
+        get_lockouts,
+        clear_lockout,
+        get_events_export,
         post_config,
         delete_config,
         backup_db,
@@ -67,18 +81,34 @@ struct LoginForm {
     token: String,
 }
 
+/// The lockout is returned as an API error, so the client gets the `429` status and the `Retry-After` header
 #[post("/", data = "<data>")]
-fn post_admin_login(data: Form<LoginForm>, mut cookies: Cookies, ip: ClientIp) -> Result<Redirect, Flash<Redirect>> {
+fn post_admin_login(
+    data: Form<LoginForm>,
+    mut cookies: Cookies,
+    ip: ClientIp,
+) -> Result<Result<Redirect, Flash<Redirect>>, Error> {
     let data = data.into_inner();
+    let ip_key = ip.ip.to_string();
+
+    if let Some(seconds) = lockout::retry_after(LockoutKind::Admin, &ip_key) {
+        return Err(lockout::locked_out_error(
+            seconds,
+            format!("Admin login locked out. IP: {}", ip.ip),
+        ));
+    }
 
     // If the token is invalid, redirect to login page
     if !_validate_token(&data.token) {
         error!("Invalid admin token. IP: {}", ip.ip);
-        Err(Flash::error(
+        lockout::register_failure(LockoutKind::Admin, &ip_key);
+        Ok(Err(Flash::error(
             Redirect::to(ADMIN_PATH),
             "Invalid admin token, please try again.",
-        ))
+        )))
     } else {
+        lockout::clear(LockoutKind::Admin, &ip_key);
+
         // If the token received is valid, generate JWT and save it as a cookie
         let claims = generate_admin_claims();
         let jwt = encode_jwt(&claims);
@@ -91,7 +121,7 @@ fn post_admin_login(data: Form<LoginForm>, mut cookies: Cookies, ip: ClientIp) -
             .finish();
 
         cookies.add(cookie);
-        Ok(Redirect::to(ADMIN_PATH))
+        Ok(Ok(Redirect::to(ADMIN_PATH)))
     }
 }
 
@@ -142,6 +172,30 @@ struct InviteData {
     email: String,
 }
 
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct SmtpTestData {
+    email: String,
+}
+
+#[post("/test/smtp", data = "<data>")]
+fn test_smtp(data: Json<SmtpTestData>, _token: AdminToken) -> EmptyResult {
+    let data: SmtpTestData = data.into_inner();
+
+    if CONFIG.mail_enabled() {
+        mail::send_test(&data.email)
+    } else {
+        err!("Mail is not enabled")
+    }
+}
+
+/// Sends a test event to the webhook and returns how the delivery went. Unlike the real events it's not recorded
+#[post("/test/webhook")]
+fn test_webhook(_token: AdminToken) -> JsonResult {
+    let delivery = webhook::send(&webhook::event_payload("test", None, None, None))?;
+    Ok(Json(delivery.to_json()))
+}
+
 #[post("/invite", data = "<data>")]
 fn invite_user(data: Json<InviteData>, _token: AdminToken, conn: DbConn) -> EmptyResult {
     let data: InviteData = data.into_inner();
@@ -159,7 +213,15 @@ fn invite_user(data: Json<InviteData>, _token: AdminToken, conn: DbConn) -> Empt
 
     if CONFIG.mail_enabled() {
         let org_name = "bitwarden_rs";
-        mail::send_invite(&user.email, &user.uuid, None, None, &org_name, None)
+        mail::send_invite(
+            &user.email,
+            &user.uuid,
+            None,
+            None,
+            &org_name,
+            None,
+            user.locale(crate::util::DEFAULT_LOCALE),
+        )
     } else {
         let invitation = Invitation::new(data.email);
         invitation.save(&conn)
@@ -197,6 +259,46 @@ fn deauth_user(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
     user.save(&conn)
 }
 
+#[post("/users/<uuid>/disable")]
+fn disable_user(uuid: String, _token: AdminToken, ip: ClientIp, conn: DbConn) -> EmptyResult {
+    set_user_enabled(&uuid, false, &ip, &conn)
+}
+
+#[post("/users/<uuid>/enable")]
+fn enable_user(uuid: String, _token: AdminToken, ip: ClientIp, conn: DbConn) -> EmptyResult {
+    set_user_enabled(&uuid, true, &ip, &conn)
+}
+
+fn set_user_enabled(uuid: &str, enabled: bool, ip: &ClientIp, conn: &DbConn) -> EmptyResult {
+    let mut user = match User::find_by_uuid(uuid, conn) {
+        Some(user) => user,
+        None => err!("User doesn't exist"),
+    };
+
+    user.enabled = enabled;
+    if enabled {
+        user.enabled_at = Some(Utc::now().naive_utc());
+    }
+    user.save(conn)?;
+
+    let event_type = if enabled {
+        EventType::AdminEnabledUser
+    } else {
+        EventType::AdminDisabledUser
+    };
+    let mut event = Event::new(event_type, Some(user.uuid.clone()));
+    event.ip_address = Some(ip.ip.to_string());
+    event.save_or_log(conn);
+
+    info!(
+        "Admin at IP {} {} the user {}",
+        ip.ip,
+        if enabled { "enabled" } else { "disabled" },
+        user.email
+    );
+    Ok(())
+}
+
 #[post("/users/<uuid>/remove-2fa")]
 fn remove_2fa(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
     let mut user = match User::find_by_uuid(&uuid, &conn) {
@@ -206,9 +308,137 @@ fn remove_2fa(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
 
     TwoFactor::delete_all_by_user(&user.uuid, &conn)?;
     user.totp_recover = None;
+    // Give the user a new grace period to enroll again when two-factor is required
+    user.twofactor_enroll_deadline = None;
     user.save(&conn)
 }
 
+#[derive(FromForm)]
+struct DevicesData {
+    page: Option<i64>,
+    #[form(field = "pageSize")]
+    page_size: Option<i64>,
+}
+
+const DEVICES_PAGE_SIZE_DEFAULT: i64 = 50;
+const DEVICES_PAGE_SIZE_MAX: i64 = 500;
+
+/// Lists the devices of all the users, most recently used first, with their owner and whether it has two-step login
+#[get("/devices?<data..>")]
+fn get_devices(data: Form<DevicesData>, _token: AdminToken, conn: DbConn) -> JsonResult {
+    use std::collections::HashMap;
+
+    let page = data.page.unwrap_or(0).max(0);
+    let page_size = data
+        .page_size
+        .unwrap_or(DEVICES_PAGE_SIZE_DEFAULT)
+        .max(1)
+        .min(DEVICES_PAGE_SIZE_MAX);
+
+    // The users usually have several devices, so each one is only loaded once
+    let mut users: HashMap<String, Option<(String, bool)>> = HashMap::new();
+    let devices_json: Vec<Value> = Device::find_all(page_size, page.saturating_mul(page_size), &conn)
+        .iter()
+        .map(|device| {
+            let user = users.entry(device.user_uuid.clone()).or_insert_with(|| {
+                User::find_by_uuid(&device.user_uuid, &conn).map(|user| {
+                    let twofactor_enabled = TwoFactor::find_by_user(&user.uuid, &conn).iter().any(|tf| tf.enabled);
+                    (user.email, twofactor_enabled)
+                })
+            });
+
+            let mut device_json = device.to_json();
+            device_json["UserId"] = Value::String(device.user_uuid.clone());
+            device_json["UserEmail"] = json!(user.as_ref().map(|(email, _)| email));
+            device_json["TwoFactorEnabled"] = json!(user.as_ref().map_or(false, |(_, enabled)| *enabled));
+            // Without a refresh token the device needs to log in again, once its access token expires
+            device_json["HasSession"] = Value::Bool(!device.refresh_token.is_empty());
+            device_json
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": devices_json,
+        "Page": page,
+        "PageSize": page_size,
+        "Total": Device::count_all(&conn),
+    })))
+}
+
+/// Deletes a device, terminating its session. Its refresh token stops working right away,
+/// the access token it already has until it expires
+#[post("/devices/<uuid>/delete")]
+fn delete_device(uuid: String, _token: AdminToken, ip: ClientIp, conn: DbConn) -> EmptyResult {
+    let device = match Device::find_by_uuid(&uuid, &conn) {
+        Some(device) => device,
+        None => err!("Device doesn't exist"),
+    };
+
+    let mut event = Event::new(EventType::AdminTerminatedSession, Some(device.user_uuid.clone()));
+    event.device_uuid = Some(device.uuid.clone());
+    event.device_type = Some(device.atype);
+    event.ip_address = Some(ip.ip.to_string());
+    event.save_or_log(&conn);
+
+    info!(
+        "Admin at IP {} terminated the session of the device {} ({}) of the user {}",
+        ip.ip, device.name, device.uuid, device.user_uuid
+    );
+
+    DeviceApprovalRequest::delete_all_by_device(&device.uuid, &conn)?;
+    device.delete(&conn)
+}
+
+#[get("/lockouts")]
+fn get_lockouts(_token: AdminToken) -> JsonResult {
+    Ok(Json(Value::Array(lockout::list_json())))
+}
+
+/// Clears the failed attempts of a user (by email) or an IP. The user events keep the IP of the admin,
+/// while the IP ones keep the unlocked IP, as there is no user to attach them to
+#[post("/lockouts/<kind>/<key>/clear")]
+fn clear_lockout(kind: String, key: String, _token: AdminToken, ip: ClientIp, conn: DbConn) -> EmptyResult {
+    let kind = match LockoutKind::from_str(&kind) {
+        Some(kind) => kind,
+        None => err!("Invalid lockout type"),
+    };
+
+    if !lockout::clear(kind, &key) {
+        err!("No failed login attempts found")
+    }
+
+    info!("Admin at IP {} cleared the lockout of {} {}", ip.ip, kind.as_str(), key);
+
+    let event = match kind {
+        LockoutKind::User => {
+            let mut event = Event::new(
+                EventType::AdminClearedUserLockout,
+                User::find_by_mail(&key, &conn).map(|u| u.uuid),
+            );
+            event.ip_address = Some(ip.ip.to_string());
+            event
+        }
+        LockoutKind::Ip | LockoutKind::Admin => {
+            let mut event = Event::new(EventType::AdminClearedIpLockout, None);
+            event.ip_address = Some(key);
+            event
+        }
+    };
+    event.save_or_log(&conn);
+
+    Ok(())
+}
+
+/// Exports the events of all the users and organizations
+#[get("/events/export?<data..>")]
+fn get_events_export(
+    data: Form<EventExportData>,
+    _token: AdminToken,
+    conn: DbConn,
+) -> ApiResult<Content<Stream<EventExport>>> {
+    EventExport::new(None, &data, conn).map(EventExport::into_response)
+}
+
 #[post("/users/update_revision")]
 fn update_revision_users(_token: AdminToken, conn: DbConn) -> EmptyResult {
     User::update_all_revisions(&conn)
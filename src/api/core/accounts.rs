@@ -1,10 +1,16 @@
+use rocket::request::Form;
 use rocket_contrib::json::Json;
+use serde_json::Value;
 
 use crate::db::models::*;
 use crate::db::DbConn;
 
-use crate::api::{EmptyResult, JsonResult, JsonUpcase, Notify, NumberOrString, PasswordData, UpdateType};
-use crate::auth::{decode_invite, Headers};
+use crate::api::{
+    check_password_hash_format, ApiResult, EmptyResult, JsonResult, JsonUpcase, Notify, NumberOrString, PasswordData,
+    UpdateType,
+};
+use crate::auth::{decode_invite, ClientIp, ClientLocale, Headers};
+use crate::lockout;
 use crate::mail;
 
 use crate::CONFIG;
@@ -14,21 +20,36 @@ use rocket::Route;
 pub fn routes() -> Vec<Route> {
     routes![
         register,
+        can_register,
         profile,
+        get_rate_limit,
         put_profile,
         post_profile,
         get_public_keys,
         post_keys,
         post_password,
         post_kdf,
+        post_set_key_connector_key,
+        post_set_password,
         post_rotatekey,
         post_sstamp,
+        post_api_key,
+        post_rotate_api_key,
+        delete_api_key,
         post_email_token,
         post_email,
         delete_account,
         post_delete_account,
         revision_date,
+        get_culture,
+        put_culture,
+        post_culture,
+        get_activity,
+        get_security_notices,
+        post_security_notice_acknowledge,
+        post_security_notices_acknowledge,
         password_hint,
+        post_magic_link,
         prelogin,
     ]
 }
@@ -46,6 +67,60 @@ struct RegisterData {
     Name: Option<String>,
     Token: Option<String>,
     OrganizationUserId: Option<String>,
+    MasterPasswordStrength: Option<PasswordStrengthData>,
+}
+
+/// What the client measured of the new master password, the server only receives its hash
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct PasswordStrengthData {
+    Score: u64,
+    Length: u64,
+    HasUpper: bool,
+    HasLower: bool,
+    HasNumbers: bool,
+    HasSpecial: bool,
+}
+
+/// Rejects a new master password below the requirements of the `MasterPassword` policies. Without
+/// `MASTER_PASSWORD_STRENGTH_REQUIRED`, the clients that don't report the strength are trusted to check them themselves.
+/// The official clients don't report it, so with them only the clients check the requirements, and a reported
+/// strength can't be verified either. The enforcement on login doesn't depend on it
+fn enforce_master_password_policy(
+    password_strength: Option<&PasswordStrengthData>,
+    policies: &[OrgPolicy],
+) -> EmptyResult {
+    let requirements = MasterPasswordRequirements::combine(policies);
+    if requirements.is_empty() {
+        return Ok(());
+    }
+
+    let strength = match password_strength {
+        Some(strength) => strength,
+        None if CONFIG.master_password_strength_required() => {
+            err!("Your organization requires the client to report the strength of the new master password")
+        }
+        None => return Ok(()),
+    };
+
+    if strength.Score < requirements.min_complexity {
+        err!("The master password is not complex enough for the policy of your organization")
+    }
+    if strength.Length < requirements.min_length {
+        err!(format!(
+            "The master password needs to be at least {} characters long",
+            requirements.min_length
+        ))
+    }
+    if (requirements.require_upper && !strength.HasUpper)
+        || (requirements.require_lower && !strength.HasLower)
+        || (requirements.require_numbers && !strength.HasNumbers)
+        || (requirements.require_special && !strength.HasSpecial)
+    {
+        err!("The master password is missing characters required by the policy of your organization")
+    }
+
+    Ok(())
 }
 
 #[derive(Deserialize, Debug)]
@@ -86,7 +161,7 @@ fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
             }
         }
         None => {
-            if CONFIG.signups_allowed() || Invitation::take(&data.Email, &conn) {
+            if signup_denied_reason(&data.Email, &conn)?.is_none() || Invitation::take(&data.Email, &conn) {
                 User::new(data.Email.clone())
             } else {
                 err!("Registration not allowed")
@@ -105,6 +180,19 @@ fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
         user.client_kdf_type = client_kdf_type;
     }
 
+    check_password_hash_format(&data.MasterPasswordHash, user.client_kdf_type)?;
+
+    // The new users are only members of the organizations they were invited to, and owners and admins are exempt
+    let policies: Vec<OrgPolicy> = UserOrganization::find_any_state_by_user(&user.uuid, &conn)
+        .iter()
+        .filter(|user_org| user_org.atype < UserOrgType::Admin)
+        .filter_map(|user_org| {
+            OrgPolicy::find_by_org_and_type(&user_org.org_uuid, OrgPolicyType::MasterPassword as i32, &conn)
+        })
+        .filter(|policy| policy.enabled)
+        .collect();
+    enforce_master_password_policy(data.MasterPasswordStrength.as_ref(), &policies)?;
+
     user.set_password(&data.MasterPasswordHash);
     user.akey = data.Key;
 
@@ -125,11 +213,82 @@ fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
     user.save(&conn)
 }
 
+/// Returns why a new, not invited, user can't sign up with this email, if they can't.
+/// Fails when the users can't be counted, so a database error doesn't bypass `SIGNUPS_MAX_USERS`
+fn signup_denied_reason(email: &str, conn: &DbConn) -> ApiResult<Option<&'static str>> {
+    if !CONFIG.signups_allowed() {
+        return Ok(Some("Signups are disabled"));
+    }
+
+    signup_limit_reason(email, conn)
+}
+
+/// Returns why no account can be created with this email, because of `SIGNUPS_DOMAINS_WHITELIST` or `SIGNUPS_MAX_USERS`.
+/// The accounts created by the SSO login are limited the same way
+pub fn signup_limit_reason(email: &str, conn: &DbConn) -> ApiResult<Option<&'static str>> {
+    let whitelist = CONFIG.signups_domains_whitelist();
+    if !whitelist.trim().is_empty() {
+        let domain = email.rsplit('@').next().unwrap_or_default().trim().to_lowercase();
+        if !whitelist.split(',').any(|d| d.trim().eq_ignore_ascii_case(&domain)) {
+            return Ok(Some("Signups are not allowed for this email domain"));
+        }
+    }
+
+    let max_users = CONFIG.signups_max_users();
+    if max_users > 0 && User::count(conn)? >= max_users {
+        return Ok(Some("The maximum number of users has been reached"));
+    }
+
+    Ok(None)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct CanRegisterData {
+    Email: String,
+    Token: Option<String>,
+}
+
+#[post("/accounts/can-register", data = "<data>")]
+fn can_register(data: JsonUpcase<CanRegisterData>, conn: DbConn) -> JsonResult {
+    let data: CanRegisterData = data.into_inner().data;
+
+    // Whether the email is already registered isn't checked, so this doesn't reveal which accounts exist
+    let invited = match data.Token {
+        Some(token) => decode_invite(&token).map(|c| c.email == data.Email).unwrap_or(false),
+        None => false,
+    };
+
+    let reason = if invited {
+        None
+    } else {
+        signup_denied_reason(&data.Email, &conn)?
+    };
+
+    Ok(Json(json!({
+        "Allowed": reason.is_none(),
+        "Reason": reason,
+        "Object": "canRegister",
+    })))
+}
+
 #[get("/accounts/profile")]
 fn profile(headers: Headers, conn: DbConn) -> JsonResult {
     Ok(Json(headers.user.to_json(&conn)))
 }
 
+/// The API request budget left to the user, not counting this request when the limit is disabled
+#[get("/accounts/rate-limit")]
+fn get_rate_limit(headers: Headers) -> JsonResult {
+    match crate::lockout::api_ratelimit_status(&headers.user.uuid) {
+        Some(ratelimit) => Ok(Json(ratelimit.to_json())),
+        None => Ok(Json(json!({
+            "Enabled": false,
+            "Object": "rateLimit",
+        }))),
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(non_snake_case)]
 struct ProfileData {
@@ -192,6 +351,7 @@ struct ChangePassData {
     MasterPasswordHash: String,
     NewMasterPasswordHash: String,
     Key: String,
+    MasterPasswordStrength: Option<PasswordStrengthData>,
 }
 
 #[post("/accounts/password", data = "<data>")]
@@ -203,9 +363,39 @@ fn post_password(data: JsonUpcase<ChangePassData>, headers: Headers, conn: DbCon
         err!("Invalid password")
     }
 
+    check_password_hash_format(&data.NewMasterPasswordHash, user.client_kdf_type)?;
+
+    let policies = OrgPolicy::find_applicable_to_user(&user.uuid, OrgPolicyType::MasterPassword, &conn);
+    enforce_master_password_policy(data.MasterPasswordStrength.as_ref(), &policies)?;
+
+    // With a master password policy preventing the reuse, the new hash is compared with the previous ones
+    let history_count = OrgPolicy::password_history_count(&user.uuid, &conn);
+    if history_count > 0 && user.is_password_reused(&data.NewMasterPasswordHash, history_count) {
+        err!(format!(
+            "Your organization doesn't allow reusing any of your last {} master passwords",
+            history_count
+        ))
+    }
+
+    user.update_password_history(history_count);
     user.set_password(&data.NewMasterPasswordHash);
     user.akey = data.Key;
-    user.save(&conn)
+    user.save(&conn)?;
+
+    revoke_other_sessions(&user, &headers.device, &conn)
+}
+
+/// With `REVOKE_SESSIONS_ON_PASSWORD_CHANGE`, logs out the other devices once the password hash changed
+fn revoke_other_sessions(user: &User, device: &Device, conn: &DbConn) -> EmptyResult {
+    if CONFIG.revoke_sessions_on_password_change() {
+        let revoked = Device::delete_all_by_user_except(&user.uuid, &device.uuid, conn)?;
+        info!(
+            "Revoked {} sessions of {} after the password change",
+            revoked, user.email
+        );
+    }
+
+    Ok(())
 }
 
 #[derive(Deserialize)]
@@ -230,8 +420,102 @@ fn post_kdf(data: JsonUpcase<ChangeKdfData>, headers: Headers, conn: DbConn) ->
 
     user.client_kdf_iter = data.KdfIterations;
     user.client_kdf_type = data.Kdf;
+    check_password_hash_format(&data.NewMasterPasswordHash, user.client_kdf_type)?;
+
+    // The password hash changes with the KDF, the previous one is remembered like with a password change
+    user.update_password_history(OrgPolicy::password_history_count(&user.uuid, &conn));
     user.set_password(&data.NewMasterPasswordHash);
     user.akey = data.Key;
+    user.save(&conn)?;
+
+    revoke_other_sessions(&user, &headers.device, &conn)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct SetKeyConnectorKeyData {
+    Key: String,
+    Keys: KeysData,
+    Kdf: i32,
+    KdfIterations: i32,
+    OrgIdentifier: String,
+}
+
+/// Used by the users without a master password to keep their key in the Key Connector, instead of setting a password.
+/// Only the members of organizations that require single sign-on can use it
+#[post("/accounts/set-key-connector-key", data = "<data>")]
+fn post_set_key_connector_key(data: JsonUpcase<SetKeyConnectorKeyData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    let data: SetKeyConnectorKeyData = data.into_inner().data;
+    let mut user = headers.user;
+
+    if CONFIG.key_connector_url().is_none() {
+        err!("Key Connector is disabled")
+    }
+
+    if !user.password_hash.is_empty() || user.uses_key_connector {
+        err!("User already has a master password or uses Key Connector")
+    }
+
+    // The clients send the identifier used for the single sign-on, not the uuid
+    let org = match Organization::find_by_identifier(&data.OrgIdentifier, &conn) {
+        Some(org) => org,
+        None => err!("The organization doesn't use Key Connector"),
+    };
+
+    let is_member = match UserOrganization::find_by_user_and_org(&user.uuid, &org.uuid, &conn) {
+        Some(user_org) => user_org.status == UserOrgStatus::Confirmed as i32,
+        None => false,
+    };
+    let requires_sso = OrgPolicy::find_by_org_and_type(&org.uuid, OrgPolicyType::RequireSso as i32, &conn)
+        .map_or(false, |policy| policy.enabled);
+
+    if !is_member || !requires_sso {
+        err!("The organization doesn't use Key Connector")
+    }
+
+    user.akey = data.Key;
+    user.private_key = Some(data.Keys.EncryptedPrivateKey);
+    user.public_key = Some(data.Keys.PublicKey);
+    user.client_kdf_type = data.Kdf;
+    user.client_kdf_iter = data.KdfIterations;
+    user.uses_key_connector = true;
+    user.save(&conn)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct SetPasswordData {
+    Key: String,
+    Keys: KeysData,
+    Kdf: i32,
+    KdfIterations: i32,
+    MasterPasswordHash: String,
+    MasterPasswordHint: Option<String>,
+    MasterPasswordStrength: Option<PasswordStrengthData>,
+}
+
+/// Sets the first master password of the accounts created by the SSO login
+#[post("/accounts/set-password", data = "<data>")]
+fn post_set_password(data: JsonUpcase<SetPasswordData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    let data: SetPasswordData = data.into_inner().data;
+    let mut user = headers.user;
+
+    if !user.password_hash.is_empty() || user.uses_key_connector {
+        err!("User already has a master password or uses Key Connector")
+    }
+
+    user.client_kdf_type = data.Kdf;
+    user.client_kdf_iter = data.KdfIterations;
+    check_password_hash_format(&data.MasterPasswordHash, user.client_kdf_type)?;
+
+    let policies = OrgPolicy::find_applicable_to_user(&user.uuid, OrgPolicyType::MasterPassword, &conn);
+    enforce_master_password_policy(data.MasterPasswordStrength.as_ref(), &policies)?;
+
+    user.set_password(&data.MasterPasswordHash);
+    user.akey = data.Key;
+    user.private_key = Some(data.Keys.EncryptedPrivateKey);
+    user.public_key = Some(data.Keys.PublicKey);
+    user.password_hint = data.MasterPasswordHint;
     user.save(&conn)
 }
 
@@ -300,12 +584,18 @@ fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, conn: DbConn, nt:
             &conn,
             &nt,
             UpdateType::CipherUpdate,
-        )?
+        )?;
+
+        // The previous versions, including the one kept by this update, are encrypted with the old key
+        CipherRevision::delete_all_by_cipher(&saved_cipher.uuid, &conn)?;
     }
 
     // Update user data
     let mut user = headers.user;
 
+    // The devices would still have the old user key
+    Device::clear_trust_by_user(&user.uuid, &conn)?;
+
     user.akey = data.Key;
     user.private_key = Some(data.PrivateKey);
     user.reset_security_stamp();
@@ -327,6 +617,67 @@ fn post_sstamp(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -
     user.save(&conn)
 }
 
+fn _api_key_json(user: &User, api_key: &str) -> Value {
+    json!({
+        "ClientId": user.api_client_id(),
+        "ApiKey": api_key,
+        "RevisionDate": crate::util::format_date(&user.updated_at),
+        "Object": "apiKey",
+    })
+}
+
+/// Returns the API key of the user, generating it the first time
+#[post("/accounts/api-key", data = "<data>")]
+fn post_api_key(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+    let mut user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    let api_key = match user.api_key.clone() {
+        Some(api_key) => api_key,
+        None => {
+            let api_key = user.new_api_key();
+            user.save(&conn)?;
+            api_key
+        }
+    };
+
+    Ok(Json(_api_key_json(&user, &api_key)))
+}
+
+/// Replaces the API key, the previous one can't be used to log in anymore
+#[post("/accounts/rotate-api-key", data = "<data>")]
+fn post_rotate_api_key(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+    let mut user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    let api_key = user.new_api_key();
+    user.save(&conn)?;
+
+    Ok(Json(_api_key_json(&user, &api_key)))
+}
+
+/// Revokes the API key, which also rejects the access tokens already issued for it
+#[delete("/accounts/api-key", data = "<data>")]
+fn delete_api_key(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    let data: PasswordData = data.into_inner().data;
+    let mut user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    user.api_key = None;
+    user.save(&conn)
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct EmailTokenData {
@@ -374,12 +725,67 @@ fn post_email(data: JsonUpcase<ChangeEmailData>, headers: Headers, conn: DbConn)
         err!("Email already in use");
     }
 
+    check_password_hash_format(&data.NewMasterPasswordHash, user.client_kdf_type)?;
     user.email = data.NewEmail;
 
     user.set_password(&data.NewMasterPasswordHash);
     user.akey = data.Key;
 
-    user.save(&conn)
+    user.save(&conn)?;
+    revoke_other_sessions(&user, &headers.device, &conn)?;
+    revoke_memberships_outside_domains(&user, &conn)
+}
+
+/// Removes the user from the organizations with the `MembershipDomains` policy that don't allow their email domain.
+/// Like with the other policies, owners and admins are exempt. Removing the membership also removes the copy
+/// of the organization key encrypted for the user, along with their collections of that organization
+fn revoke_memberships_outside_domains(user: &User, conn: &DbConn) -> EmptyResult {
+    let domain = match user.email.rsplit('@').next() {
+        Some(domain) => domain.to_lowercase(),
+        None => return Ok(()),
+    };
+
+    for user_org in UserOrganization::find_any_state_by_user(&user.uuid, conn) {
+        if user_org.atype >= UserOrgType::Admin {
+            continue;
+        }
+
+        let policy_type = OrgPolicyType::MembershipDomains as i32;
+        let policy = match OrgPolicy::find_by_org_and_type(&user_org.org_uuid, policy_type, conn) {
+            Some(policy) if policy.enabled => policy,
+            _ => continue,
+        };
+
+        if policy.membership_domains().contains(&domain) {
+            continue;
+        }
+
+        let org_name = match Organization::find_by_uuid(&user_org.org_uuid, conn) {
+            Some(org) => org.name,
+            None => continue,
+        };
+
+        info!(
+            "Removing user {} from organization {}, their email domain is not allowed",
+            user.uuid, user_org.org_uuid
+        );
+
+        let mut event = Event::new(EventType::OrganizationUserRemoved, Some(user.uuid.clone()));
+        event.org_uuid = Some(user_org.org_uuid.clone());
+        event.save_or_log(conn);
+
+        user_org.delete(conn)?;
+
+        // The email is already changed, so a failure to notify the user shouldn't fail the request
+        if CONFIG.mail_enabled() {
+            let locale = user.locale(crate::util::DEFAULT_LOCALE);
+            if let Err(e) = mail::send_membership_revoked(&user.email, &org_name, locale) {
+                error!("Error sending the membership removal email: {:#?}", e);
+            }
+        }
+    }
+
+    Ok(())
 }
 
 #[post("/accounts/delete", data = "<data>")]
@@ -405,6 +811,133 @@ fn revision_date(headers: Headers) -> String {
     revision_date.to_string()
 }
 
+#[get("/accounts/culture")]
+fn get_culture(headers: Headers, locale: ClientLocale) -> JsonResult {
+    Ok(Json(json!({
+        "Culture": headers.user.locale(locale.locale),
+        "Preferred": headers.user.culture,
+        "Object": "culture",
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct CultureData {
+    Culture: Option<String>,
+}
+
+#[put("/accounts/culture", data = "<data>")]
+fn put_culture(data: JsonUpcase<CultureData>, headers: Headers, conn: DbConn, locale: ClientLocale) -> JsonResult {
+    post_culture(data, headers, conn, locale)
+}
+
+#[post("/accounts/culture", data = "<data>")]
+fn post_culture(data: JsonUpcase<CultureData>, headers: Headers, conn: DbConn, locale: ClientLocale) -> JsonResult {
+    let data: CultureData = data.into_inner().data;
+    let mut user = headers.user;
+
+    // An empty value removes the preference, so the one requested by the client is used again
+    user.culture = match data.Culture.as_ref().map(|c| c.trim()) {
+        None | Some("") => None,
+        Some(culture) => match crate::util::find_locale(culture) {
+            Some(supported) => Some(supported.to_string()),
+            None => err!(format!("Unsupported culture: {}", culture)),
+        },
+    };
+    user.save(&conn)?;
+
+    Ok(Json(json!({
+        "Culture": user.locale(locale.locale),
+        "Preferred": user.culture,
+        "Object": "culture",
+    })))
+}
+
+#[derive(FromForm)]
+struct ActivityData {
+    page: Option<i64>,
+    #[form(field = "pageSize")]
+    page_size: Option<i64>,
+}
+
+const ACTIVITY_PAGE_SIZE_DEFAULT: i64 = 50;
+const ACTIVITY_PAGE_SIZE_MAX: i64 = 200;
+
+impl ActivityData {
+    /// The requested page and page size, clamped to the allowed values
+    fn page_and_size(&self) -> (i64, i64) {
+        let page = self.page.unwrap_or(0).max(0);
+        let page_size = self
+            .page_size
+            .unwrap_or(ACTIVITY_PAGE_SIZE_DEFAULT)
+            .max(1)
+            .min(ACTIVITY_PAGE_SIZE_MAX);
+
+        (page, page_size)
+    }
+}
+
+#[get("/accounts/activity?<data..>")]
+fn get_activity(data: Form<ActivityData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let (page, page_size) = data.page_and_size();
+
+    let login_events = [
+        EventType::UserLoggedIn as i32,
+        EventType::UserFailedLogIn as i32,
+        EventType::UserFailedLogIn2fa as i32,
+    ];
+
+    let events = Event::find_by_user_and_types(&headers.user.uuid, &login_events, page_size, page * page_size, &conn);
+    let devices = Device::find_by_user(&headers.user.uuid, &conn);
+    let events_json: Vec<Value> = events
+        .iter()
+        .map(|event| {
+            let device = devices.iter().find(|d| Some(&d.uuid) == event.device_uuid.as_ref());
+            event.to_json(device.map(|d| d.name.as_str()))
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": events_json,
+        "Object": "list",
+        "Page": page,
+        "PageSize": page_size,
+    })))
+}
+
+/// The security notices the user hasn't acknowledged yet, newest first
+#[get("/accounts/security-notices?<data..>")]
+fn get_security_notices(data: Form<ActivityData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let (page, page_size) = data.page_and_size();
+
+    let notices = SecurityNotice::find_unacknowledged_by_user(&headers.user.uuid, page_size, page * page_size, &conn);
+    let notices_json: Vec<Value> = notices.iter().map(SecurityNotice::to_json).collect();
+
+    Ok(Json(json!({
+        "Data": notices_json,
+        "Object": "list",
+        "Page": page,
+        "PageSize": page_size,
+    })))
+}
+
+#[post("/accounts/security-notices/<uuid>/acknowledge")]
+fn post_security_notice_acknowledge(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let mut notice = match SecurityNotice::find_by_uuid_and_user(&uuid, &headers.user.uuid, &conn) {
+        Some(notice) => notice,
+        None => err!("Security notice not found"),
+    };
+
+    notice.acknowledge(&conn)?;
+
+    Ok(Json(notice.to_json()))
+}
+
+#[post("/accounts/security-notices/acknowledge")]
+fn post_security_notices_acknowledge(headers: Headers, conn: DbConn) -> EmptyResult {
+    SecurityNotice::acknowledge_all_by_user(&headers.user.uuid, &conn)
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct PasswordHintData {
@@ -412,16 +945,21 @@ struct PasswordHintData {
 }
 
 #[post("/accounts/password-hint", data = "<data>")]
-fn password_hint(data: JsonUpcase<PasswordHintData>, conn: DbConn) -> EmptyResult {
+fn password_hint(data: JsonUpcase<PasswordHintData>, conn: DbConn, locale: ClientLocale) -> EmptyResult {
     let data: PasswordHintData = data.into_inner().data;
 
-    let hint = match User::find_by_mail(&data.Email, &conn) {
-        Some(user) => user.password_hint,
+    if CONFIG.disable_email_recovery() {
+        err!("Recovery is disabled on this server")
+    }
+
+    let user = match User::find_by_mail(&data.Email, &conn) {
+        Some(user) => user,
         None => return Ok(()),
     };
+    let hint = user.password_hint.clone();
 
     if CONFIG.mail_enabled() {
-        mail::send_password_hint(&data.Email, hint)?;
+        mail::send_password_hint(&data.Email, hint, user.locale(locale.locale))?;
     } else if CONFIG.show_password_hint() {
         if let Some(hint) = hint {
             err!(format!("Your password hint is: {}", &hint));
@@ -433,23 +971,160 @@ fn password_hint(data: JsonUpcase<PasswordHintData>, conn: DbConn) -> EmptyResul
     Ok(())
 }
 
+/// Minimum time between two magic link emails for the same account
+const MAGIC_LINK_INTERVAL_SECONDS: i64 = 60;
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct MagicLinkData {
+    Email: String,
+}
+
+/// Sends a single-use login link to the user. To not disclose which accounts exist, it always succeeds
+#[post("/accounts/magic-link", data = "<data>")]
+fn post_magic_link(data: JsonUpcase<MagicLinkData>, conn: DbConn, ip: ClientIp, locale: ClientLocale) -> EmptyResult {
+    use chrono::{Duration, Utc};
+
+    let data: MagicLinkData = data.into_inner().data;
+
+    if CONFIG.disable_email_recovery() {
+        err!("Recovery is disabled on this server")
+    }
+
+    if !CONFIG.magic_link_enabled() || !CONFIG.mail_enabled() {
+        err!("Magic link login is disabled")
+    }
+
+    // Counted for every email, so it doesn't tell the accounts apart either
+    if let Some(seconds) = lockout::register_magic_link_request(&ip.ip.to_string()) {
+        return Err(lockout::ratelimited_error(
+            seconds,
+            format!("Magic link requests rate limited. IP: {}", ip.ip),
+        ));
+    }
+
+    // Invited users don't have an account to log in to yet
+    let mut user = match User::find_by_mail(&data.Email, &conn) {
+        Some(user) if !user.password_hash.is_empty() => user,
+        _ => return Ok(()),
+    };
+
+    if let Some(sent_at) = user.magic_link_sent_at {
+        if Utc::now().naive_utc() < sent_at + Duration::seconds(MAGIC_LINK_INTERVAL_SECONDS) {
+            warn!("Magic link for {} requested again too soon, ignoring", user.email);
+            return Ok(());
+        }
+    }
+
+    let nonce = user.new_magic_link_nonce();
+    user.save(&conn)?;
+
+    // A failure to send it would otherwise tell that the account exists
+    if let Err(e) = mail::send_magic_link(
+        &user.email,
+        &user.uuid,
+        &nonce,
+        &ip.ip.to_string(),
+        user.locale(locale.locale),
+    ) {
+        error!("Error sending the magic link email: {:#?}", e);
+    }
+
+    Ok(())
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct PreloginData {
     Email: String,
 }
 
+/// The KDF parameters the client needs before deriving the master key. The unknown emails get the parameters
+/// of a new account instead of an error, and the response is built the same way, so it doesn't tell them apart
 #[post("/accounts/prelogin", data = "<data>")]
 fn prelogin(data: JsonUpcase<PreloginData>, conn: DbConn) -> JsonResult {
     let data: PreloginData = data.into_inner().data;
 
+    let default_iter = std::cmp::max(User::CLIENT_KDF_ITER_REGISTER, CONFIG.client_kdf_iter_minimum());
     let (kdf_type, kdf_iter) = match User::find_by_mail(&data.Email, &conn) {
-        Some(user) => (user.client_kdf_type, user.client_kdf_iter),
-        None => (User::CLIENT_KDF_TYPE_DEFAULT, User::CLIENT_KDF_ITER_DEFAULT),
+        // Not a valid iteration count, answered like an unknown email
+        Some(ref user) if user.client_kdf_iter <= 0 => (user.client_kdf_type, default_iter),
+        Some(ref user) => (user.client_kdf_type, user.client_kdf_iter),
+        None => (User::CLIENT_KDF_TYPE_DEFAULT, default_iter),
     };
 
     Ok(Json(json!({
         "Kdf": kdf_type,
-        "KdfIterations": kdf_iter
+        "KdfIterations": kdf_iter,
+        // Only used by Argon2, PBKDF2 only has the iterations
+        "KdfMemory": Value::Null,
+        "KdfParallelism": Value::Null,
     })))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn master_password_policy(data: &str) -> OrgPolicy {
+        let mut policy = OrgPolicy::new("org".into(), OrgPolicyType::MasterPassword, data.into());
+        policy.enabled = true;
+        policy
+    }
+
+    fn strength(score: u64, length: u64) -> PasswordStrengthData {
+        PasswordStrengthData {
+            Score: score,
+            Length: length,
+            HasUpper: true,
+            HasLower: true,
+            HasNumbers: true,
+            HasSpecial: false,
+        }
+    }
+
+    #[test]
+    fn enforces_the_minimum_length() {
+        let policies = vec![master_password_policy(r#"{"minLength": 12}"#)];
+
+        assert!(enforce_master_password_policy(Some(&strength(4, 11)), &policies).is_err());
+        assert!(enforce_master_password_policy(Some(&strength(4, 12)), &policies).is_ok());
+    }
+
+    #[test]
+    fn enforces_the_complexity() {
+        let policies = vec![master_password_policy(r#"{"minComplexity": 3}"#)];
+
+        assert!(enforce_master_password_policy(Some(&strength(2, 30)), &policies).is_err());
+        assert!(enforce_master_password_policy(Some(&strength(3, 30)), &policies).is_ok());
+    }
+
+    #[test]
+    fn enforces_the_required_characters() {
+        let policies = vec![master_password_policy(r#"{"requireUpper": true, "requireNumbers": true}"#)];
+        assert!(enforce_master_password_policy(Some(&strength(4, 30)), &policies).is_ok());
+
+        let policies = vec![master_password_policy(r#"{"requireSpecial": true}"#)];
+        assert!(enforce_master_password_policy(Some(&strength(4, 30)), &policies).is_err());
+    }
+
+    #[test]
+    fn combines_the_policies_of_several_organizations() {
+        let policies = vec![
+            master_password_policy(r#"{"minLength": 8, "minComplexity": 4}"#),
+            master_password_policy(r#"{"minLength": 16}"#),
+        ];
+
+        assert!(enforce_master_password_policy(Some(&strength(4, 12)), &policies).is_err());
+        assert!(enforce_master_password_policy(Some(&strength(3, 16)), &policies).is_err());
+        assert!(enforce_master_password_policy(Some(&strength(4, 16)), &policies).is_ok());
+    }
+
+    #[test]
+    fn accepts_any_password_without_requirements() {
+        let policies = vec![master_password_policy(r#"{"enforceOnLogin": true}"#)];
+
+        assert!(enforce_master_password_policy(Some(&strength(0, 1)), &policies).is_ok());
+        assert!(enforce_master_password_policy(None, &[]).is_ok());
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use rocket::http::ContentType;
+use rocket::{Data, Route};
+
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use multipart::server::save::SavedData;
+use multipart::server::{Multipart, SaveResult};
+
+use data_encoding::HEXLOWER;
+
+use crate::db::models::*;
+use crate::db::DbConn;
+
+use crate::crypto;
+
+use crate::api::{EmptyResult, JsonResult, JsonUpcase, Notify, UpdateType};
+use crate::auth::Headers;
+
+use crate::CONFIG;
+
+/// Only registered with the `attachments` feature, the attachments of the existing ciphers are still returned with them
+pub fn routes() -> Vec<Route> {
+    routes![
+        post_attachment,
+        post_attachment_admin,
+        post_attachment_share,
+        delete_attachment_post,
+        delete_attachment_post_admin,
+        delete_attachment,
+        delete_attachment_admin,
+        get_attachments,
+        delete_attachments,
+    ]
+}
+
+#[post("/ciphers/<uuid>/attachment", format = "multipart/form-data", data = "<data>")]
+fn post_attachment(
+    uuid: String,
+    data: Data,
+    content_type: &ContentType,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher is not write accessible")
+    }
+
+    let max_attachments = CONFIG.max_attachments_per_cipher();
+    let mut attachment_count = Attachment::find_by_cipher(&cipher.uuid, &conn).len() as i64;
+    if attachment_limit_reached(attachment_count, max_attachments) {
+        err!(format!("A cipher can't have more than {} attachments", max_attachments))
+    }
+    let mut rejected_files = 0;
+
+    let mut params = content_type.params();
+    let boundary_pair = params.next().expect("No boundary provided");
+    let boundary = boundary_pair.1;
+
+    let base_path = Path::new(&CONFIG.attachments_folder()).join(&cipher.uuid);
+
+    let mut attachment_key = None;
+
+    Multipart::with_body(data.open(), boundary)
+        .foreach_entry(|mut field| {
+            match &*field.headers.name {
+                "key" => {
+                    use std::io::Read;
+                    let mut key_buffer = String::new();
+                    if field.data.read_to_string(&mut key_buffer).is_ok() {
+                        attachment_key = Some(key_buffer);
+                    }
+                }
+                "data" => {
+                    // The limit was checked above, but a single request could have more than one file
+                    if attachment_limit_reached(attachment_count, max_attachments) {
+                        rejected_files += 1;
+                        return;
+                    }
+
+                    // This is provided by the client, don't trust it
+                    let name = field.headers.filename.expect("No filename provided");
+
+                    let file_name = HEXLOWER.encode(&crypto::get_random(vec![0; 10]));
+                    let path = base_path.join(&file_name);
+
+                    let size = match field.data.save().memory_threshold(0).size_limit(None).with_path(path) {
+                        SaveResult::Full(SavedData::File(_, size)) => size as i32,
+                        SaveResult::Full(other) => {
+                            error!("Attachment is not a file: {:?}", other);
+                            return;
+                        }
+                        SaveResult::Partial(_, reason) => {
+                            error!("Partial result: {:?}", reason);
+                            return;
+                        }
+                        SaveResult::Error(e) => {
+                            error!("Error: {:?}", e);
+                            return;
+                        }
+                    };
+
+                    let mut attachment = Attachment::new(file_name, cipher.uuid.clone(), name, size);
+                    attachment.akey = attachment_key.clone();
+                    attachment.save(&conn).expect("Error saving attachment");
+                    attachment_count += 1;
+                }
+                _ => error!("Invalid multipart name"),
+            }
+        })
+        .expect("Error processing multipart data");
+
+    nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(&conn));
+
+    // The files before the limit are kept, the client is told about the others
+    if rejected_files > 0 {
+        err!(format!(
+            "A cipher can't have more than {} attachments, {} of the files weren't saved",
+            max_attachments, rejected_files
+        ))
+    }
+
+    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
+}
+
+/// Whether a cipher with `count` attachments can't have another one, `max_attachments` is 0 for no limit
+fn attachment_limit_reached(count: i64, max_attachments: i64) -> bool {
+    max_attachments > 0 && count >= max_attachments
+}
+
+#[post("/ciphers/<uuid>/attachment-admin", format = "multipart/form-data", data = "<data>")]
+fn post_attachment_admin(
+    uuid: String,
+    data: Data,
+    content_type: &ContentType,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    post_attachment(uuid, data, content_type, headers, conn, nt)
+}
+
+#[post(
+    "/ciphers/<uuid>/attachment/<attachment_id>/share",
+    format = "multipart/form-data",
+    data = "<data>"
+)]
+fn post_attachment_share(
+    uuid: String,
+    attachment_id: String,
+    data: Data,
+    content_type: &ContentType,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, &conn, &nt)?;
+    post_attachment(uuid, data, content_type, headers, conn, nt)
+}
+
+#[post("/ciphers/<uuid>/attachment/<attachment_id>/delete-admin")]
+fn delete_attachment_post_admin(
+    uuid: String,
+    attachment_id: String,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> EmptyResult {
+    delete_attachment(uuid, attachment_id, headers, conn, nt)
+}
+
+#[post("/ciphers/<uuid>/attachment/<attachment_id>/delete")]
+fn delete_attachment_post(
+    uuid: String,
+    attachment_id: String,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> EmptyResult {
+    delete_attachment(uuid, attachment_id, headers, conn, nt)
+}
+
+#[delete("/ciphers/<uuid>/attachment/<attachment_id>")]
+fn delete_attachment(uuid: String, attachment_id: String, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
+    _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, &conn, &nt)
+}
+
+#[delete("/ciphers/<uuid>/attachment/<attachment_id>/admin")]
+fn delete_attachment_admin(
+    uuid: String,
+    attachment_id: String,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> EmptyResult {
+    _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, &conn, &nt)
+}
+
+#[get("/ciphers/attachments")]
+fn get_attachments(headers: Headers, conn: DbConn) -> JsonResult {
+    let attachments = Attachment::find_owned_by_user(&headers.user.uuid, &conn);
+    let attachments_json: Vec<Value> = attachments
+        .iter()
+        .map(|a| {
+            let mut json = a.to_json(&headers.host);
+            json["CipherId"] = Value::String(a.cipher_uuid.clone());
+            json
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": attachments_json,
+        "Object": "list",
+        "StorageUsed": Attachment::size_owned_by_user(&headers.user.uuid, &conn),
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct DeleteAttachmentsData {
+    Ids: Vec<String>,
+}
+
+#[post("/ciphers/attachments/delete", data = "<data>")]
+fn delete_attachments(
+    data: JsonUpcase<DeleteAttachmentsData>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let data: DeleteAttachmentsData = data.into_inner().data;
+
+    // Check all the attachments before deleting anything
+    let mut attachments = Vec::new();
+    let mut ciphers: HashMap<String, Cipher> = HashMap::new();
+    for id in data.Ids {
+        let attachment = match Attachment::find_by_id(&id, &conn) {
+            Some(attachment) => attachment,
+            None => err!("Attachment doesn't exist"),
+        };
+
+        if !ciphers.contains_key(&attachment.cipher_uuid) {
+            let cipher = match Cipher::find_by_uuid(&attachment.cipher_uuid, &conn) {
+                Some(cipher) => cipher,
+                None => err!("Cipher doesn't exist"),
+            };
+
+            if cipher.user_uuid.as_ref() != Some(&headers.user.uuid) {
+                err!("Attachment is not owned by user")
+            }
+            ciphers.insert(attachment.cipher_uuid.clone(), cipher);
+        }
+
+        attachments.push(attachment);
+    }
+
+    Attachment::delete_all(attachments, &conn)?;
+
+    for cipher in ciphers.values() {
+        nt.send_cipher_update(UpdateType::CipherUpdate, cipher, &cipher.update_users_revision(&conn));
+    }
+
+    Ok(Json(json!({
+        "StorageUsed": Attachment::size_owned_by_user(&headers.user.uuid, &conn),
+    })))
+}
+
+fn _delete_cipher_attachment_by_id(
+    uuid: &str,
+    attachment_id: &str,
+    headers: &Headers,
+    conn: &DbConn,
+    nt: &Notify,
+) -> EmptyResult {
+    let attachment = match Attachment::find_by_id(&attachment_id, &conn) {
+        Some(attachment) => attachment,
+        None => err!("Attachment doesn't exist"),
+    };
+
+    if attachment.cipher_uuid != uuid {
+        err!("Attachment from other cipher")
+    }
+
+    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher cannot be deleted by user")
+    }
+
+    // Delete attachment
+    attachment.delete(&conn)?;
+    nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(&conn));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::attachment_limit_reached;
+
+    /// Counts the files of an upload like `post_attachment`, returning the saved and the rejected ones
+    fn upload(existing: i64, files: i64, max_attachments: i64) -> (i64, i64) {
+        let mut count = existing;
+        let mut rejected = 0;
+        for _ in 0..files {
+            if attachment_limit_reached(count, max_attachments) {
+                rejected += 1;
+            } else {
+                count += 1;
+            }
+        }
+        (count - existing, rejected)
+    }
+
+    #[test]
+    fn accepts_the_files_up_to_the_limit() {
+        assert_eq!(upload(0, 3, 3), (3, 0));
+        assert_eq!(upload(2, 1, 3), (1, 0));
+    }
+
+    #[test]
+    fn rejects_the_files_beyond_the_limit() {
+        assert_eq!(upload(0, 5, 3), (3, 2));
+        assert_eq!(upload(3, 1, 3), (0, 1));
+    }
+
+    #[test]
+    fn zero_is_no_limit() {
+        assert_eq!(upload(1_000, 10, 0), (10, 0));
+    }
+}
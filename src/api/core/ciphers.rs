@@ -1,23 +1,14 @@
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
 
-use rocket::http::ContentType;
-use rocket::{request::Form, Data, Route};
+use rocket::{request::Form, Route};
 
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
-use multipart::server::save::SavedData;
-use multipart::server::{Multipart, SaveResult};
-
-use data_encoding::HEXLOWER;
-
 use crate::db::models::*;
 use crate::db::DbConn;
 
-use crate::crypto;
-
-use crate::api::{self, EmptyResult, JsonResult, JsonUpcase, Notify, PasswordData, UpdateType};
+use crate::api::{self, ApiResult, EmptyResult, JsonResult, JsonUpcase, Notify, PasswordData, UpdateType};
 use crate::auth::Headers;
 
 use crate::CONFIG;
@@ -34,13 +25,6 @@ pub fn routes() -> Vec<Route> {
         post_ciphers_admin,
         post_ciphers_create,
         post_ciphers_import,
-        post_attachment,
-        post_attachment_admin,
-        post_attachment_share,
-        delete_attachment_post,
-        delete_attachment_post_admin,
-        delete_attachment,
-        delete_attachment_admin,
         post_cipher_admin,
         post_cipher_share,
         put_cipher_share,
@@ -60,6 +44,13 @@ pub fn routes() -> Vec<Route> {
         post_collections_update,
         post_collections_admin,
         put_collections_admin,
+        get_search_metadata,
+        post_cipher_transfer,
+        get_cipher_transfers,
+        accept_cipher_transfer,
+        delete_cipher_transfer,
+        get_cipher_revisions,
+        restore_cipher_revision,
     ]
 }
 
@@ -85,6 +76,14 @@ fn sync(data: Form<SyncData>, headers: Headers, conn: DbConn) -> JsonResult {
         .map(|c| c.to_json(&headers.host, &headers.user.uuid, &conn))
         .collect();
 
+    let sends = Send::find_by_user(&headers.user.uuid, &conn);
+    let sends_json: Vec<Value> = sends.iter().map(Send::to_json).collect();
+
+    let policies = OrgPolicy::find_confirmed_by_user(&headers.user.uuid, &conn);
+    let policies_json: Vec<Value> = policies.iter().map(OrgPolicy::to_json).collect();
+
+    let vault_timeout_json = api::vault_timeout_json(&headers.user.uuid, &conn);
+
     let domains_json = if data.exclude_domains {
         Value::Null
     } else {
@@ -97,6 +96,9 @@ fn sync(data: Form<SyncData>, headers: Headers, conn: DbConn) -> JsonResult {
         "Collections": collections_json,
         "Ciphers": ciphers_json,
         "Domains": domains_json,
+        "Sends": sends_json,
+        "Policies": policies_json,
+        "VaultTimeout": vault_timeout_json,
         "Object": "sync"
     })))
 }
@@ -148,7 +150,7 @@ pub struct CipherData {
     // Id is optional as it is included only in bulk share
     pub Id: Option<String>,
     // Folder id is not included in import
-    FolderId: Option<String>,
+    pub FolderId: Option<String>,
     // TODO: Some of these might appear all the time, no need for Option
     OrganizationId: Option<String>,
 
@@ -176,7 +178,7 @@ pub struct CipherData {
     // These are used during key rotation
     #[serde(rename = "Attachments")]
     _Attachments: Option<Value>, // Unused, contains map of {id: filename}
-    Attachments2: Option<HashMap<String, Attachments2Data>>,
+    pub Attachments2: Option<HashMap<String, Attachments2Data>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -287,6 +289,10 @@ pub fn update_cipher_from_data(
         None => err!("Data missing"),
     };
 
+    if data.Type == 1 && CONFIG.validate_uri_match() {
+        check_uri_matches(&type_data)?;
+    }
+
     // TODO: ******* Backwards compat start **********
     // To remove backwards compatibility, just delete this code,
     // and remove the compat code from cipher::to_json
@@ -296,6 +302,12 @@ pub fn update_cipher_from_data(
     type_data["PasswordHistory"] = data.PasswordHistory.clone().unwrap_or(Value::Null);
     // TODO: ******* Backwards compat end **********
 
+    // Keep the previous version, before it's replaced
+    let revision = match ut {
+        UpdateType::CipherUpdate if CONFIG.cipher_history_depth() > 0 => Some(CipherRevision::from_cipher(cipher)),
+        _ => None,
+    };
+
     cipher.favorite = data.Favorite.unwrap_or(false);
     cipher.name = data.Name;
     cipher.notes = data.Notes;
@@ -306,6 +318,10 @@ pub fn update_cipher_from_data(
     cipher.save(&conn)?;
     cipher.move_to_folder(data.FolderId, &headers.user.uuid, &conn)?;
 
+    if let Some(revision) = revision {
+        revision.save_bounded(CONFIG.cipher_history_depth(), &conn)?;
+    }
+
     if ut != UpdateType::None {
         nt.send_cipher_update(ut, &cipher, &cipher.update_users_revision(&conn));
     }
@@ -313,6 +329,54 @@ pub fn update_cipher_from_data(
     Ok(())
 }
 
+/// Checks that the match detection of every URI of a login is a known one, and that the regular expressions
+/// compile when they are not encrypted. The URIs without a match detection use the default of the client
+fn check_uri_matches(login: &Value) -> EmptyResult {
+    use num_traits::FromPrimitive;
+
+    let uris = match login["Uris"].as_array() {
+        Some(uris) => uris,
+        None => return Ok(()),
+    };
+
+    for uri in uris {
+        let match_type = match uri["Match"] {
+            Value::Null => continue,
+            Value::Number(ref number) => number.as_i64(),
+            Value::String(ref string) => string.parse::<i64>().ok(),
+            _ => None,
+        };
+
+        let match_type = match match_type.and_then(UriMatchType::from_i64) {
+            Some(match_type) => match_type,
+            None => err!(format!("Invalid URI match detection: {}", uri["Match"])),
+        };
+
+        if let (UriMatchType::RegularExpression, Some(pattern)) = (match_type, uri["Uri"].as_str()) {
+            if !is_encrypted_string(pattern) && regex::Regex::new(pattern).is_err() {
+                err!(format!("Invalid regular expression in URI: {}", pattern))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the value looks like the strings encrypted by the clients: the encryption type, a dot,
+/// and the base64 encoded parts separated by pipes
+pub fn is_encrypted_string(value: &str) -> bool {
+    let mut parts = value.splitn(2, '.');
+
+    match (parts.next(), parts.next()) {
+        (Some(enc_type), Some(data)) => {
+            enc_type.parse::<u8>().is_ok()
+                && !data.is_empty()
+                && data.chars().all(|c| c.is_ascii_alphanumeric() || "+/=|".contains(c))
+        }
+        _ => false,
+    }
+}
+
 use super::folders::FolderData;
 
 #[derive(Deserialize)]
@@ -631,147 +695,6 @@ fn share_cipher_by_uuid(
     }
 }
 
-#[post("/ciphers/<uuid>/attachment", format = "multipart/form-data", data = "<data>")]
-fn post_attachment(
-    uuid: String,
-    data: Data,
-    content_type: &ContentType,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify,
-) -> JsonResult {
-    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
-        Some(cipher) => cipher,
-        None => err!("Cipher doesn't exist"),
-    };
-
-    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
-        err!("Cipher is not write accessible")
-    }
-
-    let mut params = content_type.params();
-    let boundary_pair = params.next().expect("No boundary provided");
-    let boundary = boundary_pair.1;
-
-    let base_path = Path::new(&CONFIG.attachments_folder()).join(&cipher.uuid);
-
-    let mut attachment_key = None;
-
-    Multipart::with_body(data.open(), boundary)
-        .foreach_entry(|mut field| {
-            match &*field.headers.name {
-                "key" => {
-                    use std::io::Read;
-                    let mut key_buffer = String::new();
-                    if field.data.read_to_string(&mut key_buffer).is_ok() {
-                        attachment_key = Some(key_buffer);
-                    }
-                }
-                "data" => {
-                    // This is provided by the client, don't trust it
-                    let name = field.headers.filename.expect("No filename provided");
-
-                    let file_name = HEXLOWER.encode(&crypto::get_random(vec![0; 10]));
-                    let path = base_path.join(&file_name);
-
-                    let size = match field.data.save().memory_threshold(0).size_limit(None).with_path(path) {
-                        SaveResult::Full(SavedData::File(_, size)) => size as i32,
-                        SaveResult::Full(other) => {
-                            error!("Attachment is not a file: {:?}", other);
-                            return;
-                        }
-                        SaveResult::Partial(_, reason) => {
-                            error!("Partial result: {:?}", reason);
-                            return;
-                        }
-                        SaveResult::Error(e) => {
-                            error!("Error: {:?}", e);
-                            return;
-                        }
-                    };
-
-                    let mut attachment = Attachment::new(file_name, cipher.uuid.clone(), name, size);
-                    attachment.akey = attachment_key.clone();
-                    attachment.save(&conn).expect("Error saving attachment");
-                }
-                _ => error!("Invalid multipart name"),
-            }
-        })
-        .expect("Error processing multipart data");
-
-    nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(&conn));
-
-    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
-}
-
-#[post("/ciphers/<uuid>/attachment-admin", format = "multipart/form-data", data = "<data>")]
-fn post_attachment_admin(
-    uuid: String,
-    data: Data,
-    content_type: &ContentType,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify,
-) -> JsonResult {
-    post_attachment(uuid, data, content_type, headers, conn, nt)
-}
-
-#[post(
-    "/ciphers/<uuid>/attachment/<attachment_id>/share",
-    format = "multipart/form-data",
-    data = "<data>"
-)]
-fn post_attachment_share(
-    uuid: String,
-    attachment_id: String,
-    data: Data,
-    content_type: &ContentType,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify,
-) -> JsonResult {
-    _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, &conn, &nt)?;
-    post_attachment(uuid, data, content_type, headers, conn, nt)
-}
-
-#[post("/ciphers/<uuid>/attachment/<attachment_id>/delete-admin")]
-fn delete_attachment_post_admin(
-    uuid: String,
-    attachment_id: String,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify,
-) -> EmptyResult {
-    delete_attachment(uuid, attachment_id, headers, conn, nt)
-}
-
-#[post("/ciphers/<uuid>/attachment/<attachment_id>/delete")]
-fn delete_attachment_post(
-    uuid: String,
-    attachment_id: String,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify,
-) -> EmptyResult {
-    delete_attachment(uuid, attachment_id, headers, conn, nt)
-}
-
-#[delete("/ciphers/<uuid>/attachment/<attachment_id>")]
-fn delete_attachment(uuid: String, attachment_id: String, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
-    _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, &conn, &nt)
-}
-
-#[delete("/ciphers/<uuid>/attachment/<attachment_id>/admin")]
-fn delete_attachment_admin(
-    uuid: String,
-    attachment_id: String,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify,
-) -> EmptyResult {
-    _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, &conn, &nt)
-}
-
 #[post("/ciphers/<uuid>/delete")]
 fn delete_cipher_post(uuid: String, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
     _delete_cipher_by_uuid(&uuid, &headers, &conn, &nt)
@@ -804,6 +727,16 @@ fn delete_cipher_selected(data: JsonUpcase<Value>, headers: Headers, conn: DbCon
         None => err!("Request missing ids field"),
     };
 
+    let uuids: Vec<&str> = uuids.collect();
+
+    // Check all of them first, so that nothing is deleted if one of them can't be
+    for uuid in &uuids {
+        match Cipher::find_by_uuid(uuid, &conn) {
+            Some(cipher) => _check_cipher_deletable(&cipher, &headers, &conn)?,
+            None => err!("Cipher doesn't exist"),
+        }
+    }
+
     for uuid in uuids {
         if let error @ Err(_) = _delete_cipher_by_uuid(uuid, &headers, &conn, &nt) {
             return error;
@@ -929,48 +862,355 @@ fn delete_all(
     }
 }
 
-fn _delete_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &DbConn, nt: &Notify) -> EmptyResult {
+/// The `SEARCH_METADATA_FIELDS` of the ciphers owned by the user, the organization ciphers are not included
+#[get("/ciphers/search-metadata")]
+fn get_search_metadata(headers: Headers, conn: DbConn) -> JsonResult {
+    let fields_config = CONFIG.search_metadata_fields();
+    let fields: Vec<&str> = fields_config
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .collect();
+    if fields.is_empty() {
+        err!("The search metadata is disabled on this server")
+    }
+
+    let ciphers_json: Vec<Value> = Cipher::find_owned_by_user(&headers.user.uuid, &conn)
+        .iter()
+        .map(|c| c.to_json_search_metadata(&fields, &headers.user.uuid, &conn))
+        .collect();
+
+    Ok(Json(json!({
+        "Data": ciphers_json,
+        "Fields": fields,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct CipherTransferData {
+    MasterPasswordHash: String,
+    Email: String,
+    // Cipher contents encrypted by the client for the recipient
+    Data: String,
+}
+
+#[post("/ciphers/<uuid>/transfer", data = "<data>")]
+fn post_cipher_transfer(
+    uuid: String,
+    data: JsonUpcase<CipherTransferData>,
+    headers: Headers,
+    conn: DbConn,
+) -> JsonResult {
+    let data: CipherTransferData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
     let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
         Some(cipher) => cipher,
         None => err!("Cipher doesn't exist"),
     };
 
-    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
-        err!("Cipher can't be deleted by user")
+    if cipher.user_uuid.as_ref() != Some(&headers.user.uuid) {
+        err!("Only personal ciphers owned by the user can be transferred")
     }
 
-    cipher.delete(&conn)?;
-    nt.send_cipher_update(UpdateType::CipherDelete, &cipher, &cipher.update_users_revision(&conn));
-    Ok(())
+    let recipient = match User::find_by_mail(&data.Email, &conn) {
+        Some(user) if user.uuid != headers.user.uuid && user.public_key.is_some() => user,
+        _ => err!("The recipient can't receive ciphers"),
+    };
+
+    // Both users need to be confirmed members of a common organization
+    let recipient_orgs: HashSet<String> = UserOrganization::find_by_user(&recipient.uuid, &conn)
+        .into_iter()
+        .map(|uo| uo.org_uuid)
+        .collect();
+    let shares_org = UserOrganization::find_by_user(&headers.user.uuid, &conn)
+        .iter()
+        .any(|uo| recipient_orgs.contains(&uo.org_uuid));
+
+    if !shares_org {
+        err!("The recipient needs to be a member of one of your organizations")
+    }
+
+    // Replace any previous pending transfer of this cipher
+    CipherTransfer::delete_all_by_cipher(&cipher.uuid, &conn)?;
+
+    let transfer = CipherTransfer::new(cipher.uuid, headers.user.uuid, recipient.uuid, data.Data);
+    transfer.save(&conn)?;
+
+    Ok(Json(transfer.to_json(&conn)))
 }
 
-fn _delete_cipher_attachment_by_id(
-    uuid: &str,
-    attachment_id: &str,
-    headers: &Headers,
-    conn: &DbConn,
-    nt: &Notify,
-) -> EmptyResult {
-    let attachment = match Attachment::find_by_id(&attachment_id, &conn) {
-        Some(attachment) => attachment,
-        None => err!("Attachment doesn't exist"),
+#[get("/ciphers/transfers")]
+fn get_cipher_transfers(headers: Headers, conn: DbConn) -> JsonResult {
+    let transfers = CipherTransfer::find_by_user(&headers.user.uuid, &conn);
+    let transfers_json: Vec<Value> = transfers.iter().map(|t| t.to_json(&conn)).collect();
+
+    Ok(Json(json!({
+        "Data": transfers_json,
+        "Object": "list",
+    })))
+}
+
+#[post("/ciphers/transfers/<uuid>/accept", data = "<data>")]
+fn accept_cipher_transfer(
+    uuid: String,
+    data: JsonUpcase<CipherData>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let data: CipherData = data.into_inner().data;
+
+    let transfer = match CipherTransfer::find_by_uuid(&uuid, &conn) {
+        Some(transfer) if transfer.recipient_uuid == headers.user.uuid => transfer,
+        _ => err!("Cipher transfer doesn't exist"),
+    };
+
+    let mut cipher = match Cipher::find_by_uuid(&transfer.cipher_uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
     };
 
-    if attachment.cipher_uuid != uuid {
-        err!("Attachment from other cipher")
+    if cipher.user_uuid.as_ref() != Some(&transfer.sender_uuid) {
+        err!("The cipher is no longer owned by the sender")
     }
 
+    if data.OrganizationId.is_some() {
+        err!("Transferred ciphers can't be moved to an organization")
+    }
+
+    // The attachment keys are encrypted with the sender key, so they all need to be provided again
+    let attachments = Attachment::find_by_cipher(&cipher.uuid, &conn);
+    let provided = data.Attachments2.as_ref();
+    if attachments
+        .iter()
+        .any(|a| !provided.map_or(false, |p| p.contains_key(&a.id)))
+    {
+        err!("All the attachments need to be encrypted for the recipient")
+    }
+
+    use diesel::Connection;
+    let sender_uuid = transfer.sender_uuid.clone();
+    conn.transaction::<_, crate::error::Error, _>(|| {
+        // The folder belongs to the sender
+        FolderCipher::delete_all_by_cipher(&cipher.uuid, &conn)?;
+        update_cipher_from_data(&mut cipher, data, &headers, false, &conn, &nt, UpdateType::None)?;
+        _complete_cipher_transfer(&cipher, transfer, &conn)
+    })?;
+
+    info!(
+        "Cipher {} transferred from user {} to user {}",
+        cipher.uuid, sender_uuid, headers.user.uuid
+    );
+
+    let mut event = Event::new(EventType::CipherUpdated, Some(headers.user.uuid.clone()));
+    event.cipher_uuid = Some(cipher.uuid.clone());
+    event.save_or_log(&conn);
+
+    User::update_uuid_revision(&sender_uuid, &conn);
+    nt.send_cipher_update(UpdateType::CipherDelete, &cipher, &[sender_uuid]);
+    nt.send_cipher_update(UpdateType::CipherCreate, &cipher, &cipher.update_users_revision(&conn));
+
+    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
+}
+
+/// Once the cipher was encrypted for the recipient. Its previous versions are encrypted with the sender key,
+/// so they are removed, the recipient couldn't decrypt them
+fn _complete_cipher_transfer(cipher: &Cipher, transfer: CipherTransfer, conn: &DbConn) -> EmptyResult {
+    CipherRevision::delete_all_by_cipher(&cipher.uuid, conn)?;
+    transfer.delete(conn)
+}
+
+#[post("/ciphers/transfers/<uuid>/delete")]
+fn delete_cipher_transfer(uuid: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    // Both the sender and the recipient can cancel the transfer
+    match CipherTransfer::find_by_uuid(&uuid, &conn) {
+        Some(transfer) if transfer.sender_uuid == headers.user.uuid || transfer.recipient_uuid == headers.user.uuid => {
+            transfer.delete(&conn)
+        }
+        _ => err!("Cipher transfer doesn't exist"),
+    }
+}
+
+#[get("/ciphers/<uuid>/revisions")]
+fn get_cipher_revisions(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
     let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
         Some(cipher) => cipher,
         None => err!("Cipher doesn't exist"),
     };
 
+    if !cipher.is_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher is not owned by user")
+    }
+
+    let revisions_json: Vec<Value> = CipherRevision::find_by_cipher(&cipher.uuid, &conn)
+        .iter()
+        .map(CipherRevision::to_json)
+        .collect();
+
+    Ok(Json(json!({
+      "Data": revisions_json,
+      "Object": "list",
+      "ContinuationToken": null
+    })))
+}
+
+#[post("/ciphers/<uuid>/revisions/<revision_id>/restore")]
+fn restore_cipher_revision(
+    uuid: String,
+    revision_id: String,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let mut cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
     if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
-        err!("Cipher cannot be deleted by user")
+        err!("Cipher is not write accessible")
+    }
+
+    let revision = _find_restorable_revision(&cipher, &revision_id, &conn)?;
+
+    // The current version is kept too, so that the restore can be undone
+    let current = CipherRevision::from_cipher(&cipher);
+
+    revision.apply_to(&mut cipher);
+    cipher.save(&conn)?;
+
+    if CONFIG.cipher_history_depth() > 0 {
+        current.save_bounded(CONFIG.cipher_history_depth(), &conn)?;
     }
 
-    // Delete attachment
-    attachment.delete(&conn)?;
     nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(&conn));
+
+    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
+}
+
+fn _find_restorable_revision(cipher: &Cipher, revision_id: &str, conn: &DbConn) -> ApiResult<CipherRevision> {
+    let revision = match CipherRevision::find_by_uuid(revision_id, conn) {
+        Some(revision) if revision.cipher_uuid == cipher.uuid => revision,
+        _ => err!("Cipher revision doesn't exist"),
+    };
+
+    // The old version is encrypted with the key the cipher had back then, so it can't be restored after it was shared
+    if revision.organization_uuid != cipher.organization_uuid {
+        err!("This version of the cipher was encrypted with a different key and can't be restored")
+    }
+
+    Ok(revision)
+}
+
+fn _delete_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &DbConn, nt: &Notify) -> EmptyResult {
+    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    _check_cipher_deletable(&cipher, headers, conn)?;
+
+    cipher.delete(&conn)?;
+    nt.send_cipher_update(UpdateType::CipherDelete, &cipher, &cipher.update_users_revision(&conn));
+    Ok(())
+}
+
+fn _check_cipher_deletable(cipher: &Cipher, headers: &Headers, conn: &DbConn) -> EmptyResult {
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher can't be deleted by user")
+    }
+
+    if CONFIG.collection_delete_permission() && !cipher.is_delete_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("You don't have permission to delete ciphers from this collection")
+    }
+
+    // With the policy, editing the ciphers of the organization isn't enough to delete them, the role needs to allow it
+    if let Some(ref org_uuid) = cipher.organization_uuid {
+        let policy_type = OrgPolicyType::RestrictItemDeletion as i32;
+        if let Some(policy) = OrgPolicy::find_by_org_and_type(org_uuid, policy_type, conn).filter(|p| p.enabled) {
+            let role = UserOrganization::find_by_user_and_org(&headers.user.uuid, org_uuid, conn).map(|uo| uo.atype);
+            if !org_role_can_delete(role, &policy) {
+                err!("Due to an Enterprise Policy, you are not allowed to delete the items of this organization")
+            }
+        }
+    }
+
     Ok(())
 }
+
+/// With the `RestrictItemDeletion` policy, whether the role of the user in the organization can delete its ciphers.
+/// Editing them isn't enough, the members with the user role can't
+fn org_role_can_delete(role: Option<i32>, policy: &OrgPolicy) -> bool {
+    match role {
+        Some(atype) if atype >= UserOrgType::Admin => true,
+        Some(atype) if atype == UserOrgType::Manager => policy.managers_can_delete(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deletion_policy(data: &str) -> OrgPolicy {
+        OrgPolicy::new(String::from("org"), OrgPolicyType::RestrictItemDeletion, data.to_string())
+    }
+
+    #[test]
+    fn members_that_can_only_edit_can_not_delete() {
+        let policy = deletion_policy("{}");
+
+        assert!(!org_role_can_delete(Some(UserOrgType::User as i32), &policy));
+        assert!(!org_role_can_delete(None, &policy));
+    }
+
+    #[test]
+    fn owners_and_admins_can_delete() {
+        let policy = deletion_policy(r#"{"managersCanDelete": false}"#);
+
+        assert!(org_role_can_delete(Some(UserOrgType::Owner as i32), &policy));
+        assert!(org_role_can_delete(Some(UserOrgType::Admin as i32), &policy));
+    }
+
+    #[test]
+    fn managers_can_delete_unless_the_policy_disables_it() {
+        let manager = Some(UserOrgType::Manager as i32);
+
+        assert!(org_role_can_delete(manager, &deletion_policy("{}")));
+        assert!(org_role_can_delete(manager, &deletion_policy(r#"{"managersCanDelete": true}"#)));
+        assert!(!org_role_can_delete(manager, &deletion_policy(r#"{"managersCanDelete": false}"#)));
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn transferred_ciphers_can_not_restore_the_sender_versions() {
+        let conn = crate::migrations::test_connection();
+        let mut sender = User::new("sender@example.com".into());
+        let mut recipient = User::new("recipient@example.com".into());
+        sender.save(&conn).unwrap();
+        recipient.save(&conn).unwrap();
+
+        let mut cipher = Cipher::new(1, "Login".into());
+        cipher.user_uuid = Some(sender.uuid.clone());
+        cipher.save(&conn).unwrap();
+        let revision = CipherRevision::from_cipher(&cipher);
+        revision.save(&conn).unwrap();
+        assert!(_find_restorable_revision(&cipher, &revision.uuid, &conn).is_ok());
+
+        let transfer = CipherTransfer::new(cipher.uuid.clone(), sender.uuid, recipient.uuid.clone(), "{}".into());
+        transfer.save(&conn).unwrap();
+        cipher.user_uuid = Some(recipient.uuid);
+        cipher.save(&conn).unwrap();
+        _complete_cipher_transfer(&cipher, transfer, &conn).unwrap();
+
+        assert!(_find_restorable_revision(&cipher, &revision.uuid, &conn).is_err());
+        assert!(CipherRevision::find_by_cipher(&cipher.uuid, &conn).is_empty());
+    }
+}
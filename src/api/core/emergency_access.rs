@@ -0,0 +1,225 @@
+use chrono::Utc;
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use crate::api::{EmptyResult, JsonResult, JsonUpcase, NumberOrString};
+use crate::auth::Headers;
+use crate::db::models::*;
+use crate::db::DbConn;
+
+use rocket::Route;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_trusted,
+        get_granted,
+        get_status,
+        send_invite,
+        accept_invite,
+        confirm_access,
+        initiate_recovery,
+        approve_recovery,
+        reject_recovery,
+        delete_access,
+    ]
+}
+
+fn get_emergency_access(uuid: &str, conn: &DbConn) -> Result<EmergencyAccess, crate::error::Error> {
+    match EmergencyAccess::find_by_uuid(uuid, conn) {
+        Some(access) => Ok(access),
+        None => err!("Emergency access not found"),
+    }
+}
+
+#[get("/emergency-access/trusted")]
+fn get_trusted(headers: Headers, conn: DbConn) -> JsonResult {
+    let accesses_json: Vec<Value> = EmergencyAccess::find_by_grantor(&headers.user.uuid, &conn)
+        .iter()
+        .map(|ea| ea.to_json(&conn))
+        .collect();
+
+    Ok(Json(json!({
+        "Data": accesses_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+#[get("/emergency-access/granted")]
+fn get_granted(headers: Headers, conn: DbConn) -> JsonResult {
+    let accesses_json: Vec<Value> = EmergencyAccess::find_by_grantee(&headers.user.uuid, &conn)
+        .iter()
+        .map(|ea| ea.to_json(&conn))
+        .collect();
+
+    Ok(Json(json!({
+        "Data": accesses_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+/// Only the grantor and the grantee can see the status of the access
+#[get("/emergency-access/<uuid>/status")]
+fn get_status(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let access = get_emergency_access(&uuid, &conn)?;
+
+    if !access.is_involved(&headers.user.uuid) {
+        err!("Emergency access not found")
+    }
+
+    Ok(Json(access.to_json_status()))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct EmergencyAccessInviteData {
+    Email: String,
+    Type: NumberOrString,
+    WaitTimeDays: i32,
+}
+
+/// The grantee needs to have an account already, they see the invitation in their granted list.
+/// To not disclose which accounts exist, the unknown emails succeed the same way, without inviting anyone
+#[post("/emergency-access/invite", data = "<data>")]
+fn send_invite(data: JsonUpcase<EmergencyAccessInviteData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    use num_traits::FromPrimitive;
+
+    let data: EmergencyAccessInviteData = data.into_inner().data;
+
+    let atype = match EmergencyAccessType::from_i32(data.Type.into_i32()?) {
+        Some(atype) => atype,
+        None => err!("Invalid emergency access type"),
+    };
+
+    if data.WaitTimeDays < 1 {
+        err!("The wait time needs to be at least one day")
+    }
+
+    let grantee = match User::find_by_mail(&data.Email, &conn) {
+        Some(user) => user,
+        None => {
+            info!("Emergency access invitation to the unknown email {}, ignoring", data.Email);
+            return Ok(());
+        }
+    };
+
+    if grantee.uuid == headers.user.uuid {
+        err!("You can't grant emergency access to yourself")
+    }
+
+    if EmergencyAccess::find_by_grantor_and_grantee(&headers.user.uuid, &grantee.uuid, &conn).is_some() {
+        err!("Emergency access already granted to this user")
+    }
+
+    EmergencyAccess::new(headers.user.uuid, grantee.uuid, atype, data.WaitTimeDays).save(&conn)
+}
+
+#[post("/emergency-access/<uuid>/accept")]
+fn accept_invite(uuid: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    let mut access = get_emergency_access(&uuid, &conn)?;
+
+    if access.grantee_uuid != headers.user.uuid {
+        err!("Emergency access not found")
+    }
+
+    if access.status != EmergencyAccessStatus::Invited as i32 {
+        err!("Emergency access already accepted")
+    }
+
+    access.status = EmergencyAccessStatus::Accepted as i32;
+    access.save(&conn)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ConfirmData {
+    Key: String,
+}
+
+#[post("/emergency-access/<uuid>/confirm", data = "<data>")]
+fn confirm_access(uuid: String, data: JsonUpcase<ConfirmData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    let data: ConfirmData = data.into_inner().data;
+    let mut access = get_emergency_access(&uuid, &conn)?;
+
+    if access.grantor_uuid != headers.user.uuid {
+        err!("Emergency access not found")
+    }
+
+    if access.status != EmergencyAccessStatus::Accepted as i32 {
+        err!("Emergency access not accepted yet")
+    }
+
+    access.key_encrypted = Some(data.Key);
+    access.status = EmergencyAccessStatus::Confirmed as i32;
+    access.save(&conn)
+}
+
+#[post("/emergency-access/<uuid>/initiate")]
+fn initiate_recovery(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let mut access = get_emergency_access(&uuid, &conn)?;
+
+    if access.grantee_uuid != headers.user.uuid {
+        err!("Emergency access not found")
+    }
+
+    if access.status != EmergencyAccessStatus::Confirmed as i32 {
+        err!("Emergency access not confirmed or recovery already initiated")
+    }
+
+    access.status = EmergencyAccessStatus::RecoveryInitiated as i32;
+    access.recovery_initiated_at = Some(Utc::now().naive_utc());
+    access.save(&conn)?;
+
+    Ok(Json(access.to_json_status()))
+}
+
+#[post("/emergency-access/<uuid>/approve")]
+fn approve_recovery(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let mut access = get_emergency_access(&uuid, &conn)?;
+
+    if access.grantor_uuid != headers.user.uuid {
+        err!("Emergency access not found")
+    }
+
+    if access.status != EmergencyAccessStatus::RecoveryInitiated as i32 {
+        err!("Emergency access recovery not initiated")
+    }
+
+    access.status = EmergencyAccessStatus::RecoveryApproved as i32;
+    access.save(&conn)?;
+
+    Ok(Json(access.to_json_status()))
+}
+
+/// Rejecting sends the access back to confirmed, so the grantee can initiate the recovery again.
+/// Once approved, either explicitly or after the wait time, the access needs to be deleted instead
+#[post("/emergency-access/<uuid>/reject")]
+fn reject_recovery(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let mut access = get_emergency_access(&uuid, &conn)?;
+
+    if access.grantor_uuid != headers.user.uuid {
+        err!("Emergency access not found")
+    }
+
+    if access.current_status() != EmergencyAccessStatus::RecoveryInitiated as i32 {
+        err!("Emergency access recovery not initiated")
+    }
+
+    access.status = EmergencyAccessStatus::Confirmed as i32;
+    access.recovery_initiated_at = None;
+    access.save(&conn)?;
+
+    Ok(Json(access.to_json_status()))
+}
+
+#[delete("/emergency-access/<uuid>")]
+fn delete_access(uuid: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    let access = get_emergency_access(&uuid, &conn)?;
+
+    if !access.is_involved(&headers.user.uuid) {
+        err!("Emergency access not found")
+    }
+
+    access.delete(&conn)
+}
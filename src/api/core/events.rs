@@ -0,0 +1,296 @@
+use std::io::{self, Read};
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use num_traits::FromPrimitive;
+use rocket::http::ContentType;
+use rocket::request::Form;
+use rocket::response::{content::Content, Stream};
+use rocket::Route;
+use serde_json::Value;
+
+use crate::api::ApiResult;
+use crate::auth::AdminHeaders;
+use crate::db::models::*;
+use crate::db::DbConn;
+
+pub fn routes() -> Vec<Route> {
+    routes![get_org_events_export]
+}
+
+/// Number of events loaded from the database at a time while streaming an export
+const EXPORT_PAGE_SIZE: i64 = 500;
+/// When no start date is provided, the exports include the events of the last days
+const EXPORT_DEFAULT_DAYS: i64 = 30;
+
+#[derive(FromForm)]
+pub struct EventExportData {
+    start: Option<String>,
+    end: Option<String>,
+    format: Option<String>,
+    columns: Option<String>,
+}
+
+#[get("/organizations/<org_id>/events/export?<data..>")]
+fn get_org_events_export(
+    org_id: String,
+    data: Form<EventExportData>,
+    _headers: AdminHeaders,
+    conn: DbConn,
+) -> ApiResult<Content<Stream<EventExport>>> {
+    EventExport::new(Some(org_id), &data, conn).map(EventExport::into_response)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ExportFormat {
+    Csv,
+    Json,
+}
+
+/// The device ids aren't exported, a login with the id of an approved device doesn't need a new approval
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum EventColumn {
+    Date,
+    Type,
+    TypeName,
+    UserId,
+    OrganizationId,
+    CipherId,
+    DeviceType,
+    IpAddress,
+    ActingUserId,
+}
+
+impl EventColumn {
+    const ALL: [EventColumn; 9] = [
+        EventColumn::Date,
+        EventColumn::Type,
+        EventColumn::TypeName,
+        EventColumn::UserId,
+        EventColumn::OrganizationId,
+        EventColumn::CipherId,
+        EventColumn::DeviceType,
+        EventColumn::IpAddress,
+        EventColumn::ActingUserId,
+    ];
+
+    /// Same names as the keys of the event JSON
+    fn name(self) -> &'static str {
+        match self {
+            EventColumn::Date => "Date",
+            EventColumn::Type => "Type",
+            EventColumn::TypeName => "TypeName",
+            EventColumn::UserId => "UserId",
+            EventColumn::OrganizationId => "OrganizationId",
+            EventColumn::CipherId => "CipherId",
+            EventColumn::DeviceType => "DeviceType",
+            EventColumn::IpAddress => "IpAddress",
+            EventColumn::ActingUserId => "ActingUserId",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().cloned().find(|c| c.name().eq_ignore_ascii_case(name))
+    }
+
+    fn value(self, event: &Event) -> Value {
+        match self {
+            EventColumn::Date => Value::String(crate::util::format_date(&event.event_date)),
+            EventColumn::Type => Value::from(event.event_type),
+            EventColumn::TypeName => match EventType::from_i32(event.event_type) {
+                Some(etype) => Value::String(format!("{:?}", etype)),
+                None => Value::Null,
+            },
+            EventColumn::UserId => json!(event.user_uuid),
+            EventColumn::OrganizationId => json!(event.org_uuid),
+            EventColumn::CipherId => json!(event.cipher_uuid),
+            EventColumn::DeviceType => json!(event.device_type),
+            EventColumn::IpAddress => json!(event.ip_address),
+            EventColumn::ActingUserId => json!(event.act_user_uuid),
+        }
+    }
+}
+
+fn parse_date(date: &str) -> ApiResult<NaiveDateTime> {
+    match DateTime::parse_from_rfc3339(date) {
+        Ok(date) => Ok(date.naive_utc()),
+        Err(_) => err!("Invalid date format"),
+    }
+}
+
+/// Quotes the CSV fields that need it, and the ones that spreadsheets would read as formulas
+fn csv_field(value: &Value) -> String {
+    let text = match value {
+        Value::Null => return String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+
+    if text.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') || text.starts_with(|c| "=+-@".contains(c)) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+/// Export of the events between two dates, as CSV or as a JSON array. The events are loaded from the
+/// database a page at a time while the response is sent, so big logs are never kept in memory
+pub struct EventExport {
+    conn: DbConn,
+    org_uuid: Option<String>,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    format: ExportFormat,
+    columns: Vec<EventColumn>,
+
+    offset: i64,
+    buffer: Vec<u8>,
+    position: usize,
+    started: bool,
+    finished: bool,
+}
+
+impl EventExport {
+    /// Exports the events of the organization, or all the events when `org_uuid` is None
+    pub fn new(org_uuid: Option<String>, data: &EventExportData, conn: DbConn) -> ApiResult<Self> {
+        let format = match data.format.as_ref().map(String::as_str) {
+            None | Some("csv") => ExportFormat::Csv,
+            Some("json") => ExportFormat::Json,
+            Some(_) => err!("Invalid export format, use csv or json"),
+        };
+
+        let columns = match data.columns {
+            None => EventColumn::ALL.to_vec(),
+            Some(ref columns) => {
+                let mut selected = Vec::new();
+                for name in columns.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                    match EventColumn::from_name(name) {
+                        Some(column) => selected.push(column),
+                        None => err!(format!("Invalid column: {}", name)),
+                    }
+                }
+                if selected.is_empty() {
+                    err!("No columns selected")
+                }
+                selected
+            }
+        };
+
+        let end = match data.end {
+            Some(ref end) => parse_date(end)?,
+            None => Utc::now().naive_utc(),
+        };
+        let start = match data.start {
+            Some(ref start) => parse_date(start)?,
+            None => end - Duration::days(EXPORT_DEFAULT_DAYS),
+        };
+        if start > end {
+            err!("The start date must be before the end date")
+        }
+
+        Ok(Self {
+            conn,
+            org_uuid,
+            start,
+            end,
+            format,
+            columns,
+
+            offset: 0,
+            buffer: Vec::new(),
+            position: 0,
+            started: false,
+            finished: false,
+        })
+    }
+
+    pub fn into_response(self) -> Content<Stream<EventExport>> {
+        let content_type = match self.format {
+            ExportFormat::Csv => ContentType::CSV,
+            ExportFormat::Json => ContentType::JSON,
+        };
+
+        Content(content_type, Stream::from(self))
+    }
+
+    /// Replaces the buffer with the next page of events, adding the header and footer when needed.
+    /// The response is already being sent, so the database errors can only end it as an I/O error
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        self.buffer.clear();
+        self.position = 0;
+
+        if !self.started {
+            self.started = true;
+            match self.format {
+                ExportFormat::Csv => {
+                    let header: Vec<&str> = self.columns.iter().map(|c| c.name()).collect();
+                    self.buffer.extend_from_slice(header.join(",").as_bytes());
+                    self.buffer.extend_from_slice(b"\r\n");
+                }
+                ExportFormat::Json => self.buffer.push(b'['),
+            }
+        }
+
+        let events = Event::find_by_date_range(
+            self.org_uuid.as_ref().map(String::as_str),
+            self.start,
+            self.end,
+            EXPORT_PAGE_SIZE,
+            self.offset,
+            &self.conn,
+        )
+        .map_err(|e| {
+            error!("Error exporting the events: {:#?}", e);
+            io::Error::new(io::ErrorKind::Other, e.to_string())
+        })?;
+
+        for (i, event) in events.iter().enumerate() {
+            match self.format {
+                ExportFormat::Csv => {
+                    let row: Vec<String> = self.columns.iter().map(|c| csv_field(&c.value(event))).collect();
+                    self.buffer.extend_from_slice(row.join(",").as_bytes());
+                    self.buffer.extend_from_slice(b"\r\n");
+                }
+                ExportFormat::Json => {
+                    if self.offset > 0 || i > 0 {
+                        self.buffer.push(b',');
+                    }
+                    let object: serde_json::Map<String, Value> = self
+                        .columns
+                        .iter()
+                        .map(|c| (c.name().to_string(), c.value(event)))
+                        .collect();
+                    self.buffer
+                        .extend_from_slice(Value::Object(object).to_string().as_bytes());
+                }
+            }
+        }
+
+        self.offset += events.len() as i64;
+
+        if (events.len() as i64) < EXPORT_PAGE_SIZE {
+            self.finished = true;
+            if self.format == ExportFormat::Json {
+                self.buffer.push(b']');
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for EventExport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.buffer.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.fill_buffer()?;
+        }
+
+        let len = std::cmp::min(buf.len(), self.buffer.len() - self.position);
+        buf[..len].copy_from_slice(&self.buffer[self.position..self.position + len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
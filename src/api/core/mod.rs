@@ -1,24 +1,50 @@
-mod accounts;
+pub(crate) mod accounts;
+#[cfg(feature = "attachments")]
+mod attachments;
 mod ciphers;
+#[cfg(feature = "emergency_access")]
+mod emergency_access;
+pub(crate) mod events;
 mod folders;
 mod organizations;
+#[cfg(feature = "sends")]
+mod sends;
 pub(crate) mod two_factor;
 
 pub fn routes() -> Vec<Route> {
     let mut mod_routes = routes![
         clear_device_token,
         put_device_token,
+        put_device_keys,
+        post_device_retrieve_keys,
+        get_devices,
+        get_pending_devices,
+        post_device_approve,
+        post_device_deny,
+        post_revoke_other_devices,
+        post_device_approve_link,
         get_eq_domains,
         post_eq_domains,
         put_eq_domains,
+        put_eq_domains_global,
+        post_eq_domains_custom,
+        delete_eq_domains_custom,
         hibp_breach,
+        now,
     ];
 
     let mut routes = Vec::new();
     routes.append(&mut accounts::routes());
+    #[cfg(feature = "attachments")]
+    routes.append(&mut attachments::routes());
     routes.append(&mut ciphers::routes());
+    #[cfg(feature = "emergency_access")]
+    routes.append(&mut emergency_access::routes());
+    routes.append(&mut events::routes());
     routes.append(&mut folders::routes());
     routes.append(&mut organizations::routes());
+    #[cfg(feature = "sends")]
+    routes.append(&mut sends::routes());
     routes.append(&mut two_factor::routes());
     routes.append(&mut mod_routes);
 
@@ -28,13 +54,15 @@ pub fn routes() -> Vec<Route> {
 //
 // Move this somewhere else
 //
+use rocket::request::Form;
 use rocket::Route;
 
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
-use crate::api::{EmptyResult, JsonResult, JsonUpcase};
+use crate::api::{ApiResult, EmptyResult, JsonResult, JsonUpcase, PasswordData};
 use crate::auth::Headers;
+use crate::db::models::{Device, DeviceApprovalRequest, OrgPolicy, OrgPolicyType, User};
 use crate::db::DbConn;
 use crate::error::Error;
 
@@ -79,9 +107,164 @@ struct GlobalDomain {
 
 const GLOBAL_DOMAINS: &str = include_str!("../../static/global_domains.json");
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct DeviceKeysData {
+    EncryptedUserKey: String,
+    EncryptedPublicKey: String,
+    EncryptedPrivateKey: String,
+}
+
+/// Trusts the device, storing its keys. Like their retrieval, it can only be done by the device itself
+#[put("/devices/<uuid>/keys", data = "<data>")]
+fn put_device_keys(uuid: String, data: JsonUpcase<DeviceKeysData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: DeviceKeysData = data.into_inner().data;
+    let mut device = headers.device;
+
+    if device.uuid != uuid {
+        err!("The keys of a device can only be set by the device itself")
+    }
+
+    device.encrypted_user_key = Some(data.EncryptedUserKey);
+    device.encrypted_public_key = Some(data.EncryptedPublicKey);
+    device.encrypted_private_key = Some(data.EncryptedPrivateKey);
+    device.save(&conn)?;
+
+    Ok(Json(device.to_json_protected()))
+}
+
+#[post("/devices/<uuid>/retrieve-keys")]
+fn post_device_retrieve_keys(uuid: String, headers: Headers) -> JsonResult {
+    let device = headers.device;
+
+    // The device comes from the access token, so this never gives the keys of a device to another one
+    if device.uuid != uuid {
+        err!("The keys of a device can only be retrieved by the device itself")
+    }
+
+    if !device.is_trusted() {
+        err!("Device is not trusted")
+    }
+
+    Ok(Json(device.to_json_protected()))
+}
+
+/// The devices of the user, the most recently used first, with the IP they were last used from
+#[get("/devices")]
+fn get_devices(headers: Headers, conn: DbConn) -> JsonResult {
+    let mut devices = Device::find_by_user(&headers.user.uuid, &conn);
+    devices.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
+
+    let devices_json: Vec<Value> = devices
+        .iter()
+        .map(|device| {
+            let mut device_json = device.to_json();
+            device_json["Current"] = json!(device.uuid == headers.device.uuid);
+            device_json
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": devices_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+/// The new devices waiting for approval, with `DEVICE_APPROVAL_REQUIRED`
+#[get("/devices/pending")]
+fn get_pending_devices(headers: Headers, conn: DbConn) -> JsonResult {
+    let devices_json: Vec<Value> = Device::find_pending_by_user(&headers.user.uuid, &conn)
+        .iter()
+        .map(Device::to_json_pending)
+        .collect();
+
+    Ok(Json(json!({
+        "Data": devices_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+/// The pending devices are found by their approval token, the `Id` of `/devices/pending`
+fn get_pending_device(token: &str, user_uuid: &str, conn: &DbConn) -> ApiResult<Device> {
+    match Device::find_pending_by_approval_token(token, conn) {
+        Some(device) if device.user_uuid == user_uuid => Ok(device),
+        _ => err!("Pending device not found"),
+    }
+}
+
+/// With the `AdminDeviceApproval` policy only the admins of the organization can approve the new devices
+fn check_self_approval_allowed(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+    if OrgPolicy::is_applicable_to_user(user_uuid, OrgPolicyType::AdminDeviceApproval, conn) {
+        err!("The new devices need to be approved by an administrator of your organization")
+    }
+    Ok(())
+}
+
+/// Approves a new device from one of the other devices of the user, it can log in after that
+#[post("/devices/<id>/approve")]
+fn post_device_approve(id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let mut device = get_pending_device(&id, &headers.user.uuid, &conn)?;
+    check_self_approval_allowed(&headers.user.uuid, &conn)?;
+
+    device.approve();
+    device.save(&conn)?;
+
+    Ok(Json(device.to_json()))
+}
+
+#[post("/devices/<id>/deny")]
+fn post_device_deny(id: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    let device = get_pending_device(&id, &headers.user.uuid, &conn)?;
+
+    DeviceApprovalRequest::delete_all_by_device(&device.uuid, &conn)?;
+    device.delete(&conn)
+}
+
+/// Logs out all the other devices of the user, their refresh tokens stop working right away
+#[post("/devices/revoke-others", data = "<data>")]
+fn post_revoke_other_devices(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    let revoked = Device::delete_all_by_user_except(&headers.user.uuid, &headers.device.uuid, &conn)?;
+
+    Ok(Json(json!({
+        "Revoked": revoked,
+        "Object": "revokedDevices"
+    })))
+}
+
+#[derive(FromForm)]
+struct DeviceApprovalForm {
+    token: String,
+}
+
+/// The confirmation page of the link sent by email posts here, opening the link alone doesn't approve the device
+#[post("/devices/approve", data = "<data>")]
+fn post_device_approve_link(data: Form<DeviceApprovalForm>, conn: DbConn) -> ApiResult<&'static str> {
+    let mut device = match Device::find_pending_by_approval_token(&data.token, &conn) {
+        Some(device) => device,
+        None => err!("Invalid or expired approval link"),
+    };
+    check_self_approval_allowed(&device.user_uuid, &conn)?;
+
+    device.approve();
+    device.save(&conn)?;
+
+    Ok("The device was approved, you can now log in with it")
+}
+
 #[get("/settings/domains")]
 fn get_eq_domains(headers: Headers) -> JsonResult {
-    let user = headers.user;
+    Ok(Json(eq_domains_json(&headers.user)))
+}
+
+fn eq_domains_json(user: &User) -> Value {
     use serde_json::from_str;
 
     let equivalent_domains: Vec<Vec<String>> = from_str(&user.equivalent_domains).unwrap();
@@ -93,11 +276,63 @@ fn get_eq_domains(headers: Headers) -> JsonResult {
         global.Excluded = excluded_globals.contains(&global.Type);
     }
 
-    Ok(Json(json!({
+    json!({
         "EquivalentDomains": equivalent_domains,
         "GlobalEquivalentDomains": globals,
         "Object": "domains",
-    })))
+    })
+}
+
+/// Validates a domain of a custom group, returning it normalized
+fn check_eq_domain(domain: &str) -> Result<String, Error> {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+
+    let valid = !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        });
+
+    if !valid {
+        err!(format!("Invalid domain: {}", domain))
+    }
+
+    Ok(domain)
+}
+
+/// Normalizes the valid domains and drops the duplicates and empty groups. The invalid domains and global types are only
+/// rejected by the endpoints that add them one at a time, replacing the whole configuration accepts the same as before
+fn save_eq_domains(
+    user: &mut User,
+    excluded_globals: Vec<i32>,
+    equivalent_domains: Vec<Vec<String>>,
+    conn: &DbConn,
+) -> EmptyResult {
+    use serde_json::to_string;
+
+    let mut domain_groups = Vec::with_capacity(equivalent_domains.len());
+    for group in equivalent_domains {
+        let mut domains = Vec::with_capacity(group.len());
+        for domain in group {
+            let domain = check_eq_domain(&domain).unwrap_or(domain);
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+
+        if !domains.is_empty() {
+            domain_groups.push(domains);
+        }
+    }
+
+    user.excluded_globals = to_string(&excluded_globals).unwrap_or_else(|_| "[]".to_string());
+    user.equivalent_domains = to_string(&domain_groups).unwrap_or_else(|_| "[]".to_string());
+
+    user.save(&conn)
 }
 
 #[derive(Deserialize, Debug)]
@@ -115,12 +350,7 @@ fn post_eq_domains(data: JsonUpcase<EquivDomainData>, headers: Headers, conn: Db
     let equivalent_domains = data.EquivalentDomains.unwrap_or_default();
 
     let mut user = headers.user;
-    use serde_json::to_string;
-
-    user.excluded_globals = to_string(&excluded_globals).unwrap_or_else(|_| "[]".to_string());
-    user.equivalent_domains = to_string(&equivalent_domains).unwrap_or_else(|_| "[]".to_string());
-
-    user.save(&conn)?;
+    save_eq_domains(&mut user, excluded_globals, equivalent_domains, &conn)?;
 
     Ok(Json(json!({})))
 }
@@ -130,6 +360,98 @@ fn put_eq_domains(data: JsonUpcase<EquivDomainData>, headers: Headers, conn: DbC
     post_eq_domains(data, headers, conn)
 }
 
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct GlobalExcludedData {
+    Excluded: bool,
+}
+
+#[put("/settings/domains/global/<global_type>", data = "<data>")]
+fn put_eq_domains_global(
+    global_type: i32,
+    data: JsonUpcase<GlobalExcludedData>,
+    headers: Headers,
+    conn: DbConn,
+) -> JsonResult {
+    let data: GlobalExcludedData = data.into_inner().data;
+    let mut user = headers.user;
+
+    let mut excluded_globals: Vec<i32> = serde_json::from_str(&user.excluded_globals).unwrap_or_default();
+    let equivalent_domains: Vec<Vec<String>> = serde_json::from_str(&user.equivalent_domains).unwrap_or_default();
+
+    let globals: Vec<GlobalDomain> = serde_json::from_str(GLOBAL_DOMAINS).unwrap();
+    if data.Excluded && !globals.iter().any(|g| g.Type == global_type) {
+        err!(format!("Invalid global equivalent domains type: {}", global_type))
+    }
+
+    excluded_globals.retain(|t| *t != global_type);
+    if data.Excluded {
+        excluded_globals.push(global_type);
+    }
+
+    save_eq_domains(&mut user, excluded_globals, equivalent_domains, &conn)?;
+    Ok(Json(eq_domains_json(&user)))
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct CustomDomainsData {
+    Domains: Vec<String>,
+}
+
+#[post("/settings/domains/custom", data = "<data>")]
+fn post_eq_domains_custom(data: JsonUpcase<CustomDomainsData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: CustomDomainsData = data.into_inner().data;
+    let mut user = headers.user;
+
+    let excluded_globals: Vec<i32> = serde_json::from_str(&user.excluded_globals).unwrap_or_default();
+    let mut equivalent_domains: Vec<Vec<String>> = serde_json::from_str(&user.equivalent_domains).unwrap_or_default();
+
+    if data.Domains.len() < 2 {
+        err!("An equivalent domains group needs at least two domains")
+    }
+    let domains = data
+        .Domains
+        .iter()
+        .map(|domain| check_eq_domain(domain))
+        .collect::<Result<Vec<String>, Error>>()?;
+    equivalent_domains.push(domains);
+
+    save_eq_domains(&mut user, excluded_globals, equivalent_domains, &conn)?;
+    Ok(Json(eq_domains_json(&user)))
+}
+
+/// Removes a custom group, identified by its position in the EquivalentDomains list
+#[delete("/settings/domains/custom/<index>")]
+fn delete_eq_domains_custom(index: usize, headers: Headers, conn: DbConn) -> JsonResult {
+    let mut user = headers.user;
+
+    let excluded_globals: Vec<i32> = serde_json::from_str(&user.excluded_globals).unwrap_or_default();
+    let mut equivalent_domains: Vec<Vec<String>> = serde_json::from_str(&user.equivalent_domains).unwrap_or_default();
+
+    if index >= equivalent_domains.len() {
+        err!("Equivalent domains group doesn't exist")
+    }
+    equivalent_domains.remove(index);
+
+    save_eq_domains(&mut user, excluded_globals, equivalent_domains, &conn)?;
+    Ok(Json(eq_domains_json(&user)))
+}
+
+/// Unauthenticated, so that the clients can compare it with their own clock before logging in. The skew is the difference
+/// tolerated when validating the tokens and the two-factor codes, beyond which the clients should warn about their clock
+#[get("/now")]
+fn now() -> Json<Value> {
+    use crate::util::format_date;
+    use chrono::Utc;
+
+    Json(json!({
+        "ServerTime": format_date(&Utc::now().naive_utc()),
+        "ClockSkewSeconds": crate::CONFIG.token_clock_skew_seconds(),
+        "Object": "serverTime",
+    }))
+}
+
 #[get("/hibp/breach?<username>")]
 fn hibp_breach(username: String) -> JsonResult {
     let user_agent = "Bitwarden_RS";
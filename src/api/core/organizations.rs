@@ -4,21 +4,25 @@ use rocket_contrib::json::Json;
 use serde_json::Value;
 
 use crate::api::{
-    EmptyResult, JsonResult, JsonUpcase, JsonUpcaseVec, Notify, NumberOrString, PasswordData, UpdateType,
+    ApiResult, EmptyResult, JsonResult, JsonUpcase, JsonUpcaseVec, Notify, NumberOrString, PasswordData, UpdateType,
 };
-use crate::auth::{decode_invite, AdminHeaders, Headers, OwnerHeaders};
+use crate::auth::{decode_invite, is_expired_invite, AdminHeaders, ClientIp, ClientLocale, Headers, OwnerHeaders};
 use crate::db::models::*;
 use crate::db::DbConn;
 use crate::mail;
+use crate::util::DEFAULT_LOCALE;
 use crate::CONFIG;
 
 pub fn routes() -> Vec<Route> {
     routes![
         get_organization,
+        get_organization_usage,
         create_organization,
+        get_default_collection_names,
         delete_organization,
         post_delete_organization,
         leave_organization,
+        get_user_org_keys,
         get_user_collections,
         get_org_collections,
         get_org_collection_detail,
@@ -34,17 +38,29 @@ pub fn routes() -> Vec<Route> {
         delete_organization_collection,
         post_organization_collection_delete,
         get_org_details,
+        get_org_export,
         get_org_users,
+        get_org_members_report,
+        list_policies,
+        get_policy,
+        put_policy,
+        preview_policy,
         send_invite,
         reinvite_user,
         confirm_invite,
+        bulk_confirm_invite,
         accept_invite,
+        verify_invite,
         get_user,
         edit_user,
         put_organization_user,
         delete_user,
         post_delete_user,
         post_org_import,
+        post_org_rotate_key,
+        get_device_approvals,
+        approve_device_request,
+        deny_device_request,
     ]
 }
 
@@ -57,6 +73,8 @@ struct OrgData {
     Name: String,
     #[serde(rename = "PlanType")]
     _PlanType: NumberOrString, // Ignored, always use the same plan
+    /// The names of `ORG_DEFAULT_COLLECTIONS`, encrypted by the client with the organization key
+    DefaultCollectionNames: Option<Vec<String>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -64,6 +82,7 @@ struct OrgData {
 struct OrganizationUpdateData {
     BillingEmail: String,
     Name: String,
+    Identifier: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -80,18 +99,76 @@ fn create_organization(headers: Headers, data: JsonUpcase<OrgData>, conn: DbConn
     let mut user_org = UserOrganization::new(headers.user.uuid.clone(), org.uuid.clone());
     let collection = Collection::new(org.uuid.clone(), data.CollectionName);
 
+    // The server can't encrypt the names with the organization key, the clients that support them send them encrypted.
+    // Without them only the collection chosen by the user is created
+    let default_names = data.DefaultCollectionNames.unwrap_or_default();
+    if !default_names.is_empty() {
+        if default_names.len() != default_collection_names(&org.name).len() {
+            err!("The default collections don't match the ones of the server")
+        }
+        if !default_names
+            .iter()
+            .all(|name| super::ciphers::is_encrypted_string(name))
+        {
+            err!("The names of the default collections need to be encrypted")
+        }
+    }
+    let default_collections = default_names
+        .into_iter()
+        .map(|name| Collection::new(org.uuid.clone(), name));
+
     user_org.akey = data.Key;
     user_org.access_all = true;
     user_org.atype = UserOrgType::Owner as i32;
     user_org.status = UserOrgStatus::Confirmed as i32;
 
-    org.save(&conn)?;
-    user_org.save(&conn)?;
-    collection.save(&conn)?;
+    // The creator becomes the owner, the same as a promotion
+    check_admin_2fa_enabled(&user_org.user_uuid, &conn)?;
+    check_single_org(&user_org.user_uuid, &org.uuid, user_org.atype, &conn)?;
+
+    use diesel::Connection;
+    conn.transaction::<_, crate::error::Error, _>(|| {
+        org.save(&conn)?;
+        user_org.save(&conn)?;
+        collection.save(&conn)?;
+
+        for c in default_collections {
+            c.save(&conn)?;
+        }
+        Ok(())
+    })?;
 
     Ok(Json(org.to_json()))
 }
 
+#[derive(FromForm)]
+struct DefaultCollectionNamesData {
+    name: Option<String>,
+}
+
+/// The names of the default collections of a new organization with this name, for the client to encrypt them
+#[get("/organizations/default-collection-names?<data..>")]
+fn get_default_collection_names(data: Form<DefaultCollectionNamesData>, _headers: Headers) -> JsonResult {
+    let org_name = data.name.clone().unwrap_or_default();
+
+    Ok(Json(json!({
+        "Data": default_collection_names(&org_name),
+        "Object": "list",
+    })))
+}
+
+/// Names of the extra collections to create with a new organization, from the comma separated
+/// `ORG_DEFAULT_COLLECTIONS` template, where `{org}` is replaced by the organization name
+fn default_collection_names(org_name: &str) -> Vec<String> {
+    CONFIG
+        .org_default_collections()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| name.replace("{org}", org_name))
+        .collect()
+}
+
 #[delete("/organizations/<org_id>", data = "<data>")]
 fn delete_organization(
     org_id: String,
@@ -149,6 +226,23 @@ fn get_organization(org_id: String, _headers: OwnerHeaders, conn: DbConn) -> Jso
     }
 }
 
+/// Usage summary for billing and reporting. Every membership takes a seat, including the pending invitations
+#[get("/organizations/<org_id>/usage")]
+fn get_organization_usage(org_id: String, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
+    if Organization::find_by_uuid(&org_id, &conn).is_none() {
+        err!("Can't find organization details")
+    }
+
+    Ok(Json(json!({
+        "OrganizationId": org_id,
+        "Seats": UserOrganization::count_by_org(&org_id, None, &conn),
+        "ActiveMembers": UserOrganization::count_by_org(&org_id, Some(UserOrgStatus::Confirmed), &conn),
+        "Collections": Collection::count_by_org(&org_id, &conn),
+        "StorageBytes": Attachment::size_by_org(&org_id, &conn),
+        "Object": "organizationUsage"
+    })))
+}
+
 #[put("/organizations/<org_id>", data = "<data>")]
 fn put_organization(
     org_id: String,
@@ -173,13 +267,45 @@ fn post_organization(
         None => err!("Can't find organization details"),
     };
 
+    // The identifiers can't be shared, they are how the organization is found without its uuid
+    let identifier = data.Identifier.map(|i| i.trim().to_string()).filter(|i| !i.is_empty());
+    if let Some(ref identifier) = identifier {
+        if Organization::find_by_identifier(identifier, &conn).map_or(false, |other| other.uuid != org.uuid) {
+            err!("The identifier is already used by another organization")
+        }
+    }
+
     org.name = data.Name;
     org.billing_email = data.BillingEmail;
+    org.identifier = identifier;
 
     org.save(&conn)?;
     Ok(Json(org.to_json()))
 }
 
+/// The organization keys of the user, encrypted with their public key, for all the organizations at once.
+/// Like in the sync, only the confirmed memberships have a key
+#[get("/organizations/keys")]
+fn get_user_org_keys(headers: Headers, conn: DbConn) -> JsonResult {
+    let keys_json: Vec<Value> = UserOrganization::find_by_user(&headers.user.uuid, &conn)
+        .iter()
+        .filter(|user_org| user_org.status == UserOrgStatus::Confirmed as i32)
+        .map(|user_org| {
+            json!({
+                "OrganizationId": user_org.org_uuid,
+                "Key": user_org.akey,
+                "Object": "organizationUserKey",
+            })
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": keys_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
 // GET /api/collections?writeOnly=false
 #[get("/collections")]
 fn get_user_collections(headers: Headers, conn: DbConn) -> JsonResult {
@@ -369,7 +495,7 @@ fn get_collection_users(org_id: String, coll_id: String, _headers: AdminHeaders,
         .map(|col_user| {
             UserOrganization::find_by_user_and_org(&col_user.user_uuid, &org_id, &conn)
                 .unwrap()
-                .to_json_collection_user_details(col_user.read_only)
+                .to_json_collection_user_details(col_user.read_only, col_user.can_delete)
         })
         .collect();
 
@@ -403,7 +529,7 @@ fn put_collection_users(
             continue;
         }
 
-        CollectionUser::save(&user.user_uuid, &coll_id, d.ReadOnly, &conn)?;
+        CollectionUser::save(&user.user_uuid, &coll_id, d.ReadOnly, d.can_delete(), &conn)?;
     }
 
     Ok(())
@@ -430,6 +556,60 @@ fn get_org_details(data: Form<OrgIdData>, headers: Headers, conn: DbConn) -> Jso
     })))
 }
 
+/// Returns the data of the organization for the clients to export it. Everything is still encrypted with the
+/// organization key, so the plaintext and the password protected exports are both produced by the clients.
+/// With the `DisableExport` policy only the owners can export it, and with the `PasswordProtectedExport` policy
+/// the clients are told to protect the export with a password
+#[get("/organizations/<org_id>/export")]
+fn get_org_export(org_id: String, headers: AdminHeaders, conn: DbConn, ip: ClientIp) -> JsonResult {
+    let password_required = _check_org_export(&org_id, headers.org_user_type, &conn)?;
+
+    let collections_json: Vec<Value> = Collection::find_by_organization(&org_id, &conn)
+        .iter()
+        .map(Collection::to_json)
+        .collect();
+    let ciphers_json: Vec<Value> = Cipher::find_by_org(&org_id, &conn)
+        .iter()
+        .map(|c| c.to_json(&headers.host, &headers.user.uuid, &conn))
+        .collect();
+
+    let mut event = Event::new(
+        EventType::OrganizationClientExportedVault,
+        Some(headers.user.uuid.clone()),
+    );
+    event.org_uuid = Some(org_id);
+    event.device_uuid = Some(headers.device.uuid.clone());
+    event.device_type = Some(headers.device.atype);
+    event.ip_address = Some(ip.ip.to_string());
+    event.save_or_log(&conn);
+
+    Ok(Json(json!({
+        "Collections": {
+            "Data": collections_json,
+            "Object": "list",
+            "ContinuationToken": null,
+        },
+        "Ciphers": {
+            "Data": ciphers_json,
+            "Object": "list",
+            "ContinuationToken": null,
+        },
+        "PasswordProtectedExportRequired": password_required,
+    })))
+}
+
+/// Checks that the member can export the data of the organization, the `DisableExport` policy comes first.
+/// Returns whether the export needs to be protected with a password
+fn _check_org_export(org_id: &str, user_type: UserOrgType, conn: &DbConn) -> ApiResult<bool> {
+    let policy_enabled =
+        |atype: OrgPolicyType| OrgPolicy::find_by_org_and_type(org_id, atype as i32, conn).map_or(false, |p| p.enabled);
+
+    if policy_enabled(OrgPolicyType::DisableExport) && user_type != UserOrgType::Owner {
+        err!("The policies of the organization don't allow exporting its data")
+    }
+    Ok(policy_enabled(OrgPolicyType::PasswordProtectedExport))
+}
+
 #[get("/organizations/<org_id>/users")]
 fn get_org_users(org_id: String, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
     let users = UserOrganization::find_by_org(&org_id, &conn);
@@ -442,11 +622,228 @@ fn get_org_users(org_id: String, _headers: AdminHeaders, conn: DbConn) -> JsonRe
     })))
 }
 
+#[derive(FromForm)]
+struct MembersReportData {
+    filter: Option<String>,
+    page: Option<i64>,
+    #[form(field = "pageSize")]
+    page_size: Option<i64>,
+}
+
+#[get("/organizations/<org_id>/members-report?<data..>")]
+fn get_org_members_report(
+    org_id: String,
+    data: Form<MembersReportData>,
+    _headers: AdminHeaders,
+    conn: DbConn,
+) -> JsonResult {
+    use chrono::NaiveDateTime;
+    use std::collections::HashMap;
+
+    const DEFAULT_PAGE_SIZE: i64 = 50;
+    const MAX_PAGE_SIZE: i64 = 200;
+
+    let page = data.page.unwrap_or(0).max(0);
+    let page_size = data.page_size.unwrap_or(DEFAULT_PAGE_SIZE).max(1).min(MAX_PAGE_SIZE);
+
+    let status_filter = match data.filter.as_ref().map(String::as_str) {
+        None | Some("") | Some("no2fa") => None,
+        Some("invited") => Some(UserOrgStatus::Invited as i32),
+        Some("accepted") => Some(UserOrgStatus::Accepted as i32),
+        Some("confirmed") => Some(UserOrgStatus::Confirmed as i32),
+        Some(f) => err!(format!("Invalid filter: {}", f)),
+    };
+    let only_without_2fa = data.filter.as_ref().map_or(false, |f| f == "no2fa");
+
+    let (members, total) = UserOrganization::find_report_page(
+        &org_id,
+        status_filter,
+        only_without_2fa,
+        page_size,
+        page.saturating_mul(page_size),
+        &conn,
+    );
+
+    // The two-factor methods and the devices of the whole page at once, instead of a query by member
+    let user_uuids: Vec<String> = members.iter().map(|(_, u)| u.uuid.clone()).collect();
+    let twofactors = TwoFactor::find_by_users(&user_uuids, &conn);
+    let last_access: HashMap<String, NaiveDateTime> = Device::find_last_activity_by_users(&user_uuids, &conn)
+        .into_iter()
+        .filter_map(|(user_uuid, date)| date.map(|date| (user_uuid, date)))
+        .collect();
+
+    let page_json: Vec<Value> = members
+        .iter()
+        .map(|(uo, u)| uo.to_json_member_report(u, &twofactors, last_access.get(&u.uuid).cloned()))
+        .collect();
+
+    Ok(Json(json!({
+        "Data": page_json,
+        "Object": "list",
+        "Page": page,
+        "PageSize": page_size,
+        "Total": total,
+    })))
+}
+
+#[get("/organizations/<org_id>/policies")]
+fn list_policies(org_id: String, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
+    let policies = OrgPolicy::find_by_org(&org_id, &conn);
+    let policies_json: Vec<Value> = policies.iter().map(OrgPolicy::to_json).collect();
+
+    Ok(Json(json!({
+        "Data": policies_json,
+        "Object": "list",
+        "ContinuationToken": null
+    })))
+}
+
+#[get("/organizations/<org_id>/policies/<pol_type>")]
+fn get_policy(org_id: String, pol_type: i32, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
+    use num_traits::FromPrimitive;
+
+    let pol_type_enum = match OrgPolicyType::from_i32(pol_type) {
+        Some(pol_type_enum) => pol_type_enum,
+        None => err!("Invalid policy type"),
+    };
+
+    let policy = match OrgPolicy::find_by_org_and_type(&org_id, pol_type, &conn) {
+        Some(p) => p,
+        None => OrgPolicy::new(org_id, pol_type_enum, "{}".to_string()),
+    };
+
+    Ok(Json(policy.to_json()))
+}
+
+#[derive(Deserialize)]
+struct PolicyData {
+    enabled: bool,
+    #[serde(rename = "type")]
+    _type: i32,
+    data: Value,
+}
+
+#[put("/organizations/<org_id>/policies/<pol_type>", data = "<data>")]
+fn put_policy(
+    org_id: String,
+    pol_type: i32,
+    data: Json<PolicyData>,
+    _headers: AdminHeaders,
+    conn: DbConn,
+) -> JsonResult {
+    use num_traits::FromPrimitive;
+
+    let data: PolicyData = data.into_inner();
+
+    let pol_type_enum = match OrgPolicyType::from_i32(pol_type) {
+        Some(pol_type_enum) => pol_type_enum,
+        None => err!("Invalid policy type"),
+    };
+
+    let mut policy = match OrgPolicy::find_by_org_and_type(&org_id, pol_type, &conn) {
+        Some(p) => p,
+        None => OrgPolicy::new(org_id, pol_type_enum, "{}".to_string()),
+    };
+
+    let previous = (policy.enabled, policy.master_password_requirements());
+    policy.enabled = data.enabled;
+    policy.data = serde_json::to_string(&data.data)?;
+
+    if policy.enabled && pol_type_enum == OrgPolicyType::MembershipDomains && policy.membership_domains().is_empty() {
+        err!("At least one domain is required")
+    }
+    if policy.enabled && pol_type_enum == OrgPolicyType::DefaultCollections {
+        for default in policy.default_collections() {
+            if Collection::find_by_uuid_and_org(&default.uuid, &policy.org_uuid, &conn).is_none() {
+                err!(format!(
+                    "The collection {} doesn't belong to the organization",
+                    default.uuid
+                ))
+            }
+        }
+    }
+    // It dates the requirements, the members only need to update their password when they change
+    if (policy.enabled, policy.master_password_requirements()) != previous {
+        policy.updated_at = Some(chrono::Utc::now().naive_utc());
+    }
+    policy.save(&conn)?;
+
+    Ok(Json(policy.to_json()))
+}
+
+/// Lists the members that wouldn't comply with a policy if it was enabled, without changing anything.
+/// Like when they are enforced, owners and admins are exempt
+#[get("/organizations/<org_id>/policies/<pol_type>/preview")]
+fn preview_policy(org_id: String, pol_type: i32, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
+    use num_traits::FromPrimitive;
+
+    let pol_type = match OrgPolicyType::from_i32(pol_type) {
+        Some(pol_type) => pol_type,
+        None => err!("Invalid policy type"),
+    };
+
+    // The master password requirements are previewed as they are stored
+    let policy = OrgPolicy::find_by_org_and_type(&org_id, pol_type as i32, &conn);
+
+    let mut affected = Vec::new();
+    for user_org in UserOrganization::find_by_org(&org_id, &conn) {
+        if user_org.atype >= UserOrgType::Admin || user_org.status == UserOrgStatus::Invited as i32 {
+            continue;
+        }
+
+        let user = match User::find_by_uuid(&user_org.user_uuid, &conn) {
+            Some(user) => user,
+            None => continue,
+        };
+
+        let reason = match pol_type {
+            OrgPolicyType::TwoFactorAuthentication if TwoFactor::find_by_user(&user.uuid, &conn).is_empty() => {
+                "The member doesn't have two-step login enabled"
+            }
+            OrgPolicyType::SingleOrg
+                if UserOrganization::find_by_user(&user.uuid, &conn)
+                    .iter()
+                    .any(|uo| uo.org_uuid != org_id) =>
+            {
+                "The member is part of other organizations"
+            }
+            OrgPolicyType::MasterPassword if policy.as_ref().map_or(false, |p| p.would_force_password_reset(&user)) => {
+                "The member will need to update the master password when logging in"
+            }
+            // The generator requirements are only enforced by the clients, and the new master passwords
+            // are checked against the requirements when they are changed
+            _ => continue,
+        };
+
+        let twofactors = TwoFactor::find_by_user(&user.uuid, &conn);
+        let last_access = Device::find_last_activity_by_user(&user.uuid, &conn);
+        let mut member = user_org.to_json_member_report(&user, &twofactors, last_access);
+        member["Reason"] = Value::String(reason.to_string());
+        affected.push(member);
+    }
+
+    let total = affected.len();
+    Ok(Json(json!({
+        "Type": pol_type as i32,
+        "Data": affected,
+        "Object": "list",
+        "Total": total,
+    })))
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct CollectionData {
     Id: String,
     ReadOnly: bool,
+    CanDelete: Option<bool>,
+}
+
+impl CollectionData {
+    // The official clients don't send it yet, so deleting is allowed unless it's explicitly disabled
+    fn can_delete(&self) -> bool {
+        self.CanDelete.unwrap_or(true)
+    }
 }
 
 #[derive(Deserialize)]
@@ -514,7 +911,7 @@ fn send_invite(org_id: String, data: JsonUpcase<InviteData>, headers: AdminHeade
                 match Collection::find_by_uuid_and_org(&col.Id, &org_id, &conn) {
                     None => err!("Collection not found in Organization"),
                     Some(collection) => {
-                        CollectionUser::save(&user.uuid, &collection.uuid, col.ReadOnly, &conn)?;
+                        CollectionUser::save(&user.uuid, &collection.uuid, col.ReadOnly, col.can_delete(), &conn)?;
                     }
                 }
             }
@@ -535,6 +932,7 @@ fn send_invite(org_id: String, data: JsonUpcase<InviteData>, headers: AdminHeade
                 Some(new_user.uuid),
                 &org_name,
                 Some(headers.user.email.clone()),
+                user.locale(DEFAULT_LOCALE),
             )?;
         }
     }
@@ -579,6 +977,7 @@ fn reinvite_user(org_id: String, user_org: String, headers: AdminHeaders, conn:
             Some(user_org.uuid),
             &org_name,
             Some(headers.user.email),
+            user.locale(DEFAULT_LOCALE),
         )?;
     } else {
         let invitation = Invitation::new(user.email.clone());
@@ -594,15 +993,65 @@ struct AcceptData {
     Token: String,
 }
 
+/// Checks an invitation without accepting it, so that the clients can show where the user is invited to
+#[post("/organizations/invite/verify", data = "<data>")]
+fn verify_invite(data: JsonUpcase<AcceptData>, conn: DbConn) -> JsonResult {
+    use crate::util::format_date;
+    use chrono::NaiveDateTime;
+
+    let data: AcceptData = data.into_inner().data;
+
+    let claims = match decode_invite(&data.Token) {
+        Ok(claims) => claims,
+        Err(_) if is_expired_invite(&data.Token) => err!("The invitation has expired"),
+        Err(_) => err!("The invitation is not valid"),
+    };
+
+    let org = match (&claims.user_org_id, &claims.org_id) {
+        (Some(user_org), Some(org_id)) => {
+            let user_org = match UserOrganization::find_by_uuid_and_org(user_org, org_id, &conn) {
+                Some(user_org) => user_org,
+                None => err!("The invitation has been revoked"),
+            };
+
+            if user_org.status != UserOrgStatus::Invited as i32 {
+                err!("User already accepted the invitation")
+            }
+
+            match Organization::find_by_uuid(org_id, &conn) {
+                Some(org) => Some(org),
+                None => err!("Organization not found."),
+            }
+        }
+        _ => None,
+    };
+
+    Ok(Json(json!({
+        "Email": claims.email,
+        "OrganizationId": org.as_ref().map(|o| &o.uuid),
+        "OrganizationName": org.as_ref().map(|o| &o.name),
+        "InvitedByEmail": claims.invited_by_email,
+        "UserExists": User::find_by_mail(&claims.email, &conn).is_some(),
+        "ExpirationDate": NaiveDateTime::from_timestamp_opt(claims.exp, 0).as_ref().map(format_date),
+        "Object": "organizationInvite",
+    })))
+}
+
 #[post("/organizations/<_org_id>/users/<_org_user_id>/accept", data = "<data>")]
-fn accept_invite(_org_id: String, _org_user_id: String, data: JsonUpcase<AcceptData>, conn: DbConn) -> EmptyResult {
+fn accept_invite(
+    _org_id: String,
+    _org_user_id: String,
+    data: JsonUpcase<AcceptData>,
+    conn: DbConn,
+    locale: ClientLocale,
+) -> EmptyResult {
     // The web-vault passes org_id and org_user_id in the URL, but we are just reading them from the JWT instead
     let data: AcceptData = data.into_inner().data;
     let token = &data.Token;
     let claims = decode_invite(&token)?;
 
-    match User::find_by_mail(&claims.email, &conn) {
-        Some(_) => {
+    let user = match User::find_by_mail(&claims.email, &conn) {
+        Some(user) => {
             Invitation::take(&claims.email, &conn);
 
             if let (Some(user_org), Some(org)) = (&claims.user_org_id, &claims.org_id) {
@@ -615,12 +1064,15 @@ fn accept_invite(_org_id: String, _org_user_id: String, data: JsonUpcase<AcceptD
                     err!("User already accepted the invitation")
                 }
 
+                check_single_org(&user_org.user_uuid, &user_org.org_uuid, user_org.atype, &conn)?;
+
                 user_org.status = UserOrgStatus::Accepted as i32;
                 user_org.save(&conn)?;
             }
+            user
         }
         None => err!("Invited user not found"),
-    }
+    };
 
     if CONFIG.mail_enabled() {
         let mut org_name = String::from("bitwarden_rs");
@@ -632,10 +1084,13 @@ fn accept_invite(_org_id: String, _org_user_id: String, data: JsonUpcase<AcceptD
         };
         if let Some(invited_by_email) = &claims.invited_by_email {
             // User was invited to an organization, so they must be confirmed manually after acceptance
-            mail::send_invite_accepted(&claims.email, invited_by_email, &org_name)?;
+            let inviter_locale = User::find_by_mail(invited_by_email, &conn)
+                .and_then(|u| u.culture)
+                .unwrap_or_else(|| DEFAULT_LOCALE.to_string());
+            mail::send_invite_accepted(&claims.email, invited_by_email, &org_name, &inviter_locale)?;
         } else {
             // User was invited from /admin, so they are automatically confirmed
-            mail::send_invite_confirmed(&claims.email, &org_name)?;
+            mail::send_invite_confirmed(&claims.email, &org_name, user.locale(locale.locale))?;
         }
     }
 
@@ -652,7 +1107,64 @@ fn confirm_invite(
 ) -> EmptyResult {
     let data = data.into_inner().data;
 
-    let mut user_to_confirm = match UserOrganization::find_by_uuid_and_org(&org_user_id, &org_id, &conn) {
+    let key = match data["Key"].as_str() {
+        Some(key) => key,
+        None => err!("Invalid key provided"),
+    };
+
+    _confirm_invite(&org_id, &org_user_id, key, &headers, &conn)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct BulkConfirmData {
+    Keys: Vec<BulkConfirmKey>,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct BulkConfirmKey {
+    Id: String,
+    Key: String,
+}
+
+/// Confirms several members at once. Every member is confirmed in its own transaction, so a failure doesn't affect the
+/// others, and the result lists the error of each one, empty when it was confirmed
+#[post("/organizations/<org_id>/users/confirm", data = "<data>")]
+fn bulk_confirm_invite(
+    org_id: String,
+    data: JsonUpcase<BulkConfirmData>,
+    headers: AdminHeaders,
+    conn: DbConn,
+) -> JsonResult {
+    let data: BulkConfirmData = data.into_inner().data;
+
+    let results: Vec<Value> = data
+        .Keys
+        .iter()
+        .map(|member| {
+            let error = match _confirm_invite(&org_id, &member.Id, &member.Key, &headers, &conn) {
+                Ok(()) => String::new(),
+                Err(e) => e.message().to_string(),
+            };
+
+            json!({
+                "Id": member.Id,
+                "Error": error,
+                "Object": "OrganizationBulkConfirmResponseModel",
+            })
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": results,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+fn _confirm_invite(org_id: &str, org_user_id: &str, key: &str, headers: &AdminHeaders, conn: &DbConn) -> EmptyResult {
+    let mut user_to_confirm = match UserOrganization::find_by_uuid_and_org(org_user_id, org_id, conn) {
         Some(user) => user,
         None => err!("The specified user isn't a member of the organization"),
     };
@@ -665,25 +1177,126 @@ fn confirm_invite(
         err!("User in invalid state")
     }
 
-    user_to_confirm.status = UserOrgStatus::Confirmed as i32;
-    user_to_confirm.akey = match data["Key"].as_str() {
-        Some(key) => key.to_string(),
-        None => err!("Invalid key provided"),
-    };
+    if user_to_confirm.atype >= UserOrgType::Admin {
+        check_admin_2fa_enabled(&user_to_confirm.user_uuid, conn)?;
+    }
+
+    // The policies could have been enabled after the invitation was accepted
+    check_single_org(&user_to_confirm.user_uuid, org_id, user_to_confirm.atype, conn)?;
 
+    user_to_confirm.status = UserOrgStatus::Confirmed as i32;
+    user_to_confirm.akey = key.to_string();
+
+    // The event belongs to the confirmed member, the acting user and the device are the ones of the admin
+    let mut event = Event::new(
+        EventType::OrganizationUserConfirmed,
+        Some(user_to_confirm.user_uuid.clone()),
+    );
+    event.org_uuid = Some(org_id.to_string());
+    event.act_user_uuid = Some(headers.user.uuid.clone());
+    event.device_uuid = Some(headers.device.uuid.clone());
+    event.device_type = Some(headers.device.atype);
+
+    // The membership, its event and its default collections are saved together, or not at all
+    use diesel::Connection;
+    conn.transaction::<_, crate::error::Error, _>(|| {
+        user_to_confirm.save(conn)?;
+        event.save(conn)?;
+        grant_default_collections(&user_to_confirm, conn)
+    })?;
+
+    // Only sent once the member is confirmed, it can't be undone anymore if it fails
     if CONFIG.mail_enabled() {
-        let org_name = match Organization::find_by_uuid(&org_id, &conn) {
-            Some(org) => org.name,
-            None => err!("Error looking up organization."),
-        };
-        let address = match User::find_by_uuid(&user_to_confirm.user_uuid, &conn) {
-            Some(user) => user.email,
-            None => err!("Error looking up user."),
+        let org_name = Organization::find_by_uuid(org_id, conn).map_or_else(String::new, |org| org.name);
+        if let Some(user) = User::find_by_uuid(&user_to_confirm.user_uuid, conn) {
+            if let Err(e) = mail::send_invite_confirmed(&user.email, &org_name, user.locale(DEFAULT_LOCALE)) {
+                error!("Error sending the invite confirmed email: {:#?}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// With the `DefaultCollections` policy, gives the confirmed member access to the default collections,
+/// keeping the access they were already given. The members with access to all the collections are skipped
+fn grant_default_collections(user_org: &UserOrganization, conn: &DbConn) -> EmptyResult {
+    if user_org.access_all {
+        return Ok(());
+    }
+
+    let policy =
+        match OrgPolicy::find_by_org_and_type(&user_org.org_uuid, OrgPolicyType::DefaultCollections as i32, conn) {
+            Some(policy) => policy,
+            None => return Ok(()),
         };
-        mail::send_invite_confirmed(&address, &org_name)?;
+    if !policy.enabled {
+        return Ok(());
     }
 
-    user_to_confirm.save(&conn)
+    for default in policy.default_collections() {
+        // The collection could have been deleted after the policy was saved
+        if Collection::find_by_uuid_and_org(&default.uuid, &user_org.org_uuid, conn).is_none()
+            || CollectionUser::find_by_collection_and_user(&default.uuid, &user_org.user_uuid, conn).is_some()
+        {
+            continue;
+        }
+
+        CollectionUser::save(
+            &user_org.user_uuid,
+            &default.uuid,
+            default.read_only,
+            default.can_delete,
+            conn,
+        )?;
+        info!(
+            "Granted user {} access to the default collection {} of organization {}. Read only: {}, can delete: {}",
+            user_org.user_uuid, default.uuid, user_org.org_uuid, default.read_only, default.can_delete
+        );
+    }
+
+    Ok(())
+}
+
+/// With `REQUIRE_2FA_ORG_ADMINS`, only users with a second factor can become owners or admins
+fn check_admin_2fa_enabled(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+    if CONFIG.require_2fa_org_admins() && TwoFactor::find_by_user(user_uuid, conn).is_empty() {
+        err!("Organization owners and admins need to have two-step login enabled")
+    }
+    Ok(())
+}
+
+/// Enforces the single organization policy when a user joins the organization of `org_uuid` as `atype`: neither the
+/// organizations they are already part of, nor the one they are joining, can have it enabled. Like the other policies,
+/// it doesn't apply to owners and admins
+fn check_single_org(user_uuid: &str, org_uuid: &str, atype: i32, conn: &DbConn) -> EmptyResult {
+    let policy_enabled = |org_uuid: &str| {
+        OrgPolicy::find_by_org_and_type(org_uuid, OrgPolicyType::SingleOrg as i32, conn).map_or(false, |p| p.enabled)
+    };
+    let org_name = |org_uuid: &str| Organization::find_by_uuid(org_uuid, conn).map_or_else(String::new, |o| o.name);
+
+    let others: Vec<UserOrganization> = UserOrganization::find_any_state_by_user(user_uuid, conn)
+        .into_iter()
+        .filter(|uo| uo.org_uuid != org_uuid && uo.status != UserOrgStatus::Invited as i32)
+        .collect();
+
+    for other in &others {
+        if other.atype < UserOrgType::Admin && policy_enabled(&other.org_uuid) {
+            err!(format!(
+                "You can't join other organizations, {} only allows its members to be part of one",
+                org_name(&other.org_uuid)
+            ))
+        }
+    }
+
+    if !others.is_empty() && atype < UserOrgType::Admin && policy_enabled(org_uuid) {
+        err!(format!(
+            "{} only allows its members to be part of one organization, leave the other ones first",
+            org_name(org_uuid)
+        ))
+    }
+
+    Ok(())
 }
 
 #[get("/organizations/<org_id>/users/<org_user_id>")]
@@ -755,6 +1368,10 @@ fn edit_user(
         }
     }
 
+    if new_type >= UserOrgType::Admin {
+        check_admin_2fa_enabled(&user_to_edit.user_uuid, &conn)?;
+    }
+
     user_to_edit.access_all = data.AccessAll;
     user_to_edit.atype = new_type as i32;
 
@@ -769,7 +1386,13 @@ fn edit_user(
             match Collection::find_by_uuid_and_org(&col.Id, &org_id, &conn) {
                 None => err!("Collection not found in Organization"),
                 Some(collection) => {
-                    CollectionUser::save(&user_to_edit.user_uuid, &collection.uuid, col.ReadOnly, &conn)?;
+                    CollectionUser::save(
+                        &user_to_edit.user_uuid,
+                        &collection.uuid,
+                        col.ReadOnly,
+                        col.can_delete(),
+                        &conn,
+                    )?;
                 }
             }
         }
@@ -901,3 +1524,289 @@ fn post_org_import(
     let mut user = headers.user;
     user.update_revision(&conn)
 }
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgKeyData {
+    MasterPasswordHash: String,
+    // The new organization key, encrypted for every confirmed member
+    Members: Vec<MemberKeyData>,
+    Collections: Vec<CollectionNameData>,
+    Ciphers: Vec<CipherData>,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct MemberKeyData {
+    Id: String,
+    Key: String,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct CollectionNameData {
+    Id: String,
+    Name: String,
+}
+
+/// Checks that the submitted ids are exactly the expected ones, without missing or duplicated entries
+fn check_same_ids<'a>(expected: impl Iterator<Item = &'a str>, submitted: &[&'a str], what: &str) -> EmptyResult {
+    use std::collections::HashSet;
+
+    let expected: HashSet<&str> = expected.collect();
+    let submitted_set: HashSet<&str> = submitted.iter().cloned().collect();
+
+    if submitted_set.len() != submitted.len() || submitted_set != expected {
+        err!(format!(
+            "All the {} of the organization need to be included exactly once, otherwise they couldn't be decrypted",
+            what
+        ))
+    }
+
+    Ok(())
+}
+
+/// Replaces the organization key. The clients send everything encrypted with the old key re-encrypted with the
+/// new one, and the new key for every member, and they are all updated at once or not at all
+#[post("/organizations/<org_id>/keys/rotate", data = "<data>")]
+fn post_org_rotate_key(
+    org_id: String,
+    data: JsonUpcase<OrgKeyData>,
+    headers: OwnerHeaders,
+    conn: DbConn,
+    nt: Notify,
+) -> EmptyResult {
+    use diesel::Connection;
+    use std::collections::HashMap;
+
+    let data: OrgKeyData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    // Only the confirmed members have the key, the others will receive the new one when they are confirmed
+    let mut members: Vec<UserOrganization> = UserOrganization::find_by_org(&org_id, &conn)
+        .into_iter()
+        .filter(|uo| uo.status == UserOrgStatus::Confirmed as i32)
+        .collect();
+    let mut collections = Collection::find_by_organization(&org_id, &conn);
+    let mut ciphers: HashMap<String, Cipher> = Cipher::find_by_org(&org_id, &conn)
+        .into_iter()
+        .map(|c| (c.uuid.clone(), c))
+        .collect();
+
+    let submitted_members: Vec<&str> = data.Members.iter().map(|m| m.Id.as_str()).collect();
+    check_same_ids(members.iter().map(|uo| uo.uuid.as_str()), &submitted_members, "members")?;
+
+    let submitted_collections: Vec<&str> = data.Collections.iter().map(|c| c.Id.as_str()).collect();
+    check_same_ids(
+        collections.iter().map(|c| c.uuid.as_str()),
+        &submitted_collections,
+        "collections",
+    )?;
+
+    let submitted_ciphers: Vec<&str> = data
+        .Ciphers
+        .iter()
+        .filter_map(|c| c.Id.as_ref())
+        .map(String::as_str)
+        .collect();
+    if submitted_ciphers.len() != data.Ciphers.len() {
+        err!("All the ciphers need an Id")
+    }
+    check_same_ids(ciphers.keys().map(String::as_str), &submitted_ciphers, "ciphers")?;
+
+    // The keys of the attachments are encrypted with the organization key too
+    let attachments = Attachment::find_by_ciphers(ciphers.keys().cloned().collect(), &conn);
+    for cipher_data in &data.Ciphers {
+        let cipher_uuid = cipher_data.Id.as_ref().unwrap();
+        let submitted_attachments: Vec<&str> = cipher_data
+            .Attachments2
+            .as_ref()
+            .map_or_else(Vec::new, |a| a.keys().map(String::as_str).collect());
+        check_same_ids(
+            attachments
+                .iter()
+                .filter(|a| &a.cipher_uuid == cipher_uuid)
+                .map(|a| a.id.as_str()),
+            &submitted_attachments,
+            "attachments",
+        )?;
+    }
+
+    let cipher_headers = Headers {
+        host: headers.host,
+        device: headers.device,
+        user: headers.user,
+    };
+
+    conn.transaction::<_, crate::error::Error, _>(|| {
+        for member in members.iter_mut() {
+            member.akey = data.Members.iter().find(|m| m.Id == member.uuid).unwrap().Key.clone();
+            member.save(&conn)?;
+        }
+
+        for collection in collections.iter_mut() {
+            collection.name = data
+                .Collections
+                .iter()
+                .find(|c| c.Id == collection.uuid)
+                .unwrap()
+                .Name
+                .clone();
+            collection.save(&conn)?;
+        }
+
+        for mut cipher_data in data.Ciphers {
+            let mut cipher = ciphers.remove(cipher_data.Id.as_ref().unwrap()).unwrap();
+
+            // The folders are personal, the one sent by the owner is only theirs and stays as it is
+            cipher_data.FolderId = cipher.get_folder_uuid(&cipher_headers.user.uuid, &conn);
+
+            // The previous versions are encrypted with the old key, which won't be available anymore
+            CipherRevision::delete_all_by_cipher(&cipher.uuid, &conn)?;
+            update_cipher_from_data(
+                &mut cipher,
+                cipher_data,
+                &cipher_headers,
+                false,
+                &conn,
+                &nt,
+                UpdateType::None,
+            )?;
+        }
+
+        Ok(())
+    })?;
+
+    info!(
+        "Organization {} key rotated by user {}",
+        org_id, cipher_headers.user.uuid
+    );
+
+    let mut event = Event::new(EventType::OrganizationUpdated, Some(cipher_headers.user.uuid.clone()));
+    event.org_uuid = Some(org_id.clone());
+    event.device_uuid = Some(cipher_headers.device.uuid.clone());
+    event.device_type = Some(cipher_headers.device.atype);
+    event.save_or_log(&conn);
+
+    // Make every member fetch the new key and the re-encrypted data before using the organization again
+    for member in members {
+        if let Some(user) = User::find_by_uuid(&member.user_uuid, &conn) {
+            User::update_uuid_revision(&user.uuid, &conn);
+            nt.send_user_update(UpdateType::OrgKeys, &user);
+        }
+    }
+
+    Ok(())
+}
+
+/// The new devices of the members waiting for an admin, with the `AdminDeviceApproval` policy
+#[get("/organizations/<org_id>/device-approvals")]
+fn get_device_approvals(org_id: String, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
+    let requests_json: Vec<Value> = DeviceApprovalRequest::find_by_org(&org_id, &conn)
+        .iter()
+        .filter_map(|request| {
+            let user = User::find_by_uuid(&request.user_uuid, &conn)?;
+            let device = Device::find_by_uuid(&request.device_uuid, &conn)?;
+            Some(request.to_json(&user, &device))
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": requests_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+/// Returns the request and its device, as long as the device is still pending and owned by the user of the request
+fn get_device_request(uuid: &str, org_id: &str, conn: &DbConn) -> ApiResult<(DeviceApprovalRequest, Device)> {
+    let request = match DeviceApprovalRequest::find_by_uuid_and_org(uuid, org_id, conn) {
+        Some(request) => request,
+        None => err!("Device approval request not found"),
+    };
+
+    match Device::find_by_uuid(&request.device_uuid, conn) {
+        Some(device) if device.user_uuid == request.user_uuid && !device.approved => Ok((request, device)),
+        _ => err!("Device approval request not found"),
+    }
+}
+
+/// Approving the device in one organization is enough, the requests of the other ones are removed too
+#[post("/organizations/<org_id>/device-approvals/<uuid>/approve")]
+fn approve_device_request(uuid: String, org_id: String, headers: AdminHeaders, conn: DbConn) -> JsonResult {
+    let (request, mut device) = get_device_request(&uuid, &org_id, &conn)?;
+
+    device.approve();
+    device.save(&conn)?;
+    DeviceApprovalRequest::delete_all_by_device(&request.device_uuid, &conn)?;
+
+    info!(
+        "Device {} of user {} approved by {} in organization {}",
+        device.uuid, request.user_uuid, headers.user.email, org_id
+    );
+
+    // The admins only see the IP of the login they approve, in the request, not where the device is used from
+    let mut device_json = device.to_json();
+    if let Some(object) = device_json.as_object_mut() {
+        object.remove("LastUsedIp");
+    }
+    Ok(Json(device_json))
+}
+
+/// Removes the pending device, it needs to log in again to make a new request
+#[post("/organizations/<org_id>/device-approvals/<uuid>/deny")]
+fn deny_device_request(uuid: String, org_id: String, headers: AdminHeaders, conn: DbConn) -> EmptyResult {
+    let (request, device) = get_device_request(&uuid, &org_id, &conn)?;
+
+    DeviceApprovalRequest::delete_all_by_device(&request.device_uuid, &conn)?;
+    device.delete(&conn)?;
+
+    info!(
+        "Device {} of user {} denied by {} in organization {}",
+        request.device_uuid, request.user_uuid, headers.user.email, org_id
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "sqlite")]
+    fn enable_policy(org: &Organization, atype: OrgPolicyType, conn: &DbConn) {
+        let mut policy = OrgPolicy::new(org.uuid.clone(), atype, "{}".into());
+        policy.enabled = true;
+        policy.save(conn).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn the_export_policies_are_checked() {
+        let conn = crate::migrations::test_connection();
+        let org = Organization::new("Organization".into(), "billing@example.com".into());
+        org.save(&conn).unwrap();
+
+        assert!(!_check_org_export(&org.uuid, UserOrgType::Admin, &conn).unwrap());
+
+        enable_policy(&org, OrgPolicyType::PasswordProtectedExport, &conn);
+        assert!(_check_org_export(&org.uuid, UserOrgType::Admin, &conn).unwrap());
+
+        // Disabling the export takes precedence, only the owners still get it protected with a password
+        enable_policy(&org, OrgPolicyType::DisableExport, &conn);
+        assert!(_check_org_export(&org.uuid, UserOrgType::Admin, &conn).is_err());
+        assert!(_check_org_export(&org.uuid, UserOrgType::Owner, &conn).unwrap());
+    }
+
+    #[test]
+    fn the_password_protected_export_policy_is_a_valid_type() {
+        use num_traits::FromPrimitive;
+
+        let policy = OrgPolicy::new("org".into(), OrgPolicyType::PasswordProtectedExport, "{}".into());
+        assert_eq!(policy.to_json()["Type"], 106);
+        assert_eq!(OrgPolicyType::from_i32(106), Some(OrgPolicyType::PasswordProtectedExport));
+    }
+}
@@ -0,0 +1,296 @@
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use crate::db::models::*;
+use crate::db::DbConn;
+
+use crate::api::{ApiResult, EmptyResult, JsonResult, JsonUpcase};
+use crate::auth::{ClientIp, Headers};
+use crate::lockout;
+use crate::CONFIG;
+
+use rocket::Route;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_sends,
+        get_send,
+        get_send_link,
+        post_send,
+        put_send,
+        put_remove_password,
+        delete_send,
+        post_access,
+    ]
+}
+
+/// Sends can't be kept for longer than this, like in the official server
+const SEND_MAX_DELETION_DAYS: i64 = 31;
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+pub struct SendData {
+    Type: i32,
+    Key: String,
+    Password: Option<String>,
+    MaxAccessCount: Option<i32>,
+    ExpirationDate: Option<String>,
+    DeletionDate: String,
+    Disabled: bool,
+
+    Name: String,
+    Notes: Option<String>,
+    Text: Option<Value>,
+    File: Option<Value>,
+}
+
+fn parse_date(date: &str) -> ApiResult<NaiveDateTime> {
+    match DateTime::parse_from_rfc3339(date) {
+        Ok(date) => Ok(date.naive_utc()),
+        Err(_) => err!("Invalid date format"),
+    }
+}
+
+/// Checks that the user is allowed to create and edit Sends, both by the server config and by their organizations
+fn enforce_sends_allowed(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+    if !CONFIG.sends_allowed() {
+        err!("Sends are disabled on this server")
+    }
+
+    if OrgPolicy::is_applicable_to_user(user_uuid, OrgPolicyType::DisableSend, conn) {
+        err!("Due to an Enterprise Policy, you are only able to delete an existing Send.")
+    }
+
+    Ok(())
+}
+
+/// Validates the data and returns the serialized contents and the deletion and expiration dates
+fn check_send_data(data: &SendData) -> ApiResult<(String, NaiveDateTime, Option<NaiveDateTime>)> {
+    if data.Type != SendType::Text as i32 {
+        err!("Only text Sends are supported")
+    }
+
+    let contents = match data.Text {
+        Some(ref text) => text.to_string(),
+        None => err!("Send data not provided"),
+    };
+
+    if data.File.is_some() {
+        err!("File Sends are not supported")
+    }
+
+    let deletion_date = parse_date(&data.DeletionDate)?;
+    if deletion_date > Utc::now().naive_utc() + Duration::days(SEND_MAX_DELETION_DAYS) {
+        err!(format!(
+            "You cannot have a Send with a deletion date that far into the future. Adjust the Deletion Date to a value less than {} days from now and try again.",
+            SEND_MAX_DELETION_DAYS
+        ))
+    }
+
+    let expiration_date = match data.ExpirationDate {
+        Some(ref date) => Some(parse_date(date)?),
+        None => None,
+    };
+
+    if let Some(max) = data.MaxAccessCount {
+        if max <= 0 {
+            err!("Max access count must be positive")
+        }
+    }
+
+    Ok((contents, deletion_date, expiration_date))
+}
+
+#[get("/sends")]
+fn get_sends(headers: Headers, conn: DbConn) -> JsonResult {
+    let sends = Send::find_by_user(&headers.user.uuid, &conn);
+
+    let sends_json: Vec<Value> = sends.iter().map(Send::to_json).collect();
+
+    Ok(Json(json!({
+      "Data": sends_json,
+      "Object": "list",
+      "ContinuationToken": null,
+    })))
+}
+
+#[get("/sends/<uuid>")]
+fn get_send(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let send = match Send::find_by_uuid(&uuid, &conn) {
+        Some(send) => send,
+        None => err!("Send not found"),
+    };
+
+    if send.user_uuid != headers.user.uuid {
+        err!("Send is not owned by user")
+    }
+
+    Ok(Json(send.to_json()))
+}
+
+#[get("/sends/<uuid>/link")]
+fn get_send_link(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let send = match Send::find_by_uuid(&uuid, &conn) {
+        Some(send) => send,
+        None => err!("Send not found"),
+    };
+
+    if send.user_uuid != headers.user.uuid {
+        err!("Send is not owned by user")
+    }
+
+    Ok(Json(send.to_json_link(&headers.host)))
+}
+
+#[post("/sends", data = "<data>")]
+fn post_send(data: JsonUpcase<SendData>, headers: Headers, conn: DbConn) -> JsonResult {
+    enforce_sends_allowed(&headers.user.uuid, &conn)?;
+
+    let data: SendData = data.into_inner().data;
+    let (contents, deletion_date, expiration_date) = check_send_data(&data)?;
+
+    let mut send = Send::new(
+        headers.user.uuid,
+        data.Type,
+        data.Name,
+        contents,
+        data.Key,
+        deletion_date,
+    );
+    send.notes = data.Notes;
+    send.max_access_count = data.MaxAccessCount;
+    send.expiration_date = expiration_date;
+    send.disabled = data.Disabled;
+    send.set_password(data.Password.as_ref().map(String::as_str));
+
+    send.save(&conn)?;
+
+    let mut send_json = send.to_json();
+    send_json["Link"] = send.to_json_link(&headers.host);
+    Ok(Json(send_json))
+}
+
+#[put("/sends/<uuid>", data = "<data>")]
+fn put_send(uuid: String, data: JsonUpcase<SendData>, headers: Headers, conn: DbConn) -> JsonResult {
+    enforce_sends_allowed(&headers.user.uuid, &conn)?;
+
+    let data: SendData = data.into_inner().data;
+    let (contents, deletion_date, expiration_date) = check_send_data(&data)?;
+
+    let mut send = match Send::find_by_uuid(&uuid, &conn) {
+        Some(send) => send,
+        None => err!("Send not found"),
+    };
+
+    if send.user_uuid != headers.user.uuid {
+        err!("Send is not owned by user")
+    }
+
+    if send.atype != data.Type {
+        err!("Sends can't change type")
+    }
+
+    send.name = data.Name;
+    send.notes = data.Notes;
+    send.data = contents;
+    send.akey = data.Key;
+    send.max_access_count = data.MaxAccessCount;
+    send.expiration_date = expiration_date;
+    send.deletion_date = deletion_date;
+    send.disabled = data.Disabled;
+
+    // The password is only changed when a new one is provided, removing it has its own endpoint
+    if let Some(ref password) = data.Password {
+        send.set_password(Some(password));
+    }
+
+    send.save(&conn)?;
+
+    Ok(Json(send.to_json()))
+}
+
+#[put("/sends/<uuid>/remove-password")]
+fn put_remove_password(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let mut send = match Send::find_by_uuid(&uuid, &conn) {
+        Some(send) => send,
+        None => err!("Send not found"),
+    };
+
+    if send.user_uuid != headers.user.uuid {
+        err!("Send is not owned by user")
+    }
+
+    send.set_password(None);
+    send.save(&conn)?;
+
+    Ok(Json(send.to_json()))
+}
+
+#[delete("/sends/<uuid>")]
+fn delete_send(uuid: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    let send = match Send::find_by_uuid(&uuid, &conn) {
+        Some(send) => send,
+        None => err!("Send not found"),
+    };
+
+    if send.user_uuid != headers.user.uuid {
+        err!("Send is not owned by user")
+    }
+
+    send.delete(&conn)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+pub struct SendAccessData {
+    Password: Option<String>,
+}
+
+/// Public endpoint used by the recipients of a Send. The password is checked before the access is
+/// counted, so a wrong password never uses up one of the allowed accesses.
+/// The wrong passwords count for the rate limit of the failed logins of the IP, so they can't be guessed
+#[post("/sends/access/<access_id>", data = "<data>")]
+fn post_access(access_id: String, data: JsonUpcase<SendAccessData>, ip: ClientIp, conn: DbConn) -> JsonResult {
+    let data: SendAccessData = data.into_inner().data;
+    let ip_key = ip.ip.to_string();
+
+    if let Some(seconds) = lockout::ratelimit_retry_after(&ip_key) {
+        return Err(lockout::ratelimited_error(
+            seconds,
+            format!("Send access rate limited. IP: {}", ip.ip),
+        ));
+    }
+
+    let mut send = match Send::find_by_access_id(&access_id, &conn) {
+        Some(send) => send,
+        None => err!("Send not found"),
+    };
+
+    if send.is_unavailable() {
+        err!("Send not found")
+    }
+
+    // The Sends created before they were disabled stay in the database, but can't be accessed anymore
+    if enforce_sends_allowed(&send.user_uuid, &conn).is_err() {
+        err!("Sends are disabled")
+    }
+
+    if send.has_password() {
+        match data.Password {
+            Some(ref password) if send.check_password(password) => (),
+            Some(_) => {
+                lockout::register_ratelimit_failure(&ip_key);
+                err!("Invalid password", format!("Invalid Send password. IP: {}", ip.ip))
+            }
+            None => err!("Password not provided"),
+        }
+    }
+
+    // Early exit to avoid the update, the actual check is done atomically when incrementing
+    if send.is_exhausted() || !send.increment_access_count(&conn)? {
+        err!("Max access count reached")
+    }
+
+    Ok(Json(send.to_json_access()))
+}
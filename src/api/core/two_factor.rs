@@ -1,33 +1,59 @@
-use data_encoding::{BASE32, BASE64};
+use std::net::IpAddr;
+
+use data_encoding::{BASE32, BASE64, HEXLOWER};
+use jsonwebtoken::{Algorithm, Header};
 use rocket_contrib::json::Json;
 use serde_json;
 use serde_json::Value;
 
 use crate::api::{ApiResult, EmptyResult, JsonResult, JsonUpcase, NumberOrString, PasswordData};
-use crate::auth::Headers;
+use crate::auth::{decode_duo_state, encode_jwt, generate_duo_state_claims, ClientIp, Headers};
 use crate::crypto;
 use crate::db::{
-    models::{TwoFactor, TwoFactorType, User},
+    models::{
+        Device, DeviceType, OrgPolicy, OrgPolicyType, SecurityNotice, SecurityNoticeType, TwoFactor, TwoFactorType,
+        User,
+    },
     DbConn,
 };
 use crate::error::{Error, MapResult};
+use crate::lockout;
+use crate::mail;
+use crate::util::{self, DEFAULT_LOCALE};
 
 use rocket::Route;
 
 pub fn routes() -> Vec<Route> {
     routes![
         get_twofactor,
+        post_preferred_provider,
+        put_preferred_provider,
+        get_trusted_ip_ranges,
+        post_trusted_ip_ranges,
+        put_trusted_ip_ranges,
         get_recover,
         recover,
+        revoke_remembered_devices,
         disable_twofactor,
         disable_twofactor_put,
         generate_authenticator,
         activate_authenticator,
         activate_authenticator_put,
+        delete_authenticator,
         generate_u2f,
         generate_u2f_challenge,
         activate_u2f,
         activate_u2f_put,
+        get_webauthn,
+        generate_webauthn_challenge,
+        activate_webauthn,
+        activate_webauthn_put,
+        delete_webauthn,
+        get_email,
+        send_email_verification,
+        activate_email,
+        activate_email_put,
+        send_email_login,
         generate_yubikey,
         activate_yubikey,
         activate_yubikey_put,
@@ -40,15 +66,183 @@ pub fn routes() -> Vec<Route> {
 #[get("/two-factor")]
 fn get_twofactor(headers: Headers, conn: DbConn) -> JsonResult {
     let twofactors = TwoFactor::find_by_user(&headers.user.uuid, &conn);
-    let twofactors_json: Vec<Value> = twofactors.iter().map(TwoFactor::to_json_list).collect();
+    let mut twofactors_json: Vec<Value> = twofactors.iter().map(TwoFactor::to_json_list).collect();
+
+    // The migrated keys are still usable with U2F, by the clients that don't support WebAuthn
+    if has_migrated_u2f_keys(&headers.user.uuid, &conn) {
+        twofactors_json.push(json!({
+            "Enabled": true,
+            "Type": TwoFactorType::U2f as i32,
+            "Object": "twoFactorProvider"
+        }));
+    }
 
     Ok(Json(json!({
         "Data": twofactors_json,
+        "PreferredProvider": TwoFactor::find_preferred_provider(&headers.user.uuid, &conn),
+        "FactorCount": count_factors(&twofactors),
+        "MaxFactors": CONFIG.max_twofactor_factors(),
         "Object": "list",
         "ContinuationToken": null,
     })))
 }
 
+/// The enabled provider types of the user, in the order they are offered at login
+pub fn enabled_providers(user_uuid: &str, twofactors: &[TwoFactor], conn: &DbConn) -> Vec<i32> {
+    let mut providers: Vec<i32> = twofactors.iter().map(|tf| tf.atype).collect();
+    if has_migrated_u2f_keys(user_uuid, conn) {
+        providers.push(TwoFactorType::U2f as i32);
+    }
+
+    // The security keys are checked against the origin of the DOMAIN, so they can't be used without it
+    if !CONFIG.domain_set() {
+        providers.retain(|p| *p != TwoFactorType::Webauthn as i32 && *p != TwoFactorType::U2f as i32);
+    }
+
+    TwoFactor::sort_providers(&mut providers, TwoFactor::find_preferred_provider(user_uuid, conn));
+    providers
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct PreferredProviderData {
+    MasterPasswordHash: String,
+    /// Removes the preference when not provided
+    Type: Option<NumberOrString>,
+}
+
+/// Selects the provider asked for at login when the client doesn't select one
+#[post("/two-factor/preferred", data = "<data>")]
+fn post_preferred_provider(data: JsonUpcase<PreferredProviderData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: PreferredProviderData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let atype = match data.Type {
+        Some(atype) => Some(atype.into_i32()?),
+        None => None,
+    };
+
+    if let Some(atype) = atype {
+        let twofactors = TwoFactor::find_by_user(&user.uuid, &conn);
+        if !enabled_providers(&user.uuid, &twofactors, &conn).contains(&atype) {
+            err!("The two-step login provider is not enabled")
+        }
+    }
+
+    TwoFactor::set_preferred_provider(&user.uuid, atype, &conn)?;
+
+    Ok(Json(json!({
+        "Type": atype,
+        "Object": "twoFactorPreferredProvider"
+    })))
+}
+
+#[put("/two-factor/preferred", data = "<data>")]
+fn put_preferred_provider(data: JsonUpcase<PreferredProviderData>, headers: Headers, conn: DbConn) -> JsonResult {
+    post_preferred_provider(data, headers, conn)
+}
+
+/// Maximum number of trusted IP ranges per user
+const MAX_TRUSTED_IP_RANGES: usize = 16;
+/// The broadest ranges that can be trusted, a /16 for IPv4 and a /48 for IPv6
+const MIN_TRUSTED_PREFIX_V4: u8 = 16;
+const MIN_TRUSTED_PREFIX_V6: u8 = 48;
+
+/// Whether the user can skip the two-step login from the trusted IP ranges, the organizations can forbid it
+fn trusted_ip_ranges_allowed(user_uuid: &str, conn: &DbConn) -> bool {
+    CONFIG.trusted_ip_ranges_enabled()
+        && !OrgPolicy::is_applicable_to_user(user_uuid, OrgPolicyType::DisableTrustedIpRanges, conn)
+}
+
+/// Whether the two-step login can be skipped, because the IP is in one of the trusted ranges of the user
+pub fn is_trusted_ip(user_uuid: &str, ip: &IpAddr, conn: &DbConn) -> bool {
+    trusted_ip_ranges_allowed(user_uuid, conn)
+        && TwoFactor::find_trusted_ip_ranges(user_uuid, conn)
+            .iter()
+            .any(|range| util::ip_in_range(ip, range))
+}
+
+fn trusted_ip_ranges_json(user_uuid: &str, conn: &DbConn) -> Value {
+    json!({
+        "Enabled": trusted_ip_ranges_allowed(user_uuid, conn),
+        "Ranges": TwoFactor::find_trusted_ip_ranges(user_uuid, conn),
+        "Object": "twoFactorTrustedIpRanges"
+    })
+}
+
+#[get("/two-factor/trusted-ips")]
+fn get_trusted_ip_ranges(headers: Headers, conn: DbConn) -> JsonResult {
+    Ok(Json(trusted_ip_ranges_json(&headers.user.uuid, &conn)))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct TrustedIpRangesData {
+    MasterPasswordHash: String,
+    /// Replaces all the ranges, an empty list removes them
+    Ranges: Vec<String>,
+}
+
+#[post("/two-factor/trusted-ips", data = "<data>")]
+fn post_trusted_ip_ranges(data: JsonUpcase<TrustedIpRangesData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: TrustedIpRangesData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    // They can always be removed, even when they can't be used anymore
+    if !data.Ranges.is_empty() && !trusted_ip_ranges_allowed(&user.uuid, &conn) {
+        err!("Trusted IP ranges are not allowed")
+    }
+
+    if data.Ranges.len() > MAX_TRUSTED_IP_RANGES {
+        err!(format!("You can't trust more than {} IP ranges", MAX_TRUSTED_IP_RANGES))
+    }
+
+    let mut ranges = Vec::with_capacity(data.Ranges.len());
+    for range in &data.Ranges {
+        let (addr, prefix) = match util::parse_ip_range(range) {
+            Some(parsed) => parsed,
+            None => err!(format!("Invalid IP range: {}", range)),
+        };
+
+        let min_prefix = if addr.is_ipv4() {
+            MIN_TRUSTED_PREFIX_V4
+        } else {
+            MIN_TRUSTED_PREFIX_V6
+        };
+        if prefix < min_prefix {
+            err!(format!("The IP range {} is too broad", range))
+        }
+
+        let range = format!("{}/{}", addr, prefix);
+        if !ranges.contains(&range) {
+            ranges.push(range);
+        }
+    }
+
+    TwoFactor::set_trusted_ip_ranges(&user.uuid, &ranges, &conn)?;
+    _notify_twofactor_changed(
+        &user.uuid,
+        Some(TwoFactorType::TrustedIpRanges as i32),
+        !ranges.is_empty(),
+        &conn,
+    );
+
+    Ok(Json(trusted_ip_ranges_json(&user.uuid, &conn)))
+}
+
+#[put("/two-factor/trusted-ips", data = "<data>")]
+fn put_trusted_ip_ranges(data: JsonUpcase<TrustedIpRangesData>, headers: Headers, conn: DbConn) -> JsonResult {
+    post_trusted_ip_ranges(data, headers, conn)
+}
+
 #[post("/two-factor/get-recover", data = "<data>")]
 fn get_recover(data: JsonUpcase<PasswordData>, headers: Headers) -> JsonResult {
     let data: PasswordData = data.into_inner().data;
@@ -100,9 +294,30 @@ fn recover(data: JsonUpcase<RecoverTwoFactor>, conn: DbConn) -> JsonResult {
     // Remove the recovery code, not needed without twofactors
     user.totp_recover = None;
     user.save(&conn)?;
+
+    _notify_twofactor_changed(&user.uuid, None, false, &conn);
     Ok(Json(json!({})))
 }
 
+/// Forgets the second factor remembered on all the devices of the user, they need to provide it again on the next login
+#[post("/two-factor/remembered-devices/revoke", data = "<data>")]
+fn revoke_remembered_devices(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let revoked = Device::clear_twofactor_remember_by_user(&user.uuid, &conn)?;
+    info!("Revoked {} remembered devices of {}", revoked, user.email);
+
+    Ok(Json(json!({
+        "Revoked": revoked,
+        "Object": "twoFactorRememberedDevices"
+    })))
+}
+
 fn _generate_recover_code(user: &mut User, conn: &DbConn) {
     if user.totp_recover.is_none() {
         let totp_recover = BASE32.encode(&crypto::get_random(vec![0u8; 20]));
@@ -111,6 +326,70 @@ fn _generate_recover_code(user: &mut User, conn: &DbConn) {
     }
 }
 
+/// Leaves a security notice for every change to the second factors of the user, `atype` is None when all of them were removed.
+/// When the last one is removed, the enrollment deadline is reset, so a new grace period starts on the next login
+fn _notify_twofactor_changed(user_uuid: &str, atype: Option<i32>, enabled: bool, conn: &DbConn) {
+    SecurityNotice::new(
+        user_uuid.to_string(),
+        SecurityNoticeType::TwoFactorChanged,
+        json!({
+            "TwoFactorType": atype,
+            "Enabled": enabled,
+        }),
+    )
+    .save_or_log(conn);
+
+    if !enabled && TwoFactor::find_by_user(user_uuid, conn).is_empty() {
+        if let Some(mut user) = User::find_by_uuid(user_uuid, conn) {
+            if user.twofactor_enroll_deadline.is_some() {
+                user.twofactor_enroll_deadline = None;
+                if let Err(e) = user.save(conn) {
+                    error!("Error resetting the two-step login enrollment deadline: {:#?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Counts the registered second factors, where every security key and YubiKey is a separate one
+fn count_factors(twofactors: &[TwoFactor]) -> u64 {
+    use num_traits::FromPrimitive;
+
+    twofactors
+        .iter()
+        .map(|tf| match TwoFactorType::from_i32(tf.atype) {
+            Some(TwoFactorType::U2f) | Some(TwoFactorType::Webauthn) => {
+                serde_json::from_str::<Vec<Value>>(&tf.data).map_or(1, |regs| regs.len())
+            }
+            Some(TwoFactorType::YubiKey) => {
+                serde_json::from_str::<YubikeyMetadata>(&tf.data).map_or(1, |m| m.Keys.len())
+            }
+            Some(TwoFactorType::Authenticator) => parse_authenticator_keys(tf).map_or(1, |keys| keys.len()),
+            _ => 1,
+        })
+        .sum::<usize>() as u64
+}
+
+/// Checks that the user stays within `MAX_TWOFACTOR_FACTORS` after setting `new_count` factors of the given type,
+/// which replace the ones of that type that were already registered
+fn enforce_max_factors(user_uuid: &str, atype: TwoFactorType, new_count: usize, conn: &DbConn) -> EmptyResult {
+    let max = CONFIG.max_twofactor_factors();
+    if max == 0 {
+        return Ok(());
+    }
+
+    let others: Vec<TwoFactor> = TwoFactor::find_by_user(user_uuid, conn)
+        .into_iter()
+        .filter(|tf| tf.atype != atype as i32)
+        .collect();
+
+    if count_factors(&others) + new_count as u64 > max {
+        err!(format!("You can't register more than {} second factors", max))
+    }
+
+    Ok(())
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct DisableTwoFactorData {
@@ -134,6 +413,15 @@ fn disable_twofactor(data: JsonUpcase<DisableTwoFactorData>, headers: Headers, c
         twofactor.delete(&conn)?;
     }
 
+    // The U2F keys are kept with the WebAuthn ones once migrated
+    if type_ == TwoFactorType::U2f as i32 {
+        let mut regs = get_webauthn_registrations(&user.uuid, &conn)?;
+        regs.retain(|r| !r.migrated);
+        save_webauthn_registrations(&user.uuid, &regs, &conn)?;
+    }
+
+    _notify_twofactor_changed(&user.uuid, Some(type_), false, &conn);
+
     Ok(Json(json!({
         "Enabled": false,
         "Type": type_,
@@ -146,6 +434,63 @@ fn disable_twofactor_put(data: JsonUpcase<DisableTwoFactorData>, headers: Header
     disable_twofactor(data, headers, conn)
 }
 
+/// A TOTP secret of the user, there can be several of them like a backup one. The codes are checked against all of them
+#[derive(Serialize, Deserialize)]
+struct AuthenticatorKey {
+    id: i32,
+    name: String,
+    key: String,
+    /// The time step of the last code used with this secret, so it can't be used again
+    last_used: i32,
+}
+
+impl AuthenticatorKey {
+    fn to_json(&self) -> Value {
+        json!({
+            "Id": self.id,
+            "Name": self.name,
+        })
+    }
+}
+
+/// The secrets are a JSON list, the single secret saved before there could be several is just the base32 key
+fn parse_authenticator_keys(tf: &TwoFactor) -> ApiResult<Vec<AuthenticatorKey>> {
+    if tf.data.starts_with('[') {
+        return Ok(serde_json::from_str(&tf.data)?);
+    }
+
+    Ok(vec![AuthenticatorKey {
+        id: 1,
+        name: String::from("Authenticator"),
+        key: tf.data.clone(),
+        last_used: tf.last_used,
+    }])
+}
+
+fn get_authenticator_keys(user_uuid: &str, conn: &DbConn) -> ApiResult<Vec<AuthenticatorKey>> {
+    match TwoFactor::find_by_user_and_type(user_uuid, TwoFactorType::Authenticator as i32, conn) {
+        Some(tf) => parse_authenticator_keys(&tf),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Saves the secrets, removing the authenticator from the user when there are none left
+fn save_authenticator_keys(user_uuid: &str, keys: &[AuthenticatorKey], conn: &DbConn) -> EmptyResult {
+    if keys.is_empty() {
+        return match TwoFactor::find_by_user_and_type(user_uuid, TwoFactorType::Authenticator as i32, conn) {
+            Some(tf) => tf.delete(conn),
+            None => Ok(()),
+        };
+    }
+
+    TwoFactor::new(
+        user_uuid.into(),
+        TwoFactorType::Authenticator,
+        serde_json::to_string(keys)?,
+    )
+    .save(conn)
+}
+
 #[post("/two-factor/get-authenticator", data = "<data>")]
 fn generate_authenticator(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
     let data: PasswordData = data.into_inner().data;
@@ -155,17 +500,19 @@ fn generate_authenticator(data: JsonUpcase<PasswordData>, headers: Headers, conn
         err!("Invalid password");
     }
 
-    let type_ = TwoFactorType::Authenticator as i32;
-    let twofactor = TwoFactor::find_by_user_and_type(&user.uuid, type_, &conn);
+    let keys = get_authenticator_keys(&user.uuid, &conn)?;
 
-    let (enabled, key) = match twofactor {
-        Some(tf) => (true, tf.data),
-        _ => (false, BASE32.encode(&crypto::get_random(vec![0u8; 20]))),
+    // The clients that only know about one secret get the first one
+    let (enabled, key) = match keys.first() {
+        Some(first) => (true, first.key.clone()),
+        None => (false, BASE32.encode(&crypto::get_random(vec![0u8; 20]))),
     };
 
+    let keys_json: Vec<Value> = keys.iter().map(AuthenticatorKey::to_json).collect();
     Ok(Json(json!({
         "Enabled": enabled,
         "Key": key,
+        "Keys": keys_json,
         "Object": "twoFactorAuthenticator"
     })))
 }
@@ -176,13 +523,20 @@ struct EnableAuthenticatorData {
     MasterPasswordHash: String,
     Key: String,
     Token: NumberOrString,
+    /// The slot of the secret, one in use is replaced. Without it, like from the official clients, the first one is
+    /// replaced and the backup secrets are kept
+    Id: Option<NumberOrString>,
+    /// Up to `AUTHENTICATOR_NAME_MAX_CHARS` characters
+    Name: Option<String>,
 }
 
+const AUTHENTICATOR_NAME_MAX_CHARS: usize = 50;
+
 #[post("/two-factor/authenticator", data = "<data>")]
 fn activate_authenticator(data: JsonUpcase<EnableAuthenticatorData>, headers: Headers, conn: DbConn) -> JsonResult {
     let data: EnableAuthenticatorData = data.into_inner().data;
     let password_hash = data.MasterPasswordHash;
-    let key = data.Key;
+    let key = data.Key.to_uppercase();
     let token = data.Token.into_i32()? as u64;
 
     let mut user = headers.user;
@@ -201,18 +555,46 @@ fn activate_authenticator(data: JsonUpcase<EnableAuthenticatorData>, headers: He
         err!("Invalid key length")
     }
 
-    let type_ = TwoFactorType::Authenticator;
-    let twofactor = TwoFactor::new(user.uuid.clone(), type_, key.to_uppercase());
+    let name = data.Name.unwrap_or_else(|| String::from("Authenticator"));
+    if name.chars().count() > AUTHENTICATOR_NAME_MAX_CHARS {
+        err!(format!(
+            "The name of the authenticator can't be longer than {} characters",
+            AUTHENTICATOR_NAME_MAX_CHARS
+        ))
+    }
+
+    let mut keys = get_authenticator_keys(&user.uuid, &conn)?;
+    let id = match data.Id {
+        Some(id) => id.into_i32()?,
+        None => 1,
+    };
+
+    keys.retain(|k| k.id != id);
+    if keys.iter().any(|k| k.key == key) {
+        err!("This authenticator secret is already registered")
+    }
+
+    // Validate the token provided with the key, it can't be used to log in afterwards
+    keys.push(AuthenticatorKey {
+        id,
+        name,
+        last_used: validate_totp_code(token, &key, 0)?,
+        key: key.clone(),
+    });
+    keys.sort_by_key(|k| k.id);
 
-    // Validate the token provided with the key
-    validate_totp_code(token, &twofactor.data)?;
+    let type_ = TwoFactorType::Authenticator;
+    enforce_max_factors(&user.uuid, type_, keys.len(), &conn)?;
 
     _generate_recover_code(&mut user, &conn);
-    twofactor.save(&conn)?;
+    save_authenticator_keys(&user.uuid, &keys, &conn)?;
+    _notify_twofactor_changed(&user.uuid, Some(type_ as i32), true, &conn);
 
+    let keys_json: Vec<Value> = keys.iter().map(AuthenticatorKey::to_json).collect();
     Ok(Json(json!({
         "Enabled": true,
         "Key": key,
+        "Keys": keys_json,
         "Object": "twoFactorAuthenticator"
     })))
 }
@@ -222,29 +604,120 @@ fn activate_authenticator_put(data: JsonUpcase<EnableAuthenticatorData>, headers
     activate_authenticator(data, headers, conn)
 }
 
-pub fn validate_totp_code_str(totp_code: &str, secret: &str) -> EmptyResult {
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct DeleteAuthenticatorData {
+    Id: NumberOrString,
+    MasterPasswordHash: String,
+}
+
+/// Removes one of the secrets, the authenticator is disabled with the last one
+#[delete("/two-factor/authenticator", data = "<data>")]
+fn delete_authenticator(data: JsonUpcase<DeleteAuthenticatorData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: DeleteAuthenticatorData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let mut keys = get_authenticator_keys(&user.uuid, &conn)?;
+
+    let id = data.Id.into_i32()?;
+    if !keys.iter().any(|k| k.id == id) {
+        err!("Authenticator secret not found")
+    }
+
+    keys.retain(|k| k.id != id);
+    save_authenticator_keys(&user.uuid, &keys, &conn)?;
+    _notify_twofactor_changed(
+        &user.uuid,
+        Some(TwoFactorType::Authenticator as i32),
+        !keys.is_empty(),
+        &conn,
+    );
+
+    let keys_json: Vec<Value> = keys.iter().map(AuthenticatorKey::to_json).collect();
+    Ok(Json(json!({
+        "Enabled": !keys.is_empty(),
+        "Keys": keys_json,
+        "Object": "twoFactorAuthenticator"
+    })))
+}
+
+/// Validates the code of the authenticator used to log in against each of the secrets of the user,
+/// and remembers its time step in the one that matched so it can't be used again
+pub fn validate_totp_login(user_uuid: &str, totp_code: &str, conn: &DbConn) -> EmptyResult {
+    let mut keys = get_authenticator_keys(user_uuid, conn)?;
+    if keys.is_empty() {
+        err!("Two factor doesn't exist")
+    }
+
     let totp_code: u64 = match totp_code.parse() {
         Ok(code) => code,
         _ => err!("TOTP code is not a number"),
     };
 
-    validate_totp_code(totp_code, secret)
+    // The error of the last secret is returned when none of them match
+    let mut error = None;
+    for key in &mut keys {
+        match validate_totp_code(totp_code, &key.key, key.last_used) {
+            Ok(time_step) => {
+                key.last_used = time_step;
+                error = None;
+                break;
+            }
+            Err(e) => error = Some(e),
+        }
+    }
+
+    match error {
+        Some(e) => Err(e),
+        None => save_authenticator_keys(user_uuid, &keys, conn),
+    }
 }
 
-pub fn validate_totp_code(totp_code: u64, secret: &str) -> EmptyResult {
-    use oath::{totp_raw_now, HashType};
+/// Returns the time step of the code, which has to be after `last_used`
+pub fn validate_totp_code(totp_code: u64, secret: &str, last_used: i32) -> ApiResult<i32> {
+    use oath::{totp_raw_custom_time, HashType};
+
+    const STEP: u64 = 30;
 
     let decoded_secret = match BASE32.decode(secret.as_bytes()) {
         Ok(s) => s,
         Err(_) => err!("Invalid TOTP secret"),
     };
 
-    let generated = totp_raw_now(&decoded_secret, 6, 0, 30, &HashType::SHA1);
-    if generated != totp_code {
-        err!("Invalid TOTP code");
+    // The current step first, then the neighbouring ones covered by the skew
+    let skew_steps = CONFIG.totp_skew_steps();
+    let now = Utc::now().timestamp() as u64;
+    let mut times = vec![(now, 0)];
+    for step in 1..=skew_steps {
+        let offset = step * STEP;
+        times.push((now.saturating_sub(offset), offset));
+        times.push((now + offset, offset));
     }
 
-    Ok(())
+    for (time, offset) in times {
+        if totp_raw_custom_time(&decoded_secret, 6, 0, STEP, time, &HashType::SHA1) != totp_code {
+            continue;
+        }
+
+        let time_step = (time / STEP) as i32;
+        if time_step <= last_used {
+            err!("Invalid TOTP code", "TOTP code already used")
+        }
+
+        if offset > 0 {
+            warn!(
+                "TOTP code only accepted because of the TOTP skew steps ({} seconds off)",
+                offset
+            );
+        }
+        return Ok(time_step);
+    }
+
+    err!("Invalid TOTP code");
 }
 
 use u2f::messages::{RegisterResponse, SignResponse, U2fSignRequest};
@@ -271,11 +744,14 @@ fn generate_u2f(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn)
         err!("Invalid password");
     }
 
-    let (enabled, keys) = get_u2f_registrations(&headers.user.uuid, &conn)?;
-    let keys_json: Vec<Value> = keys.iter().map(U2FRegistration::to_json).collect();
+    let keys: Vec<WebauthnRegistration> = get_webauthn_registrations(&headers.user.uuid, &conn)?
+        .into_iter()
+        .filter(|r| r.migrated)
+        .collect();
+    let keys_json: Vec<Value> = keys.iter().map(WebauthnRegistration::to_json).collect();
 
     Ok(Json(json!({
-        "Enabled": enabled,
+        "Enabled": !keys.is_empty(),
         "Keys": keys_json,
         "Object": "twoFactorU2f"
     })))
@@ -330,16 +806,6 @@ struct U2FRegistration {
     compromised: bool,
 }
 
-impl U2FRegistration {
-    fn to_json(&self) -> Value {
-        json!({
-            "Id": self.id,
-            "Name": self.name,
-            "Compromised": self.compromised,
-        })
-    }
-}
-
 // This struct is copied from the U2F lib
 // to add an optional error code
 #[derive(Deserialize)]
@@ -349,196 +815,997 @@ struct RegisterResponseCopy {
     pub version: String,
     pub client_data: String,
 
-    pub error_code: Option<NumberOrString>,
+    pub error_code: Option<NumberOrString>,
+}
+
+impl Into<RegisterResponse> for RegisterResponseCopy {
+    fn into(self) -> RegisterResponse {
+        RegisterResponse {
+            registration_data: self.registration_data,
+            version: self.version,
+            client_data: self.client_data,
+        }
+    }
+}
+
+#[post("/two-factor/u2f", data = "<data>")]
+fn activate_u2f(data: JsonUpcase<EnableU2FData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: EnableU2FData = data.into_inner().data;
+    let mut user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let tf_type = TwoFactorType::U2fRegisterChallenge as i32;
+    let tf_challenge = match TwoFactor::find_by_user_and_type(&user.uuid, tf_type, &conn) {
+        Some(c) => c,
+        None => err!("Can't recover challenge"),
+    };
+
+    let challenge: Challenge = serde_json::from_str(&tf_challenge.data)?;
+    tf_challenge.delete(&conn)?;
+
+    let response: RegisterResponseCopy = serde_json::from_str(&data.DeviceResponse)?;
+
+    let error_code = response
+        .error_code
+        .clone()
+        .map_or("0".into(), NumberOrString::into_string);
+
+    if error_code != "0" {
+        err!("Error registering U2F token")
+    }
+
+    let registration = U2F.register_response(challenge.clone(), response.into())?;
+    let full_registration = U2FRegistration {
+        id: data.Id.into_i32()?,
+        name: data.Name,
+        reg: registration,
+        compromised: false,
+        counter: 0,
+    };
+
+    // The new keys are stored with the WebAuthn ones, like the migrated keys
+    let mut regs = get_webauthn_registrations(&user.uuid, &conn)?;
+
+    let mut full_registration = WebauthnRegistration::from_u2f(full_registration);
+    full_registration.id = next_free_id(&regs, full_registration.id);
+    regs.push(full_registration);
+    enforce_max_factors(&user.uuid, TwoFactorType::Webauthn, regs.len(), &conn)?;
+    save_webauthn_registrations(&user.uuid, &regs, &conn)?;
+    _notify_twofactor_changed(&user.uuid, Some(TwoFactorType::Webauthn as i32), true, &conn);
+
+    _generate_recover_code(&mut user, &conn);
+
+    let keys_json: Vec<Value> = regs
+        .iter()
+        .filter(|r| r.migrated)
+        .map(WebauthnRegistration::to_json)
+        .collect();
+    Ok(Json(json!({
+        "Enabled": true,
+        "Keys": keys_json,
+        "Object": "twoFactorU2f"
+    })))
+}
+
+#[put("/two-factor/u2f", data = "<data>")]
+fn activate_u2f_put(data: JsonUpcase<EnableU2FData>, headers: Headers, conn: DbConn) -> JsonResult {
+    activate_u2f(data, headers, conn)
+}
+
+fn _create_u2f_challenge(user_uuid: &str, type_: TwoFactorType, conn: &DbConn) -> Challenge {
+    let challenge = U2F.generate_challenge().unwrap();
+
+    TwoFactor::new(user_uuid.into(), type_, serde_json::to_string(&challenge).unwrap())
+        .save(conn)
+        .expect("Error saving challenge");
+
+    challenge
+}
+
+fn save_u2f_registrations(user_uuid: &str, regs: &[U2FRegistration], conn: &DbConn) -> EmptyResult {
+    TwoFactor::new(user_uuid.into(), TwoFactorType::U2f, serde_json::to_string(regs)?).save(&conn)
+}
+
+fn get_u2f_registrations(user_uuid: &str, conn: &DbConn) -> Result<(bool, Vec<U2FRegistration>), Error> {
+    let type_ = TwoFactorType::U2f as i32;
+    let (enabled, regs) = match TwoFactor::find_by_user_and_type(user_uuid, type_, conn) {
+        Some(tf) => (tf.enabled, tf.data),
+        None => return Ok((false, Vec::new())), // If no data, return empty list
+    };
+
+    let data = match serde_json::from_str(&regs) {
+        Ok(d) => d,
+        Err(_) => {
+            // If error, try old format
+            let mut old_regs = _old_parse_registrations(&regs);
+
+            if old_regs.len() != 1 {
+                err!("The old U2F format only allows one device")
+            }
+
+            // Convert to new format
+            let new_regs = vec![U2FRegistration {
+                id: 1,
+                name: "Unnamed U2F key".into(),
+                reg: old_regs.remove(0),
+                compromised: false,
+                counter: 0,
+            }];
+
+            // Save new format
+            save_u2f_registrations(user_uuid, &new_regs, &conn)?;
+
+            new_regs
+        }
+    };
+
+    Ok((enabled, data))
+}
+
+fn _old_parse_registrations(registations: &str) -> Vec<Registration> {
+    #[derive(Deserialize)]
+    struct Helper(#[serde(with = "RegistrationDef")] Registration);
+
+    let regs: Vec<Value> = serde_json::from_str(registations).expect("Can't parse Registration data");
+
+    regs.into_iter()
+        .map(|r| serde_json::from_value(r).unwrap())
+        .map(|Helper(r)| r)
+        .collect()
+}
+
+pub fn generate_u2f_login(user_uuid: &str, conn: &DbConn) -> ApiResult<U2fSignRequest> {
+    let challenge = _create_u2f_challenge(user_uuid, TwoFactorType::U2fLoginChallenge, conn);
+
+    let mut registrations = Vec::new();
+    for reg in get_webauthn_registrations(user_uuid, conn)?
+        .iter()
+        .filter(|r| r.migrated)
+    {
+        registrations.push(reg.to_u2f_registration()?);
+    }
+
+    if registrations.is_empty() {
+        err!("No U2F devices registered")
+    }
+
+    Ok(U2F.sign_request(challenge, registrations))
+}
+
+pub fn validate_u2f_login(user_uuid: &str, response: &str, conn: &DbConn) -> EmptyResult {
+    let challenge_type = TwoFactorType::U2fLoginChallenge as i32;
+    let tf_challenge = TwoFactor::find_by_user_and_type(user_uuid, challenge_type, &conn);
+
+    let challenge = match tf_challenge {
+        Some(tf_challenge) => {
+            let challenge: Challenge = serde_json::from_str(&tf_challenge.data)?;
+            tf_challenge.delete(&conn)?;
+            challenge
+        }
+        None => err!("Can't recover login challenge"),
+    };
+    let response: SignResponse = serde_json::from_str(response)?;
+    let mut registrations = get_webauthn_registrations(user_uuid, conn)?;
+    if registrations.is_empty() {
+        err!("No U2F devices registered")
+    }
+
+    // Only the key that signed the challenge is checked
+    let i = match find_registration(&registrations, &response.key_handle) {
+        Some(i) => i,
+        None => err!("This security key isn't registered"),
+    };
+
+    // The keys registered with WebAuthn are scoped to the domain instead of the U2F app id, so U2F can't use them
+    if !registrations[i].migrated {
+        err!("This security key was registered with WebAuthn, use a client that supports it")
+    }
+
+    let reg = registrations[i].to_u2f_registration()?;
+    let counter = registrations[i].counter;
+    match U2F.sign_response(challenge, reg, response, counter) {
+        Ok(new_counter) => {
+            registrations[i].counter = new_counter;
+            save_webauthn_registrations(user_uuid, &registrations, &conn)
+        }
+        Err(u2f::u2ferror::U2fError::CounterTooLow) => {
+            registrations[i].compromised = true;
+            save_webauthn_registrations(user_uuid, &registrations, &conn)?;
+
+            err!("This device might be compromised!")
+        }
+        Err(e) => err!("error verifying response", format!("Error verifying the U2F response: {:#}", e)),
+    }
+}
+
+/// Position of the registration with this key handle, or credential id, in any of the base64 variants of the clients
+fn find_registration(registrations: &[WebauthnRegistration], key_handle: &str) -> Option<usize> {
+    let key_handle = webauthn::decode_base64(key_handle).ok()?;
+    registrations.iter().position(|r| {
+        webauthn::decode_base64(&r.credential_id).map_or(false, |id| crypto::ct_eq(&id, &key_handle))
+    })
+}
+
+use crate::webauthn;
+
+/// Time the clients have to use the security key, in milliseconds
+const WEBAUTHN_TIMEOUT_MS: u64 = 60_000;
+
+/// A security key registered with WebAuthn, or migrated from U2F.
+/// The credential id and the public key, an uncompressed ES256 point, are in base64url
+#[derive(Serialize, Deserialize)]
+struct WebauthnRegistration {
+    id: i32,
+    name: String,
+    /// The migrated keys are scoped to the U2F app id, and can still be used with U2F
+    migrated: bool,
+    credential_id: String,
+    public_key: String,
+    counter: u32,
+    compromised: bool,
+}
+
+impl WebauthnRegistration {
+    fn from_u2f(reg: U2FRegistration) -> Self {
+        Self {
+            id: reg.id,
+            name: reg.name,
+            migrated: true,
+            credential_id: webauthn::encode_base64(&reg.reg.key_handle),
+            public_key: webauthn::encode_base64(&reg.reg.pub_key),
+            counter: reg.counter,
+            compromised: reg.compromised,
+        }
+    }
+
+    fn to_u2f_registration(&self) -> ApiResult<Registration> {
+        Ok(Registration {
+            key_handle: webauthn::decode_base64(&self.credential_id)?,
+            pub_key: webauthn::decode_base64(&self.public_key)?,
+            attestation_cert: None,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "Id": self.id,
+            "Name": self.name,
+            "Migrated": self.migrated,
+            "Compromised": self.compromised,
+        })
+    }
+}
+
+/// The origin the clients run in and the relying party id, both taken from `DOMAIN`
+fn webauthn_origin_and_rp_id() -> ApiResult<(String, String)> {
+    if !CONFIG.domain_set() {
+        err!("`DOMAIN` environment variable is not set. WebAuthn disabled")
+    }
+
+    let url = match reqwest::Url::parse(&CONFIG.domain()) {
+        Ok(url) => url,
+        Err(_) => err!("`DOMAIN` is not a valid URL. WebAuthn disabled"),
+    };
+
+    match url.host_str() {
+        Some(host) => Ok((url.origin().ascii_serialization(), host.to_string())),
+        None => err!("`DOMAIN` doesn't have a host. WebAuthn disabled"),
+    }
+}
+
+fn get_webauthn_registrations(user_uuid: &str, conn: &DbConn) -> ApiResult<Vec<WebauthnRegistration>> {
+    match TwoFactor::find_by_user_and_type(user_uuid, TwoFactorType::Webauthn as i32, conn) {
+        Some(tf) => Ok(serde_json::from_str(&tf.data)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Saves the registrations, removing WebAuthn from the user when there are none left
+fn save_webauthn_registrations(user_uuid: &str, regs: &[WebauthnRegistration], conn: &DbConn) -> EmptyResult {
+    if regs.is_empty() {
+        return match TwoFactor::find_by_user_and_type(user_uuid, TwoFactorType::Webauthn as i32, conn) {
+            Some(tf) => tf.delete(conn),
+            None => Ok(()),
+        };
+    }
+
+    TwoFactor::new(user_uuid.into(), TwoFactorType::Webauthn, serde_json::to_string(regs)?).save(conn)
+}
+
+/// The clients have a fixed number of slots for the keys, the migrated ones get a new id when theirs is in use
+fn next_free_id(regs: &[WebauthnRegistration], id: i32) -> i32 {
+    if regs.iter().any(|r| r.id == id) {
+        regs.iter().map(|r| r.id).max().unwrap_or(0) + 1
+    } else {
+        id
+    }
+}
+
+pub fn has_migrated_u2f_keys(user_uuid: &str, conn: &DbConn) -> bool {
+    get_webauthn_registrations(user_uuid, conn)
+        .map(|regs| regs.iter().any(|r| r.migrated))
+        .unwrap_or(false)
+}
+
+/// Moves the U2F registrations of the user to WebAuthn, so that the keys don't need to be registered again.
+/// They keep working with U2F for the clients that don't support WebAuthn
+fn migrate_u2f_to_webauthn(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+    use diesel::Connection;
+
+    if TwoFactor::find_by_user_and_type(user_uuid, TwoFactorType::U2f as i32, conn).is_none() {
+        return Ok(());
+    }
+
+    let u2f_regs = get_u2f_registrations(user_uuid, conn)?.1;
+    let mut regs = get_webauthn_registrations(user_uuid, conn)?;
+
+    for reg in u2f_regs {
+        let mut reg = WebauthnRegistration::from_u2f(reg);
+        reg.id = next_free_id(&regs, reg.id);
+        regs.push(reg);
+    }
+
+    conn.transaction::<_, Error, _>(|| {
+        save_webauthn_registrations(user_uuid, &regs, conn)?;
+
+        // Loaded again, the old format is converted when loading the registrations, which replaces the row
+        match TwoFactor::find_by_user_and_type(user_uuid, TwoFactorType::U2f as i32, conn) {
+            Some(tf) => tf.delete(conn),
+            None => Ok(()),
+        }
+    })
+}
+
+/// Moves the U2F registrations of all the users to WebAuthn, run once at startup
+pub fn migrate_all_u2f_to_webauthn(conn: &DbConn) -> EmptyResult {
+    for tf in TwoFactor::find_by_type(TwoFactorType::U2f as i32, conn) {
+        migrate_u2f_to_webauthn(&tf.user_uuid, conn)?;
+    }
+    Ok(())
+}
+
+#[post("/two-factor/get-webauthn", data = "<data>")]
+fn get_webauthn(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    webauthn_origin_and_rp_id()?;
+    let data: PasswordData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let regs = get_webauthn_registrations(&headers.user.uuid, &conn)?;
+    let keys_json: Vec<Value> = regs.iter().map(WebauthnRegistration::to_json).collect();
+
+    Ok(Json(json!({
+        "Enabled": !regs.is_empty(),
+        "Keys": keys_json,
+        "Object": "twoFactorWebAuthn"
+    })))
+}
+
+#[post("/two-factor/get-webauthn-challenge", data = "<data>")]
+fn generate_webauthn_challenge(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let (_, rp_id) = webauthn_origin_and_rp_id()?;
+    let data: PasswordData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let exclude_credentials: Vec<Value> = get_webauthn_registrations(&user.uuid, &conn)?
+        .iter()
+        .map(|r| json!({ "type": "public-key", "id": r.credential_id }))
+        .collect();
+
+    let challenge = webauthn::new_challenge();
+    TwoFactor::new(
+        user.uuid.clone(),
+        TwoFactorType::WebauthnRegisterChallenge,
+        challenge.clone(),
+    )
+    .save(&conn)?;
+
+    // Only ES256 is supported, which is what the security keys use
+    Ok(Json(json!({
+        "rp": { "id": rp_id, "name": "Bitwarden" },
+        "user": {
+            "id": webauthn::encode_base64(user.uuid.as_bytes()),
+            "name": user.email,
+            "displayName": user.name,
+        },
+        "challenge": challenge,
+        "pubKeyCredParams": [{ "type": "public-key", "alg": webauthn::COSE_ALG_ES256 }],
+        "timeout": WEBAUTHN_TIMEOUT_MS,
+        "attestation": "none",
+        "excludeCredentials": exclude_credentials,
+        "authenticatorSelection": {
+            "requireResidentKey": false,
+            "userVerification": "discouraged",
+        },
+        "extensions": {},
+        "status": "ok",
+        "errorMessage": "",
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct EnableWebauthnData {
+    Id: NumberOrString, // 1..5
+    Name: String,
+    MasterPasswordHash: String,
+    DeviceResponse: WebauthnRegisterResponse,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct WebauthnRegisterResponse {
+    Response: WebauthnAttestationResponse,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct WebauthnAttestationResponse {
+    AttestationObject: String,
+    #[serde(alias = "ClientDataJSON")]
+    ClientDataJson: String,
+}
+
+#[post("/two-factor/webauthn", data = "<data>")]
+fn activate_webauthn(data: JsonUpcase<EnableWebauthnData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let (origin, rp_id) = webauthn_origin_and_rp_id()?;
+    let data: EnableWebauthnData = data.into_inner().data;
+    let mut user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let tf_type = TwoFactorType::WebauthnRegisterChallenge as i32;
+    let tf_challenge = match TwoFactor::find_by_user_and_type(&user.uuid, tf_type, &conn) {
+        Some(c) => c,
+        None => err!("Can't recover challenge"),
+    };
+    let challenge = tf_challenge.data.clone();
+    tf_challenge.delete(&conn)?;
+
+    let response = data.DeviceResponse.Response;
+    let credential = webauthn::parse_registration(
+        &webauthn::decode_base64(&response.AttestationObject)?,
+        &webauthn::decode_base64(&response.ClientDataJson)?,
+        &challenge,
+        &origin,
+        &rp_id,
+    )?;
+
+    let mut regs = get_webauthn_registrations(&user.uuid, &conn)?;
+
+    let credential_id = webauthn::encode_base64(&credential.id);
+    if regs.iter().any(|r| r.credential_id == credential_id) {
+        err!("This security key is already registered")
+    }
+
+    // A key registered in a slot that's in use replaces the previous one
+    let id = data.Id.into_i32()?;
+    regs.retain(|r| r.id != id);
+    regs.push(WebauthnRegistration {
+        id,
+        name: data.Name,
+        migrated: false,
+        credential_id,
+        public_key: webauthn::encode_base64(&credential.public_key),
+        counter: credential.counter,
+        compromised: false,
+    });
+
+    enforce_max_factors(&user.uuid, TwoFactorType::Webauthn, regs.len(), &conn)?;
+    save_webauthn_registrations(&user.uuid, &regs, &conn)?;
+    _notify_twofactor_changed(&user.uuid, Some(TwoFactorType::Webauthn as i32), true, &conn);
+
+    _generate_recover_code(&mut user, &conn);
+
+    let keys_json: Vec<Value> = regs.iter().map(WebauthnRegistration::to_json).collect();
+    Ok(Json(json!({
+        "Enabled": true,
+        "Keys": keys_json,
+        "Object": "twoFactorWebAuthn"
+    })))
+}
+
+#[put("/two-factor/webauthn", data = "<data>")]
+fn activate_webauthn_put(data: JsonUpcase<EnableWebauthnData>, headers: Headers, conn: DbConn) -> JsonResult {
+    activate_webauthn(data, headers, conn)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct DeleteWebauthnData {
+    Id: NumberOrString,
+    MasterPasswordHash: String,
+}
+
+#[delete("/two-factor/webauthn", data = "<data>")]
+fn delete_webauthn(data: JsonUpcase<DeleteWebauthnData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: DeleteWebauthnData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let mut regs = get_webauthn_registrations(&user.uuid, &conn)?;
+
+    let id = data.Id.into_i32()?;
+    if !regs.iter().any(|r| r.id == id) {
+        err!("Security key not found")
+    }
+
+    regs.retain(|r| r.id != id);
+    save_webauthn_registrations(&user.uuid, &regs, &conn)?;
+    _notify_twofactor_changed(
+        &user.uuid,
+        Some(TwoFactorType::Webauthn as i32),
+        !regs.is_empty(),
+        &conn,
+    );
+
+    let keys_json: Vec<Value> = regs.iter().map(WebauthnRegistration::to_json).collect();
+    Ok(Json(json!({
+        "Enabled": !regs.is_empty(),
+        "Keys": keys_json,
+        "Object": "twoFactorWebAuthn"
+    })))
+}
+
+/// The request sent to the clients at login, they pass it to the browser to get the assertion
+pub fn generate_webauthn_login(user_uuid: &str, conn: &DbConn) -> ApiResult<Value> {
+    let (_, rp_id) = webauthn_origin_and_rp_id()?;
+
+    let regs = get_webauthn_registrations(user_uuid, conn)?;
+    if regs.is_empty() {
+        err!("No WebAuthn devices registered")
+    }
+
+    let challenge = webauthn::new_challenge();
+    TwoFactor::new(
+        user_uuid.into(),
+        TwoFactorType::WebauthnLoginChallenge,
+        challenge.clone(),
+    )
+    .save(conn)?;
+
+    let allow_credentials: Vec<Value> = regs
+        .iter()
+        .map(|r| json!({ "type": "public-key", "id": r.credential_id }))
+        .collect();
+
+    let mut request = json!({
+        "challenge": challenge,
+        "timeout": WEBAUTHN_TIMEOUT_MS,
+        "rpId": rp_id,
+        "allowCredentials": allow_credentials,
+        "userVerification": "discouraged",
+        "extensions": {},
+        "status": "ok",
+        "errorMessage": "",
+    });
+
+    // The browsers only find the migrated keys with the U2F app id
+    if regs.iter().any(|r| r.migrated) {
+        request["extensions"]["appid"] = Value::String(APP_ID.to_string());
+    }
+
+    Ok(request)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WebauthnLoginResponse {
+    raw_id: String,
+    response: WebauthnAssertionResponse,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WebauthnAssertionResponse {
+    authenticator_data: String,
+    #[serde(alias = "clientDataJSON")]
+    client_data_json: String,
+    signature: String,
+}
+
+pub fn validate_webauthn_login(user_uuid: &str, response: &str, conn: &DbConn) -> EmptyResult {
+    let (origin, rp_id) = webauthn_origin_and_rp_id()?;
+
+    let challenge_type = TwoFactorType::WebauthnLoginChallenge as i32;
+    let challenge = match TwoFactor::find_by_user_and_type(user_uuid, challenge_type, conn) {
+        Some(tf_challenge) => {
+            let challenge = tf_challenge.data.clone();
+            tf_challenge.delete(conn)?;
+            challenge
+        }
+        None => err!("Can't recover login challenge"),
+    };
+
+    let response: WebauthnLoginResponse = serde_json::from_str(response)?;
+    let credential_id = webauthn::encode_base64(&webauthn::decode_base64(&response.raw_id)?);
+
+    let mut regs = get_webauthn_registrations(user_uuid, conn)?;
+    let reg = match regs.iter_mut().find(|r| r.credential_id == credential_id) {
+        Some(reg) => reg,
+        None => err!("Security key not registered"),
+    };
+
+    let app_id = APP_ID.to_string();
+    let rp_ids: Vec<&str> = if reg.migrated {
+        vec![rp_id.as_str(), app_id.as_str()]
+    } else {
+        vec![rp_id.as_str()]
+    };
+
+    let counter = webauthn::verify_assertion(
+        &webauthn::decode_base64(&response.response.authenticator_data)?,
+        &webauthn::decode_base64(&response.response.client_data_json)?,
+        &webauthn::decode_base64(&response.response.signature)?,
+        &challenge,
+        &origin,
+        &rp_ids,
+        &webauthn::decode_base64(&reg.public_key)?,
+    )?;
+
+    // The authenticators without a counter always send 0, otherwise it has to increase
+    if (counter != 0 || reg.counter != 0) && counter <= reg.counter {
+        reg.compromised = true;
+        save_webauthn_registrations(user_uuid, &regs, conn)?;
+
+        err!("This device might be compromised!");
+    }
+
+    reg.counter = counter;
+    save_webauthn_registrations(user_uuid, &regs, conn)
+}
+
+//
+// Email
+//
+
+/// Number of digits of the codes sent by email
+const EMAIL_TOKEN_DIGITS: u32 = 6;
+/// Number of wrong codes until the codes expire, even if new ones are sent, so guessing one needs a few attempts per window
+const EMAIL_TOKEN_MAX_ATTEMPTS: i32 = 5;
+/// Seconds to wait before sending another code, so the address can't be flooded
+const EMAIL_TOKEN_RESEND_SECONDS: i64 = 60;
+/// Purpose of the server key that hashes the codes, a leaked database isn't enough to check the few possible codes
+const EMAIL_TOKEN_KEY_PURPOSE: &str = "email_2fa_token";
+
+/// The address the codes are sent to, with the hash of the last code sent. The hash is removed once the code is used
+#[derive(Serialize, Deserialize)]
+struct EmailTokenData {
+    email: String,
+    token_hash: Option<String>,
+    /// Unix timestamp of when the last code was sent
+    token_sent: i64,
+    /// Wrong codes since the first code of the window, a new code doesn't reset them
+    #[serde(default)]
+    attempts: i32,
+}
+
+fn hash_email_token(token: &str) -> String {
+    let key = crate::auth::server_key(EMAIL_TOKEN_KEY_PURPOSE);
+    HEXLOWER.encode(&crypto::hmac_sha256(&key, token.trim().as_bytes()))
+}
+
+impl EmailTokenData {
+    fn new(email: String) -> Self {
+        Self {
+            email,
+            token_hash: None,
+            token_sent: 0,
+            attempts: 0,
+        }
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        now > self.token_sent + CONFIG.email_2fa_code_minutes() * 60
+    }
+
+    /// Replaces the previous code with a new one, returning it. Fails when the last one was sent too recently,
+    /// or too many wrong codes were tried and the last one didn't expire yet
+    fn set_token(&mut self) -> ApiResult<String> {
+        let now = Utc::now().timestamp();
+        if now < self.token_sent + EMAIL_TOKEN_RESEND_SECONDS {
+            err!(format!(
+                "A verification code was just sent, wait {} seconds to request a new one",
+                self.token_sent + EMAIL_TOKEN_RESEND_SECONDS - now
+            ))
+        }
+
+        if self.is_expired(now) {
+            self.attempts = 0;
+        } else if self.attempts >= EMAIL_TOKEN_MAX_ATTEMPTS {
+            err!("Too many wrong verification codes, wait for the last one to expire")
+        }
+
+        let random = crypto::get_random(vec![0u8; 8]);
+        let number = random.iter().fold(0u64, |acc, &b| (acc << 8) | u64::from(b));
+        let token = format!(
+            "{:0width$}",
+            number % 10u64.pow(EMAIL_TOKEN_DIGITS),
+            width = EMAIL_TOKEN_DIGITS as usize
+        );
+
+        self.token_hash = Some(hash_email_token(&token));
+        self.token_sent = now;
+        Ok(token)
+    }
+
+    /// Checks the code, which is used up when it matches. The data needs to be saved after it, even when it fails
+    fn take_token(&mut self, token: &str) -> EmptyResult {
+        let token_hash = match self.token_hash.take() {
+            Some(hash) => hash,
+            None => err!("No verification code was sent, or it was already used"),
+        };
+
+        if self.is_expired(Utc::now().timestamp()) {
+            err!("The verification code expired, request a new one")
+        }
+
+        if !crypto::ct_eq(hash_email_token(token), &token_hash) {
+            self.attempts += 1;
+            if self.attempts < EMAIL_TOKEN_MAX_ATTEMPTS {
+                self.token_hash = Some(token_hash);
+            }
+            err!("Invalid verification code")
+        }
+
+        self.attempts = 0;
+        Ok(())
+    }
+}
+
+/// Removes the expired codes from the data of the email two-step login, returning how many were removed
+pub fn clear_expired_email_tokens(conn: &DbConn) -> ApiResult<usize> {
+    let now = Utc::now().timestamp();
+    let mut cleared = 0;
+
+    for mut twofactor in TwoFactor::find_by_type(TwoFactorType::Email as i32, conn) {
+        let mut email_data: EmailTokenData = match serde_json::from_str(&twofactor.data) {
+            Ok(email_data) => email_data,
+            Err(_) => continue,
+        };
+        if email_data.token_hash.is_none() || !email_data.is_expired(now) {
+            continue;
+        }
+
+        // The wrong attempts are kept, the next code resets them as it would have
+        email_data.token_hash = None;
+        twofactor.data = serde_json::to_string(&email_data)?;
+        twofactor.save(conn)?;
+        cleared += 1;
+    }
+
+    Ok(cleared)
+}
+
+/// Hides most of the address, keeping the start of the name and the domain so that the user can recognize it
+fn mask_email(email: &str) -> String {
+    let (name, domain) = match email.rfind('@') {
+        Some(at) => email.split_at(at),
+        None => (email, ""),
+    };
+
+    let len = name.chars().count();
+    let shown = std::cmp::min(2, len / 2);
+    let visible: String = name.chars().take(shown).collect();
+
+    format!("{}{}{}", visible, "*".repeat(len - shown), domain)
+}
+
+fn check_email_2fa_available() -> EmptyResult {
+    if !CONFIG.mail_enabled() {
+        err!("The email two-step login requires SMTP to be configured")
+    }
+    Ok(())
 }
 
-impl Into<RegisterResponse> for RegisterResponseCopy {
-    fn into(self) -> RegisterResponse {
-        RegisterResponse {
-            registration_data: self.registration_data,
-            version: self.version,
-            client_data: self.client_data,
+fn get_email_twofactor(user_uuid: &str, conn: &DbConn) -> ApiResult<(TwoFactor, EmailTokenData)> {
+    match TwoFactor::find_by_user_and_type(user_uuid, TwoFactorType::Email as i32, conn) {
+        Some(twofactor) => {
+            let email_data = serde_json::from_str(&twofactor.data)?;
+            Ok((twofactor, email_data))
         }
+        None => err!("The email two-step login is not enabled"),
     }
 }
 
-#[post("/two-factor/u2f", data = "<data>")]
-fn activate_u2f(data: JsonUpcase<EnableU2FData>, headers: Headers, conn: DbConn) -> JsonResult {
-    let data: EnableU2FData = data.into_inner().data;
-    let mut user = headers.user;
+#[post("/two-factor/get-email", data = "<data>")]
+fn get_email(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+    let user = headers.user;
 
     if !user.check_valid_password(&data.MasterPasswordHash) {
         err!("Invalid password");
     }
 
-    let tf_type = TwoFactorType::U2fRegisterChallenge as i32;
-    let tf_challenge = match TwoFactor::find_by_user_and_type(&user.uuid, tf_type, &conn) {
-        Some(c) => c,
-        None => err!("Can't recover challenge"),
+    // Until it's enabled, the account address is suggested
+    let (enabled, email) = match get_email_twofactor(&user.uuid, &conn) {
+        Ok((_, email_data)) => (true, email_data.email),
+        Err(_) => (false, user.email),
     };
 
-    let challenge: Challenge = serde_json::from_str(&tf_challenge.data)?;
-    tf_challenge.delete(&conn)?;
+    Ok(Json(json!({
+        "Email": email,
+        "Enabled": enabled,
+        "Object": "twoFactorEmail"
+    })))
+}
 
-    let response: RegisterResponseCopy = serde_json::from_str(&data.DeviceResponse)?;
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct SendEmailData {
+    Email: String,
+    MasterPasswordHash: String,
+}
 
-    let error_code = response
-        .error_code
-        .clone()
-        .map_or("0".into(), NumberOrString::into_string);
+/// Sends the code that verifies the address, before the provider is enabled with it
+#[post("/two-factor/send-email", data = "<data>")]
+fn send_email_verification(data: JsonUpcase<SendEmailData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    check_email_2fa_available()?;
 
-    if error_code != "0" {
-        err!("Error registering U2F token")
+    let data: SendEmailData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
     }
 
-    let registration = U2F.register_response(challenge.clone(), response.into())?;
-    let full_registration = U2FRegistration {
-        id: data.Id.into_i32()?,
-        name: data.Name,
-        reg: registration,
-        compromised: false,
-        counter: 0,
+    // The previous code is replaced, keeping when it was sent and the wrong attempts
+    let challenge_type = TwoFactorType::EmailVerificationChallenge;
+    let mut email_data = match TwoFactor::find_by_user_and_type(&user.uuid, challenge_type as i32, &conn) {
+        Some(challenge) => serde_json::from_str(&challenge.data)?,
+        None => EmailTokenData::new(String::new()),
     };
+    email_data.email = data.Email.trim().to_lowercase();
+    let token = email_data.set_token()?;
 
-    let mut regs = get_u2f_registrations(&user.uuid, &conn)?.1;
-
-    // TODO: Check that there is no repeat Id
-    regs.push(full_registration);
-    save_u2f_registrations(&user.uuid, &regs, &conn)?;
-
-    _generate_recover_code(&mut user, &conn);
+    TwoFactor::new(user.uuid.clone(), challenge_type, serde_json::to_string(&email_data)?).save(&conn)?;
 
-    let keys_json: Vec<Value> = regs.iter().map(U2FRegistration::to_json).collect();
-    Ok(Json(json!({
-        "Enabled": true,
-        "Keys": keys_json,
-        "Object": "twoFactorU2f"
-    })))
+    mail::send_twofactor_email_token(&email_data.email, &token, user.locale(DEFAULT_LOCALE))
 }
 
-#[put("/two-factor/u2f", data = "<data>")]
-fn activate_u2f_put(data: JsonUpcase<EnableU2FData>, headers: Headers, conn: DbConn) -> JsonResult {
-    activate_u2f(data, headers, conn)
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct EnableEmailData {
+    Email: String,
+    MasterPasswordHash: String,
+    Token: NumberOrString,
 }
 
-fn _create_u2f_challenge(user_uuid: &str, type_: TwoFactorType, conn: &DbConn) -> Challenge {
-    let challenge = U2F.generate_challenge().unwrap();
-
-    TwoFactor::new(user_uuid.into(), type_, serde_json::to_string(&challenge).unwrap())
-        .save(conn)
-        .expect("Error saving challenge");
-
-    challenge
-}
+#[post("/two-factor/email", data = "<data>")]
+fn activate_email(data: JsonUpcase<EnableEmailData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: EnableEmailData = data.into_inner().data;
+    let mut user = headers.user;
 
-fn save_u2f_registrations(user_uuid: &str, regs: &[U2FRegistration], conn: &DbConn) -> EmptyResult {
-    TwoFactor::new(user_uuid.into(), TwoFactorType::U2f, serde_json::to_string(regs)?).save(&conn)
-}
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
 
-fn get_u2f_registrations(user_uuid: &str, conn: &DbConn) -> Result<(bool, Vec<U2FRegistration>), Error> {
-    let type_ = TwoFactorType::U2f as i32;
-    let (enabled, regs) = match TwoFactor::find_by_user_and_type(user_uuid, type_, conn) {
-        Some(tf) => (tf.enabled, tf.data),
-        None => return Ok((false, Vec::new())), // If no data, return empty list
+    let challenge_type = TwoFactorType::EmailVerificationChallenge as i32;
+    let mut challenge = match TwoFactor::find_by_user_and_type(&user.uuid, challenge_type, &conn) {
+        Some(challenge) => challenge,
+        None => err!("Request a verification code first"),
     };
 
-    let data = match serde_json::from_str(&regs) {
-        Ok(d) => d,
-        Err(_) => {
-            // If error, try old format
-            let mut old_regs = _old_parse_registrations(&regs);
+    let mut email_data: EmailTokenData = serde_json::from_str(&challenge.data)?;
+    if email_data.email != data.Email.trim().to_lowercase() {
+        err!("The verification code was sent to a different email")
+    }
 
-            if old_regs.len() != 1 {
-                err!("The old U2F format only allows one device")
-            }
+    if let Err(e) = email_data.take_token(&data.Token.into_string()) {
+        challenge.data = serde_json::to_string(&email_data)?;
+        challenge.save(&conn)?;
+        return Err(e);
+    }
 
-            // Convert to new format
-            let new_regs = vec![U2FRegistration {
-                id: 1,
-                name: "Unnamed U2F key".into(),
-                reg: old_regs.remove(0),
-                compromised: false,
-                counter: 0,
-            }];
+    enforce_max_factors(&user.uuid, TwoFactorType::Email, 1, &conn)?;
+    challenge.delete(&conn)?;
 
-            // Save new format
-            save_u2f_registrations(user_uuid, &new_regs, &conn)?;
+    let twofactor = TwoFactor::new(
+        user.uuid.clone(),
+        TwoFactorType::Email,
+        serde_json::to_string(&email_data)?,
+    );
+    twofactor.save(&conn)?;
+    _notify_twofactor_changed(&user.uuid, Some(TwoFactorType::Email as i32), true, &conn);
 
-            new_regs
-        }
-    };
+    _generate_recover_code(&mut user, &conn);
 
-    Ok((enabled, data))
+    Ok(Json(json!({
+        "Email": email_data.email,
+        "Enabled": true,
+        "Object": "twoFactorEmail"
+    })))
 }
 
-fn _old_parse_registrations(registations: &str) -> Vec<Registration> {
-    #[derive(Deserialize)]
-    struct Helper(#[serde(with = "RegistrationDef")] Registration);
+#[put("/two-factor/email", data = "<data>")]
+fn activate_email_put(data: JsonUpcase<EnableEmailData>, headers: Headers, conn: DbConn) -> JsonResult {
+    activate_email(data, headers, conn)
+}
 
-    let regs: Vec<Value> = serde_json::from_str(registations).expect("Can't parse Registration data");
+/// Sends a new code during a login, when the user asks for it. The password is checked like in the login itself
+#[post("/two-factor/send-email-login", data = "<data>")]
+fn send_email_login(data: JsonUpcase<SendEmailData>, ip: ClientIp, conn: DbConn) -> EmptyResult {
+    let data: SendEmailData = data.into_inner().data;
+    let ip_key = ip.ip.to_string();
+
+    if let Some(seconds) = lockout::ratelimit_retry_after(&ip_key) {
+        return Err(lockout::ratelimited_error(
+            seconds,
+            format!("IP: {}. Rate limited.", ip.ip),
+        ));
+    }
 
-    regs.into_iter()
-        .map(|r| serde_json::from_value(r).unwrap())
-        .map(|Helper(r)| r)
-        .collect()
+    let user = match User::find_by_mail(&data.Email, &conn) {
+        Some(user) if user.check_valid_password(&data.MasterPasswordHash) => user,
+        _ => {
+            lockout::register_ratelimit_failure(&ip_key);
+            err!(
+                "Username or password is incorrect. Try again",
+                format!("IP: {}. Username: {}.", ip.ip, data.Email)
+            )
+        }
+    };
+
+    send_email_login_token(&user, &conn)
 }
 
-pub fn generate_u2f_login(user_uuid: &str, conn: &DbConn) -> ApiResult<U2fSignRequest> {
-    let challenge = _create_u2f_challenge(user_uuid, TwoFactorType::U2fLoginChallenge, conn);
+pub fn send_email_login_token(user: &User, conn: &DbConn) -> EmptyResult {
+    check_email_2fa_available()?;
 
-    let registrations: Vec<_> = get_u2f_registrations(user_uuid, conn)?
-        .1
-        .into_iter()
-        .map(|r| r.reg)
-        .collect();
+    let (mut twofactor, mut email_data) = get_email_twofactor(&user.uuid, conn)?;
+    let token = email_data.set_token()?;
 
-    if registrations.is_empty() {
-        err!("No U2F devices registered")
-    }
+    twofactor.data = serde_json::to_string(&email_data)?;
+    twofactor.save(conn)?;
 
-    Ok(U2F.sign_request(challenge, registrations))
+    mail::send_twofactor_email_token(&email_data.email, &token, user.locale(DEFAULT_LOCALE))
 }
 
-pub fn validate_u2f_login(user_uuid: &str, response: &str, conn: &DbConn) -> EmptyResult {
-    let challenge_type = TwoFactorType::U2fLoginChallenge as i32;
-    let tf_challenge = TwoFactor::find_by_user_and_type(user_uuid, challenge_type, &conn);
-
-    let challenge = match tf_challenge {
-        Some(tf_challenge) => {
-            let challenge: Challenge = serde_json::from_str(&tf_challenge.data)?;
-            tf_challenge.delete(&conn)?;
-            challenge
-        }
-        None => err!("Can't recover login challenge"),
-    };
-    let response: SignResponse = serde_json::from_str(response)?;
-    let mut registrations = get_u2f_registrations(user_uuid, conn)?.1;
-    if registrations.is_empty() {
-        err!("No U2F devices registered")
-    }
+/// The data the clients show when asking for the code: the masked address, and whether a code was just sent.
+/// The code is only sent automatically when `send` is true, otherwise the clients request it with `send_email_login`
+pub fn email_login_metadata(user_uuid: &str, send: bool, conn: &DbConn) -> ApiResult<Value> {
+    let code_sent = send
+        && match User::find_by_uuid(user_uuid, conn) {
+            Some(user) => match send_email_login_token(&user, conn) {
+                Ok(()) => true,
+                Err(e) => {
+                    error!("Error sending the two-step login email: {:#?}", e);
+                    false
+                }
+            },
+            None => false,
+        };
+
+    let (_, email_data) = get_email_twofactor(user_uuid, conn)?;
+
+    Ok(json!({
+        "Email": mask_email(&email_data.email),
+        "CodeSent": code_sent,
+    }))
+}
 
-    for reg in &mut registrations {
-        let response = U2F.sign_response(challenge.clone(), reg.reg.clone(), response.clone(), reg.counter);
-        match response {
-            Ok(new_counter) => {
-                reg.counter = new_counter;
-                save_u2f_registrations(user_uuid, &registrations, &conn)?;
+pub fn validate_email_login(user_uuid: &str, token: &str, conn: &DbConn) -> EmptyResult {
+    let (mut twofactor, mut email_data) = get_email_twofactor(user_uuid, conn)?;
+    let result = email_data.take_token(token);
 
-                return Ok(());
-            }
-            Err(u2f::u2ferror::U2fError::CounterTooLow) => {
-                reg.compromised = true;
-                save_u2f_registrations(user_uuid, &registrations, &conn)?;
+    twofactor.data = serde_json::to_string(&email_data)?;
+    twofactor.save(conn)?;
 
-                err!("This device might be compromised!");
-            }
-            Err(e) => {
-                warn!("E {:#}", e);
-                // break;
-            }
-        }
-    }
-    err!("error verifying response")
+    result
 }
 
 #[derive(Deserialize, Debug)]
@@ -586,13 +1853,39 @@ fn get_yubico_credentials() -> Result<(String, String), Error> {
     }
 }
 
+/// The validation servers from `YUBICO_SERVER`, `None` to use YubiCloud
+fn yubico_servers() -> Option<Vec<String>> {
+    let servers: Vec<String> = CONFIG
+        .yubico_server()?
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect();
+
+    if servers.is_empty() {
+        None
+    } else {
+        Some(servers)
+    }
+}
+
 fn verify_yubikey_otp(otp: String) -> EmptyResult {
     let (yubico_id, yubico_secret) = get_yubico_credentials()?;
+    verify_yubikey_otp_with_servers(otp, yubico_id, yubico_secret, yubico_servers())
+}
 
+fn verify_yubikey_otp_with_servers(
+    otp: String,
+    yubico_id: String,
+    yubico_secret: String,
+    servers: Option<Vec<String>>,
+) -> EmptyResult {
     let config = Config::default().set_client_id(yubico_id).set_key(yubico_secret);
 
-    match CONFIG.yubico_server() {
-        Some(server) => verify(otp, config.set_api_hosts(vec![server])),
+    // The self-hosted servers replace YubiCloud, the responses are checked the same way
+    match servers {
+        Some(servers) => verify(otp, config.set_api_hosts(servers)),
         None => verify(otp, config),
     }
     .map_res("Failed to verify OTP")
@@ -669,6 +1962,7 @@ fn activate_yubikey(data: JsonUpcase<EnableYubikeyData>, headers: Headers, conn:
     }
 
     let yubikey_ids: Vec<String> = yubikeys.into_iter().map(|x| (&x[..12]).to_owned()).collect();
+    enforce_max_factors(&user.uuid, TwoFactorType::YubiKey, yubikey_ids.len(), &conn)?;
 
     let yubikey_metadata = YubikeyMetadata {
         Keys: yubikey_ids,
@@ -677,6 +1971,7 @@ fn activate_yubikey(data: JsonUpcase<EnableYubikeyData>, headers: Headers, conn:
 
     yubikey_data.data = serde_json::to_string(&yubikey_metadata).unwrap();
     yubikey_data.save(&conn)?;
+    _notify_twofactor_changed(&user.uuid, Some(TwoFactorType::YubiKey as i32), true, &conn);
 
     _generate_recover_code(&mut user, &conn);
 
@@ -849,15 +2144,21 @@ fn activate_duo(data: JsonUpcase<EnableDuoData>, headers: Headers, conn: DbConn)
     let (data, data_str) = if check_duo_fields_custom(&data) {
         let data_req: DuoData = data.into();
         let data_str = serde_json::to_string(&data_req)?;
-        duo_api_request("GET", "/auth/v2/check", "", &data_req).map_res("Failed to validate Duo credentials")?;
+        if CONFIG.duo_use_iframe() {
+            duo_api_request("GET", "/auth/v2/check", "", &data_req).map_res("Failed to validate Duo credentials")?;
+        } else {
+            duo_health_check(&data_req).map_res("Failed to validate Duo credentials")?;
+        }
         (data_req.obscure(), data_str)
     } else {
         (DuoData::secret(), String::new())
     };
 
     let type_ = TwoFactorType::Duo;
+    enforce_max_factors(&headers.user.uuid, type_, 1, &conn)?;
     let twofactor = TwoFactor::new(headers.user.uuid.clone(), type_, data_str);
     twofactor.save(&conn)?;
+    _notify_twofactor_changed(&headers.user.uuid, Some(type_ as i32), true, &conn);
 
     Ok(Json(json!({
         "Enabled": true,
@@ -930,12 +2231,16 @@ fn get_user_duo_data(uuid: &str, conn: &DbConn) -> DuoStatus {
     DuoStatus::Disabled(false)
 }
 
-// let (ik, sk, ak, host) = get_duo_keys();
-fn get_duo_keys_email(email: &str, conn: &DbConn) -> ApiResult<(String, String, String, String)> {
-    let data = User::find_by_mail(email, &conn)
+fn get_duo_data_email(email: &str, conn: &DbConn) -> ApiResult<DuoData> {
+    User::find_by_mail(email, &conn)
         .and_then(|u| get_user_duo_data(&u.uuid, &conn).data())
         .or_else(DuoData::global)
-        .map_res("Can't fetch Duo keys")?;
+        .map_res("Can't fetch Duo keys")
+}
+
+// let (ik, sk, ak, host) = get_duo_keys();
+fn get_duo_keys_email(email: &str, conn: &DbConn) -> ApiResult<(String, String, String, String)> {
+    let data = get_duo_data_email(email, conn)?;
 
     Ok((data.ik, data.sk, CONFIG.get_duo_akey(), data.host))
 }
@@ -958,7 +2263,21 @@ fn sign_duo_values(key: &str, email: &str, ikey: &str, prefix: &str, expire: i64
     format!("{}|{}", cookie, crypto::hmac_sign(key, &cookie))
 }
 
-pub fn validate_duo_login(email: &str, response: &str, conn: &DbConn) -> EmptyResult {
+/// Validates the response of the Universal Prompt, or of the legacy iframe one with `DUO_USE_IFRAME`
+pub fn validate_duo_login(user_uuid: &str, response: &str, conn: &DbConn) -> EmptyResult {
+    let user = match User::find_by_uuid(user_uuid, conn) {
+        Some(user) => user,
+        None => err!("User does not exist"),
+    };
+
+    if CONFIG.duo_use_iframe() {
+        validate_duo_iframe_login(&user.email, response, conn)
+    } else {
+        validate_duo_universal_login(&user, response, conn)
+    }
+}
+
+fn validate_duo_iframe_login(email: &str, response: &str, conn: &DbConn) -> EmptyResult {
     let split: Vec<&str> = response.split(':').collect();
     if split.len() != 2 {
         err!("Invalid response length");
@@ -1030,8 +2349,405 @@ fn parse_duo_values(key: &str, val: &str, ikey: &str, prefix: &str, time: i64) -
     };
 
     if time >= expire {
-        err!("Expired authorization")
+        if time >= expire + CONFIG.token_clock_skew_seconds() as i64 {
+            err!("Expired authorization")
+        }
+        warn!("Duo authorization only accepted because of the clock skew leeway");
     }
 
     Ok(username.into())
 }
+
+//
+// Duo Universal Prompt
+//
+
+/// Page of the web vault receiving the redirect of the Universal Prompt, it passes the code and the state to the client
+const DUO_REDIRECT_CONNECTOR: &str = "duo-redirect-connector.html";
+const DUO_AGENT: &str = "bitwarden_rs:Duo/2.0 (Rust)";
+
+/// The connector returns the code to the client differently for each kind of client, with the same names as the official server
+fn duo_redirect_client(device_type: i32) -> &'static str {
+    use num_traits::FromPrimitive;
+
+    match DeviceType::from_i32(device_type) {
+        Some(DeviceType::Android) | Some(DeviceType::Ios) | Some(DeviceType::AndroidAmazon) => "mobile",
+        Some(DeviceType::WindowsDesktop)
+        | Some(DeviceType::MacOsDesktop)
+        | Some(DeviceType::LinuxDesktop)
+        | Some(DeviceType::UWP) => "desktop",
+        Some(DeviceType::ChromeExtension)
+        | Some(DeviceType::FirefoxExtension)
+        | Some(DeviceType::OperaExtension)
+        | Some(DeviceType::EdgeExtension)
+        | Some(DeviceType::VivaldiExtension)
+        | Some(DeviceType::SafariExtension) => "browser",
+        _ => "web",
+    }
+}
+
+/// The requests to Duo are JWTs signed with the client secret
+fn duo_jwt(claims: &Value, data: &DuoData) -> ApiResult<String> {
+    jsonwebtoken::encode(&Header::new(Algorithm::HS512), claims, data.sk.as_bytes())
+        .map_res("Error signing the Duo request")
+}
+
+/// Sends a request to one of the OIDC endpoints of Duo, authenticated with a client assertion
+fn duo_oauth_request(data: &DuoData, path: &str, params: &[(&str, &str)]) -> ApiResult<Value> {
+    use reqwest::{header::USER_AGENT, Client};
+
+    let url = format!("https://{}{}", data.host, path);
+    let now = Utc::now().timestamp();
+    let assertion = duo_jwt(
+        &json!({
+            "iss": data.ik,
+            "sub": data.ik,
+            "aud": url,
+            "exp": now + DUO_EXPIRE,
+            "iat": now,
+            "jti": util::get_uuid(),
+        }),
+        data,
+    )?;
+
+    let mut form = vec![
+        (
+            "client_assertion_type",
+            "urn:ietf:params:oauth:client-assertion-type:jwt-bearer",
+        ),
+        ("client_assertion", assertion.as_str()),
+    ];
+    form.extend_from_slice(params);
+
+    let response = Client::new()
+        .post(&url)
+        .header(USER_AGENT, DUO_AGENT)
+        .form(&form)
+        .send()?
+        .error_for_status()?
+        .json()?;
+
+    Ok(response)
+}
+
+/// Checks that the client id and secret are valid and that Duo is reachable
+fn duo_health_check(data: &DuoData) -> EmptyResult {
+    let response = duo_oauth_request(data, "/oauth/v1/health_check", &[("client_id", data.ik.as_str())])?;
+
+    if response["stat"].as_str() != Some("OK") {
+        err!("Duo health check failed")
+    }
+
+    Ok(())
+}
+
+/// The URL of the Universal Prompt the client opens for the user, Duo redirects to the connector of the web vault once done
+pub fn generate_duo_auth_url(user_uuid: &str, device_type: i32, conn: &DbConn) -> ApiResult<String> {
+    let user = match User::find_by_uuid(user_uuid, conn) {
+        Some(user) => user,
+        None => err!("User does not exist"),
+    };
+    let data = get_duo_data_email(&user.email, conn)?;
+
+    let redirect_uri = format!(
+        "{}/{}?client={}",
+        CONFIG.domain(),
+        DUO_REDIRECT_CONNECTOR,
+        duo_redirect_client(device_type)
+    );
+    let state_claims = generate_duo_state_claims(user.uuid.clone(), redirect_uri.clone());
+    let state = encode_jwt(&state_claims);
+
+    let now = Utc::now().timestamp();
+    let request = duo_jwt(
+        &json!({
+            "response_type": "code",
+            "scope": "openid",
+            "exp": now + DUO_EXPIRE,
+            "client_id": data.ik,
+            "redirect_uri": redirect_uri,
+            "state": state,
+            "duo_uname": user.email,
+            "iss": data.ik,
+            "aud": format!("https://{}", data.host),
+            "nonce": state_claims.nonce,
+            "use_duo_code_attribute": true,
+        }),
+        &data,
+    )?;
+
+    Ok(format!(
+        "https://{}/oauth/v1/authorize?response_type=code&client_id={}&request={}",
+        data.host, data.ik, request
+    ))
+}
+
+/// The client sends the code and the state it got from the connector as `<duo_code>|<state>`. The code is exchanged
+/// for an id token, which has the result of the authentication
+fn validate_duo_universal_login(user: &User, response: &str, conn: &DbConn) -> EmptyResult {
+    let mut split = response.splitn(2, '|');
+    let (code, state) = match (split.next(), split.next()) {
+        (Some(code), Some(state)) if !code.is_empty() => (code, state),
+        _ => err!("Invalid Duo response"),
+    };
+
+    let state_claims = match decode_duo_state(state) {
+        Ok(claims) => claims,
+        Err(_) => err!("Invalid or expired Duo state"),
+    };
+    if state_claims.sub != user.uuid {
+        err!("Invalid or expired Duo state")
+    }
+
+    let data = get_duo_data_email(&user.email, conn)?;
+    let response = duo_oauth_request(
+        &data,
+        "/oauth/v1/token",
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", state_claims.redirect_uri.as_str()),
+        ],
+    )
+    .map_res("Error exchanging the Duo code")?;
+
+    let id_token = match response["id_token"].as_str() {
+        Some(id_token) => id_token,
+        None => err!("Invalid Duo token response"),
+    };
+
+    let validation = jsonwebtoken::Validation {
+        leeway: CONFIG.token_clock_skew_seconds() as i64,
+        validate_exp: true,
+        validate_nbf: false,
+        aud: Some(Value::String(data.ik.clone())),
+        iss: Some(format!("https://{}/oauth/v1/token", data.host)),
+        sub: None,
+        algorithms: vec![Algorithm::HS512],
+    };
+    let claims: Value = match jsonwebtoken::decode(id_token, data.sk.as_bytes(), &validation) {
+        Ok(token) => token.claims,
+        Err(_) => err!("Invalid Duo id token"),
+    };
+
+    if !crypto::ct_eq(claims["nonce"].as_str().unwrap_or_default(), &state_claims.nonce) {
+        err!("Duo nonce doesn't match")
+    }
+    if !claims["preferred_username"]
+        .as_str()
+        .map_or(false, |username| username.eq_ignore_ascii_case(&user.email))
+    {
+        err!("Duo user doesn't match")
+    }
+    if claims["auth_result"]["status"].as_str() != Some("allow") {
+        err!("Duo denied the authentication")
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authenticator_key(id: i32) -> AuthenticatorKey {
+        AuthenticatorKey {
+            id,
+            name: format!("Authenticator {}", id),
+            key: BASE32.encode(&crypto::get_random(vec![0u8; 20])),
+            last_used: 0,
+        }
+    }
+
+    fn current_totp_code(key: &str) -> u64 {
+        use oath::{totp_raw_custom_time, HashType};
+
+        let secret = BASE32.decode(key.as_bytes()).unwrap();
+        totp_raw_custom_time(&secret, 6, 0, 30, Utc::now().timestamp() as u64, &HashType::SHA1)
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn totp_codes_of_any_secret_are_accepted() {
+        use crate::db::models::User;
+
+        let conn = crate::migrations::test_connection();
+        let mut user = User::new("user@example.com".into());
+        user.save(&conn).unwrap();
+
+        let (main, backup) = (authenticator_key(1), authenticator_key(2));
+        let backup_code = current_totp_code(&backup.key);
+        let other_code = current_totp_code(&authenticator_key(3).key);
+        save_authenticator_keys(&user.uuid, &[main, backup], &conn).unwrap();
+
+        assert!(validate_totp_login(&user.uuid, &other_code.to_string(), &conn).is_err());
+        validate_totp_login(&user.uuid, &backup_code.to_string(), &conn).unwrap();
+
+        // Only the secret that matched is marked as used
+        let keys = get_authenticator_keys(&user.uuid, &conn).unwrap();
+        assert_eq!(keys[0].last_used, 0);
+        assert!(keys[1].last_used > 0);
+        assert!(validate_totp_login(&user.uuid, &backup_code.to_string(), &conn).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn the_cleanup_removes_the_expired_codes_and_challenges() {
+        use crate::db::models::User;
+
+        let conn = crate::migrations::test_connection();
+        let mut user = User::new("user@example.com".into());
+        user.save(&conn).unwrap();
+
+        let mut email_data = EmailTokenData::new(user.email.clone());
+        email_data.set_token().unwrap();
+        email_data.token_sent -= CONFIG.email_2fa_code_minutes() * 60 + 1;
+        let data = serde_json::to_string(&email_data).unwrap();
+        TwoFactor::new(user.uuid.clone(), TwoFactorType::Email, data.clone()).save(&conn).unwrap();
+
+        let expired = Utc::now().naive_utc() - chrono::Duration::minutes(CONFIG.email_2fa_code_minutes() + 1);
+        let mut challenge = TwoFactor::new(user.uuid.clone(), TwoFactorType::EmailVerificationChallenge, data);
+        challenge.created_at = Some(expired);
+        challenge.save(&conn).unwrap();
+        let mut challenge = TwoFactor::new(user.uuid.clone(), TwoFactorType::WebauthnLoginChallenge, "old".into());
+        challenge.created_at = None;
+        challenge.save(&conn).unwrap();
+        TwoFactor::new(user.uuid.clone(), TwoFactorType::U2fLoginChallenge, "new".into()).save(&conn).unwrap();
+
+        assert_eq!(clear_expired_email_tokens(&conn).unwrap(), 1);
+        assert_eq!(clear_expired_email_tokens(&conn).unwrap(), 0);
+        let (_, email_data) = get_email_twofactor(&user.uuid, &conn).unwrap();
+        assert!(email_data.token_hash.is_none());
+
+        assert_eq!(TwoFactor::delete_expired_challenges(&conn).unwrap(), 2);
+        let find = |atype: TwoFactorType| TwoFactor::find_by_user_and_type(&user.uuid, atype as i32, &conn);
+        assert!(find(TwoFactorType::EmailVerificationChallenge).is_none());
+        assert!(find(TwoFactorType::WebauthnLoginChallenge).is_none());
+        assert!(find(TwoFactorType::U2fLoginChallenge).is_some());
+    }
+
+    fn registration(id: i32, credential_id: &str) -> WebauthnRegistration {
+        WebauthnRegistration {
+            id,
+            name: format!("Key {}", id),
+            migrated: true,
+            credential_id: credential_id.to_string(),
+            public_key: String::new(),
+            counter: 0,
+            compromised: false,
+        }
+    }
+
+    #[test]
+    fn finds_the_registration_of_the_key_handle() {
+        let regs = vec![registration(1, "AAEC"), registration(2, "ZGVmZ2hpamtsbW5vcHFycw")];
+
+        assert_eq!(find_registration(&regs, "ZGVmZ2hpamtsbW5vcHFycw"), Some(1));
+        assert_eq!(find_registration(&regs, "ZGVmZ2hpamtsbW5vcHFycw=="), Some(1));
+        assert_eq!(find_registration(&regs, "AAEC"), Some(0));
+    }
+
+    #[test]
+    fn rejects_an_unknown_key_handle() {
+        let regs = vec![registration(1, "AAEC")];
+
+        assert_eq!(find_registration(&regs, "AAED"), None);
+        assert_eq!(find_registration(&regs, "not base64!"), None);
+        assert_eq!(find_registration(&[], "AAEC"), None);
+    }
+
+    const YUBICO_SECRET: &str = "c2VjcmV0a2V5MTIzNDU2Nzg5MA==";
+    const YUBIKEY_OTP: &str = "cccccckdvvulethkhtvkrtbeukiettredndjdhekfdlc";
+
+    /// A validation server answering a single request, signing the response with the secret.
+    /// Returns its URL and the query of the request it received
+    fn mock_yubico_server(secret: &'static str) -> (String, std::sync::mpsc::Receiver<String>) {
+        use ring::{digest, hmac};
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/wsapi/2.0/verify", listener.local_addr().unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buf).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+
+            let request = String::from_utf8(request).unwrap();
+            let target = request.split(' ').nth(1).unwrap_or_default();
+            let query = target.splitn(2, '?').nth(1).unwrap_or_default().to_string();
+            let param = |name: &str| {
+                query
+                    .split('&')
+                    .filter_map(|p| {
+                        let mut kv = p.splitn(2, '=');
+                        match (kv.next(), kv.next()) {
+                            (Some(k), Some(v)) if k == name => Some(v.to_string()),
+                            _ => None,
+                        }
+                    })
+                    .next()
+                    .unwrap_or_default()
+            };
+
+            // The parameters are signed sorted by name, like the requests
+            let signed = format!(
+                "nonce={}&otp={}&sl=100&status=OK&t=2019-09-20T18:58:53Z0000",
+                param("nonce"),
+                param("otp")
+            );
+            let key = hmac::SigningKey::new(&digest::SHA1, &BASE64.decode(secret.as_bytes()).unwrap());
+            let signature = BASE64.encode(hmac::sign(&key, signed.as_bytes()).as_ref());
+
+            let body = format!("h={}\r\n{}\r\n\r\n", signature, signed.replace('&', "\r\n"));
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+            tx.send(query).ok();
+        });
+
+        (url, rx)
+    }
+
+    #[test]
+    fn verifies_the_otp_with_the_self_hosted_server() {
+        let (url, rx) = mock_yubico_server(YUBICO_SECRET);
+
+        let result = verify_yubikey_otp_with_servers(
+            YUBIKEY_OTP.to_string(),
+            "1".to_string(),
+            YUBICO_SECRET.to_string(),
+            Some(vec![url]),
+        );
+        assert!(result.is_ok());
+
+        let query = rx.recv().unwrap();
+        assert!(query.contains("id=1"));
+        assert!(query.contains(&format!("otp={}", YUBIKEY_OTP)));
+    }
+
+    #[test]
+    fn rejects_a_response_signed_with_another_secret() {
+        let (url, _rx) = mock_yubico_server("b3RoZXJzZWNyZXRrZXkxMjM0NQ==");
+
+        let result = verify_yubikey_otp_with_servers(
+            YUBIKEY_OTP.to_string(),
+            "1".to_string(),
+            YUBICO_SECRET.to_string(),
+            Some(vec![url]),
+        );
+        assert!(result.is_err());
+    }
+}
@@ -1,4 +1,6 @@
-use rocket::request::{Form, FormItems, FromForm};
+use chrono::{NaiveDateTime, Utc};
+use rocket::request::{Form, FormItems, FromForm, LenientForm};
+use rocket::response::Redirect;
 use rocket::Route;
 
 use rocket_contrib::json::Json;
@@ -11,26 +13,87 @@ use crate::db::DbConn;
 
 use crate::util;
 
-use crate::api::{ApiResult, EmptyResult, JsonResult};
+use crate::api::{check_password_hash_format, vault_timeout_json, ApiResult, EmptyResult, JsonResult};
 
-use crate::auth::ClientIp;
+use crate::auth::{ClientIp, ClientLocale};
+
+use crate::captcha;
+
+use crate::lockout::{self, LockoutKind};
 
 use crate::mail;
 
+use crate::metrics;
+
+use crate::sso;
+
+use crate::webhook;
+
+use crate::error::{Error, ErrorCode};
+
 use crate::CONFIG;
 
 pub fn routes() -> Vec<Route> {
-    routes![login]
+    routes![login, authorize, oidc_signin, sso_prevalidate]
 }
 
+/// The grant types supported by `/connect/token`
+pub const GRANT_TYPES: &[&str] = &[
+    "password",
+    "refresh_token",
+    "client_credentials",
+    "magic_link",
+    "authorization_code",
+];
+
 #[post("/connect/token", data = "<data>")]
-fn login(data: Form<ConnectData>, conn: DbConn, ip: ClientIp) -> JsonResult {
+fn login(data: Form<ConnectData>, conn: DbConn, ip: ClientIp, locale: ClientLocale) -> JsonResult {
     let data: ConnectData = data.into_inner();
 
+    // Only the known grant types are labels, so the clients can't add new ones
+    let grant_type = match GRANT_TYPES.iter().find(|t| **t == data.grant_type) {
+        Some(grant_type) => *grant_type,
+        None => "invalid",
+    };
+
+    let start = std::time::Instant::now();
+    let result = _login(data, conn, ip, locale);
+    metrics::observe(metrics::LOGIN_DURATION, &[("grant_type", grant_type)], start.elapsed());
+
+    if grant_type == "refresh_token" {
+        let result_label = if result.is_ok() { "success" } else { "failure" };
+        metrics::inc(metrics::REFRESH_TOKEN_USES, &[("result", result_label)]);
+    } else {
+        metrics::inc(metrics::LOGIN_ATTEMPTS, &[("grant_type", grant_type)]);
+
+        // Asking for the second factor is part of a login, the challenges are counted separately
+        if let Err(ref e) = result {
+            if e.api_code() != Some(ErrorCode::TwoFactorRequired) {
+                let reason = e
+                    .api_code()
+                    .map_or_else(|| String::from("other"), |c| c.as_str().to_lowercase());
+                metrics::inc(metrics::LOGIN_FAILURES, &[("reason", &reason)]);
+            }
+        }
+    }
+
+    result
+}
+
+fn _login(data: ConnectData, conn: DbConn, ip: ClientIp, locale: ClientLocale) -> JsonResult {
+    // Rejected before anything is looked up
+    let grant_type = data.grant_type.as_str();
+    if CONFIG.disabled_grant_types().split(',').any(|t| t.trim() == grant_type) {
+        err!(
+            format!("The {} grant type is disabled on this server", grant_type),
+            format!("IP: {}. Disabled grant type: {}.", ip.ip, grant_type)
+        )
+    }
+
     match data.grant_type.as_ref() {
         "refresh_token" => {
             _check_is_some(&data.refresh_token, "refresh_token cannot be blank")?;
-            _refresh_login(data, conn)
+            _refresh_login(data, conn, ip)
         }
         "password" => {
             _check_is_some(&data.client_id, "client_id cannot be blank")?;
@@ -42,86 +105,738 @@ fn login(data: Form<ConnectData>, conn: DbConn, ip: ClientIp) -> JsonResult {
             _check_is_some(&data.device_name, "device_name cannot be blank")?;
             _check_is_some(&data.device_type, "device_type cannot be blank")?;
 
-            _password_login(data, conn, ip)
+            _password_login(data, conn, ip, locale)
+        }
+        "client_credentials" => {
+            _check_is_some(&data.client_id, "client_id cannot be blank")?;
+            _check_is_some(&data.client_secret, "client_secret cannot be blank")?;
+            _check_is_some(&data.scope, "scope cannot be blank")?;
+
+            _check_is_some(&data.device_identifier, "device_identifier cannot be blank")?;
+            _check_is_some(&data.device_name, "device_name cannot be blank")?;
+            _check_is_some(&data.device_type, "device_type cannot be blank")?;
+
+            _api_key_login(data, conn, ip, locale)
+        }
+        "magic_link" => {
+            _check_is_some(&data.token, "token cannot be blank")?;
+            _check_is_some(&data.scope, "scope cannot be blank")?;
+
+            _check_is_some(&data.device_identifier, "device_identifier cannot be blank")?;
+            _check_is_some(&data.device_name, "device_name cannot be blank")?;
+            _check_is_some(&data.device_type, "device_type cannot be blank")?;
+
+            _magic_link_login(data, conn, ip, locale)
+        }
+        "authorization_code" => {
+            _check_is_some(&data.code, "code cannot be blank")?;
+            _check_is_some(&data.code_verifier, "code_verifier cannot be blank")?;
+            _check_is_some(&data.redirect_uri, "redirect_uri cannot be blank")?;
+            _check_is_some(&data.scope, "scope cannot be blank")?;
+
+            _check_is_some(&data.device_identifier, "device_identifier cannot be blank")?;
+            _check_is_some(&data.device_name, "device_name cannot be blank")?;
+            _check_is_some(&data.device_type, "device_type cannot be blank")?;
+
+            _sso_login(data, conn, ip, locale)
         }
         t => err!("Invalid type", t),
     }
 }
 
-fn _refresh_login(data: ConnectData, conn: DbConn) -> JsonResult {
+fn _refresh_login(data: ConnectData, conn: DbConn, ip: ClientIp) -> JsonResult {
     // Extract token
     let token = data.refresh_token.unwrap();
 
-    // Get device by refresh token
+    // The devices logged in only with the API key don't have a refresh token
+    if token.is_empty() {
+        err_code!(ErrorCode::InvalidRefreshToken, "Invalid refresh token")
+    }
+
+    // Get device by refresh token. The revoked and deleted sessions are unauthorized, so the clients log out
     let mut device = match Device::find_by_refresh_token(&token, &conn) {
         Some(device) => device,
-        None => err!("Invalid refresh token"),
+        None => {
+            _check_refresh_token_reuse(&token, &ip, &conn);
+            _log_login("refresh_login", false, None, None, None, &ip);
+            return Err(Error::new("Invalid refresh token", format!("IP: {}.", ip.ip))
+                .with_code(401)
+                .with_api_code(ErrorCode::InvalidRefreshToken));
+        }
     };
 
+    // The pending devices don't have a refresh token yet
+    if !device.approved {
+        err_code!(ErrorCode::InvalidRefreshToken, "Invalid refresh token")
+    }
+
+    // The previous token is only accepted right after it was replaced, and then the current one is returned
+    let rotated_out = device.refresh_token != token;
+    if rotated_out && !device.in_rotation_grace() {
+        _revoke_reused_refresh_token(device, &ip, &conn);
+        _log_login("refresh_login", false, None, None, None, &ip);
+        err_code!(ErrorCode::InvalidRefreshToken, "Invalid refresh token")
+    }
+
+    // The token is revoked, so the device needs a full login even if it's used again from the bound IP
+    if !_check_refresh_ip(&device, &ip) {
+        _log_login("refresh_login", false, None, Some(device.atype), None, &ip);
+        device.revoke_refresh_tokens();
+        device.save(&conn)?;
+        if let Some(user) = User::find_by_uuid(&device.user_uuid, &conn) {
+            crate::auth::notify_session_terminated(
+                &user,
+                &device,
+                "The session was used from a different IP address",
+                &ip.ip,
+                &conn,
+            );
+        }
+        return Err(Error::new(
+            "Refresh token is not valid from this location, please log in again",
+            format!("IP: {}. Device: {}.", ip.ip, device.uuid),
+        )
+        .with_code(401)
+        .with_api_code(ErrorCode::InvalidRefreshToken));
+    }
+
     // COMMON
     let user = User::find_by_uuid(&device.user_uuid, &conn).unwrap();
+    _check_user_enabled(&user, &ip)?;
     let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
 
+    // With REFRESH_TOKEN_ROTATION the token that was just used is rotated out, using it again revokes the whole family
+    if CONFIG.refresh_token_rotation() && !rotated_out {
+        device.rotate_refresh_token();
+    }
     let (access_token, expires_in) = device.refresh_tokens(&user, orgs);
 
+    if device.refresh_ip.is_none() {
+        device.refresh_ip = Some(ip.ip.to_string());
+    }
+
+    device.last_used_ip = Some(ip.ip.to_string());
     device.save(&conn)?;
-    Ok(Json(json!({
+    let result = json!({
         "access_token": access_token,
         "expires_in": expires_in,
         "token_type": "Bearer",
         "refresh_token": device.refresh_token,
         "Key": user.akey,
         "PrivateKey": user.private_key,
-    })))
+        "MasterPasswordReprompt": device.requires_reprompt(),
+        "UserDecryptionOptions": _user_decryption_options(&user, &device),
+        "VaultTimeout": vault_timeout_json(&user.uuid, &conn),
+        "Policies": _login_policies_json(&user.uuid, &conn),
+    });
+
+    _log_login("refresh_login", true, Some(&user.email), Some(device.atype), None, &ip);
+    Ok(Json(_token_response(result)))
 }
 
-fn _password_login(data: ConnectData, conn: DbConn, ip: ClientIp) -> JsonResult {
+fn _password_login(data: ConnectData, conn: DbConn, ip: ClientIp, locale: ClientLocale) -> JsonResult {
     // Validate scope
     let scope = data.scope.as_ref().unwrap();
     if scope != "api offline_access" {
         err!("Scope not supported")
     }
 
-    // Get the user
+    // Check the lockouts before anything else, so that locked out users can't keep guessing
     let username = data.username.as_ref().unwrap();
+    let ip_key = ip.ip.to_string();
+    let retry_after = std::cmp::max(
+        lockout::retry_after(LockoutKind::Ip, &ip_key),
+        lockout::retry_after(LockoutKind::User, username),
+    );
+    if let Some(seconds) = retry_after {
+        return Err(lockout::locked_out_error(
+            seconds,
+            format!("IP: {}. Username: {}. Locked out.", ip.ip, username),
+        ));
+    }
+    _check_ratelimit(&ip)?;
+    _check_captcha(&data, &ip)?;
+
+    // Get the user
+    let device_type = data.parsed_device_type();
+    let device_name = data.device_name.as_ref().map(String::as_str);
     let user = match User::find_by_mail(username, &conn) {
         Some(user) => user,
-        None => err!(
-            "Username or password is incorrect. Try again",
-            format!("IP: {}. Username: {}.", ip.ip, username)
-        ),
+        None => {
+            _log_login("password_login", false, Some(username), device_type, None, &ip);
+            _send_login_event("login_failed", Some(username), device_name, &ip);
+            // Counted like for an existing account, so the lockouts don't tell them apart
+            let ip_locked = lockout::register_failure(LockoutKind::Ip, &ip_key);
+            let user_locked = lockout::register_failure(LockoutKind::User, username);
+            if ip_locked || user_locked {
+                _send_login_event("lockout", Some(username), device_name, &ip);
+            }
+            lockout::register_ratelimit_failure(&ip_key);
+            lockout::register_captcha_failure(&ip_key);
+            err_code!(
+                ErrorCode::InvalidCredentials,
+                "Username or password is incorrect. Try again",
+                format!("IP: {}. Username: {}.", ip.ip, username)
+            )
+        }
     };
 
-    // Check password
+    // Check password. A malformed hash fails like a wrong one, so it doesn't reveal that the account
+    // exists and still counts for the lockouts, the rate limit and the captcha
     let password = data.password.as_ref().unwrap();
-    if !user.check_valid_password(password) {
-        err!(
+    let well_formed = match check_password_hash_format(password, user.client_kdf_type) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("{:#?}", e);
+            false
+        }
+    };
+    if !well_formed || !user.check_valid_password(password) {
+        _log_event(EventType::UserFailedLogIn, &user.uuid, None, &ip, &conn);
+        _log_login("password_login", false, Some(username), device_type, None, &ip);
+        _send_login_event("login_failed", Some(&user.email), device_name, &ip);
+        let ip_locked = lockout::register_failure(LockoutKind::Ip, &ip_key);
+        let user_locked = lockout::register_failure(LockoutKind::User, username);
+        if ip_locked || user_locked {
+            _send_login_event("lockout", Some(&user.email), device_name, &ip);
+        }
+        lockout::register_ratelimit_failure(&ip_key);
+        lockout::register_captcha_failure(&ip_key);
+        err_code!(
+            ErrorCode::InvalidCredentials,
             "Username or password is incorrect. Try again",
             format!("IP: {}. Username: {}.", ip.ip, username)
         )
     }
 
+    // The IP counter is kept, a valid account shouldn't allow guessing other ones
+    lockout::clear(LockoutKind::User, username);
+
+    let password = password.clone();
+    _authenticated_login(user, data, conn, ip, locale, LoginMethod::Password(&password))
+}
+
+/// Login with the personal API key, used by the CLI. The key replaces both the password and the second factor,
+/// and the access token is marked as coming from it, so that it can't be used for the account management
+fn _api_key_login(data: ConnectData, conn: DbConn, ip: ClientIp, locale: ClientLocale) -> JsonResult {
+    let scope = data.scope.as_ref().unwrap();
+    if scope != "api" {
+        err!("Scope not supported")
+    }
+
+    let ip_key = ip.ip.to_string();
+    if let Some(seconds) = lockout::retry_after(LockoutKind::Ip, &ip_key) {
+        return Err(lockout::locked_out_error(
+            seconds,
+            format!("IP: {}. Locked out.", ip.ip),
+        ));
+    }
+    _check_ratelimit(&ip)?;
+
+    // The same error as a wrong password, so the client ids can't be told apart
+    let client_id = data.client_id.as_ref().unwrap();
+    let user = User::find_by_api_client_id(client_id, &conn);
+    let client_secret = data.client_secret.as_ref().unwrap();
+    if !user.as_ref().map_or(false, |u| u.check_api_key(client_secret)) {
+        if let Some(ref user) = user {
+            _log_event(EventType::UserFailedLogIn, &user.uuid, None, &ip, &conn);
+        }
+        let username = user.as_ref().map(|u| u.email.as_str());
+        _log_login("api_key_login", false, username, data.parsed_device_type(), None, &ip);
+        lockout::register_failure(LockoutKind::Ip, &ip_key);
+        lockout::register_ratelimit_failure(&ip_key);
+        err_code!(
+            ErrorCode::InvalidCredentials,
+            "Username or password is incorrect. Try again",
+            format!("IP: {}. Client id: {}.", ip.ip, client_id)
+        )
+    }
+    let mut user = user.unwrap();
+    _check_user_enabled(&user, &ip)?;
+
+    // The key replaces the second factor, but the users that have to enroll one still need to do it in time
+    let enroll_deadline = _check_2fa_enrollment(&mut user, &conn)?;
+
     let (mut device, new_device) = get_device(&data, &conn, &user);
 
-    let twofactor_token = twofactor_auth(&user.uuid, &data, &mut device, &conn)?;
+    // The API key doesn't replace the approval of an administrator, like with the other logins
+    if !device.approved {
+        let admin_policies = OrgPolicy::find_applicable_to_user(&user.uuid, OrgPolicyType::AdminDeviceApproval, &conn);
+        if !admin_policies.is_empty() {
+            device.save(&conn)?;
+            _request_admin_device_approval(&user, &device, &admin_policies, &ip, &conn)?;
+
+            err_code!(
+                ErrorCode::DeviceApprovalRequired,
+                "This device is awaiting approval from an administrator of your organization",
+                format!("IP: {}. Username: {}. Device: {}.", ip.ip, user.email, device.uuid)
+            )
+        }
+    }
 
-    if CONFIG.mail_enabled() && new_device {
-        if let Err(e) = mail::send_new_device_logged_in(&user.email, &ip.ip.to_string(), &device.updated_at, &device.name) {
-            error!("Error sending new device email: {:#?}", e);
+    // Otherwise the API key is trusted like an approved device, but it can't approve the pending ones
+    if new_device {
+        device.approve();
+        _log_login("new_device", true, Some(&user.email), Some(device.atype), None, &ip);
+        metrics::inc(metrics::NEW_DEVICES, &[]);
+        _notify_new_device(&user, &device, &ip, None, &locale, &conn)?;
+    } else if !device.approved {
+        err_code!(
+            ErrorCode::DeviceApprovalRequired,
+            "This device needs to be approved before it can log in",
+            format!("IP: {}. Username: {}. Device: {}.", ip.ip, user.email, device.uuid)
+        )
+    }
 
-            if CONFIG.require_device_email() {
-                err!("Could not send login notification email. Please contact your administrator.")
+    let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
+    let (access_token, expires_in) = device.api_key_tokens(&user, orgs);
+    device.last_used_ip = Some(ip.ip.to_string());
+    device.save(&conn)?;
+
+    let mut result = json!({
+        "access_token": access_token,
+        "expires_in": expires_in,
+        "token_type": "Bearer",
+        "scope": "api",
+        "Key": user.akey,
+        "PrivateKey": user.private_key,
+        "Kdf": user.client_kdf_type,
+        "KdfIterations": user.client_kdf_iter,
+        "UserDecryptionOptions": _user_decryption_options(&user, &device),
+        "VaultTimeout": vault_timeout_json(&user.uuid, &conn),
+        "Policies": _login_policies_json(&user.uuid, &conn),
+        "ForcePasswordReset": OrgPolicy::is_password_reset_forced(&user, &conn),
+    });
+
+    if let Some(deadline) = enroll_deadline {
+        result["TwoFactorEnrollmentRequired"] = Value::Bool(true);
+        result["TwoFactorEnrollmentDeadline"] = Value::String(util::format_date(&deadline));
+    }
+
+    _log_event(EventType::UserLoggedIn, &user.uuid, Some(&device), &ip, &conn);
+    lockout::clear_ratelimit(&ip_key);
+
+    _log_login("api_key_login", true, Some(&user.email), Some(device.atype), None, &ip);
+    info!("User {} logged in with the API key. IP: {}", user.email, ip.ip);
+    Ok(Json(_token_response(result)))
+}
+
+/// Login with the token of a magic link, which replaces the password check. The second factor is still required,
+/// and the vault keys are returned encrypted like always, so the master password is still needed to unlock it
+fn _magic_link_login(data: ConnectData, conn: DbConn, ip: ClientIp, locale: ClientLocale) -> JsonResult {
+    // The links sent before it was disabled can't be used either
+    if CONFIG.disable_email_recovery() {
+        err!("Recovery is disabled on this server")
+    }
+
+    if !CONFIG.magic_link_enabled() {
+        err!("Magic link login is disabled")
+    }
+
+    let scope = data.scope.as_ref().unwrap();
+    if scope != "api offline_access" {
+        err!("Scope not supported")
+    }
+
+    _check_ratelimit(&ip)?;
+
+    let claims = match crate::auth::decode_magic_link(data.token.as_ref().unwrap()) {
+        Ok(claims) => claims,
+        Err(_) => err!("Invalid or expired login link", format!("IP: {}.", ip.ip)),
+    };
+
+    let user = match User::find_by_uuid(&claims.sub, &conn) {
+        Some(user) => user,
+        None => err!("Invalid or expired login link", format!("IP: {}.", ip.ip)),
+    };
+
+    // The links requested before it was enabled aren't bound to any IP, so they can't be used
+    let ip_str = ip.ip.to_string();
+    let ip_allowed = !CONFIG.magic_link_bind_ip() || claims.ip.as_ref() == Some(&ip_str);
+
+    if !ip_allowed || !user.check_magic_link_nonce(&claims.nonce) {
+        _log_event(EventType::UserFailedLogIn, &user.uuid, None, &ip, &conn);
+        let device_type = data.parsed_device_type();
+        _log_login("magic_link_login", false, Some(&user.email), device_type, None, &ip);
+        lockout::register_ratelimit_failure(&ip.ip.to_string());
+        err!(
+            "Invalid or expired login link",
+            format!("IP: {}. Username: {}.", ip.ip, user.email)
+        )
+    }
+
+    // The link is only used up once the second factor is provided, so that it can be repeated with it
+    _authenticated_login(user, data, conn, ip, locale, LoginMethod::MagicLink(&claims.nonce))
+}
+
+/// Login with the authorization code given to the client at the end of the SSO login, which replaces the password check.
+/// The second factor is still required, the client sends the same code again along with it
+fn _sso_login(data: ConnectData, conn: DbConn, ip: ClientIp, locale: ClientLocale) -> JsonResult {
+    if !CONFIG.sso_enabled() {
+        err!("SSO login is disabled")
+    }
+
+    let scope = data.scope.as_ref().unwrap();
+    if scope != "api offline_access" {
+        err!("Scope not supported")
+    }
+
+    _check_ratelimit(&ip)?;
+
+    let claims = match crate::auth::decode_sso_code(data.code.as_ref().unwrap()) {
+        Ok(claims) => claims,
+        Err(_) => err!("Invalid or expired authorization code", format!("IP: {}.", ip.ip)),
+    };
+
+    // PKCE, only the client that started the login knows the verifier
+    let verifier = data.code_verifier.as_ref().unwrap();
+    let challenge = data_encoding::BASE64URL_NOPAD.encode(&crate::crypto::sha256(verifier.as_bytes()));
+    let redirect_valid = data.redirect_uri.as_ref() == Some(&claims.redirect_uri);
+
+    if !redirect_valid || !crate::crypto::ct_eq(&challenge, &claims.code_challenge) || sso::is_code_used(&claims.nonce)
+    {
+        let device_type = data.parsed_device_type();
+        _log_login("sso_login", false, None, device_type, None, &ip);
+        lockout::register_ratelimit_failure(&ip.ip.to_string());
+        err!("Invalid or expired authorization code", format!("IP: {}.", ip.ip))
+    }
+
+    let user = match User::find_by_uuid(&claims.sub, &conn) {
+        Some(user) => user,
+        None => err!("Invalid or expired authorization code", format!("IP: {}.", ip.ip)),
+    };
+
+    _authenticated_login(
+        user,
+        data,
+        conn,
+        ip,
+        locale,
+        LoginMethod::Sso(&claims.nonce, claims.exp),
+    )
+}
+
+#[derive(FromForm)]
+struct AuthorizeData {
+    redirect_uri: String,
+    response_type: String,
+    state: String,
+    code_challenge: String,
+    code_challenge_method: String,
+}
+
+/// Starts the SSO login of a client, sending the user to the identity provider
+#[get("/connect/authorize?<data..>")]
+fn authorize(data: LenientForm<AuthorizeData>, ip: ClientIp) -> ApiResult<Redirect> {
+    if !CONFIG.sso_enabled() {
+        err!("SSO login is disabled")
+    }
+    let data = data.into_inner();
+
+    if data.response_type != "code" || data.code_challenge_method != "S256" {
+        err!("Only the authorization code flow with a S256 code challenge is supported")
+    }
+    if !_is_sso_redirect_allowed(&data.redirect_uri) {
+        err!(
+            "Invalid redirect URI",
+            format!("IP: {}. Redirect URI: {}.", ip.ip, data.redirect_uri)
+        )
+    }
+
+    let claims = crate::auth::generate_sso_state_claims(data.state, data.redirect_uri, data.code_challenge);
+    let url = sso::authorize_url(&crate::auth::encode_jwt(&claims), &claims.nonce)?;
+    Ok(Redirect::to(url))
+}
+
+/// The identity provider sends the user back here, and then they are sent to the client with an authorization code
+#[get("/connect/oidc-signin?<code>&<state>&<error>&<error_description>")]
+fn oidc_signin(
+    code: Option<String>,
+    state: String,
+    error: Option<String>,
+    error_description: Option<String>,
+    conn: DbConn,
+    ip: ClientIp,
+) -> ApiResult<Redirect> {
+    if !CONFIG.sso_enabled() {
+        err!("SSO login is disabled")
+    }
+
+    let state = match crate::auth::decode_sso_state(&state) {
+        Ok(state) => state,
+        Err(_) => err!("Invalid or expired SSO login", format!("IP: {}.", ip.ip)),
+    };
+
+    // Once the state is valid the errors go back to the client, so the user isn't left on this page
+    let code = match (code, error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            let description = error_description.or(error).unwrap_or_default();
+            warn!(
+                "SSO login failed at the identity provider: {}. IP: {}",
+                description, ip.ip
+            );
+            return Ok(_sso_client_error(&state, "The login failed at the identity provider"));
+        }
+    };
+
+    let sso_user = match sso::exchange_code(&code, &state.nonce) {
+        Ok(sso_user) => sso_user,
+        Err(e) => {
+            error!("Error validating the SSO login: {:#?}. IP: {}", e, ip.ip);
+            return Ok(_sso_client_error(&state, "The login couldn't be validated"));
+        }
+    };
+
+    let user = match User::find_by_mail(&sso_user.email, &conn) {
+        Some(user) => user,
+        None if CONFIG.sso_auto_provision() => {
+            match crate::api::core::accounts::signup_limit_reason(&sso_user.email, &conn) {
+                Ok(None) => {}
+                Ok(Some(reason)) => {
+                    warn!("SSO login of {} not provisioned: {}. IP: {}", sso_user.email, reason, ip.ip);
+                    return Ok(_sso_client_error(&state, reason));
+                }
+                Err(e) => {
+                    error!("Error checking the signup limits of {}: {:#?}", sso_user.email, e);
+                    return Ok(_sso_client_error(&state, "The account couldn't be created"));
+                }
+            }
+
+            let mut user = User::new(sso_user.email.clone());
+            if let Some(name) = sso_user.name {
+                user.name = name;
             }
+            user.save(&conn)?;
+
+            info!(
+                "Created the account of {} from the SSO login. IP: {}",
+                user.email, ip.ip
+            );
+            user
+        }
+        None => {
+            warn!("SSO login of {} without an account. IP: {}", sso_user.email, ip.ip);
+            return Ok(_sso_client_error(
+                &state,
+                "There is no account with this email, ask an administrator for an invitation",
+            ));
         }
+    };
+
+    let redirect_uri = state.redirect_uri.clone();
+    let client_state = state.sub.clone();
+    let code = crate::auth::encode_jwt(&crate::auth::generate_sso_code_claims(user.uuid, state));
+
+    Ok(Redirect::to(sso::url_with_params(
+        &redirect_uri,
+        &[("code", code.as_str()), ("state", client_state.as_str())],
+    )))
+}
+
+/// The clients ask for a token before the SSO login, the official server uses it to find the organization.
+/// There is only one identity provider here, so it's not needed
+#[get("/sso/prevalidate")]
+fn sso_prevalidate() -> JsonResult {
+    if !CONFIG.sso_enabled() {
+        err!("SSO login is disabled")
+    }
+
+    Ok(Json(json!({ "token": util::get_uuid() })))
+}
+
+/// The clients get the code at the connector of the web vault, with the scheme of the desktop app,
+/// or at the local server of the CLI
+fn _is_sso_redirect_allowed(uri: &str) -> bool {
+    if uri.starts_with(&format!("{}/", CONFIG.domain())) || uri.starts_with("bitwarden://") {
+        return true;
+    }
+
+    // Only a port can follow the local host, anything else could be part of another host
+    ["http://localhost:", "http://127.0.0.1:"].iter().any(|prefix| {
+        if !uri.starts_with(prefix) {
+            return false;
+        }
+        let rest = &uri[prefix.len()..];
+        let port = &rest[..rest.find(|c| c == '/' || c == '?').unwrap_or_else(|| rest.len())];
+        !port.is_empty() && port.chars().all(|c| c.is_ascii_digit())
+    })
+}
+
+fn _sso_client_error(state: &crate::auth::SsoStateJWTClaims, description: &str) -> Redirect {
+    Redirect::to(sso::url_with_params(
+        &state.redirect_uri,
+        &[
+            ("error", "access_denied"),
+            ("error_description", description),
+            ("state", state.sub.as_str()),
+        ],
+    ))
+}
+
+/// How the user was identified before `_authenticated_login`
+#[derive(Clone, Copy)]
+enum LoginMethod<'a> {
+    /// The valid password, its hash is upgraded once the login succeeds
+    Password(&'a str),
+    /// The nonce of the link, consumed once the login succeeds
+    MagicLink(&'a str),
+    /// The nonce and the expiration of the authorization code, it's used up once the login succeeds
+    Sso(&'a str, i64),
+}
+
+/// Completes a login once the user was identified, checking the second factor and issuing the device tokens.
+/// The magic links and the SSO codes are used up after the second factor check
+fn _authenticated_login(
+    mut user: User,
+    data: ConnectData,
+    conn: DbConn,
+    ip: ClientIp,
+    locale: ClientLocale,
+    method: LoginMethod,
+) -> JsonResult {
+    _check_user_enabled(&user, &ip)?;
+
+    if let Some(seconds) = user.twofactor_lockout_seconds() {
+        return Err(lockout::locked_out_error(
+            seconds,
+            format!("IP: {}. Username: {}. Two-step login locked.", ip.ip, user.email),
+        ));
+    }
+
+    let event = match method {
+        LoginMethod::Password(_) => "password_login",
+        LoginMethod::MagicLink(_) => "magic_link_login",
+        LoginMethod::Sso(..) => "sso_login",
+    };
+
+    let (mut device, new_device) = get_device(&data, &conn, &user);
+
+    let twofactor_token = match twofactor_auth(&user.uuid, &data, &mut device, &ip, method, &conn) {
+        Ok(token) => {
+            if data.two_factor_token.is_some() {
+                let provider = data.two_factor_provider;
+                _log_login("twofactor", true, Some(&user.email), Some(device.atype), provider, &ip);
+            }
+            token
+        }
+        Err(mut e) => {
+            // Without a token this is just the request for the second factor
+            if data.two_factor_token.is_some() {
+                let provider = data.two_factor_provider;
+                _log_login("twofactor", false, Some(&user.email), Some(device.atype), provider, &ip);
+                _log_event(EventType::UserFailedLogIn2fa, &user.uuid, Some(&device), &ip, &conn);
+                _send_login_event("twofactor_failed", Some(&user.email), Some(&device.name), &ip);
+                let provider_label = _twofactor_provider_label(data.two_factor_provider);
+                metrics::inc(metrics::TWOFACTOR_FAILURES, &[("provider", provider_label)]);
+                lockout::register_ratelimit_failure(&ip.ip.to_string());
+
+                // An expired remembered device isn't a wrong code, the client just asks for one
+                if data.two_factor_provider != Some(TwoFactorType::Remember as i32) {
+                    _register_twofactor_failure(&mut user, &ip, &locale, &conn);
+                    if e.api_code().is_none() {
+                        e = e.with_api_code(ErrorCode::InvalidTwoFactorCode);
+                    }
+                }
+            }
+            return Err(e);
+        }
+    };
+
+    if user.reset_twofactor_failures() {
+        user.save(&conn)?;
+    }
+
+    // The pending device is kept, so that it can be approved. A login link stays usable until then
+    if !device.approved {
+        device.save(&conn)?;
+
+        // Only an admin can approve it then, the user's other devices and the email link can't
+        let admin_policies = OrgPolicy::find_applicable_to_user(&user.uuid, OrgPolicyType::AdminDeviceApproval, &conn);
+        if !admin_policies.is_empty() {
+            _request_admin_device_approval(&user, &device, &admin_policies, &ip, &conn)?;
+
+            err_code!(
+                ErrorCode::DeviceApprovalRequired,
+                "This device is awaiting approval from an administrator of your organization",
+                format!("IP: {}. Username: {}. Device: {}.", ip.ip, user.email, device.uuid)
+            )
+        }
+
+        if CONFIG.mail_enabled() && new_device {
+            if let Err(e) = mail::send_device_approval(
+                &user.email,
+                device.approval_token.as_ref().map_or("", String::as_str),
+                &ip.ip.to_string(),
+                &device.name,
+                user.locale(locale.locale),
+            ) {
+                error!("Error sending device approval email: {:#?}", e);
+            }
+        }
+
+        err_code!(
+            ErrorCode::DeviceApprovalRequired,
+            "This device needs to be approved before it can log in. Approve it from one of your other devices, or with the link sent to your email",
+            format!("IP: {}. Username: {}. Device: {}.", ip.ip, user.email, device.uuid)
+        )
+    }
+
+    match method {
+        LoginMethod::MagicLink(nonce) if !user.take_magic_link_nonce(nonce, &conn) => err!(
+            "Invalid or expired login link",
+            format!("IP: {}. Username: {}.", ip.ip, user.email)
+        ),
+        LoginMethod::Sso(nonce, exp) if !sso::take_code(nonce, exp) => err!(
+            "Invalid or expired authorization code",
+            format!("IP: {}. Username: {}.", ip.ip, user.email)
+        ),
+        _ => (),
+    }
+
+    // After the nonces are used, so a login that fails doesn't log out the other devices
+    _enforce_device_limit(&user, &device, &ip, &conn)?;
+
+    // The location is remembered on every login, but the unusual ones are only reported along with a new device
+    let unusual_location = _check_login_location(&mut user, &ip, &conn);
+
+    if new_device {
+        _log_login("new_device", true, Some(&user.email), Some(device.atype), None, &ip);
+        metrics::inc(metrics::NEW_DEVICES, &[]);
+        _send_login_event("new_device", Some(&user.email), Some(&device.name), &ip);
+        _notify_new_device(
+            &user,
+            &device,
+            &ip,
+            unusual_location.as_ref().map(String::as_str),
+            &locale,
+            &conn,
+        )?;
     }
 
     // Common
-    let user = User::find_by_uuid(&device.user_uuid, &conn).unwrap();
+    let mut user = User::find_by_uuid(&device.user_uuid, &conn).unwrap();
+    let enroll_deadline = _check_2fa_enrollment(&mut user, &conn)?;
+
     let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
 
+    device.new_refresh_family();
     let (access_token, expires_in) = device.refresh_tokens(&user, orgs);
+    device.refresh_ip = Some(ip.ip.to_string());
+    device.last_used_ip = Some(ip.ip.to_string());
     device.save(&conn)?;
 
+    if let LoginMethod::Password(password) = method {
+        if user.upgrade_password_hash(password) {
+            user.save(&conn)?;
+            info!(
+                "Upgraded the password hash of {} to {}",
+                user.email,
+                CONFIG.password_algorithm()
+            );
+        }
+    }
+
     let mut result = json!({
         "access_token": access_token,
         "expires_in": expires_in,
@@ -129,33 +844,571 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: ClientIp) -> JsonResult
         "refresh_token": device.refresh_token,
         "Key": user.akey,
         "PrivateKey": user.private_key,
+        "MasterPasswordReprompt": device.requires_reprompt(),
+        "UserDecryptionOptions": _user_decryption_options(&user, &device),
+        "VaultTimeout": vault_timeout_json(&user.uuid, &conn),
+        "Policies": _login_policies_json(&user.uuid, &conn),
+        "KdfUpgradeNeeded": user.kdf_upgrade_needed(),
+        // The password can't be checked again, so it's only known to predate the policy
+        "ForcePasswordReset": OrgPolicy::is_password_reset_forced(&user, &conn),
+        // The accounts created by the SSO login still need to choose their master password
+        "ResetMasterPassword": user.password_hash.is_empty() && !user.uses_key_connector,
     });
 
     if let Some(token) = twofactor_token {
         result["TwoFactorToken"] = Value::String(token);
     }
 
-    info!("User {} logged in successfully. IP: {}", username, ip.ip);
-    Ok(Json(result))
+    if let Some(deadline) = enroll_deadline {
+        result["TwoFactorEnrollmentRequired"] = Value::Bool(true);
+        result["TwoFactorEnrollmentDeadline"] = Value::String(util::format_date(&deadline));
+    }
+
+    _log_event(EventType::UserLoggedIn, &user.uuid, Some(&device), &ip, &conn);
+    lockout::clear_ratelimit(&ip.ip.to_string());
+
+    let provider = data.two_factor_provider;
+    _log_login(event, true, Some(&user.email), Some(device.atype), provider, &ip);
+    _send_login_event("login_success", Some(&user.email), Some(&device.name), &ip);
+    info!("User {} logged in successfully. IP: {}", user.email, ip.ip);
+    Ok(Json(_token_response(result)))
+}
+
+/// With `MAX_DEVICES_PER_USER`, makes room for a device without a session by logging out the least recently used ones,
+/// or rejects it without `MAX_DEVICES_EVICT_OLDEST`. Only checked after the second factor, so the password alone can't do it
+fn _enforce_device_limit(user: &User, device: &Device, ip: &ClientIp, conn: &DbConn) -> EmptyResult {
+    let max_devices = CONFIG.max_devices_per_user() as usize;
+    if max_devices == 0 || !device.refresh_token.is_empty() {
+        return Ok(());
+    }
+
+    let active: Vec<Device> = Device::find_active_by_user(&user.uuid, conn)
+        .into_iter()
+        .filter(|d| d.uuid != device.uuid)
+        .collect();
+    let evicted = devices_over_limit(active, max_devices);
+    if evicted.is_empty() {
+        return Ok(());
+    }
+
+    if !CONFIG.max_devices_evict_oldest() {
+        err!(
+            format!(
+                "You are already logged in on {} devices, log out from one of them first",
+                max_devices
+            ),
+            format!("IP: {}. Username: {}. Too many devices.", ip.ip, user.email)
+        )
+    }
+
+    for mut old_device in evicted {
+        old_device.revoke_refresh_tokens();
+        old_device.save(conn)?;
+        warn!(
+            "Logged out the device {} ({}) of {}, over the limit of {} devices. IP: {}",
+            old_device.name, old_device.uuid, user.email, max_devices, ip.ip
+        );
+    }
+
+    Ok(())
+}
+
+/// The active devices to log out so that one more fits in the limit, taken from the least recently used
+fn devices_over_limit(mut active: Vec<Device>, max_devices: usize) -> Vec<Device> {
+    let over = (active.len() + 1).saturating_sub(max_devices);
+    active.truncate(over);
+    active
+}
+
+/// With `GEOIP_DATABASE_PATH`, remembers the location of the login, returning it when it's unusual for the user.
+/// The lookups that fail don't affect the login
+fn _check_login_location(user: &mut User, ip: &ClientIp, conn: &DbConn) -> Option<String> {
+    let location = crate::geoip::lookup(&ip.ip)?.key();
+
+    let unusual = user.register_login_location(&location);
+    if let Err(e) = user.save_login_locations(conn) {
+        error!("Error saving the login locations of {}: {:#?}", user.email, e);
+    }
+
+    if unusual {
+        warn!(
+            "User {} logged in from an unusual location: {}. IP: {}",
+            user.email, location, ip.ip
+        );
+        Some(location)
+    } else {
+        None
+    }
+}
+
+/// Creates the approval requests of the device missing in the organizations with the policy,
+/// notifying the admins of those organizations about the new ones
+fn _request_admin_device_approval(
+    user: &User,
+    device: &Device,
+    policies: &[OrgPolicy],
+    ip: &ClientIp,
+    conn: &DbConn,
+) -> EmptyResult {
+    let ip_str = ip.ip.to_string();
+
+    for policy in policies {
+        if DeviceApprovalRequest::exists_by_device_and_org(&device.uuid, &policy.org_uuid, conn) {
+            continue;
+        }
+
+        DeviceApprovalRequest::new(
+            user.uuid.clone(),
+            policy.org_uuid.clone(),
+            device.uuid.clone(),
+            ip_str.clone(),
+        )
+        .save(conn)?;
+
+        if CONFIG.mail_enabled() {
+            let org_name = match Organization::find_by_uuid(&policy.org_uuid, conn) {
+                Some(org) => org.name,
+                None => continue,
+            };
+
+            let admins = UserOrganization::find_by_org(&policy.org_uuid, conn)
+                .into_iter()
+                .filter(|uo| uo.status == UserOrgStatus::Confirmed as i32 && uo.atype >= UserOrgType::Admin);
+
+            for admin in admins.filter_map(|uo| User::find_by_uuid(&uo.user_uuid, conn)) {
+                if let Err(e) = mail::send_admin_device_approval(
+                    &admin.email,
+                    &user.email,
+                    &org_name,
+                    &ip_str,
+                    &device.name,
+                    admin.locale(util::DEFAULT_LOCALE),
+                ) {
+                    error!("Error sending admin device approval email: {:#?}", e);
+                }
+            }
+        }
+
+        let mut payload = webhook::event_payload(
+            "device_approval_requested",
+            Some(&user.email),
+            Some(&ip_str),
+            Some(&device.name),
+        );
+        payload["organization"] = json!(policy.org_uuid);
+        webhook::send_in_background(payload);
+    }
+
+    Ok(())
+}
+
+/// Leaves a security notice and sends the email about the login from a new device.
+/// `unusual_location` is set when the login also comes from a location where the user didn't log in from recently
+fn _notify_new_device(
+    user: &User,
+    device: &Device,
+    ip: &ClientIp,
+    unusual_location: Option<&str>,
+    locale: &ClientLocale,
+    conn: &DbConn,
+) -> EmptyResult {
+    SecurityNotice::new(
+        user.uuid.clone(),
+        SecurityNoticeType::NewDevice,
+        json!({
+            "DeviceName": device.name,
+            "DeviceType": device.atype,
+            "IpAddress": ip.ip.to_string(),
+            "UnusualLocation": unusual_location,
+        }),
+    )
+    .save_or_log(conn);
+
+    if CONFIG.mail_enabled() {
+        let ip_str = ip.ip.to_string();
+        let user_locale = user.locale(locale.locale);
+        let sent = match unusual_location {
+            Some(location) => mail::send_new_location_logged_in(
+                &user.email,
+                &ip_str,
+                location,
+                &device.updated_at,
+                &device.name,
+                user_locale,
+            ),
+            None => {
+                mail::send_new_device_logged_in(&user.email, &ip_str, &device.updated_at, &device.name, user_locale)
+            }
+        };
+
+        if let Err(e) = sent {
+            error!("Error sending new device email: {:#?}", e);
+
+            if CONFIG.require_device_email() {
+                err!("Could not send login notification email. Please contact your administrator.")
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The reset password and master password policies that apply to the user, so that the clients can prompt for them.
+/// Both lists are always present, empty when the user isn't a member of any organization with them
+fn _login_policies_json(user_uuid: &str, conn: &DbConn) -> Value {
+    let mut reset_password = Vec::new();
+    let mut master_password = Vec::new();
+
+    // Owners and admins are exempt, like from the rest of the policies
+    for user_org in UserOrganization::find_by_user(user_uuid, conn) {
+        if user_org.atype >= UserOrgType::Admin {
+            continue;
+        }
+
+        let enabled_policy = |atype: OrgPolicyType| {
+            OrgPolicy::find_by_org_and_type(&user_org.org_uuid, atype as i32, conn).filter(|p| p.enabled)
+        };
+
+        if let Some(policy) = enabled_policy(OrgPolicyType::ResetPassword) {
+            reset_password.push(policy.to_json());
+        }
+        if let Some(policy) = enabled_policy(OrgPolicyType::MasterPassword) {
+            master_password.push(policy.to_json());
+        }
+    }
+
+    json!({
+        "ResetPassword": reset_password,
+        "MasterPassword": master_password,
+    })
+}
+
+/// Describes how the newer clients can decrypt the vault of the user. The older clients ignore it
+fn _user_decryption_options(user: &User, device: &Device) -> Value {
+    let key_connector = match CONFIG.key_connector_url() {
+        Some(url) if user.uses_key_connector => json!({ "KeyConnectorUrl": url }),
+        _ => Value::Null,
+    };
+
+    // Approvals from other devices or admins are not supported, the device needs to have been trusted before
+    let trusted_device = if device.is_trusted() {
+        json!({
+            "HasAdminApproval": false,
+            "HasLoginApprovingDevice": false,
+            "HasManageResetPasswordPermission": false,
+            "EncryptedPrivateKey": device.encrypted_private_key,
+            "EncryptedUserKey": device.encrypted_user_key,
+        })
+    } else {
+        Value::Null
+    };
+
+    json!({
+        "HasMasterPassword": !user.password_hash.is_empty(),
+        "TrustedDeviceOption": trusted_device,
+        "KeyConnectorOption": key_connector,
+        "Object": "userDecryptionOptions",
+    })
+}
+
+/// With `TOKEN_RESPONSE_LOWERCASE`, converts the Bitwarden style keys like `PrivateKey` to snake_case like `private_key`,
+/// so all the keys of the response follow the same convention as the OAuth ones
+fn _token_response(result: Value) -> Value {
+    if !CONFIG.token_response_lowercase() {
+        return result;
+    }
+
+    _snake_case_keys(result)
+}
+
+/// Converts the keys of the objects to snake_case, including the ones of the nested objects and arrays
+fn _snake_case_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| (util::snake_case(&k), _snake_case_keys(v)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(_snake_case_keys).collect()),
+        value => value,
+    }
+}
+
+/// When `REQUIRE_2FA` or `REQUIRE_2FA_ORG_ADMINS` apply, returns the enrollment deadline of a user without a second
+/// factor, starting the grace period on the first login they apply to and failing once it's over. The
+/// `TwoFactorAuthentication` policy of an organization has no grace period, its members can't log in until they enroll
+fn _check_2fa_enrollment(user: &mut User, conn: &DbConn) -> ApiResult<Option<NaiveDateTime>> {
+    // Org owners and admins can still log in after the deadline, only managing the organizations is blocked
+    let required_for_orgs = CONFIG.require_2fa_org_admins()
+        && UserOrganization::find_by_user(&user.uuid, conn)
+            .iter()
+            .any(|uo| uo.atype >= UserOrgType::Admin);
+    // The owners and admins are exempt from the policy, like from the other ones
+    let required_by_policy = OrgPolicy::is_applicable_to_user(&user.uuid, OrgPolicyType::TwoFactorAuthentication, conn);
+
+    let mut reasons = 0;
+    if CONFIG.require_2fa() {
+        reasons |= User::TWOFACTOR_ENROLL_SERVER;
+    }
+    if required_for_orgs {
+        reasons |= User::TWOFACTOR_ENROLL_ORG_ADMINS;
+    }
+
+    if (reasons == 0 && !required_by_policy) || !TwoFactor::find_by_user(&user.uuid, conn).is_empty() {
+        return Ok(None);
+    }
+
+    if required_by_policy {
+        err_code!(
+            ErrorCode::TwoFactorEnrollmentRequired,
+            "Your organization requires two-step login. Please enroll a two-step login method before logging in, or contact the administrator of your organization",
+            format!("Username: {}. Two-step login required by an organization policy.", user.email)
+        )
+    }
+
+    let now = Utc::now().naive_utc();
+    if user.update_twofactor_enroll_deadline(reasons, now) {
+        user.save(conn)?;
+    }
+    let deadline = user.twofactor_enroll_deadline.unwrap();
+
+    if now > deadline && CONFIG.require_2fa() {
+        err_code!(
+            ErrorCode::TwoFactorEnrollmentRequired,
+            "Two-step login is required on this server and the enrollment period is over. Please contact your administrator",
+            format!("Username: {}.", user.email)
+        )
+    }
+
+    Ok(Some(deadline))
+}
+
+/// Counts a wrong two-step login code, locking the account and alerting the user once `TWOFACTOR_LOCKOUT_ATTEMPTS` is reached
+fn _register_twofactor_failure(user: &mut User, ip: &ClientIp, locale: &ClientLocale, conn: &DbConn) {
+    let locked = user.register_twofactor_failure();
+    if let Err(e) = user.save(conn) {
+        error!("Error saving the two-step login failures: {:#?}", e);
+    }
+
+    if !locked {
+        return;
+    }
+
+    warn!(
+        "User {} locked out after too many two-step login failures. IP: {}",
+        user.email, ip.ip
+    );
+    _send_login_event("lockout", Some(&user.email), None, &ip);
+
+    SecurityNotice::new(
+        user.uuid.clone(),
+        SecurityNoticeType::TwoFactorLockout,
+        json!({
+            "IpAddress": ip.ip.to_string(),
+            "LockedSeconds": user.twofactor_lockout_seconds(),
+        }),
+    )
+    .save_or_log(conn);
+
+    if CONFIG.mail_enabled() {
+        if let Err(e) = mail::send_twofactor_lockout(&user.email, &ip.ip.to_string(), user.locale(locale.locale)) {
+            error!("Error sending the two-step login lockout email: {:#?}", e);
+        }
+    }
+}
+
+/// Rejects the IPs that failed too many logins, or two-step login codes, recently
+fn _check_ratelimit(ip: &ClientIp) -> EmptyResult {
+    match lockout::ratelimit_retry_after(&ip.ip.to_string()) {
+        Some(seconds) => Err(lockout::ratelimited_error(
+            seconds,
+            format!("IP: {}. Rate limited.", ip.ip),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Once the IP had too many failed logins, the password is only checked along with a valid captcha
+fn _check_captcha(data: &ConnectData, ip: &ClientIp) -> EmptyResult {
+    let ip_key = ip.ip.to_string();
+    if !lockout::captcha_required(&ip_key) {
+        return Ok(());
+    }
+
+    let response = match data.captcha_response {
+        Some(ref response) if !response.is_empty() => response,
+        _ => {
+            return Err(captcha::required_error(
+                "Captcha required.",
+                format!("IP: {}. Captcha required.", ip.ip),
+            ))
+        }
+    };
+
+    if !captcha::verify(response, &ip_key)? {
+        lockout::register_ratelimit_failure(&ip_key);
+        return Err(captcha::required_error(
+            "Invalid captcha. Try again",
+            format!("IP: {}. Invalid captcha.", ip.ip),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Target of the login logs of `LOG_FORMAT_JSON`, which are written without the usual prefix of the log lines
+pub const LOGIN_LOG_TARGET: &str = "login";
+
+/// With `LOG_FORMAT_JSON`, logs a login attempt as a single JSON line, on top of the usual logs,
+/// so that the log collectors don't need to parse the free-text lines
+fn _log_login(
+    event: &str,
+    success: bool,
+    username: Option<&str>,
+    device_type: Option<i32>,
+    twofactor_provider: Option<i32>,
+    ip: &ClientIp,
+) {
+    if !CONFIG.log_format_json() {
+        return;
+    }
+
+    let line = json!({
+        "timestamp": Utc::now().to_rfc3339(),
+        "event": event,
+        "success": success,
+        "username": username,
+        "ip": ip.ip.to_string(),
+        "device_type": device_type,
+        "twofactor_provider": twofactor_provider,
+    });
+
+    if success {
+        info!(target: LOGIN_LOG_TARGET, "{}", line);
+    } else {
+        warn!(target: LOGIN_LOG_TARGET, "{}", line);
+    }
+}
+
+/// Checked after the credentials, so the disabled accounts can't be told apart without them
+fn _check_user_enabled(user: &User, ip: &ClientIp) -> EmptyResult {
+    if !user.enabled {
+        err_code!(
+            ErrorCode::AccountDisabled,
+            "This account is disabled, contact the administrator of the server",
+            format!("IP: {}. Username: {}. Account disabled.", ip.ip, user.email)
+        )
+    }
+    Ok(())
+}
+
+/// With `EVENT_WEBHOOK_URL`, sends the login event to the webhook without waiting for it
+fn _send_login_event(event_type: &str, username: Option<&str>, device_name: Option<&str>, ip: &ClientIp) {
+    webhook::send_in_background(webhook::event_payload(
+        event_type,
+        username,
+        Some(&ip.ip.to_string()),
+        device_name,
+    ));
+}
+
+fn _log_event(event_type: EventType, user_uuid: &str, device: Option<&Device>, ip: &ClientIp, conn: &DbConn) {
+    let mut event = Event::new(event_type, Some(user_uuid.to_string()));
+    if let Some(device) = device {
+        event.device_uuid = Some(device.uuid.clone());
+        event.device_type = Some(device.atype);
+    }
+    event.ip_address = Some(ip.ip.to_string());
+    event.save_or_log(conn);
+}
+
+/// A refresh token that was already rotated out was used again, so either it or the current one was stolen.
+/// Both are revoked, the legitimate client needs to log in again
+fn _check_refresh_token_reuse(token: &str, ip: &ClientIp, conn: &DbConn) {
+    if let Some(device) = Device::refresh_token_family(token).and_then(|f| Device::find_by_refresh_family(f, conn)) {
+        _revoke_reused_refresh_token(device, ip, conn);
+    }
+}
+
+/// With `REFRESH_TOKEN_REUSE_REVOKE`, revokes the refresh tokens of the device after one of its old tokens was used
+fn _revoke_reused_refresh_token(mut device: Device, ip: &ClientIp, conn: &DbConn) {
+    if !CONFIG.refresh_token_reuse_revoke() {
+        warn!(
+            "Reused refresh token of device {}, at generation {}. IP: {}",
+            device.uuid, device.refresh_generation, ip.ip
+        );
+        return;
+    }
+
+    warn!(
+        "Reused refresh token of device {}, at generation {}. Revoking its refresh tokens. IP: {}",
+        device.uuid, device.refresh_generation, ip.ip
+    );
+
+    device.revoke_refresh_tokens();
+    if let Err(e) = device.save(conn) {
+        error!("Error revoking the refresh tokens: {:#?}", e);
+    }
+
+    if let Some(user) = User::find_by_uuid(&device.user_uuid, conn) {
+        crate::auth::notify_session_terminated(
+            &user,
+            &device,
+            "A refresh token was used again after it was replaced",
+            &ip.ip,
+            conn,
+        );
+    }
+}
+
+/// Checks the request IP against the one the refresh token was issued to, following `REFRESH_TOKEN_IP_BINDING`
+fn _check_refresh_ip(device: &Device, ip: &ClientIp) -> bool {
+    let mode = CONFIG.refresh_token_ip_binding();
+    if mode == "off" {
+        return true;
+    }
+
+    // Devices logged in before the binding was enabled don't have an IP yet, they get bound on this refresh
+    let bound_ip: std::net::IpAddr = match device.refresh_ip.as_ref().and_then(|i| i.parse().ok()) {
+        Some(bound_ip) => bound_ip,
+        None => return true,
+    };
+
+    match mode.as_str() {
+        "subnet" => util::same_subnet(&bound_ip, &ip.ip),
+        _ => bound_ip == ip.ip,
+    }
 }
 
 /// Retrieves an existing device or creates a new device from ConnectData and the User
 fn get_device(data: &ConnectData, conn: &DbConn, user: &User) -> (Device, bool) {
-    // On iOS, device_type sends "iOS", on others it sends a number
-    let device_type = util::try_parse_string(data.device_type.as_ref()).unwrap_or(0);
+    let device_type_str = data.device_type.clone().unwrap_or_default();
+    let device_type = DeviceType::parse(&device_type_str);
+    if device_type == DeviceType::Unknown {
+        warn!("Unknown device type received: '{}'", device_type_str);
+    }
+    let device_type = device_type as i32;
     let device_id = data.device_identifier.clone().expect("No device id provided");
     let device_name = data.device_name.clone().expect("No device name provided");
 
     let mut new_device = false;
     // Find device or create new
-    let device = match Device::find_by_uuid(&device_id, &conn) {
-        Some(device) => {
+    let mut device = match Device::find_by_uuid(&device_id, &conn) {
+        Some(mut device) => {
             // Check if owned device, and recreate if not
             if device.user_uuid != user.uuid {
                 info!("Device exists but is owned by another user. The old device will be discarded");
                 new_device = true;
                 Device::new(device_id, user.uuid.clone(), device_name, device_type)
+            } else if device.is_pending_expired() {
+                // Waits for a new approval, with a new token
+                new_device = true;
+                Device::new(device_id, user.uuid.clone(), device_name, device_type)
             } else {
+                // Corrects the devices stored with a wrong type, before the types were validated
+                if device_type != DeviceType::Unknown as i32 {
+                    device.atype = device_type;
+                }
                 device
             }
         }
@@ -165,6 +1418,15 @@ fn get_device(data: &ConnectData, conn: &DbConn, user: &User) -> (Device, bool)
         }
     };
 
+    // The first device of the user is approved right away, otherwise there would be nothing to approve it from.
+    // With the admin approval policy even the first one waits for an admin
+    if new_device
+        && ((CONFIG.device_approval_required() && Device::find_by_user(&user.uuid, conn).iter().any(|d| d.approved))
+            || OrgPolicy::is_applicable_to_user(&user.uuid, OrgPolicyType::AdminDeviceApproval, conn))
+    {
+        device.hold_for_approval();
+    }
+
     (device, new_device)
 }
 
@@ -172,8 +1434,12 @@ fn twofactor_auth(
     user_uuid: &str,
     data: &ConnectData,
     device: &mut Device,
+    ip: &ClientIp,
+    method: LoginMethod,
     conn: &DbConn,
 ) -> ApiResult<Option<String>> {
+    use crate::api::core::two_factor as _tf;
+
     let twofactors = TwoFactor::find_by_user(user_uuid, conn);
 
     // No twofactor token if twofactor is disabled
@@ -181,40 +1447,71 @@ fn twofactor_auth(
         return Ok(None);
     }
 
-    let twofactor_ids: Vec<_> = twofactors.iter().map(|tf| tf.atype).collect();
-    let selected_id = data.two_factor_provider.unwrap_or(twofactor_ids[0]); // If we aren't given a two factor provider, asume the first one
+    // With TRUSTED_IP_RANGES_ENABLED, the user's trusted networks only need the master password.
+    // The magic links still need the second factor, or the access to the email alone would be enough
+    let password_login = match method {
+        LoginMethod::Password(_) => true,
+        _ => false,
+    };
+    if password_login && _tf::is_trusted_ip(user_uuid, &ip.ip, conn) {
+        info!("Two-step login skipped for a trusted IP range. IP: {}", ip.ip);
+        return Ok(None);
+    }
+
+    // Sorted by priority with the preferred provider first, which is the one used when the client doesn't select one
+    let twofactor_ids = _tf::enabled_providers(user_uuid, &twofactors, conn);
+    if twofactor_ids.is_empty() {
+        err!("The two-step login providers of this account need the DOMAIN to be set")
+    }
+    let selected_id = data.two_factor_provider.unwrap_or(twofactor_ids[0]);
 
     let twofactor_code = match data.two_factor_token {
         Some(ref code) => code,
-        None => err_json!(_json_err_twofactor(&twofactor_ids, user_uuid, conn)?),
+        None => {
+            metrics::inc(metrics::TWOFACTOR_CHALLENGES, &[]);
+            let providers_json = _json_err_twofactor(&twofactor_ids, user_uuid, device.atype, conn)?;
+            err_json!(providers_json, ErrorCode::TwoFactorRequired)
+        }
     };
 
     let selected_twofactor = twofactors.into_iter().filter(|tf| tf.atype == selected_id).nth(0);
 
-    use crate::api::core::two_factor as _tf;
     use crate::crypto::ct_eq;
 
     let selected_data = _selected_data(selected_twofactor);
-    let mut remember = data.two_factor_remember.unwrap_or(0);
+    let remember = data.two_factor_remember.unwrap_or(0);
 
     match TwoFactorType::from_i32(selected_id) {
-        Some(TwoFactorType::Authenticator) => _tf::validate_totp_code_str(twofactor_code, &selected_data?)?,
+        Some(TwoFactorType::Authenticator) => _tf::validate_totp_login(user_uuid, twofactor_code, conn)?,
         Some(TwoFactorType::U2f) => _tf::validate_u2f_login(user_uuid, twofactor_code, conn)?,
+        Some(TwoFactorType::Webauthn) => _tf::validate_webauthn_login(user_uuid, twofactor_code, conn)?,
         Some(TwoFactorType::YubiKey) => _tf::validate_yubikey_login(twofactor_code, &selected_data?)?,
-        Some(TwoFactorType::Duo) => _tf::validate_duo_login(data.username.as_ref().unwrap(), twofactor_code, conn)?,
+        Some(TwoFactorType::Email) => _tf::validate_email_login(user_uuid, twofactor_code, conn)?,
+        Some(TwoFactorType::Duo) => _tf::validate_duo_login(user_uuid, twofactor_code, conn)?,
 
         Some(TwoFactorType::Remember) => {
-            match device.twofactor_remember {
-                Some(ref code) if !CONFIG.disable_2fa_remember() && ct_eq(code, twofactor_code) => {
-                    remember = 1; // Make sure we also return the token here, otherwise it will only remember the first time
+            // The expired ones need the second factor again, sent with the same error as when it's missing
+            let remembered = match device.twofactor_remember {
+                Some(ref code) => {
+                    !CONFIG.disable_2fa_remember()
+                        && !device.is_twofactor_remember_expired()
+                        && ct_eq(code, twofactor_code)
                 }
-                _ => err_json!(_json_err_twofactor(&twofactor_ids, user_uuid, conn)?),
+                None => false,
+            };
+            if !remembered {
+                let providers_json = _json_err_twofactor(&twofactor_ids, user_uuid, device.atype, conn)?;
+                err_json!(providers_json, ErrorCode::TwoFactorRequired)
             }
         }
         _ => err!("Invalid two factor provider"),
     }
 
-    if !CONFIG.disable_2fa_remember() && remember == 1 {
+    if selected_id == TwoFactorType::Remember as i32 {
+        // Make sure we also return the token here, otherwise it will only remember the first time.
+        // It's replaced, but it still expires when the second factor was first remembered
+        Ok(Some(device.rotate_twofactor_remember()))
+    } else if !CONFIG.disable_2fa_remember() && remember == 1 {
         Ok(Some(device.refresh_twofactor_remember()))
     } else {
         device.delete_twofactor_remember();
@@ -222,6 +1519,14 @@ fn twofactor_auth(
     }
 }
 
+/// The name of the two-step login provider for the metrics, `default` when the client didn't select one
+fn _twofactor_provider_label(provider: Option<i32>) -> &'static str {
+    match provider {
+        Some(provider) => TwoFactor::type_name_of(provider).unwrap_or("other"),
+        None => "default",
+    }
+}
+
 fn _selected_data(tf: Option<TwoFactor>) -> ApiResult<String> {
     match tf {
         Some(tf) => Ok(tf.data),
@@ -229,7 +1534,7 @@ fn _selected_data(tf: Option<TwoFactor>) -> ApiResult<String> {
     }
 }
 
-fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &DbConn) -> ApiResult<Value> {
+fn _json_err_twofactor(providers: &[i32], user_uuid: &str, device_type: i32, conn: &DbConn) -> ApiResult<Value> {
     use crate::api::core::two_factor;
 
     let mut result = json!({
@@ -265,20 +1570,31 @@ fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &DbConn) -> Api
                 });
             }
 
-            Some(TwoFactorType::Duo) => {
-                let email = match User::find_by_uuid(user_uuid, &conn) {
-                    Some(u) => u.email,
-                    None => err!("User does not exist"),
-                };
-
-                let (signature, host) = two_factor::generate_duo_signature(&email, conn)?;
+            Some(TwoFactorType::Webauthn) if CONFIG.domain_set() => {
+                result["TwoFactorProviders2"][provider.to_string()] =
+                    two_factor::generate_webauthn_login(user_uuid, conn)?;
+            }
 
-                result["TwoFactorProviders2"][provider.to_string()] = json!({
-                    "Host": host,
-                    "Signature": signature,
-                });
+            // Like the official server, the code is only sent right away when there is no other provider to choose
+            Some(TwoFactorType::Email) => {
+                result["TwoFactorProviders2"][provider.to_string()] =
+                    two_factor::email_login_metadata(user_uuid, providers.len() == 1, conn)?;
             }
 
+            // When Duo can't be used, like when it's unreachable, the other providers still can
+            Some(TwoFactorType::Duo) => match _duo_login_metadata(user_uuid, device_type, conn) {
+                Ok(metadata) => result["TwoFactorProviders2"][provider.to_string()] = metadata,
+                Err(e) => {
+                    error!("Error preparing the Duo login, it's left out of the providers: {:#?}", e);
+                    if let Some(providers2) = result["TwoFactorProviders2"].as_object_mut() {
+                        providers2.remove(&provider.to_string());
+                    }
+                    if let Some(providers) = result["TwoFactorProviders"].as_array_mut() {
+                        providers.retain(|p| p.as_i64() != Some(i64::from(*provider)));
+                    }
+                }
+            },
+
             Some(tf_type @ TwoFactorType::YubiKey) => {
                 let twofactor = match TwoFactor::find_by_user_and_type(user_uuid, tf_type as i32, &conn) {
                     Some(tf) => tf,
@@ -299,10 +1615,33 @@ fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &DbConn) -> Api
     Ok(result)
 }
 
+/// The URL of the Universal Prompt, or the signature of the legacy iframe prompt with `DUO_USE_IFRAME`
+fn _duo_login_metadata(user_uuid: &str, device_type: i32, conn: &DbConn) -> ApiResult<Value> {
+    use crate::api::core::two_factor;
+
+    if !CONFIG.duo_use_iframe() {
+        return Ok(json!({
+            "AuthUrl": two_factor::generate_duo_auth_url(user_uuid, device_type, conn)?,
+        }));
+    }
+
+    let email = match User::find_by_uuid(user_uuid, conn) {
+        Some(u) => u.email,
+        None => err!("User does not exist"),
+    };
+
+    let (signature, host) = two_factor::generate_duo_signature(&email, conn)?;
+
+    Ok(json!({
+        "Host": host,
+        "Signature": signature,
+    }))
+}
+
 #[derive(Debug, Clone, Default)]
 #[allow(non_snake_case)]
 struct ConnectData {
-    grant_type: String, // refresh_token, password
+    grant_type: String, // refresh_token, password, client_credentials, magic_link, authorization_code
 
     // Needed for grant_type="refresh_token"
     refresh_token: Option<String>,
@@ -313,10 +1652,24 @@ struct ConnectData {
     scope: Option<String>,
     username: Option<String>,
 
+    // Needed for grant_type="client_credentials", with client_id and scope
+    client_secret: Option<String>,
+
+    // Needed for grant_type="magic_link"
+    token: Option<String>,
+
+    // Needed for grant_type="authorization_code", with scope
+    code: Option<String>,
+    code_verifier: Option<String>,
+    redirect_uri: Option<String>,
+
     device_identifier: Option<String>,
     device_name: Option<String>,
     device_type: Option<String>,
 
+    // Needed once the IP needs a captcha
+    captcha_response: Option<String>,
+
     // Needed for two-factor auth
     two_factor_provider: Option<i32>,
     two_factor_token: Option<String>,
@@ -337,15 +1690,21 @@ impl<'f> FromForm<'f> for ConnectData {
                 "granttype" => form.grant_type = value,
                 "refreshtoken" => form.refresh_token = Some(value),
                 "clientid" => form.client_id = Some(value),
+                "clientsecret" => form.client_secret = Some(value),
                 "password" => form.password = Some(value),
                 "scope" => form.scope = Some(value),
                 "username" => form.username = Some(value),
+                "token" => form.token = Some(value),
+                "code" => form.code = Some(value),
+                "codeverifier" => form.code_verifier = Some(value),
+                "redirecturi" => form.redirect_uri = Some(value),
                 "deviceidentifier" => form.device_identifier = Some(value),
                 "devicename" => form.device_name = Some(value),
                 "devicetype" => form.device_type = Some(value),
                 "twofactorprovider" => form.two_factor_provider = value.parse().ok(),
                 "twofactortoken" => form.two_factor_token = Some(value),
                 "twofactorremember" => form.two_factor_remember = value.parse().ok(),
+                "captcharesponse" => form.captcha_response = Some(value),
                 key => warn!("Detected unexpected parameter during login: {}", key),
             }
         }
@@ -354,9 +1713,180 @@ impl<'f> FromForm<'f> for ConnectData {
     }
 }
 
+impl ConnectData {
+    fn parsed_device_type(&self) -> Option<i32> {
+        self.device_type
+            .as_ref()
+            .and_then(|t| DeviceType::from_str(t))
+            .map(|t| t as i32)
+    }
+}
+
 fn _check_is_some<T>(value: &Option<T>, msg: &str) -> EmptyResult {
     if value.is_none() {
         err!(msg)
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn devices(count: usize) -> Vec<Device> {
+        (0..count)
+            .map(|i| Device::new(format!("device-{}", i), "user".into(), format!("Device {}", i), 0))
+            .collect()
+    }
+
+    fn uuids(devices: &[Device]) -> Vec<&str> {
+        devices.iter().map(|d| d.uuid.as_str()).collect()
+    }
+
+    #[test]
+    fn devices_under_the_limit_are_kept() {
+        assert!(devices_over_limit(devices(2), 3).is_empty());
+        assert!(devices_over_limit(Vec::new(), 1).is_empty());
+    }
+
+    #[test]
+    fn least_recently_used_devices_are_evicted() {
+        assert_eq!(uuids(&devices_over_limit(devices(3), 3)), ["device-0"]);
+        assert_eq!(uuids(&devices_over_limit(devices(5), 3)), ["device-0", "device-1", "device-2"]);
+        assert_eq!(uuids(&devices_over_limit(devices(2), 1)), ["device-0", "device-1"]);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn terminated_devices_can_not_refresh() {
+        let conn = crate::migrations::test_connection();
+        let mut user = User::new("user@example.com".into());
+        user.save(&conn).unwrap();
+
+        let mut device = Device::new("device".into(), user.uuid.clone(), "Firefox".into(), 0);
+        device.new_refresh_family();
+        device.save(&conn).unwrap();
+        let token = device.refresh_token.clone();
+
+        // Like the admin terminating the session
+        DeviceApprovalRequest::delete_all_by_device(&device.uuid, &conn).unwrap();
+        device.delete(&conn).unwrap();
+
+        let data = ConnectData {
+            grant_type: "refresh_token".into(),
+            refresh_token: Some(token),
+            ..Default::default()
+        };
+        let ip = ClientIp {
+            ip: "127.0.0.1".parse().unwrap(),
+        };
+        let error = _refresh_login(data, conn, ip).unwrap_err();
+        assert_eq!(error.status_code(), 401);
+        assert_eq!(error.api_code(), Some(ErrorCode::InvalidRefreshToken));
+    }
+
+    /// A device of a user with an authenticator, with a remembered second factor
+    #[cfg(feature = "sqlite")]
+    fn remembered_device(conn: &DbConn) -> (Device, String) {
+        let mut user = User::new("user@example.com".into());
+        user.save(conn).unwrap();
+        TwoFactor::new(user.uuid.clone(), TwoFactorType::Authenticator, "secret".into())
+            .save(conn)
+            .unwrap();
+
+        let mut device = Device::new("device".into(), user.uuid, "Firefox".into(), 0);
+        let token = device.refresh_twofactor_remember();
+        (device, token)
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn remember_login(device: &mut Device, token: String, conn: &DbConn) -> ApiResult<Option<String>> {
+        let data = ConnectData {
+            two_factor_provider: Some(TwoFactorType::Remember as i32),
+            two_factor_token: Some(token),
+            ..Default::default()
+        };
+        let ip = ClientIp {
+            ip: "127.0.0.1".parse().unwrap(),
+        };
+        let user_uuid = device.user_uuid.clone();
+        twofactor_auth(&user_uuid, &data, device, &ip, LoginMethod::Password("password"), conn)
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn remembered_twofactor_is_rotated() {
+        let conn = crate::migrations::test_connection();
+        let (mut device, token) = remembered_device(&conn);
+        let expires_at = device.twofactor_remember_expires_at;
+
+        let rotated = remember_login(&mut device, token.clone(), &conn).unwrap().unwrap();
+        assert_ne!(rotated, token);
+        assert_eq!(device.twofactor_remember.as_ref(), Some(&rotated));
+        assert_eq!(device.twofactor_remember_expires_at, expires_at);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn expired_remembered_twofactor_needs_the_second_factor() {
+        let conn = crate::migrations::test_connection();
+        let (mut device, token) = remembered_device(&conn);
+        device.twofactor_remember_expires_at = Some(Utc::now().naive_utc() - chrono::Duration::days(1));
+
+        let error = remember_login(&mut device, token, &conn).unwrap_err();
+        assert_eq!(error.api_code(), Some(ErrorCode::TwoFactorRequired));
+    }
+
+    /// A user in an organization with the two-step login policy, with the given role
+    #[cfg(feature = "sqlite")]
+    fn user_with_twofactor_policy(conn: &DbConn, role: UserOrgType) -> User {
+        let mut user = User::new("user@example.com".into());
+        user.save(conn).unwrap();
+
+        let org = Organization::new("Organization".into(), "billing@example.com".into());
+        org.save(conn).unwrap();
+        let mut membership = UserOrganization::new(user.uuid.clone(), org.uuid.clone());
+        membership.status = UserOrgStatus::Confirmed as i32;
+        membership.atype = role as i32;
+        membership.save(conn).unwrap();
+
+        let mut policy = OrgPolicy::new(org.uuid, OrgPolicyType::TwoFactorAuthentication, "{}".into());
+        policy.enabled = true;
+        policy.save(conn).unwrap();
+        user
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn twofactor_policy_blocks_right_away() {
+        let conn = crate::migrations::test_connection();
+        let mut user = user_with_twofactor_policy(&conn, UserOrgType::User);
+
+        let error = _check_2fa_enrollment(&mut user, &conn).unwrap_err();
+        assert_eq!(error.api_code(), Some(ErrorCode::TwoFactorEnrollmentRequired));
+        assert_eq!(user.twofactor_enroll_deadline, None);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn twofactor_policy_exempts_the_org_admins() {
+        let conn = crate::migrations::test_connection();
+        let mut user = user_with_twofactor_policy(&conn, UserOrgType::Admin);
+
+        assert_eq!(_check_2fa_enrollment(&mut user, &conn).unwrap(), None);
+        assert_eq!(user.twofactor_enroll_deadline, None);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn twofactor_policy_ignores_the_grace_period_of_other_requirements() {
+        let conn = crate::migrations::test_connection();
+        let mut user = user_with_twofactor_policy(&conn, UserOrgType::User);
+        // Still open for another requirement
+        user.twofactor_enroll_deadline = Some(Utc::now().naive_utc() + chrono::Duration::days(1));
+        user.twofactor_enroll_reasons = User::TWOFACTOR_ENROLL_SERVER;
+
+        let error = _check_2fa_enrollment(&mut user, &conn).unwrap_err();
+        assert_eq!(error.api_code(), Some(ErrorCode::TwoFactorEnrollmentRequired));
+    }
+}
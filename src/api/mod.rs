@@ -1,3 +1,4 @@
+#[cfg(feature = "admin")]
 mod admin;
 pub(crate) mod core;
 mod icons;
@@ -5,10 +6,12 @@ mod identity;
 mod notifications;
 mod web;
 
+#[cfg(feature = "admin")]
 pub use self::admin::routes as admin_routes;
 pub use self::core::routes as core_routes;
 pub use self::icons::routes as icons_routes;
 pub use self::identity::routes as identity_routes;
+pub use self::identity::{GRANT_TYPES, LOGIN_LOG_TARGET};
 pub use self::notifications::routes as notifications_routes;
 pub use self::notifications::{start_notification_server, Notify, UpdateType};
 pub use self::web::routes as web_routes;
@@ -22,6 +25,7 @@ pub type JsonResult = ApiResult<Json<Value>>;
 pub type EmptyResult = ApiResult<()>;
 
 use crate::util;
+use crate::CONFIG;
 type JsonUpcase<T> = Json<util::UpCase<T>>;
 type JsonUpcaseVec<T> = Json<Vec<util::UpCase<T>>>;
 
@@ -32,6 +36,62 @@ struct PasswordData {
     MasterPasswordHash: String,
 }
 
+/// Checks that a master password hash sent by a client looks like the output of its KDF,
+/// following `MASTER_PASSWORD_HASH_CHECK`
+fn check_password_hash_format(hash: &str, kdf_type: i32) -> EmptyResult {
+    use data_encoding::BASE64;
+
+    // The hash is always a PBKDF2-SHA256 of the master key, the KDF type only affects how that key is derived
+    let valid = match CONFIG.master_password_hash_check().as_str() {
+        "lenient" => !hash.trim().is_empty() && hash.len() <= 1024,
+        "strict" => match BASE64.decode(hash.as_bytes()) {
+            Ok(decoded) => kdf_type != crate::db::models::User::CLIENT_KDF_TYPE_DEFAULT || decoded.len() == 32,
+            Err(_) => false,
+        },
+        _ => true,
+    };
+
+    if !valid {
+        err!(
+            "The master password hash has an invalid format",
+            format!("Length: {}. KDF type: {}.", hash.len(), kdf_type)
+        )
+    }
+    Ok(())
+}
+
+/// The vault timeout the clients should use when they don't have their own. The maximum vault timeout policies of the
+/// organizations take precedence over `VAULT_TIMEOUT_MINUTES`, and with several of them the shortest one is used
+fn vault_timeout_json(user_uuid: &str, conn: &crate::db::DbConn) -> Value {
+    use crate::db::models::{OrgPolicy, OrgPolicyType};
+
+    let policy_timeout = OrgPolicy::find_applicable_to_user(user_uuid, OrgPolicyType::MaximumVaultTimeout, conn)
+        .iter()
+        .filter_map(|policy| {
+            let data: Value = serde_json::from_str(&policy.data).ok()?;
+            let minutes = data["minutes"].as_i64().filter(|m| *m > 0)?;
+            let action = data["action"].as_str().unwrap_or("lock").to_string();
+            Some((minutes, action))
+        })
+        .min_by_key(|(minutes, _)| *minutes);
+
+    match policy_timeout {
+        Some((minutes, action)) => json!({
+            "Minutes": minutes,
+            "Action": action,
+            "Source": "organization",
+        }),
+        None => match CONFIG.vault_timeout_minutes() {
+            Some(minutes) => json!({
+                "Minutes": minutes,
+                "Action": CONFIG.vault_timeout_action(),
+                "Source": "server",
+            }),
+            None => Value::Null,
+        },
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[serde(untagged)]
 enum NumberOrString {
@@ -46,7 +46,7 @@ fn negotiate(_headers: Headers, _conn: DbConn) -> JsonResult {
 use std::sync::Arc;
 use std::thread;
 
-use ws::{self, util::Token, Factory, Handler, Handshake, Message, Sender, WebSocket};
+use ws::{self, util::Token, CloseCode, Factory, Handler, Handshake, Message, Sender, WebSocket};
 
 use chashmap::CHashMap;
 use chrono::NaiveDateTime;
@@ -140,17 +140,49 @@ impl Handler for WSHandler {
             Err(_) => return Err(ws::Error::new(ws::ErrorKind::Internal, "Invalid access token provided")),
         };
 
-        // Assign the user to the handler
         let user_uuid = claims.sub;
-        self.user_uuid = Some(user_uuid.clone());
 
-        // Add the current Sender to the user list
+        // Add the current Sender to the user list, within `WEBSOCKET_MAX_CONNECTIONS_PER_USER`
+        let max = CONFIG.websocket_max_connections_per_user() as usize;
+        let close_oldest = CONFIG.websocket_limit_mode() == "close_oldest";
         let handler_insert = self.out.clone();
         let handler_update = self.out.clone();
+        let mut rejected = false;
+        let mut replaced = Vec::new();
+
+        self.users.map.upsert(
+            user_uuid.clone(),
+            || vec![handler_insert],
+            |ref mut v| {
+                if max > 0 && v.len() >= max {
+                    if close_oldest {
+                        let excess = v.len() + 1 - max;
+                        replaced.extend(v.drain(..excess));
+                    } else {
+                        rejected = true;
+                        return;
+                    }
+                }
+                v.push(handler_update);
+            },
+        );
+
+        if rejected {
+            warn!(
+                "User {} reached the limit of websocket connections, rejecting a new one",
+                user_uuid
+            );
+            return self.out.close_with_reason(CloseCode::Policy, "Too many connections");
+        }
 
-        self.users
-            .map
-            .upsert(user_uuid, || vec![handler_insert], |ref mut v| v.push(handler_update));
+        for sender in replaced {
+            if let Err(e) = sender.close_with_reason(CloseCode::Policy, "Replaced by a newer connection") {
+                warn!("Error closing a replaced websocket connection: {}", e);
+            }
+        }
+
+        // Assign the user to the handler
+        self.user_uuid = Some(user_uuid);
 
         // Schedule a ping to keep the connection alive
         self.out.timeout(PING_MS, PING)
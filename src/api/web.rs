@@ -2,7 +2,7 @@ use std::io;
 use std::path::{Path, PathBuf};
 
 use rocket::http::ContentType;
-use rocket::response::content::Content;
+use rocket::response::content::{Content, Html};
 use rocket::response::NamedFile;
 use rocket::Route;
 use rocket_contrib::json::Json;
@@ -13,11 +13,24 @@ use crate::error::Error;
 use crate::CONFIG;
 
 pub fn routes() -> Vec<Route> {
-    if CONFIG.web_vault_enabled() {
-        routes![web_index, app_id, web_files, attachments, alive, images]
+    let mut routes = if CONFIG.web_vault_enabled() {
+        routes![web_index, app_id, web_files, alive, images, jwks, server_info, metrics]
     } else {
-        routes![attachments, alive]
+        routes![alive, jwks, server_info, metrics]
+    };
+
+    #[cfg(feature = "attachments")]
+    routes.append(&mut routes![attachments]);
+
+    if CONFIG.magic_link_enabled() {
+        routes.append(&mut routes![magic_link]);
     }
+
+    if CONFIG.device_approval_required() {
+        routes.append(&mut routes![device_approval]);
+    }
+
+    routes
 }
 
 #[get("/")]
@@ -51,11 +64,68 @@ fn web_files(p: PathBuf) -> Cached<io::Result<NamedFile>> {
     Cached::long(NamedFile::open(Path::new(&CONFIG.web_vault_folder()).join(p)))
 }
 
+#[cfg(feature = "attachments")]
 #[get("/attachments/<uuid>/<file..>")]
 fn attachments(uuid: String, file: PathBuf) -> io::Result<NamedFile> {
     NamedFile::open(Path::new(&CONFIG.attachments_folder()).join(uuid).join(file))
 }
 
+/// The page the magic link emails point to, the web vault doesn't have one. It shows the code to enter in the client,
+/// and opening it doesn't use the link up, only the login does
+#[get("/magic-link?<email>&<token>")]
+fn magic_link(email: String, token: String) -> Result<Html<String>, Error> {
+    let text = CONFIG.render_template(
+        "pages/magic_link",
+        &json!({
+            "email": email,
+            "token": token,
+            "ttl": CONFIG.magic_link_ttl_minutes(),
+        }),
+    )?;
+    Ok(Html(text))
+}
+
+/// The page the device approval emails point to. Opening it doesn't approve the device, so that the link scanners
+/// of the mail servers can't, the button posts the token to `/api/devices/approve`
+#[get("/device-approval?<token>")]
+fn device_approval(token: String) -> Result<Html<String>, Error> {
+    let text = CONFIG.render_template(
+        "pages/device_approval",
+        &json!({
+            "url": CONFIG.domain(),
+            "token": token,
+        }),
+    )?;
+    Ok(Html(text))
+}
+
+#[get("/.well-known/jwks.json")]
+fn jwks() -> Json<Value> {
+    Json(json!({ "keys": [crate::auth::public_jwk()] }))
+}
+
+/// Public identity of the server. Version is the format of this response, and KeyId changes when the keys are rotated
+#[get("/.well-known/server-info.json")]
+fn server_info() -> Json<Value> {
+    use crate::auth::{public_jwk, public_key_base64, INSTALLATION_ID, PUBLIC_KEY_ID};
+
+    Json(json!({
+        "Version": 1,
+        "InstallationId": *INSTALLATION_ID,
+        "Domain": CONFIG.domain(),
+        "KeyId": *PUBLIC_KEY_ID,
+        "PublicKey": public_key_base64(),
+        "Keys": [public_jwk()],
+        "Object": "serverInfo",
+    }))
+}
+
+/// The Prometheus metrics of the logins, for the requests with `METRICS_TOKEN`
+#[get("/metrics")]
+fn metrics(_token: crate::auth::MetricsToken) -> Content<String> {
+    Content(ContentType::Plain, crate::metrics::render())
+}
+
 #[get("/alive")]
 fn alive() -> Json<String> {
     use crate::util::format_date;
@@ -72,4 +142,17 @@ fn images(filename: String) -> Result<Content<&'static [u8]>, Error> {
         "error-x.svg" => Ok(Content(ContentType::SVG, include_bytes!("../static/images/error-x.svg"))),
         _ => err!("Image not found"),
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_served_as_text() {
+        let Content(content_type, body) = metrics(crate::auth::MetricsToken {});
+        assert_eq!(content_type, ContentType::Plain);
+        for name in &[crate::metrics::LOGIN_ATTEMPTS, crate::metrics::LOGIN_DURATION, crate::metrics::NEW_DEVICES] {
+            assert!(body.contains(&format!("# TYPE {} ", name)));
+        }
+    }
+}
@@ -13,12 +13,22 @@ use crate::CONFIG;
 
 const JWT_ALGORITHM: Algorithm = Algorithm::RS256;
 
+/// Authentication method of the access tokens issued for an API key, in their `amr` claim
+pub const AMR_API_KEY: &str = "ApiKey";
+
 lazy_static! {
-    pub static ref DEFAULT_VALIDITY: Duration = Duration::hours(2);
-    static ref JWT_HEADER: Header = Header::new(JWT_ALGORITHM);
+    static ref JWT_HEADER: Header = Header {
+        kid: Some(PUBLIC_KEY_ID.clone()),
+        ..Header::new(JWT_ALGORITHM)
+    };
     pub static ref JWT_LOGIN_ISSUER: String = format!("{}|login", CONFIG.domain());
     pub static ref JWT_INVITE_ISSUER: String = format!("{}|invite", CONFIG.domain());
+    #[cfg(feature = "admin")]
     pub static ref JWT_ADMIN_ISSUER: String = format!("{}|admin", CONFIG.domain());
+    pub static ref JWT_MAGIC_LINK_ISSUER: String = format!("{}|magiclink", CONFIG.domain());
+    pub static ref JWT_DUO_STATE_ISSUER: String = format!("{}|duostate", CONFIG.domain());
+    pub static ref JWT_SSO_STATE_ISSUER: String = format!("{}|ssostate", CONFIG.domain());
+    pub static ref JWT_SSO_CODE_ISSUER: String = format!("{}|ssocode", CONFIG.domain());
     static ref PRIVATE_RSA_KEY: Vec<u8> = match read_file(&CONFIG.private_rsa_key()) {
         Ok(key) => key,
         Err(e) => panic!("Error loading private RSA Key.\n Error: {}", e),
@@ -27,6 +37,22 @@ lazy_static! {
         Ok(key) => key,
         Err(e) => panic!("Error loading public RSA Key.\n Error: {}", e),
     };
+    static ref SERVER_SECRET_KEY: Vec<u8> = match read_file(&CONFIG.server_secret_key_filename()) {
+        Ok(key) => key,
+        Err(e) => panic!("Error loading the server secret key.\n Error: {}", e),
+    };
+    /// Identifies the current key pair, it changes when the keys are rotated
+    pub static ref PUBLIC_KEY_ID: String = crate::crypto::sha256_hex(&PUBLIC_RSA_KEY)[..16].to_string();
+}
+
+/// A key derived from the server secret key, a different one for each purpose
+pub fn server_key(purpose: &str) -> Vec<u8> {
+    crate::crypto::hmac_sha256(&SERVER_SECRET_KEY, purpose.as_bytes())
+}
+
+/// How long the access tokens are valid for, with `ACCESS_TOKEN_LIFETIME_MINUTES`
+pub fn access_token_validity() -> Duration {
+    Duration::minutes(CONFIG.access_token_lifetime_minutes())
 }
 
 pub fn encode_jwt<T: Serialize>(claims: &T) -> String {
@@ -37,21 +63,66 @@ pub fn encode_jwt<T: Serialize>(claims: &T) -> String {
 }
 
 fn decode_jwt<T: DeserializeOwned>(token: &str, issuer: String) -> Result<T, Error> {
-    let validation = jsonwebtoken::Validation {
-        leeway: 30, // 30 seconds
+    decode_jwt_with_key(token, &PUBLIC_RSA_KEY, JWT_ALGORITHM, issuer)
+}
+
+/// The validity of the claims, which limits the clock skew leeway
+#[derive(Deserialize)]
+struct ValidityClaims {
+    nbf: Option<i64>,
+    exp: i64,
+}
+
+fn decode_jwt_with_key<T: DeserializeOwned>(
+    token: &str,
+    key: &[u8],
+    algorithm: Algorithm,
+    issuer: String,
+) -> Result<T, Error> {
+    let validation = |leeway| jsonwebtoken::Validation {
+        leeway,
         validate_exp: true,
         validate_nbf: true,
         aud: None,
-        iss: Some(issuer),
+        iss: Some(issuer.clone()),
         sub: None,
-        algorithms: vec![JWT_ALGORITHM],
+        algorithms: vec![algorithm],
     };
 
     let token = token.replace(char::is_whitespace, "");
 
-    jsonwebtoken::decode(&token, &PUBLIC_RSA_KEY, &validation)
-        .map(|d| d.claims)
-        .map_res("Error decoding JWT")
+    match jsonwebtoken::decode(&token, key, &validation(0)) {
+        Err(ref e) if is_time_error(e.kind()) => {
+            // Try again allowing for clock skew, and log it so the operators can notice if it keeps happening.
+            // The leeway is at most half the lifetime of the token, so that it doesn't double the short ones
+            let mut leeway = CONFIG.token_clock_skew_seconds() as i64;
+            let validity: ValidityClaims = jsonwebtoken::decode(&token, key, &validation(leeway))
+                .map(|d| d.claims)
+                .map_res("Error decoding JWT")?;
+            if let Some(nbf) = validity.nbf {
+                leeway = std::cmp::min(leeway, (validity.exp - nbf) / 2);
+            }
+
+            let claims = jsonwebtoken::decode(&token, key, &validation(leeway))
+                .map(|d| d.claims)
+                .map_res("Error decoding JWT")?;
+            warn!(
+                "Token with issuer {} only accepted because of the clock skew leeway",
+                issuer
+            );
+            Ok(claims)
+        }
+        result => result.map(|d| d.claims).map_res("Error decoding JWT"),
+    }
+}
+
+fn is_time_error(kind: &jsonwebtoken::errors::ErrorKind) -> bool {
+    use jsonwebtoken::errors::ErrorKind;
+
+    match kind {
+        ErrorKind::ExpiredSignature | ErrorKind::ImmatureSignature => true,
+        _ => false,
+    }
 }
 
 pub fn decode_login(token: &str) -> Result<LoginJWTClaims, Error> {
@@ -62,10 +133,43 @@ pub fn decode_invite(token: &str) -> Result<InviteJWTClaims, Error> {
     decode_jwt(token, JWT_INVITE_ISSUER.to_string())
 }
 
+/// Tells the expired invitations apart from the invalid ones, checking the signature but not the dates
+pub fn is_expired_invite(token: &str) -> bool {
+    let validation = jsonwebtoken::Validation {
+        leeway: 0,
+        validate_exp: false,
+        validate_nbf: false,
+        aud: None,
+        iss: Some(JWT_INVITE_ISSUER.to_string()),
+        sub: None,
+        algorithms: vec![JWT_ALGORITHM],
+    };
+
+    let token = token.replace(char::is_whitespace, "");
+    jsonwebtoken::decode::<InviteJWTClaims>(&token, &PUBLIC_RSA_KEY, &validation).is_ok()
+}
+
+#[cfg(feature = "admin")]
 pub fn decode_admin(token: &str) -> Result<AdminJWTClaims, Error> {
     decode_jwt(token, JWT_ADMIN_ISSUER.to_string())
 }
 
+pub fn decode_magic_link(token: &str) -> Result<MagicLinkJWTClaims, Error> {
+    decode_jwt(token, JWT_MAGIC_LINK_ISSUER.to_string())
+}
+
+pub fn decode_duo_state(token: &str) -> Result<DuoStateJWTClaims, Error> {
+    decode_jwt(token, JWT_DUO_STATE_ISSUER.to_string())
+}
+
+pub fn decode_sso_state(token: &str) -> Result<SsoStateJWTClaims, Error> {
+    decode_jwt(token, JWT_SSO_STATE_ISSUER.to_string())
+}
+
+pub fn decode_sso_code(token: &str) -> Result<SsoCodeJWTClaims, Error> {
+    decode_jwt(token, JWT_SSO_CODE_ISSUER.to_string())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginJWTClaims {
     // Not before
@@ -93,8 +197,17 @@ pub struct LoginJWTClaims {
     pub device: String,
     // [ "api", "offline_access" ]
     pub scope: Vec<String>,
-    // [ "Application" ]
+    // [ "Application" ], or [ "ApiKey" ] for the API key logins
     pub amr: Vec<String>,
+    // The API key the token was issued for, from `User::api_key_id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_id: Option<String>,
+}
+
+impl LoginJWTClaims {
+    pub fn is_api_key(&self) -> bool {
+        self.amr.iter().any(|m| m == AMR_API_KEY)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -134,6 +247,7 @@ pub fn generate_invite_claims(
     }
 }
 
+#[cfg(feature = "admin")]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AdminJWTClaims {
     // Not before
@@ -146,6 +260,7 @@ pub struct AdminJWTClaims {
     pub sub: String,
 }
 
+#[cfg(feature = "admin")]
 pub fn generate_admin_claims() -> AdminJWTClaims {
     let time_now = Utc::now().naive_utc();
     AdminJWTClaims {
@@ -156,14 +271,192 @@ pub fn generate_admin_claims() -> AdminJWTClaims {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MagicLinkJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject
+    pub sub: String,
+
+    // Must match the one saved for the user, which is cleared when the link is used
+    pub nonce: String,
+    // The IP the link was requested from, only checked with `MAGIC_LINK_BIND_IP`
+    pub ip: Option<String>,
+}
+
+pub fn generate_magic_link_claims(uuid: String, nonce: String, ip: String) -> MagicLinkJWTClaims {
+    let time_now = Utc::now().naive_utc();
+    MagicLinkJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::minutes(CONFIG.magic_link_ttl_minutes())).timestamp(),
+        iss: JWT_MAGIC_LINK_ISSUER.to_string(),
+        sub: uuid,
+        nonce,
+        ip: Some(ip),
+    }
+}
+
+/// The state of a Duo Universal Prompt login, Duo sends it back along with the code
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DuoStateJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject, the user
+    pub sub: String,
+
+    // Duo includes it in the id token, so the result can't come from another login
+    pub nonce: String,
+    // The code is only exchanged with the same redirect URI that was used to get it
+    pub redirect_uri: String,
+}
+
+pub fn generate_duo_state_claims(user_uuid: String, redirect_uri: String) -> DuoStateJWTClaims {
+    let time_now = Utc::now().naive_utc();
+    DuoStateJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::minutes(5)).timestamp(),
+        iss: JWT_DUO_STATE_ISSUER.to_string(),
+        sub: user_uuid,
+        nonce: BASE64URL_NOPAD.encode(&crate::crypto::get_random(vec![0u8; 16])),
+        redirect_uri,
+    }
+}
+
+/// The state of an SSO login, the identity provider sends it back along with its code
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SsoStateJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject, the state of the Bitwarden client
+    pub sub: String,
+
+    // The provider includes it in the ID token, so the token can't come from another login
+    pub nonce: String,
+    // Where the client gets the code, checked again when it's exchanged
+    pub redirect_uri: String,
+    // The PKCE challenge of the client
+    pub code_challenge: String,
+}
+
+pub fn generate_sso_state_claims(
+    client_state: String,
+    redirect_uri: String,
+    code_challenge: String,
+) -> SsoStateJWTClaims {
+    let time_now = Utc::now().naive_utc();
+    SsoStateJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::minutes(10)).timestamp(),
+        iss: JWT_SSO_STATE_ISSUER.to_string(),
+        sub: client_state,
+        nonce: BASE64URL_NOPAD.encode(&crate::crypto::get_random(vec![0u8; 16])),
+        redirect_uri,
+        code_challenge,
+    }
+}
+
+/// The authorization code given to the Bitwarden client once the identity provider identified the user
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SsoCodeJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject, the user
+    pub sub: String,
+
+    // Identifies the code, it can only be used for one login
+    pub nonce: String,
+    pub redirect_uri: String,
+    pub code_challenge: String,
+}
+
+pub fn generate_sso_code_claims(user_uuid: String, state: SsoStateJWTClaims) -> SsoCodeJWTClaims {
+    let time_now = Utc::now().naive_utc();
+    SsoCodeJWTClaims {
+        nbf: time_now.timestamp(),
+        // Long enough for the second factor, the client sends the code again along with it
+        exp: (time_now + Duration::minutes(5)).timestamp(),
+        iss: JWT_SSO_CODE_ISSUER.to_string(),
+        sub: user_uuid,
+        nonce: BASE64URL_NOPAD.encode(&crate::crypto::get_random(vec![0u8; 16])),
+        redirect_uri: state.redirect_uri,
+        code_challenge: state.code_challenge,
+    }
+}
+
+//
+// Server identity
+//
+use data_encoding::{BASE64, BASE64URL_NOPAD};
+use serde_json::Value;
+
+lazy_static! {
+    /// Random identifier of this installation, generated on the first start and kept in the data folder
+    pub static ref INSTALLATION_ID: String = load_installation_id();
+}
+
+fn load_installation_id() -> String {
+    let path = format!("{}/installation_id", CONFIG.data_folder());
+
+    if let Ok(id) = crate::util::read_file_string(&path) {
+        let id = id.trim();
+        if !id.is_empty() {
+            return id.to_string();
+        }
+    }
+
+    let id = crate::util::get_uuid();
+    if let Err(e) = std::fs::write(&path, &id) {
+        error!("Error saving the installation id to {}: {:#?}", path, e);
+    }
+    id
+}
+
+/// JSON Web Key of the public key used to sign the tokens
+pub fn public_jwk() -> Value {
+    let (n, e) = crate::crypto::rsa_public_key_components(&PUBLIC_RSA_KEY).expect("Invalid public RSA Key");
+
+    json!({
+        "kty": "RSA",
+        "use": "sig",
+        "alg": "RS256",
+        "kid": *PUBLIC_KEY_ID,
+        "n": BASE64URL_NOPAD.encode(n),
+        "e": BASE64URL_NOPAD.encode(e),
+    })
+}
+
+/// Public key in PKCS#1 DER format, encoded in base64
+pub fn public_key_base64() -> String {
+    BASE64.encode(&PUBLIC_RSA_KEY)
+}
+
 //
 // Bearer token authentication
 //
 use rocket::request::{self, FromRequest, Request};
 use rocket::Outcome;
 
-use crate::db::models::{Device, User, UserOrgStatus, UserOrgType, UserOrganization};
+use crate::db::models::{
+    Device, SecurityNotice, SecurityNoticeType, User, UserOrgStatus, UserOrgType, UserOrganization,
+};
 use crate::db::DbConn;
+use crate::lockout::ApiRateLimitStatus;
 
 pub struct Headers {
     pub host: String,
@@ -220,6 +513,11 @@ impl<'a, 'r> FromRequest<'a, 'r> for Headers {
             Err(_) => err_handler!("Invalid claim"),
         };
 
+        let api_key = claims.is_api_key();
+        if api_key && !api_key_allowed(request) {
+            err_handler!("This action is not allowed with an API key, log in with the master password")
+        }
+
         let device_uuid = claims.device;
         let user_uuid = claims.sub;
 
@@ -238,14 +536,77 @@ impl<'a, 'r> FromRequest<'a, 'r> for Headers {
             None => err_handler!("Device has no user associated"),
         };
 
+        request.local_cache(|| UserLocale(user.culture.as_ref().and_then(|c| crate::util::find_locale(c))));
+
+        if !user.enabled {
+            err_handler!("This account is disabled")
+        }
+
         if user.security_stamp != claims.sstamp {
+            if let Some(ip) = request.client_ip() {
+                notify_session_terminated(&user, &device, "The account security stamp changed", &ip, &conn);
+            }
             err_handler!("Invalid security stamp")
         }
 
+        // A revoked or rotated API key stops working right away, not only once its tokens expire
+        if api_key && (claims.api_key_id.is_none() || claims.api_key_id != user.api_key_id()) {
+            err_handler!("The API key was revoked or rotated")
+        }
+
+        // Counted once per request, even when the guard is used by the other guards
+        let ratelimit = request.local_cache(|| ApiRateLimitStatus(crate::lockout::register_api_request(&user.uuid)));
+        if let Some(ref ratelimit) = ratelimit.0 {
+            if ratelimit.exceeded {
+                warn!("User {} exceeded the API rate limit", user.email);
+                return Outcome::Failure((
+                    rocket::http::Status::TooManyRequests,
+                    "Too many requests, try again later",
+                ));
+            }
+        }
+
         Outcome::Success(Headers { host, device, user })
     }
 }
 
+/// The locale chosen by the authenticated user, cached by the `Headers` guard for the error messages
+struct UserLocale(Option<&'static str>);
+
+/// The locale of the error messages: the one chosen by the authenticated user, then the one of the
+/// Accept-Language header, then the default one
+pub fn request_locale(request: &Request) -> &'static str {
+    request
+        .local_cache(|| UserLocale(None))
+        .0
+        .or_else(|| {
+            request
+                .headers()
+                .get_one("Accept-Language")
+                .and_then(crate::util::locale_from_accept_language)
+        })
+        .unwrap_or(crate::util::DEFAULT_LOCALE)
+}
+
+fn api_key_allowed(request: &Request) -> bool {
+    let segments: Vec<&str> = request.uri().segments().collect();
+    api_key_allowed_path(request.method(), &segments)
+}
+
+/// The API keys only give access to the vault, the account security and the devices need a login with the master password.
+/// The path is compared by segments, the same way the routes are matched, so that the empty ones can't get around it
+fn api_key_allowed_path(method: rocket::http::Method, segments: &[&str]) -> bool {
+    const RESTRICTED_SECTIONS: &[&str] = &["accounts", "two-factor", "emergency-access", "devices"];
+
+    let section = match (segments.get(0), segments.get(1)) {
+        (Some(&"api"), Some(section)) if RESTRICTED_SECTIONS.contains(section) => *section,
+        _ => return true,
+    };
+
+    // The profile and the rest of the account information can still be read
+    method == rocket::http::Method::Get && section == "accounts"
+}
+
 pub struct OrgHeaders {
     pub host: String,
     pub device: Device,
@@ -316,8 +677,12 @@ impl<'a, 'r> FromRequest<'a, 'r> for AdminHeaders {
         match request.guard::<OrgHeaders>() {
             Outcome::Forward(_) => Outcome::Forward(()),
             Outcome::Failure(f) => Outcome::Failure(f),
-            Outcome::Success(headers) => {
+            Outcome::Success(mut headers) => {
                 if headers.org_user_type >= UserOrgType::Admin {
+                    if let Err(msg) = check_admin_2fa(&mut headers.user, request) {
+                        err_handler!(msg)
+                    }
+
                     Outcome::Success(Self {
                         host: headers.host,
                         device: headers.device,
@@ -332,6 +697,40 @@ impl<'a, 'r> FromRequest<'a, 'r> for AdminHeaders {
     }
 }
 
+/// With `REQUIRE_2FA_ORG_ADMINS`, blocks the organization owners and admins without a second factor
+/// once their enrollment grace period is over. The period starts the first time it applies to them
+fn check_admin_2fa(user: &mut User, request: &Request) -> Result<(), &'static str> {
+    use crate::db::models::TwoFactor;
+
+    if !CONFIG.require_2fa_org_admins() {
+        return Ok(());
+    }
+
+    let conn = match request.guard::<DbConn>() {
+        Outcome::Success(conn) => conn,
+        _ => return Err("Error getting DB"),
+    };
+
+    if !TwoFactor::find_by_user(&user.uuid, &conn).is_empty() {
+        return Ok(());
+    }
+
+    // The other requirements are only known to the login, they are kept as they were
+    let now = Utc::now().naive_utc();
+    let reasons = user.twofactor_enroll_reasons | User::TWOFACTOR_ENROLL_ORG_ADMINS;
+    if user.update_twofactor_enroll_deadline(reasons, now) {
+        user.save(&conn)
+            .map_err(|_| "Error saving the two-step login enrollment deadline")?;
+    }
+
+    match user.twofactor_enroll_deadline {
+        Some(deadline) if now > deadline => {
+            Err("Organization owners and admins need to enable two-step login to manage the organization")
+        }
+        _ => Ok(()),
+    }
+}
+
 pub struct OwnerHeaders {
     pub host: String,
     pub device: Device,
@@ -345,8 +744,12 @@ impl<'a, 'r> FromRequest<'a, 'r> for OwnerHeaders {
         match request.guard::<OrgHeaders>() {
             Outcome::Forward(_) => Outcome::Forward(()),
             Outcome::Failure(f) => Outcome::Failure(f),
-            Outcome::Success(headers) => {
+            Outcome::Success(mut headers) => {
                 if headers.org_user_type == UserOrgType::Owner {
+                    if let Err(msg) = check_admin_2fa(&mut headers.user, request) {
+                        err_handler!(msg)
+                    }
+
                     Outcome::Success(Self {
                         host: headers.host,
                         device: headers.device,
@@ -381,3 +784,180 @@ impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
         Outcome::Success(ClientIp { ip })
     }
 }
+
+/// Checks the `METRICS_TOKEN` sent as a bearer token, the metrics aren't found while they are disabled
+pub struct MetricsToken {}
+
+impl<'a, 'r> FromRequest<'a, 'r> for MetricsToken {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let token = match CONFIG.metrics_token() {
+            Some(token) if CONFIG.metrics_enabled() => token,
+            _ => return Outcome::Failure((rocket::http::Status::NotFound, "The metrics are disabled")),
+        };
+
+        let provided = match request.headers().get_one("Authorization") {
+            Some(a) if a.starts_with("Bearer ") => &a["Bearer ".len()..],
+            _ => err_handler!("No metrics token provided"),
+        };
+
+        if !crate::crypto::ct_eq(provided, &token) {
+            err_handler!("Invalid metrics token")
+        }
+
+        Outcome::Success(MetricsToken {})
+    }
+}
+
+/// Locale requested by the client with the Accept-Language header, or the default one
+pub struct ClientLocale {
+    pub locale: &'static str,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientLocale {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let locale = request
+            .headers()
+            .get_one("Accept-Language")
+            .and_then(crate::util::locale_from_accept_language)
+            .unwrap_or(crate::util::DEFAULT_LOCALE);
+
+        Outcome::Success(ClientLocale { locale })
+    }
+}
+
+//
+// Session termination notifications
+//
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    // Device uuid and reason of the last notifications sent, to avoid sending the same email repeatedly
+    static ref SESSION_TERMINATION_NOTIFIED: Mutex<HashMap<String, NaiveDateTime>> = Mutex::new(HashMap::new());
+}
+
+/// Notifies the user that a session was rejected, with a security notice and by email.
+/// This is best effort, errors are only logged
+pub fn notify_session_terminated(user: &User, device: &Device, reason: &str, ip: &IpAddr, conn: &DbConn) {
+    let now = Utc::now().naive_utc();
+    let key = format!("{}|{}", device.uuid, reason);
+    {
+        let mut notified = match SESSION_TERMINATION_NOTIFIED.lock() {
+            Ok(notified) => notified,
+            Err(_) => return,
+        };
+
+        // Forget the old entries so the map doesn't keep growing
+        notified.retain(|_, sent| now - *sent < Duration::days(1));
+
+        if notified.contains_key(&key) {
+            return;
+        }
+        notified.insert(key, now);
+    }
+
+    SecurityNotice::new(
+        user.uuid.clone(),
+        SecurityNoticeType::SessionTerminated,
+        json!({
+            "DeviceName": device.name,
+            "Reason": reason,
+            "IpAddress": ip.to_string(),
+        }),
+    )
+    .save_or_log(conn);
+
+    if !CONFIG.notify_on_session_termination() || !CONFIG.mail_enabled() {
+        return;
+    }
+
+    // Sent in the background, so the request guard doesn't wait for the SMTP server
+    let email = user.email.clone();
+    let locale = user.locale(crate::util::DEFAULT_LOCALE).to_string();
+    let ip = ip.to_string();
+    let device_name = device.name.clone();
+    let reason = reason.to_string();
+    std::thread::spawn(move || {
+        if let Err(e) = crate::mail::send_session_terminated(&email, &ip, &now, &device_name, &reason, &locale) {
+            error!("Error sending session termination email: {:#?}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Method;
+
+    #[test]
+    fn api_key_can_use_the_vault() {
+        assert!(api_key_allowed_path(Method::Get, &["api", "sync"]));
+        assert!(api_key_allowed_path(Method::Post, &["api", "ciphers"]));
+        assert!(api_key_allowed_path(Method::Put, &["api", "folders", "some-id"]));
+    }
+
+    #[test]
+    fn api_key_can_read_the_account() {
+        assert!(api_key_allowed_path(Method::Get, &["api", "accounts", "profile"]));
+        assert!(!api_key_allowed_path(Method::Post, &["api", "accounts", "password"]));
+        assert!(!api_key_allowed_path(Method::Post, &["api", "accounts", "api-key"]));
+    }
+
+    #[test]
+    fn api_key_can_not_manage_the_account_security() {
+        assert!(!api_key_allowed_path(Method::Get, &["api", "two-factor"]));
+        assert!(!api_key_allowed_path(Method::Post, &["api", "emergency-access", "invite"]));
+        assert!(!api_key_allowed_path(Method::Post, &["api", "devices", "revoke-others"]));
+    }
+
+    #[test]
+    fn api_key_sections_are_whole_segments() {
+        assert!(api_key_allowed_path(Method::Post, &["api", "accountsx"]));
+        assert!(api_key_allowed_path(Method::Post, &["identity", "accounts"]));
+    }
+
+    const TEST_ISSUER: &str = "https://vault.example.com|login";
+    const TEST_SECRET: &[u8] = b"test secret";
+
+    fn token(nbf: i64, exp: i64) -> String {
+        let claims = json!({ "nbf": nbf, "exp": exp, "iss": TEST_ISSUER });
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, TEST_SECRET).unwrap()
+    }
+
+    fn decode(token: &str) -> Result<Value, Error> {
+        decode_jwt_with_key(token, TEST_SECRET, Algorithm::HS256, TEST_ISSUER.to_string())
+    }
+
+    #[test]
+    fn short_tokens_are_rejected_after_they_expire() {
+        let now = Utc::now().timestamp();
+
+        // Minted with a 1 minute lifetime, which only gets 30 seconds of leeway
+        assert!(decode(&token(now - 30, now + 30)).is_ok());
+        assert!(decode(&token(now - 80, now - 20)).is_ok());
+        assert!(decode(&token(now - 100, now - 40)).is_err());
+    }
+
+    #[test]
+    fn long_tokens_get_the_whole_leeway() {
+        let now = Utc::now().timestamp();
+        let skew = CONFIG.token_clock_skew_seconds() as i64;
+
+        assert!(decode(&token(now - 7200, now - skew + 5)).is_ok());
+        assert!(decode(&token(now - 7200, now - skew - 5)).is_err());
+    }
+
+    #[test]
+    fn tokens_of_other_issuers_are_rejected() {
+        let now = Utc::now().timestamp();
+        let claims = json!({ "nbf": now, "exp": now + 60, "iss": "https://vault.example.com|invite" });
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, TEST_SECRET).unwrap();
+
+        assert!(decode(&token).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//
+// Verification of the hCaptcha and Cloudflare Turnstile responses, required from the IPs with repeated failed logins
+//
+use std::time::Duration;
+
+use reqwest::Client;
+use serde_json::Value;
+
+use crate::error::{Error, ErrorCode};
+use crate::CONFIG;
+
+lazy_static! {
+    static ref CLIENT: Client = Client::builder()
+        .use_sys_proxy()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+}
+
+/// The providers supported by `CAPTCHA_PROVIDER`
+pub const PROVIDERS: &[&str] = &["hcaptcha", "turnstile"];
+
+fn verify_url(provider: &str) -> &'static str {
+    match provider {
+        "turnstile" => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+        _ => "https://hcaptcha.com/siteverify",
+    }
+}
+
+/// The error telling the client to show the captcha, the official clients only know about hCaptcha
+pub fn required_error(usr_msg: &str, log_msg: String) -> Error {
+    warn!("{}", log_msg);
+
+    Error::from(json!({
+        "error": "invalid_grant",
+        "error_description": usr_msg,
+        "HCaptcha_SiteKey": CONFIG.captcha_site_key(),
+        "CaptchaProvider": CONFIG.captcha_provider(),
+        "CaptchaSiteKey": CONFIG.captcha_site_key(),
+        "ErrorModel": {
+            "Message": usr_msg,
+            "Object": "error"
+        }
+    }))
+    .with_api_code(ErrorCode::CaptchaRequired)
+}
+
+/// Checks the response of the captcha with the provider. Fails when the provider can't be reached,
+/// so the logins that need a captcha aren't accepted without one
+pub fn verify(response: &str, ip: &str) -> Result<bool, Error> {
+    let (provider, secret) = match (CONFIG.captcha_provider(), CONFIG.captcha_secret()) {
+        (Some(provider), Some(secret)) => (provider, secret),
+        _ => err!("The captcha is not configured"),
+    };
+
+    let mut params = vec![("secret", secret.as_str()), ("response", response), ("remoteip", ip)];
+    let site_key = CONFIG.captcha_site_key();
+    if let Some(ref site_key) = site_key {
+        params.push(("sitekey", site_key.as_str()));
+    }
+
+    let result: Value = CLIENT
+        .post(verify_url(&provider))
+        .form(&params)
+        .send()?
+        .error_for_status()?
+        .json()?;
+
+    Ok(result["success"].as_bool().unwrap_or(false))
+}
@@ -212,6 +212,8 @@ make_config! {
         templates_folder:       String, false,  auto,   |c| format!("{}/{}", c.data_folder, "templates");
         /// Session JWT key
         rsa_key_filename:       String, false,  auto,   |c| format!("{}/{}", c.data_folder, "rsa_key");
+        /// Server secret key, the server derives the keys of its own secrets from it, like the hashes of the email codes
+        server_secret_key_filename: String, false, auto, |c| format!("{}/{}", c.data_folder, "server_secret.key");
         /// Web vault folder
         web_vault_folder:       String, false,  def,    "web-vault/".to_string();
     },
@@ -222,6 +224,10 @@ make_config! {
         websocket_address:      String, false,  def,    "0.0.0.0".to_string();
         /// Websocket port
         websocket_port:         u16,    false,  def,    3012;
+        /// Max websocket connections per user, 0 for no limit
+        websocket_max_connections_per_user: u64, false, def, 20;
+        /// What to do with a new websocket connection over the limit, "reject" it or "close_oldest" to make room for it
+        websocket_limit_mode:   String, false,  def,    "reject".to_string();
     },
 
     /// General settings
@@ -243,14 +249,95 @@ make_config! {
         disable_icon_download:  bool,   true,   def,    false;
         /// Allow new signups |> Controls if new users can register. Note that while this is disabled, users could still be invited
         signups_allowed:        bool,   true,   def,    true;
+        /// Allowed signup domains |> Comma separated list of email domains that can sign up, empty to allow any.
+        /// Invited users can always register
+        signups_domains_whitelist: String, true, def,   String::new();
+        /// Max users |> Maximum number of accounts that can sign up, 0 for no limit. Invited users can always register
+        signups_max_users:      i64,    true,   def,    0;
         /// Allow invitations |> Controls whether users can be invited by organization admins, even when signups are disabled
         invitations_allowed:    bool,   true,   def,    true;
+        /// Enforce collection delete permission |> Only let members delete the ciphers of a collection if they were allowed to delete in it,
+        /// not just edit. Members can delete by default, it can be disabled per collection through the API
+        collection_delete_permission: bool, true, def,  true;
+        /// Default collections |> Comma separated list of collections to create along with every new organization,
+        /// {org} is replaced by the organization name. Empty to only create the one chosen by the user
+        org_default_collections: String, true,  def,    String::new();
+        /// Allow Sends |> Controls whether users can create and share Sends. While disabled, the existing Sends can't be accessed
+        /// by their recipients, but their owners can still see and delete them. Organizations can also disable them for their members with a policy
+        sends_allowed:          bool,   true,   def,    true;
+        /// Cipher history depth |> Number of previous versions kept for every cipher when it's updated, 0 to disable.
+        /// The versions are stored encrypted like the ciphers themselves, and can be listed and restored through the API
+        cipher_history_depth:   i64,    true,   def,    0;
+        /// Validate URI match detection |> Reject the logins with an unknown match detection in their URIs. The URIs are encrypted by the clients,
+        /// so the regular expressions can only be checked when they are sent in plain text, as some API integrations do
+        validate_uri_match:     bool,   true,   def,    true;
+        /// Default vault timeout |> Minutes of inactivity after which the clients that haven't configured their own timeout lock the vault.
+        /// It's only a default applied by the clients, the vault timeout policy of an organization takes precedence. Empty to not set one
+        vault_timeout_minutes:  i64,    true,   option;
+        /// Default vault timeout action |> What the clients do when the default vault timeout expires, "lock" or "logOut"
+        vault_timeout_action:   String, true,   def,    "lock".to_string();
+        /// Max attachments per cipher |> Number of attachments a cipher can have, 0 for no limit
+        max_attachments_per_cipher: i64, true,  def,    100;
+        /// Allow magic link login |> Users can request a single-use login link by email. It only gives access to the account:
+        /// the vault is still encrypted with the master password, which is needed to unlock it. Requires SMTP
+        magic_link_enabled:     bool,   true,   def,    false;
+        /// Magic link validity |> Number of minutes a magic link can be used for
+        magic_link_ttl_minutes: i64,    true,   def,    15;
+        /// Bind magic links to the IP |> A magic link can only be used from the IP address it was requested from
+        magic_link_bind_ip:     bool,   true,   def,    false;
+        /// Key Connector URL |> URL of the Key Connector used by the organizations that require single sign-on. Their members can store
+        /// their key there instead of deriving it from a master password. Empty to disable it
+        key_connector_url:      String, true,   option;
         /// Password iterations |> Number of server-side passwords hashing iterations.
         /// The changes only apply when a user changes their password. Not recommended to lower the value
         password_iterations:    i32,    true,   def,    100_000;
+        /// Password algorithm |> Server-side hash of the new and changed passwords, "pbkdf2" or "argon2id". The existing hashes keep working
+        password_algorithm:     String, true,   def,    "pbkdf2".to_string();
+        /// Upgrade the password hashes |> With Argon2id, hash again the PBKDF2 passwords of the users logging in,
+        /// instead of waiting for them to change it. The Argon2id hashes are never changed back to PBKDF2
+        password_algorithm_upgrade: bool, true, def,    true;
+        /// Argon2id memory |> KiB of memory used to hash each password with Argon2id
+        argon2_memory_kib:      u32,    true,   def,    65_536;
+        /// Argon2id iterations
+        argon2_iterations:      u32,    true,   def,    3;
+        /// Argon2id parallelism
+        argon2_parallelism:     u32,    true,   def,    4;
+        /// Minimum client KDF iterations |> The users with fewer client-side PBKDF2 iterations, or with fewer server-side ones than the
+        /// setting above, get a hint to update their KDF settings when they log in. The login isn't blocked
+        client_kdf_iter_minimum: i32,   true,   def,    5_000;
         /// Show password hints |> Controls if the password hint should be shown directly in the web page.
         /// Otherwise, if email is disabled, there is no way to see the password hint
         show_password_hint:     bool,   true,   def,    true;
+        /// Disable email recovery |> Turns off the password hints and the magic link login, leaving no way to get into an account
+        /// by email. The two-step login recovery codes keep working, as they don't depend on the email
+        disable_email_recovery: bool,   true,   def,    false;
+
+        /// Require two-factor for all users |> Users without a second factor will be asked to enroll one when they log in,
+        /// and once the grace period ends they won't be able to log in until one is enrolled
+        require_2fa:            bool,   true,   def,    false;
+        /// Two-factor enrollment grace period |> Number of days a user can keep logging in without a second factor when this server requires it
+        require_2fa_grace_days: i64,    true,   def,    7;
+        /// Require two-factor for organization owners and admins |> Only users with a second factor can become owners or admins.
+        /// The existing ones without it can't manage their organizations once the grace period ends, until they enroll one
+        require_2fa_org_admins: bool,   true,   def,    false;
+        /// Require device approval |> The new devices of a user can't log in until they are approved from one of their other devices,
+        /// or with the link sent by email. The first device of every user is approved automatically
+        device_approval_required: bool, true,   def,    false;
+        /// Device approval expiration (hours) |> How long the new devices wait for an approval, from an admin of the organization with the
+        /// admin device approval policy, or from the user with `DEVICE_APPROVAL_REQUIRED`. The expired ones are deleted, and the device needs to log in again
+        admin_device_approval_expiration_hours: i64, true, def, 72;
+        /// Require the master password strength |> With a master password policy, reject the new master passwords when the client
+        /// doesn't report their strength. The official clients check the policies themselves, but don't report it, so enabling
+        /// this rejects their password changes, and leaving it disabled means only they check it
+        master_password_strength_required: bool, true, def, false;
+        /// Max two-factor registrations |> Number of second factors a user can register, counting every security key and YubiKey. 0 for no limit
+        max_twofactor_factors:  u64,    true,   def,    20;
+        /// Trusted IP ranges |> Lets the users skip the two-step login from IP ranges they trust, like their office network. Anyone on those
+        /// networks with the master password can log in. The IP comes from the X-Real-IP header, only enable it behind a trusted proxy that always
+        /// sets it, otherwise the clients can spoof it. It doesn't apply to the magic links. Organizations can forbid it with a policy
+        trusted_ip_ranges_enabled: bool, true,  def,    false;
+        /// Email two-step login code validity |> Number of minutes the codes sent by email for the two-step login can be used for. Requires SMTP
+        email_2fa_code_minutes: i64,    true,   def,    10;
 
         /// Admin page token |> The token used to authenticate in this very same page. Changing it here won't deauthorize the current session
         admin_token:            Pass,   true,   option;
@@ -271,11 +358,97 @@ make_config! {
         /// Disable Two-Factor remember |> Enabling this would force the users to use a second factor to login every time.
         /// Note that the checkbox would still be present, but ignored.
         disable_2fa_remember:   bool,   true,   def,    false;
+        /// Two-Factor remember expiration |> Number of days a remembered second factor lasts, using it doesn't extend it. 0 to never expire
+        twofactor_remember_days: i64,   true,   def,    30;
+        /// Cleanup interval |> Number of seconds between the removals of expired data, like the remembered second factors. 0 to disable it
+        cleanup_interval_seconds: u64,  true,   def,    3_600;
+        /// Disable inactive accounts |> Number of days without logins after which the accounts are disabled by the cleanup, 0 to never disable them.
+        /// An admin can enable them again
+        inactive_account_disable_days: i64, true, def,  0;
+        /// Inactive account warning |> Number of days before an inactive account is disabled when the user is warned by email
+        inactive_account_warning_days: i64, true, def,  7;
+        /// Event retention |> Number of days the events are kept, the older ones are removed by the cleanup. 0 to keep them forever
+        events_days_retain:     i64,    true,   def,    0;
 
         /// Require new device emails |> When a user logs in an email is required to be sent.
         /// If sending the email fails the login attempt will fail.
         require_device_email:   bool,   true,   def,     false;
 
+        /// Notify on session termination |> Send an email to the user when one of their sessions is rejected by the server,
+        /// for example because the security stamp changed or the refresh token was used from a different IP
+        notify_on_session_termination: bool, true, def, false;
+
+        /// GeoIP database |> Path of a MaxMind DB file, like GeoLite2 Country or ASN. The logins from a new device in a country, or autonomous system,
+        /// where the user hasn't logged in from recently get a stronger email alert. It's read at startup, changing it needs a restart
+        geoip_database_path:    String, true,   option;
+
+        /// Login lockout attempts |> Number of failed logins after which the user, or the IP they come from, is locked out. 0 to disable it.
+        /// The counters are kept in memory, so they are lost on restart. Admins can list and clear the lockouts from the admin API.
+        /// The admin page logins are counted by IP too. Locked out clients get a 429 status with a Retry-After header
+        login_lockout_attempts: u64,    true,   def,    0;
+        /// Login lockout duration |> Number of seconds a lockout lasts, and without failures after which the counters are reset
+        login_lockout_seconds:  u64,    true,   def,    900;
+        /// Login rate limit window |> Number of seconds in which the failed logins of each IP are counted, including the wrong two-step login codes
+        login_ratelimit_seconds:   u64, true,   def,    60;
+        /// Login rate limit burst |> Number of failed logins allowed to each IP in the window, after that it has to wait. 0 to disable it
+        login_ratelimit_max_burst: u64, true,   def,    10;
+        /// API rate limit window |> Number of seconds in which the authenticated API requests of each user are counted
+        api_ratelimit_seconds:     u64, true,   def,    60;
+        /// API rate limit |> Number of authenticated API requests allowed to each user in the window. 0 to disable it.
+        /// The budget is returned in the X-RateLimit-* headers, and the requests over it get a 429 status
+        api_ratelimit_max_requests: u64, true,  def,    0;
+
+        /// Two-factor lockout attempts |> Number of consecutive wrong two-step login codes after which the account is locked,
+        /// and the user is alerted by email. 0 to disable it. Unlike the login lockouts, it's stored in the database
+        twofactor_lockout_attempts: u64, true,  def,    10;
+        /// Two-factor lockout duration |> Number of seconds the account stays locked after too many wrong two-step login codes
+        twofactor_lockout_seconds: u64, true,   def,    900;
+
+        /// Max devices per user |> Number of devices a user can be logged in on at the same time, 0 for no limit
+        max_devices_per_user:   u64,    true,   def,    0;
+        /// Log out the oldest device |> Once a user reaches the limit, a new login logs out the least recently used device.
+        /// Otherwise the new login is rejected until the user logs out from another device
+        max_devices_evict_oldest: bool, true,   def,    true;
+        /// Access token lifetime |> Number of minutes the access tokens are valid for. The clients refresh them more often with the shorter ones,
+        /// but the revoked sessions keep working until then
+        access_token_lifetime_minutes: i64, true, def,  120;
+        /// Refresh token IP binding |> Binds refresh tokens to the IP they were issued to. Valid values are "off", "subnet" and "exact".
+        /// "subnet" allows changes within the same /24 (IPv4) or /64 (IPv6). Clients that roam between networks will need to log in again
+        refresh_token_ip_binding: String, true, def,   "off".to_string();
+        /// Refresh token rotation |> Replace the refresh token every time it's used, so a leaked token stops working once the client uses it.
+        /// Disable it for the integrations that rely on the refresh tokens staying the same
+        refresh_token_rotation: bool, true,     def,    true;
+        /// Refresh token rotation grace |> Number of seconds the replaced refresh token is still accepted, for the clients that refresh concurrently
+        refresh_token_rotation_grace_seconds: u64, true, def, 30;
+        /// Revoke reused refresh tokens |> When a replaced refresh token is used again, revoke all the refresh tokens of the device,
+        /// as one of them was probably stolen. Otherwise the old token is only rejected
+        refresh_token_reuse_revoke: bool, true, def,    true;
+        /// Disabled grant types |> Comma separated list of the login grant types that are rejected, like "password" to only keep the existing sessions
+        /// working. Valid types: password, refresh_token, client_credentials, magic_link, authorization_code. Empty allows all of them
+        disabled_grant_types:   String, true,   def,    String::new();
+        /// Revoke sessions on password change |> When a user changes their master password, KDF or email, the other devices are logged out
+        /// and their refresh tokens stop working. The device that changed it stays logged in
+        revoke_sessions_on_password_change: bool, true, def, false;
+        /// Clock skew leeway |> Number of seconds of difference tolerated when validating the time of tokens and Duo responses.
+        /// The tokens get at most half their lifetime of leeway. A warning is logged every time something is only accepted because of it
+        token_clock_skew_seconds: u64, true,  def,    60;
+        /// TOTP skew steps |> Number of 30 seconds steps before and after the current one where the TOTP codes are accepted, like 1 or 2.
+        /// The clock skew leeway doesn't widen it. A code can only be used once
+        totp_skew_steps:        u64,    true,   def,    1;
+        /// Lowercase token response |> Use snake_case for all the keys of the login response, like `private_key` instead of `PrivateKey`.
+        /// Only for third-party integrations, the official clients expect the default Bitwarden format
+        token_response_lowercase: bool, true,   def,    false;
+        /// Master password hash check |> How the format of the master password hashes sent by the clients is validated. Valid values are "off",
+        /// "lenient" (not empty, reasonable length) and "strict" (base64 of a 32 bytes PBKDF2-SHA256 output)
+        master_password_hash_check: String, true, def, "lenient".to_string();
+        /// Re-prompt device types |> Comma separated list of device types (names or numbers, like "chrome,firefox,9") whose clients are asked
+        /// to prompt for the master password on every access. This is only enforced by the clients
+        reprompt_device_types:  String, true,   def,    String::new();
+        /// Search metadata fields |> Comma separated list of the cipher fields returned by /api/ciphers/search-metadata, to build search indexes.
+        /// Empty disables it. Valid fields: type, folder, favorite, uris, attachments, revisionDate. The URIs are usually encrypted by the clients,
+        /// but they can be sensitive when they aren't, so only include them when needed
+        search_metadata_fields: String, true,   def,    String::new();
+
         /// Reload templates (Dev) |> When this is set to true, the templates get reloaded with every request.
         /// ONLY use this during development, as it can slow down the server
         reload_templates:       bool,   true,   def,    false;
@@ -290,6 +463,9 @@ make_config! {
         log_file:               String, false,  option;
         /// Log level
         log_level:              String, false,  def,    "Info".to_string();
+        /// JSON login logs |> Also log the login attempts, second factors and new devices as single line JSON objects,
+        /// with the event, username, IP, device type, result and two-step provider. Requires extended logging
+        log_format_json:        bool,   false,  def,    false;
 
         /// Enable DB WAL |> Turning this off might lead to worse performance, but might help if using bitwarden_rs on some exotic filesystems,
         /// that do not support WAL. Please make sure you read project wiki on the topic before changing this setting.
@@ -307,7 +483,8 @@ make_config! {
         yubico_client_id:       String, true,   option;
         /// Secret Key
         yubico_secret_key:      Pass,   true,   option;
-        /// Server
+        /// Server |> Comma separated list of the URLs of self-hosted validation servers, instead of YubiCloud.
+        /// The requests are still signed with the client ID and secret key
         yubico_server:          String, true,   option;
     },
 
@@ -315,16 +492,76 @@ make_config! {
     duo: _enable_duo {
         /// Enabled
         _enable_duo:            bool,   true,   def,     false;
-        /// Integration Key
+        /// Client Id (Integration Key)
         duo_ikey:               String, true,   option;
-        /// Secret Key
+        /// Client Secret (Secret Key)
         duo_skey:               Pass,   true,   option;
         /// Host
         duo_host:               String, true,   option;
-        /// Application Key (generated automatically)
+        /// Use the legacy Duo prompt |> Use the deprecated iframe prompt instead of the Universal Prompt. The clients of this version
+        /// don't support the Universal Prompt yet, so it stays the default until they do. Disable it only with clients that support it
+        duo_use_iframe:         bool,   true,   def,     true;
+        /// Application Key (generated automatically) |> Only used by the legacy iframe prompt
         _duo_akey:              Pass,   false,  option;
     },
 
+    /// Login captcha settings
+    captcha: _enable_captcha {
+        /// Enabled
+        _enable_captcha:        bool,   true,   def,     true;
+        /// Provider |> The service verifying the captchas, hcaptcha or turnstile (Cloudflare Turnstile)
+        captcha_provider:       String, true,   option;
+        /// Site key
+        captcha_site_key:       String, true,   option;
+        /// Secret key
+        captcha_secret:         Pass,   true,   option;
+        /// Failure threshold |> Number of failed logins after which the logins from the same IP need a captcha.
+        /// They are forgotten once the IP stops failing for `LOGIN_LOCKOUT_SECONDS`
+        captcha_failure_threshold: u64, true,   def,     3;
+    },
+
+    /// SSO settings
+    sso: _enable_sso {
+        /// Enabled
+        _enable_sso:            bool,   true,   def,     true;
+        /// Issuer URL |> The OpenID Connect issuer of the identity provider, its configuration is read from /.well-known/openid-configuration
+        sso_issuer_url:         String, true,   option;
+        /// Client ID
+        sso_client_id:          String, true,   option;
+        /// Client secret
+        sso_client_secret:      Pass,   true,   option;
+        /// Scopes |> Requested along with openid, the ID token needs to include the email
+        sso_scopes:             String, true,   def,     "email profile".to_string();
+        /// Create the missing accounts |> The users of the provider without an account get one, and choose their master password after the first login.
+        /// Otherwise only the existing and invited users can log in. The signup domain whitelist and maximum number of users still apply.
+        /// The existing accounts, even with a master password, are linked by their email
+        sso_auto_provision:     bool,   true,   def,     false;
+        /// Require a verified email |> Only accept the ID tokens with `email_verified` set to true. Disable it only when the provider
+        /// verifies all the emails but doesn't include the claim, since the email is what links the accounts
+        sso_require_email_verified: bool, true, def,     true;
+    },
+
+    /// Security event webhook settings
+    webhook: _enable_webhook {
+        /// Enabled
+        _enable_webhook:        bool,   true,   def,     true;
+        /// URL |> The security events are sent to this URL as JSON in a POST request. The admin page can send a test event to it
+        event_webhook_url:      String, true,   option;
+        /// Signature secret |> Key of the HMAC-SHA256 signature of the events, sent in the X-Webhook-Signature header. Empty to not sign them
+        event_webhook_secret:   Pass,   true,   option;
+        /// Mask the users |> Only send a masked email of the users in the events, like j***@example.com
+        event_webhook_mask_user: bool,  true,   def,     false;
+    },
+
+    /// Prometheus metrics settings
+    metrics: _enable_metrics {
+        /// Enabled
+        _enable_metrics:        bool,   true,   def,     true;
+        /// Bearer token |> The metrics of the logins are served at /metrics to the requests with this token,
+        /// in the Authorization header. Empty to disable them
+        metrics_token:          Pass,   true,   option;
+    },
+
     /// SMTP Email Settings
     smtp: _enable_smtp {
         /// Enabled
@@ -335,6 +572,11 @@ make_config! {
         smtp_ssl:               bool,   true,   def,     true;
         /// Use explicit TLS |> Enabling this would force the use of an explicit TLS connection, instead of upgrading an insecure one with STARTTLS
         smtp_explicit_tls:      bool,   true,   def,     false;
+        /// Accept invalid certificates |> DANGEROUS: Don't validate the certificate and hostname of the SMTP server.
+        /// This makes the connection vulnerable to man-in-the-middle attacks, prefer providing the CA certificate
+        smtp_accept_invalid_certs: bool, true,  def,     false;
+        /// CA certificate |> Path to a PEM file with a CA certificate to trust when validating the SMTP server, for internal mail relays
+        smtp_ca_cert:           String, true,   option;
         /// Port
         smtp_port:              u16,    true,   auto,    |c| if c.smtp_explicit_tls {465} else if c.smtp_ssl {587} else {25};
         /// From Address
@@ -365,14 +607,134 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!("Both `YUBICO_CLIENT_ID` and `YUBICO_SECRET_KEY` need to be set for Yubikey OTP support")
     }
 
+    match cfg.websocket_limit_mode.as_str() {
+        "reject" | "close_oldest" => {}
+        _ => err!("`WEBSOCKET_LIMIT_MODE` must be one of \"reject\" or \"close_oldest\""),
+    }
+
+    if cfg.inactive_account_disable_days > 0 && cfg.inactive_account_warning_days >= cfg.inactive_account_disable_days {
+        err!("`INACTIVE_ACCOUNT_WARNING_DAYS` must be lower than `INACTIVE_ACCOUNT_DISABLE_DAYS`")
+    }
+
+    if cfg.password_algorithm != "pbkdf2" && cfg.password_algorithm != "argon2id" {
+        err!("`PASSWORD_ALGORITHM` must be one of \"pbkdf2\" or \"argon2id\"")
+    }
+
+    // The minimums of the Argon2 specification, 8 KiB of memory per lane
+    if cfg.argon2_iterations < 1 || cfg.argon2_parallelism < 1 || cfg.argon2_memory_kib < 8 * cfg.argon2_parallelism {
+        err!("`ARGON2_ITERATIONS` and `ARGON2_PARALLELISM` must be at least 1, and `ARGON2_MEMORY_KIB` at least 8 per lane")
+    }
+
+    if cfg.access_token_lifetime_minutes < 1 {
+        err!("`ACCESS_TOKEN_LIFETIME_MINUTES` must be at least 1")
+    }
+
+    if cfg.admin_device_approval_expiration_hours < 1 {
+        err!("`ADMIN_DEVICE_APPROVAL_EXPIRATION_HOURS` must be at least 1")
+    }
+
+    match cfg.refresh_token_ip_binding.as_str() {
+        "off" | "subnet" | "exact" => {}
+        _ => err!("`REFRESH_TOKEN_IP_BINDING` must be one of \"off\", \"subnet\" or \"exact\""),
+    }
+
+    for field in cfg
+        .search_metadata_fields
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+    {
+        if !crate::db::models::Cipher::SEARCH_METADATA_FIELDS.contains(&field) {
+            err!(format!("`SEARCH_METADATA_FIELDS` contains an unknown field: {}", field))
+        }
+    }
+
+    for grant_type in cfg.disabled_grant_types.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        if !crate::api::GRANT_TYPES.contains(&grant_type) {
+            err!(format!("`DISABLED_GRANT_TYPES` contains an unknown grant type: {}", grant_type))
+        }
+    }
+
+    for device_type in cfg.reprompt_device_types.split(',').filter(|t| !t.trim().is_empty()) {
+        if crate::db::models::DeviceType::from_str(device_type).is_none() {
+            err!(format!(
+                "`REPROMPT_DEVICE_TYPES` contains an unknown device type: {}",
+                device_type
+            ))
+        }
+    }
+
+    match cfg.vault_timeout_action.as_str() {
+        "lock" | "logOut" => {}
+        _ => err!("`VAULT_TIMEOUT_ACTION` must be one of \"lock\" or \"logOut\""),
+    }
+
+    if cfg.vault_timeout_minutes.map_or(false, |m| m <= 0) {
+        err!("`VAULT_TIMEOUT_MINUTES` must be positive")
+    }
+
+    match cfg.master_password_hash_check.as_str() {
+        "off" | "lenient" | "strict" => {}
+        _ => err!("`MASTER_PASSWORD_HASH_CHECK` must be one of \"off\", \"lenient\" or \"strict\""),
+    }
+
     if cfg.smtp_host.is_some() == cfg.smtp_from.is_empty() {
         err!("Both `SMTP_HOST` and `SMTP_FROM` need to be set for email support")
     }
 
+    if cfg.magic_link_enabled && cfg.smtp_host.is_none() {
+        err!("`MAGIC_LINK_ENABLED` needs SMTP to be configured to send the links")
+    }
+
+    if let Some(ref url) = cfg.key_connector_url {
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            err!("`KEY_CONNECTOR_URL` must start with http:// or https://")
+        }
+    }
+
+    if (cfg.captcha_provider.is_some() || cfg.captcha_site_key.is_some() || cfg.captcha_secret.is_some())
+        && !(cfg.captcha_provider.is_some() && cfg.captcha_site_key.is_some() && cfg.captcha_secret.is_some())
+    {
+        err!("All captcha options need to be set to enable the login captcha")
+    }
+
+    if let Some(ref provider) = cfg.captcha_provider {
+        if !crate::captcha::PROVIDERS.contains(&provider.as_str()) {
+            err!(format!(
+                "`CAPTCHA_PROVIDER` must be one of: {}",
+                crate::captcha::PROVIDERS.join(", ")
+            ))
+        }
+    }
+
+    if (cfg.sso_issuer_url.is_some() || cfg.sso_client_id.is_some() || cfg.sso_client_secret.is_some())
+        && !(cfg.sso_issuer_url.is_some() && cfg.sso_client_id.is_some() && cfg.sso_client_secret.is_some())
+    {
+        err!("`SSO_ISSUER_URL`, `SSO_CLIENT_ID` and `SSO_CLIENT_SECRET` need to be set to enable SSO")
+    }
+
+    if let Some(ref url) = cfg.sso_issuer_url {
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            err!("`SSO_ISSUER_URL` must start with http:// or https://")
+        }
+    }
+
+    if let Some(ref url) = cfg.event_webhook_url {
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            err!("`EVENT_WEBHOOK_URL` must start with http:// or https://")
+        }
+    }
+
     if cfg.smtp_username.is_some() != cfg.smtp_password.is_some() {
         err!("Both `SMTP_USERNAME` and `SMTP_PASSWORD` need to be set to enable email authentication")
     }
 
+    if let Some(ref path) = cfg.smtp_ca_cert {
+        if let Err(e) = crate::mail::load_ca_cert(path) {
+            err!(format!("`SMTP_CA_CERT` can't be loaded: {}", e))
+        }
+    }
+
     Ok(())
 }
 
@@ -439,6 +801,7 @@ impl Config {
         self.update_config(builder)
     }
 
+    #[cfg(feature = "admin")]
     pub fn delete_user_config(&self) -> Result<(), Error> {
         crate::util::delete_file(&CONFIG_FILE)?;
 
@@ -475,6 +838,32 @@ impl Config {
         inner._enable_smtp && inner.smtp_host.is_some()
     }
 
+    pub fn captcha_enabled(&self) -> bool {
+        let inner = &self.inner.read().unwrap().config;
+        inner._enable_captcha
+            && inner.captcha_provider.is_some()
+            && inner.captcha_site_key.is_some()
+            && inner.captcha_secret.is_some()
+    }
+
+    pub fn sso_enabled(&self) -> bool {
+        let inner = &self.inner.read().unwrap().config;
+        inner._enable_sso
+            && inner.sso_issuer_url.is_some()
+            && inner.sso_client_id.is_some()
+            && inner.sso_client_secret.is_some()
+    }
+
+    pub fn metrics_enabled(&self) -> bool {
+        let inner = &self.inner.read().unwrap().config;
+        inner._enable_metrics && inner.metrics_token.is_some()
+    }
+
+    pub fn webhook_enabled(&self) -> bool {
+        let inner = &self.inner.read().unwrap().config;
+        inner._enable_webhook && inner.event_webhook_url.is_some()
+    }
+
     pub fn get_duo_akey(&self) -> String {
         if let Some(akey) = self._duo_akey() {
             akey
@@ -505,6 +894,16 @@ impl Config {
             hb.render(name, data).map_err(Into::into)
         }
     }
+
+    pub fn has_template(&self, name: &str) -> bool {
+        if CONFIG.reload_templates() {
+            let hb = load_templates(CONFIG.templates_folder().as_ref());
+            hb.get_template(name).is_some()
+        } else {
+            let hb = &CONFIG.inner.read().unwrap().templates;
+            hb.get_template(name).is_some()
+        }
+    }
 }
 
 use handlebars::{
@@ -531,12 +930,25 @@ fn load_templates(path: &str) -> Handlebars {
     }
 
     // First register default templates here
+    reg!("email/admin_device_approval", ".html");
+    reg!("email/device_approval", ".html");
     reg!("email/invite_accepted", ".html");
     reg!("email/invite_confirmed", ".html");
+    reg!("email/magic_link", ".html");
+    reg!("email/membership_revoked", ".html");
     reg!("email/new_device_logged_in", ".html");
+    reg!("email/new_location_logged_in", ".html");
+    reg!("email/inactive_account_warning", ".html");
     reg!("email/pw_hint_none", ".html");
     reg!("email/pw_hint_some", ".html");
     reg!("email/send_org_invite", ".html");
+    reg!("email/session_terminated", ".html");
+    reg!("email/smtp_test", ".html");
+    reg!("email/twofactor_email", ".html");
+    reg!("email/twofactor_lockout", ".html");
+
+    reg!("pages/device_approval");
+    reg!("pages/magic_link");
 
     reg!("admin/base");
     reg!("admin/login");
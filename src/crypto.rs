@@ -22,6 +22,170 @@ pub fn verify_password_hash(secret: &[u8], salt: &[u8], previous: &[u8], iterati
     pbkdf2::verify(DIGEST_ALG, iterations, salt, secret, previous).is_ok()
 }
 
+/// Measures the average time needed to hash a password with the given number of iterations
+pub fn benchmark_password_hash(iterations: u32, rounds: u32) -> std::time::Duration {
+    benchmark(rounds, |secret, salt| {
+        hash_password(secret, salt, iterations);
+    })
+}
+
+fn benchmark(rounds: u32, hash: impl Fn(&[u8], &[u8])) -> std::time::Duration {
+    use std::time::Instant;
+
+    let salt = get_random_64();
+    let secret = get_random(vec![0u8; 32]);
+
+    let start = Instant::now();
+    for _ in 0..rounds {
+        hash(&secret, &salt);
+    }
+    start.elapsed() / rounds
+}
+
+//
+// Argon2id derivation
+//
+
+/// Hashes the secret with Argon2id, in the PHC string format that includes the parameters and the salt
+pub fn hash_password_argon2id(
+    secret: &[u8],
+    salt: &[u8],
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+) -> Vec<u8> {
+    use argon2::{Config, ThreadMode, Variant, Version};
+
+    let config = Config {
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+        mem_cost: memory_kib,
+        time_cost: iterations,
+        lanes: parallelism,
+        thread_mode: ThreadMode::Sequential,
+        secret: &[],
+        ad: &[],
+        hash_length: 32,
+    };
+
+    argon2::hash_encoded(secret, salt, &config)
+        .expect("Error hashing the password with Argon2id")
+        .into_bytes()
+}
+
+/// Verifies the secret with an Argon2id hash in the PHC string format
+pub fn verify_password_argon2id(secret: &[u8], encoded: &[u8]) -> bool {
+    match std::str::from_utf8(encoded) {
+        Ok(encoded) => argon2::verify_encoded(encoded, secret).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+/// Measures the average time needed to hash a password with the given Argon2id parameters
+pub fn benchmark_password_hash_argon2id(
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+    rounds: u32,
+) -> std::time::Duration {
+    benchmark(rounds, |secret, salt| {
+        hash_password_argon2id(secret, salt, memory_kib, iterations, parallelism);
+    })
+}
+
+/// Whether the hash is in the PHC string format of Argon2id, instead of a raw PBKDF2 hash
+pub fn is_argon2id_hash(hash: &[u8]) -> bool {
+    hash.starts_with(b"$argon2id$")
+}
+
+//
+// RSA public keys
+//
+
+/// Extracts the modulus and the public exponent from a PKCS#1 RSAPublicKey in DER format
+pub fn rsa_public_key_components(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    // Returns the contents of the element with the expected tag, and the remaining data after it
+    fn read_element(data: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+        let (&found_tag, data) = data.split_first()?;
+        let (&len, mut data) = data.split_first()?;
+        if found_tag != tag {
+            return None;
+        }
+
+        let len = if len & 0x80 == 0 {
+            len as usize
+        } else {
+            let len_bytes = (len & 0x7f) as usize;
+            if len_bytes == 0 || len_bytes > 4 || data.len() < len_bytes {
+                return None;
+            }
+            let (len, rest) = data.split_at(len_bytes);
+            data = rest;
+            len.iter().fold(0, |acc, &b| (acc << 8) | b as usize)
+        };
+
+        if data.len() < len {
+            return None;
+        }
+        Some(data.split_at(len))
+    }
+
+    // DER integers have a leading zero when the first bit is set, to keep them positive
+    fn unsigned(int: &[u8]) -> &[u8] {
+        match int.split_first() {
+            Some((&0, rest)) if !rest.is_empty() => rest,
+            _ => int,
+        }
+    }
+
+    let (sequence, _) = read_element(der, 0x30)?;
+    let (modulus, rest) = read_element(sequence, 0x02)?;
+    let (exponent, _) = read_element(rest, 0x02)?;
+    Some((unsigned(modulus), unsigned(exponent)))
+}
+
+/// Verifies an RSA PKCS#1 v1.5 SHA-256 signature, with the modulus and the public exponent of the key
+pub fn verify_rs256(modulus: &[u8], exponent: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    use ring::signature::{primitive::verify_rsa, RSA_PKCS1_2048_8192_SHA256};
+    use untrusted::Input;
+
+    verify_rsa(
+        &RSA_PKCS1_2048_8192_SHA256,
+        (Input::from(modulus), Input::from(exponent)),
+        Input::from(message),
+        Input::from(signature),
+    )
+    .is_ok()
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    use data_encoding::HEXLOWER;
+
+    HEXLOWER.encode(&sha256(data))
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA256, data).as_ref().to_vec()
+}
+
+//
+// ECDSA signatures
+//
+
+/// Verifies an ECDSA P-256 SHA-256 signature in ASN.1 format, with the public key as an uncompressed point
+pub fn verify_es256(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    use ring::signature::{self, ECDSA_P256_SHA256_ASN1};
+    use untrusted::Input;
+
+    signature::verify(
+        &ECDSA_P256_SHA256_ASN1,
+        Input::from(public_key),
+        Input::from(message),
+        Input::from(signature),
+    )
+    .is_ok()
+}
+
 //
 // HMAC
 //
@@ -34,6 +198,20 @@ pub fn hmac_sign(key: &str, data: &str) -> String {
     HEXLOWER.encode(signature.as_ref())
 }
 
+pub fn hmac_sign_sha256(key: &str, data: &str) -> String {
+    use data_encoding::HEXLOWER;
+
+    let key = hmac::SigningKey::new(&digest::SHA256, key.as_bytes());
+    let signature = hmac::sign(&key, data.as_bytes());
+
+    HEXLOWER.encode(signature.as_ref())
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = hmac::SigningKey::new(&digest::SHA256, key);
+    hmac::sign(&key, data).as_ref().to_vec()
+}
+
 //
 // Random values
 //
@@ -60,3 +238,35 @@ pub fn ct_eq<T: AsRef<[u8]>, U: AsRef<[u8]>>(a: T, b: U) -> bool {
 
     verify_slices_are_equal(a.as_ref(), b.as_ref()).is_ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The smallest parameters Argon2id accepts, to keep the tests fast
+    fn hash_argon2id(secret: &[u8], salt: &[u8]) -> Vec<u8> {
+        hash_password_argon2id(secret, salt, 8, 1, 1)
+    }
+
+    #[test]
+    fn argon2id_hashes_verify_their_password() {
+        let salt = get_random_64();
+        let hash = hash_argon2id(b"password", &salt);
+
+        assert!(is_argon2id_hash(&hash));
+        assert!(verify_password_argon2id(b"password", &hash));
+        assert!(!verify_password_argon2id(b"other password", &hash));
+    }
+
+    #[test]
+    fn hashes_of_one_algorithm_fail_with_the_other() {
+        let salt = get_random_64();
+        let pbkdf2 = hash_password(b"password", &salt, 1_000);
+        let argon2id = hash_argon2id(b"password", &salt);
+
+        assert!(!is_argon2id_hash(&pbkdf2));
+        assert!(!verify_password_argon2id(b"password", &pbkdf2));
+        assert!(!verify_password_hash(b"password", &salt, &argon2id, 1_000));
+        assert!(verify_password_hash(b"password", &salt, &pbkdf2, 1_000));
+    }
+}
@@ -9,7 +9,9 @@ use rocket::request::{self, FromRequest};
 use rocket::{Outcome, Request, State};
 
 use crate::error::Error;
+#[cfg(feature = "admin")]
 use chrono::prelude::*;
+#[cfg(feature = "admin")]
 use std::process::Command;
 
 use crate::CONFIG;
@@ -46,6 +48,7 @@ pub fn get_connection() -> Result<Connection, ConnectionError> {
 }
 
 /// Creates a back-up of the database using sqlite3
+#[cfg(feature = "admin")]
 pub fn backup_database() -> Result<(), Error> {
     let now: DateTime<Utc> = Utc::now();
     let file_date = now.format("%Y%m%d").to_string();
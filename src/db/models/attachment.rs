@@ -49,7 +49,7 @@ impl Attachment {
     }
 }
 
-use crate::db::schema::attachments;
+use crate::db::schema::{attachments, ciphers};
 use crate::db::DbConn;
 use diesel;
 use diesel::prelude::*;
@@ -77,6 +77,21 @@ impl Attachment {
         Ok(())
     }
 
+    /// Deletes all the attachment records in a single transaction, and then their files
+    pub fn delete_all(attachments: Vec<Self>, conn: &DbConn) -> EmptyResult {
+        let ids: Vec<&str> = attachments.iter().map(|a| a.id.as_str()).collect();
+
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::delete(attachments::table.filter(attachments::id.eq_any(ids))).execute(&**conn)
+        })
+        .map_res("Error deleting attachments")?;
+
+        for attachment in attachments {
+            crate::util::delete_file(&attachment.get_file_path())?;
+        }
+        Ok(())
+    }
+
     pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> EmptyResult {
         for attachment in Attachment::find_by_cipher(&cipher_uuid, &conn) {
             attachment.delete(&conn)?;
@@ -100,6 +115,45 @@ impl Attachment {
             .expect("Error loading attachments")
     }
 
+    /// Attachments of the ciphers directly owned by the user, largest first
+    pub fn find_owned_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        attachments::table
+            .inner_join(ciphers::table)
+            .filter(ciphers::user_uuid.eq(user_uuid))
+            .select(attachments::all_columns)
+            .order(attachments::file_size.desc())
+            .load::<Self>(&**conn)
+            .expect("Error loading attachments")
+    }
+
+    /// Total size in bytes of the attachments of the ciphers directly owned by the user
+    #[cfg(feature = "attachments")]
+    pub fn size_owned_by_user(user_uuid: &str, conn: &DbConn) -> i64 {
+        attachments::table
+            .inner_join(ciphers::table)
+            .filter(ciphers::user_uuid.eq(user_uuid))
+            .select(attachments::file_size)
+            .load::<i32>(&**conn)
+            .expect("Error loading attachments")
+            .into_iter()
+            .map(i64::from)
+            .sum()
+    }
+
+    /// Total size in bytes of the attachments of the organization ciphers. The sizes are added here,
+    /// the type of the SQL sum depends on the backend
+    pub fn size_by_org(org_uuid: &str, conn: &DbConn) -> i64 {
+        attachments::table
+            .inner_join(ciphers::table)
+            .filter(ciphers::organization_uuid.eq(org_uuid))
+            .select(attachments::file_size)
+            .load::<i32>(&**conn)
+            .expect("Error loading attachments")
+            .into_iter()
+            .map(i64::from)
+            .sum()
+    }
+
     pub fn find_by_ciphers(cipher_uuids: Vec<String>, conn: &DbConn) -> Vec<Self> {
         attachments::table
             .filter(attachments::cipher_uuid.eq_any(cipher_uuids))
@@ -2,7 +2,8 @@ use chrono::{NaiveDateTime, Utc};
 use serde_json::Value;
 
 use super::{
-    Attachment, CollectionCipher, FolderCipher, Organization, User, UserOrgStatus, UserOrgType, UserOrganization,
+    Attachment, CipherRevision, CipherTransfer, CollectionCipher, CollectionUser, FolderCipher, Organization, User,
+    UserOrgStatus, UserOrgType, UserOrganization,
 };
 
 #[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
@@ -35,8 +36,25 @@ pub struct Cipher {
     pub password_history: Option<String>,
 }
 
+/// How the clients match the URIs of a login for autofill
+/// https://github.com/bitwarden/server/blob/master/src/Core/Enums/UriMatchType.cs
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+pub enum UriMatchType {
+    Domain = 0,
+    Host = 1,
+    StartsWith = 2,
+    Exact = 3,
+    RegularExpression = 4,
+    Never = 5,
+}
+
 /// Local methods
 impl Cipher {
+    /// The fields that can be returned as search metadata with `SEARCH_METADATA_FIELDS`
+    pub const SEARCH_METADATA_FIELDS: &'static [&'static str] =
+        &["type", "folder", "favorite", "uris", "attachments", "revisionDate"];
+
     pub fn new(atype: i32, name: String) -> Self {
         let now = Utc::now().naive_utc();
 
@@ -61,6 +79,16 @@ impl Cipher {
     }
 }
 
+/// Whether a member can delete a cipher with these permissions, one for each collection the cipher is in,
+/// `None` for the collections they don't have access to
+fn collections_allow_delete(permissions: &[Option<CollectionUser>]) -> bool {
+    !permissions.is_empty()
+        && permissions.iter().all(|permission| match permission {
+            Some(cu) => !cu.read_only && cu.can_delete,
+            None => false,
+        })
+}
+
 use crate::db::schema::*;
 use crate::db::DbConn;
 use diesel;
@@ -126,6 +154,39 @@ impl Cipher {
         json_object
     }
 
+    /// Only the selected `SEARCH_METADATA_FIELDS`, never the name, the notes or the secrets
+    pub fn to_json_search_metadata(&self, fields: &[&str], user_uuid: &str, conn: &DbConn) -> Value {
+        use crate::util::format_date;
+
+        let mut json_object = json!({
+            "Id": self.uuid,
+            "Object": "cipherSearchMetadata",
+        });
+
+        for field in fields {
+            match *field {
+                "type" => json_object["Type"] = json!(self.atype),
+                "folder" => json_object["FolderId"] = json!(self.get_folder_uuid(user_uuid, conn)),
+                "favorite" => json_object["Favorite"] = json!(self.favorite),
+                "attachments" => {
+                    json_object["AttachmentCount"] = json!(Attachment::find_by_cipher(&self.uuid, conn).len())
+                }
+                "revisionDate" => json_object["RevisionDate"] = json!(format_date(&self.updated_at)),
+                "uris" => {
+                    let data: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
+                    let uris: Vec<Value> = match data["Uris"].as_array() {
+                        Some(uris) => uris.iter().map(|u| json!({ "Uri": u["Uri"], "Match": u["Match"] })).collect(),
+                        None => Vec::new(),
+                    };
+                    json_object["Uris"] = json!(uris);
+                }
+                _ => (),
+            }
+        }
+
+        json_object
+    }
+
     pub fn update_users_revision(&self, conn: &DbConn) -> Vec<String> {
         let mut user_uuids = Vec::new();
         match self.user_uuid {
@@ -164,6 +225,8 @@ impl Cipher {
         FolderCipher::delete_all_by_cipher(&self.uuid, &conn)?;
         CollectionCipher::delete_all_by_cipher(&self.uuid, &conn)?;
         Attachment::delete_all_by_cipher(&self.uuid, &conn)?;
+        CipherTransfer::delete_all_by_cipher(&self.uuid, &conn)?;
+        CipherRevision::delete_all_by_cipher(&self.uuid, &conn)?;
 
         diesel::delete(ciphers::table.filter(ciphers::uuid.eq(&self.uuid)))
             .execute(&**conn)
@@ -242,6 +305,39 @@ impl Cipher {
             .is_some()
     }
 
+    /// Like `is_write_accessible_to_user`, but the members without access to all the collections also need
+    /// the permission to delete in every collection the cipher is in, not just in one of them
+    pub fn is_delete_accessible_to_user(&self, user_uuid: &str, conn: &DbConn) -> bool {
+        if !self.is_write_accessible_to_user(user_uuid, conn) {
+            return false;
+        }
+
+        // Only their owner can write to the personal ciphers
+        let org_uuid = match self.organization_uuid {
+            Some(ref org_uuid) => org_uuid,
+            None => return true,
+        };
+
+        match UserOrganization::find_by_user_and_org(user_uuid, org_uuid, conn) {
+            Some(ref uo) if uo.access_all || uo.atype <= UserOrgType::Admin as i32 => return true,
+            Some(_) => {}
+            None => return false,
+        }
+
+        // A database error leaves the list empty, which doesn't allow deleting
+        let collection_uuids: Vec<String> = ciphers_collections::table
+            .filter(ciphers_collections::cipher_uuid.eq(&self.uuid))
+            .select(ciphers_collections::collection_uuid)
+            .load(&**conn)
+            .unwrap_or_default();
+
+        let permissions: Vec<Option<CollectionUser>> = collection_uuids
+            .iter()
+            .map(|collection_uuid| CollectionUser::find_by_collection_and_user(collection_uuid, user_uuid, conn))
+            .collect();
+        collections_allow_delete(&permissions)
+    }
+
     pub fn is_accessible_to_user(&self, user_uuid: &str, conn: &DbConn) -> bool {
         ciphers::table
             .filter(ciphers::uuid.eq(&self.uuid))
@@ -363,3 +459,50 @@ impl Cipher {
         .load::<String>(&**conn).unwrap_or_default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn permission(read_only: bool, can_delete: bool) -> Option<CollectionUser> {
+        Some(CollectionUser {
+            user_uuid: "user".to_string(),
+            collection_uuid: "collection".to_string(),
+            read_only,
+            can_delete,
+        })
+    }
+
+    #[test]
+    fn read_only_member_cant_delete() {
+        assert!(!collections_allow_delete(&[permission(true, true)]));
+    }
+
+    #[test]
+    fn edit_but_not_delete_member_cant_delete() {
+        assert!(!collections_allow_delete(&[permission(false, false)]));
+    }
+
+    #[test]
+    fn delete_member_can_delete() {
+        assert!(collections_allow_delete(&[permission(false, true)]));
+    }
+
+    #[test]
+    fn every_collection_needs_to_allow_deleting() {
+        assert!(!collections_allow_delete(&[
+            permission(false, true),
+            permission(false, false)
+        ]));
+        assert!(!collections_allow_delete(&[permission(false, true), None]));
+        assert!(collections_allow_delete(&[
+            permission(false, true),
+            permission(false, true)
+        ]));
+    }
+
+    #[test]
+    fn cipher_without_collections_cant_be_deleted_by_members() {
+        assert!(!collections_allow_delete(&[]));
+    }
+}
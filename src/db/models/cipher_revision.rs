@@ -0,0 +1,144 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::Cipher;
+
+/// Previous version of a cipher, kept when it's updated. Like the cipher itself, the contents are encrypted
+/// with the key the cipher had at that time, which is the organization key when organization_uuid is set
+#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[table_name = "cipher_revisions"]
+#[belongs_to(Cipher, foreign_key = "cipher_uuid")]
+#[primary_key(uuid)]
+pub struct CipherRevision {
+    pub uuid: String,
+    pub cipher_uuid: String,
+    pub organization_uuid: Option<String>,
+
+    pub atype: i32,
+    pub name: String,
+    pub notes: Option<String>,
+    pub fields: Option<String>,
+    pub data: String,
+    pub password_history: Option<String>,
+
+    /// The date in which this version of the cipher was saved
+    pub revision_date: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+/// Local methods
+impl CipherRevision {
+    pub fn from_cipher(cipher: &Cipher) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            cipher_uuid: cipher.uuid.clone(),
+            organization_uuid: cipher.organization_uuid.clone(),
+
+            atype: cipher.atype,
+            name: cipher.name.clone(),
+            notes: cipher.notes.clone(),
+            fields: cipher.fields.clone(),
+            data: cipher.data.clone(),
+            password_history: cipher.password_history.clone(),
+
+            revision_date: cipher.updated_at,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    /// Replaces the contents of the cipher with the ones of this version
+    pub fn apply_to(&self, cipher: &mut Cipher) {
+        cipher.atype = self.atype;
+        cipher.name = self.name.clone();
+        cipher.notes = self.notes.clone();
+        cipher.fields = self.fields.clone();
+        cipher.data = self.data.clone();
+        cipher.password_history = self.password_history.clone();
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        let parse = |s: &Option<String>| {
+            s.as_ref()
+                .and_then(|s| serde_json::from_str(s).ok())
+                .unwrap_or(Value::Null)
+        };
+        let data: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
+
+        json!({
+            "Id": self.uuid,
+            "CipherId": self.cipher_uuid,
+            "OrganizationId": self.organization_uuid,
+            "Type": self.atype,
+            "Name": self.name,
+            "Notes": self.notes,
+            "Fields": parse(&self.fields),
+            "Data": data,
+            "PasswordHistory": parse(&self.password_history),
+            "RevisionDate": format_date(&self.revision_date),
+            "CreationDate": format_date(&self.created_at),
+            "Object": "cipherRevision",
+        })
+    }
+}
+
+use crate::db::schema::cipher_revisions;
+use crate::db::DbConn;
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl CipherRevision {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        diesel::replace_into(cipher_revisions::table)
+            .values(self)
+            .execute(&**conn)
+            .map_res("Error saving cipher revision")
+    }
+
+    /// Saves the revision and deletes the oldest ones of the cipher, so that only `max_depth` are kept
+    pub fn save_bounded(&self, max_depth: i64, conn: &DbConn) -> EmptyResult {
+        self.save(conn)?;
+
+        let kept: Vec<String> = cipher_revisions::table
+            .filter(cipher_revisions::cipher_uuid.eq(&self.cipher_uuid))
+            .order(cipher_revisions::created_at.desc())
+            .limit(max_depth)
+            .select(cipher_revisions::uuid)
+            .load::<String>(&**conn)
+            .map_res("Error loading cipher revisions")?;
+
+        diesel::delete(
+            cipher_revisions::table
+                .filter(cipher_revisions::cipher_uuid.eq(&self.cipher_uuid))
+                .filter(cipher_revisions::uuid.ne_all(kept)),
+        )
+        .execute(&**conn)
+        .map_res("Error deleting cipher revisions")
+    }
+
+    pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::delete(cipher_revisions::table.filter(cipher_revisions::cipher_uuid.eq(cipher_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting cipher revisions")
+    }
+
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        cipher_revisions::table
+            .filter(cipher_revisions::uuid.eq(uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    pub fn find_by_cipher(cipher_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        cipher_revisions::table
+            .filter(cipher_revisions::cipher_uuid.eq(cipher_uuid))
+            .order(cipher_revisions::created_at.desc())
+            .load::<Self>(&**conn)
+            .expect("Error loading cipher revisions")
+    }
+}
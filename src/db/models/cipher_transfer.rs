@@ -0,0 +1,117 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::{Cipher, User};
+
+/// Pending transfer of a personal cipher to another user, waiting for the recipient to accept it
+#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[table_name = "cipher_transfers"]
+#[belongs_to(Cipher, foreign_key = "cipher_uuid")]
+#[primary_key(uuid)]
+pub struct CipherTransfer {
+    pub uuid: String,
+    pub cipher_uuid: String,
+    pub sender_uuid: String,
+    pub recipient_uuid: String,
+    /// Cipher contents encrypted by the sender for the recipient, opaque to the server
+    pub data: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Local methods
+impl CipherTransfer {
+    pub fn new(cipher_uuid: String, sender_uuid: String, recipient_uuid: String, data: String) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            cipher_uuid,
+            sender_uuid,
+            recipient_uuid,
+            data,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+use crate::db::schema::cipher_transfers;
+use crate::db::DbConn;
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl CipherTransfer {
+    pub fn to_json(&self, conn: &DbConn) -> Value {
+        use super::Attachment;
+        use crate::util::format_date;
+
+        let email = |uuid: &str| User::find_by_uuid(uuid, conn).map(|u| u.email);
+        let attachment_ids: Vec<String> = Attachment::find_by_cipher(&self.cipher_uuid, conn)
+            .into_iter()
+            .map(|a| a.id)
+            .collect();
+
+        json!({
+            "Id": self.uuid,
+            "CipherId": self.cipher_uuid,
+            "SenderId": self.sender_uuid,
+            "SenderEmail": email(&self.sender_uuid),
+            "RecipientId": self.recipient_uuid,
+            "RecipientEmail": email(&self.recipient_uuid),
+            "Data": self.data,
+            "AttachmentIds": attachment_ids,
+            "CreationDate": format_date(&self.created_at),
+            "Object": "cipherTransfer",
+        })
+    }
+
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        diesel::replace_into(cipher_transfers::table)
+            .values(self)
+            .execute(&**conn)
+            .map_res("Error saving cipher transfer")
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        diesel::delete(cipher_transfers::table.filter(cipher_transfers::uuid.eq(self.uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting cipher transfer")
+    }
+
+    pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::delete(cipher_transfers::table.filter(cipher_transfers::cipher_uuid.eq(cipher_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting cipher transfers")
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::delete(
+            cipher_transfers::table.filter(
+                cipher_transfers::sender_uuid
+                    .eq(user_uuid)
+                    .or(cipher_transfers::recipient_uuid.eq(user_uuid)),
+            ),
+        )
+        .execute(&**conn)
+        .map_res("Error deleting cipher transfers")
+    }
+
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        cipher_transfers::table
+            .filter(cipher_transfers::uuid.eq(uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        cipher_transfers::table
+            .filter(
+                cipher_transfers::sender_uuid
+                    .eq(user_uuid)
+                    .or(cipher_transfers::recipient_uuid.eq(user_uuid)),
+            )
+            .load::<Self>(&**conn)
+            .expect("Error loading cipher transfers")
+    }
+}
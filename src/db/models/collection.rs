@@ -121,6 +121,15 @@ impl Collection {
             .expect("Error loading collections")
     }
 
+    pub fn count_by_org(org_uuid: &str, conn: &DbConn) -> i64 {
+        collections::table
+            .filter(collections::org_uuid.eq(org_uuid))
+            .count()
+            .first::<i64>(&**conn)
+            .ok()
+            .unwrap_or(0)
+    }
+
     pub fn find_by_uuid_and_org(uuid: &str, org_uuid: &str, conn: &DbConn) -> Option<Self> {
         collections::table
             .filter(collections::uuid.eq(uuid))
@@ -186,6 +195,8 @@ pub struct CollectionUser {
     pub user_uuid: String,
     pub collection_uuid: String,
     pub read_only: bool,
+    /// Allows deleting the ciphers of the collection, when it's not read only
+    pub can_delete: bool,
 }
 
 /// Database methods
@@ -200,7 +211,13 @@ impl CollectionUser {
             .expect("Error loading users_collections")
     }
 
-    pub fn save(user_uuid: &str, collection_uuid: &str, read_only: bool, conn: &DbConn) -> EmptyResult {
+    pub fn save(
+        user_uuid: &str,
+        collection_uuid: &str,
+        read_only: bool,
+        can_delete: bool,
+        conn: &DbConn,
+    ) -> EmptyResult {
         User::update_uuid_revision(&user_uuid, conn);
 
         diesel::replace_into(users_collections::table)
@@ -208,6 +225,7 @@ impl CollectionUser {
                 users_collections::user_uuid.eq(user_uuid),
                 users_collections::collection_uuid.eq(collection_uuid),
                 users_collections::read_only.eq(read_only),
+                users_collections::can_delete.eq(can_delete),
             ))
             .execute(&**conn)
             .map_res("Error adding user to collection")
@@ -261,6 +279,22 @@ impl CollectionUser {
             .execute(&**conn)
             .map_res("Error removing user from collections")
     }
+
+    pub fn delete_all_by_user_and_org(user_uuid: &str, org_uuid: &str, conn: &DbConn) -> EmptyResult {
+        User::update_uuid_revision(&user_uuid, conn);
+
+        let org_collections = collections::table
+            .filter(collections::org_uuid.eq(org_uuid))
+            .select(collections::uuid);
+
+        diesel::delete(
+            users_collections::table
+                .filter(users_collections::user_uuid.eq(user_uuid))
+                .filter(users_collections::collection_uuid.eq_any(org_collections)),
+        )
+        .execute(&**conn)
+        .map_res("Error removing user from collections")
+    }
 }
 
 use super::Cipher;
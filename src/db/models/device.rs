@@ -1,4 +1,5 @@
 use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
 
 use super::User;
 
@@ -21,6 +22,141 @@ pub struct Device {
     pub refresh_token: String,
 
     pub twofactor_remember: Option<String>,
+
+    /// IP address the refresh token was issued to, used by `REFRESH_TOKEN_IP_BINDING`
+    pub refresh_ip: Option<String>,
+
+    /// Trusted device keys. The user key is encrypted with the public key of the device,
+    /// which is encrypted with the user key, while the private key is encrypted with the device key
+    pub encrypted_user_key: Option<String>,
+    pub encrypted_public_key: Option<String>,
+    pub encrypted_private_key: Option<String>,
+
+    /// With `DEVICE_APPROVAL_REQUIRED`, the new devices can't log in until the user approves them
+    pub approved: bool,
+    /// Secret of a pending device, the link sent by email and the user's other devices approve it with it.
+    /// The device ids are chosen by the clients, so they can't be used for it
+    pub approval_token: Option<String>,
+
+    /// The refresh tokens are rotated on every use, all the ones since the login are in the same family.
+    /// It's the prefix of the tokens, so a rotated out token can be traced back to the device
+    pub refresh_family: Option<String>,
+    pub refresh_generation: i32,
+
+    /// The token replaced by the last rotation, still accepted for `REFRESH_TOKEN_ROTATION_GRACE_SECONDS` after it
+    pub previous_refresh_token: Option<String>,
+    pub refresh_rotated_at: Option<NaiveDateTime>,
+
+    /// IP address of the last login or token refresh, `updated_at` is when it happened
+    pub last_used_ip: Option<String>,
+
+    /// Set when the second factor is remembered from `TWOFACTOR_REMEMBER_DAYS`, using it doesn't extend it
+    pub twofactor_remember_expires_at: Option<NaiveDateTime>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+pub enum DeviceType {
+    Android = 0,
+    Ios = 1,
+    ChromeExtension = 2,
+    FirefoxExtension = 3,
+    OperaExtension = 4,
+    EdgeExtension = 5,
+    WindowsDesktop = 6,
+    MacOsDesktop = 7,
+    LinuxDesktop = 8,
+    ChromeBrowser = 9,
+    FirefoxBrowser = 10,
+    OperaBrowser = 11,
+    EdgeBrowser = 12,
+    IEBrowser = 13,
+    UnknownBrowser = 14,
+    AndroidAmazon = 15,
+    UWP = 16,
+    SafariBrowser = 17,
+    VivaldiBrowser = 18,
+    VivaldiExtension = 19,
+    SafariExtension = 20,
+
+    /// Not an official type, used for the devices that sent a type we don't know
+    Unknown = -1,
+}
+
+impl DeviceType {
+    /// Parses the device_type sent by the clients, which is usually a number,
+    /// but some platforms (like iOS) send their name instead. `Unknown` is never returned
+    pub fn from_str(s: &str) -> Option<Self> {
+        use num_traits::FromPrimitive;
+
+        let s = s.trim();
+        if let Ok(n) = s.parse::<i32>() {
+            return Self::from_i32(n).filter(|t| *t != DeviceType::Unknown);
+        }
+
+        match s.to_lowercase().as_ref() {
+            "android" => Some(DeviceType::Android),
+            "ios" => Some(DeviceType::Ios),
+            "chromeextension" => Some(DeviceType::ChromeExtension),
+            "firefoxextension" => Some(DeviceType::FirefoxExtension),
+            "operaextension" => Some(DeviceType::OperaExtension),
+            "edgeextension" => Some(DeviceType::EdgeExtension),
+            "windowsdesktop" | "windows" => Some(DeviceType::WindowsDesktop),
+            "macosdesktop" | "macos" => Some(DeviceType::MacOsDesktop),
+            "linuxdesktop" | "linux" => Some(DeviceType::LinuxDesktop),
+            "chromebrowser" | "chrome" => Some(DeviceType::ChromeBrowser),
+            "firefoxbrowser" | "firefox" => Some(DeviceType::FirefoxBrowser),
+            "operabrowser" | "opera" => Some(DeviceType::OperaBrowser),
+            "edgebrowser" | "edge" => Some(DeviceType::EdgeBrowser),
+            "iebrowser" | "ie" => Some(DeviceType::IEBrowser),
+            "unknownbrowser" => Some(DeviceType::UnknownBrowser),
+            "androidamazon" => Some(DeviceType::AndroidAmazon),
+            "uwp" => Some(DeviceType::UWP),
+            "safaribrowser" | "safari" => Some(DeviceType::SafariBrowser),
+            "vivaldibrowser" | "vivaldi" => Some(DeviceType::VivaldiBrowser),
+            "vivaldiextension" => Some(DeviceType::VivaldiExtension),
+            "safariextension" => Some(DeviceType::SafariExtension),
+            _ => None,
+        }
+    }
+
+    /// Like `from_str`, but the missing and unrecognized types are `Unknown`
+    pub fn parse(s: &str) -> Self {
+        Self::from_str(s).unwrap_or(DeviceType::Unknown)
+    }
+
+    pub fn from_atype(atype: i32) -> Self {
+        use num_traits::FromPrimitive;
+
+        Self::from_i32(atype).unwrap_or(DeviceType::Unknown)
+    }
+
+    pub fn display_name(self) -> &'static str {
+        match self {
+            DeviceType::Android => "Android",
+            DeviceType::Ios => "iOS",
+            DeviceType::ChromeExtension => "Chrome Extension",
+            DeviceType::FirefoxExtension => "Firefox Extension",
+            DeviceType::OperaExtension => "Opera Extension",
+            DeviceType::EdgeExtension => "Edge Extension",
+            DeviceType::WindowsDesktop => "Windows",
+            DeviceType::MacOsDesktop => "macOS",
+            DeviceType::LinuxDesktop => "Linux",
+            DeviceType::ChromeBrowser => "Chrome",
+            DeviceType::FirefoxBrowser => "Firefox",
+            DeviceType::OperaBrowser => "Opera",
+            DeviceType::EdgeBrowser => "Edge",
+            DeviceType::IEBrowser => "Internet Explorer",
+            DeviceType::UnknownBrowser => "Unknown Browser",
+            DeviceType::AndroidAmazon => "Android (Amazon)",
+            DeviceType::UWP => "Windows (UWP)",
+            DeviceType::SafariBrowser => "Safari",
+            DeviceType::VivaldiBrowser => "Vivaldi",
+            DeviceType::VivaldiExtension => "Vivaldi Extension",
+            DeviceType::SafariExtension => "Safari Extension",
+            DeviceType::Unknown => "Unknown",
+        }
+    }
 }
 
 /// Local methods
@@ -40,10 +176,115 @@ impl Device {
             push_token: None,
             refresh_token: String::new(),
             twofactor_remember: None,
+            refresh_ip: None,
+
+            encrypted_user_key: None,
+            encrypted_public_key: None,
+            encrypted_private_key: None,
+
+            approved: true,
+            approval_token: None,
+
+            refresh_family: None,
+            refresh_generation: 0,
+
+            previous_refresh_token: None,
+            refresh_rotated_at: None,
+
+            last_used_ip: None,
+
+            twofactor_remember_expires_at: None,
         }
     }
 
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "Name": self.name,
+            "Type": self.atype,
+            "TypeName": DeviceType::from_atype(self.atype).display_name(),
+            "Identifier": self.uuid,
+            "CreationDate": format_date(&self.created_at),
+            "LastUsedDate": format_date(&self.updated_at),
+            "LastUsedIp": self.last_used_ip,
+            "Approved": self.approved,
+            "Object": "device",
+        })
+    }
+
+    /// The pending devices visible to the user, identified by their approval token instead of the device id
+    pub fn to_json_pending(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.approval_token,
+            "Name": self.name,
+            "Type": self.atype,
+            "TypeName": DeviceType::from_atype(self.atype).display_name(),
+            "CreationDate": format_date(&self.created_at),
+            "LastUsedIp": self.last_used_ip,
+            "Object": "pendingDevice",
+        })
+    }
+
+    /// The pending devices that weren't approved in time are deleted by the cleanup
+    fn pending_expiration_date() -> NaiveDateTime {
+        Utc::now().naive_utc() - chrono::Duration::hours(crate::CONFIG.admin_device_approval_expiration_hours())
+    }
+
+    /// Makes the device wait for an approval, with a new approval token
+    pub fn hold_for_approval(&mut self) {
+        use data_encoding::BASE64URL_NOPAD;
+
+        self.approved = false;
+        self.approval_token = Some(BASE64URL_NOPAD.encode(&crate::crypto::get_random_64()));
+    }
+
+    pub fn approve(&mut self) {
+        self.approved = true;
+        self.approval_token = None;
+    }
+
+    pub fn is_pending_expired(&self) -> bool {
+        !self.approved && self.created_at < Self::pending_expiration_date()
+    }
+
+    pub fn is_trusted(&self) -> bool {
+        self.encrypted_user_key.is_some() && self.encrypted_public_key.is_some() && self.encrypted_private_key.is_some()
+    }
+
+    /// The device with its trusted device keys, only meant for the device itself
+    pub fn to_json_protected(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "Name": self.name,
+            "Type": self.atype,
+            "Identifier": self.uuid,
+            "CreationDate": format_date(&self.created_at),
+            "EncryptedUserKey": self.encrypted_user_key,
+            "EncryptedPublicKey": self.encrypted_public_key,
+            "Object": "protectedDevice",
+        })
+    }
+
+    /// Remembers the second factor after it was provided, for `TWOFACTOR_REMEMBER_DAYS`
     pub fn refresh_twofactor_remember(&mut self) -> String {
+        let days = crate::CONFIG.twofactor_remember_days();
+        self.twofactor_remember_expires_at = if days > 0 {
+            Some(Utc::now().naive_utc() + chrono::Duration::days(days))
+        } else {
+            None
+        };
+
+        self.rotate_twofactor_remember()
+    }
+
+    /// Replaces the token of the remembered second factor after it was used, keeping its expiration
+    pub fn rotate_twofactor_remember(&mut self) -> String {
         use crate::crypto;
         use data_encoding::BASE64;
 
@@ -55,17 +296,121 @@ impl Device {
 
     pub fn delete_twofactor_remember(&mut self) {
         self.twofactor_remember = None;
+        self.twofactor_remember_expires_at = None;
+    }
+
+    /// Whether the remembered second factor can't be used anymore. The ones remembered before they had an expiration
+    /// are expired, unless `TWOFACTOR_REMEMBER_DAYS` disables it: the last use of the device doesn't tell when they were made
+    pub fn is_twofactor_remember_expired(&self) -> bool {
+        if crate::CONFIG.twofactor_remember_days() <= 0 {
+            return false;
+        }
+
+        self.twofactor_remember_expires_at
+            .map_or(true, |expires_at| expires_at < Utc::now().naive_utc())
+    }
+
+    /// Whether the clients of this type are asked to prompt for the master password on every access, see `REPROMPT_DEVICE_TYPES`
+    pub fn requires_reprompt(&self) -> bool {
+        crate::CONFIG
+            .reprompt_device_types()
+            .split(',')
+            .filter_map(DeviceType::from_str)
+            .any(|t| t as i32 == self.atype)
+    }
+
+    /// Starts a new family of refresh tokens, invalidating the current one, as done on every login
+    pub fn new_refresh_family(&mut self) {
+        use data_encoding::HEXLOWER;
+
+        self.refresh_family = Some(HEXLOWER.encode(&crate::crypto::get_random(vec![0u8; 16])));
+        self.refresh_generation = 0;
+        self.previous_refresh_token = None;
+        self.refresh_rotated_at = None;
+        self.set_new_refresh_token();
+    }
+
+    /// Replaces the refresh token with the next one of its family. The tokens from before the rotation
+    /// don't have a family yet, so they start one
+    pub fn rotate_refresh_token(&mut self) {
+        if self.refresh_family.is_none() {
+            self.new_refresh_family();
+            return;
+        }
+
+        self.refresh_generation += 1;
+        self.previous_refresh_token = Some(self.refresh_token.clone());
+        self.refresh_rotated_at = Some(Utc::now().naive_utc());
+        self.set_new_refresh_token();
+    }
+
+    /// Whether the token replaced by the last rotation can still be used, because it was replaced moments ago.
+    /// The clients that refresh concurrently, like several tabs of the web vault, get the current token instead
+    pub fn in_rotation_grace(&self) -> bool {
+        let grace = chrono::Duration::seconds(crate::CONFIG.refresh_token_rotation_grace_seconds() as i64);
+        match self.refresh_rotated_at {
+            Some(rotated_at) => Utc::now().naive_utc() - rotated_at <= grace,
+            None => false,
+        }
+    }
+
+    /// Invalidates all the refresh tokens of the family, the device needs to log in again
+    pub fn revoke_refresh_tokens(&mut self) {
+        self.refresh_token = String::new();
+        self.refresh_family = None;
+        self.refresh_generation = 0;
+        self.previous_refresh_token = None;
+        self.refresh_rotated_at = None;
+    }
+
+    fn set_new_refresh_token(&mut self) {
+        use data_encoding::BASE64URL_NOPAD;
+
+        let random = BASE64URL_NOPAD.encode(&crate::crypto::get_random_64());
+        self.refresh_token = match self.refresh_family {
+            Some(ref family) => format!("{}.{}", family, random),
+            None => random,
+        };
+    }
+
+    /// The family of a refresh token, the tokens from before the rotation don't have one
+    pub fn refresh_token_family(refresh_token: &str) -> Option<&str> {
+        let mut parts = refresh_token.splitn(2, '.');
+        match (parts.next(), parts.next()) {
+            (Some(family), Some(_)) if !family.is_empty() => Some(family),
+            _ => None,
+        }
     }
 
     pub fn refresh_tokens(&mut self, user: &super::User, orgs: Vec<super::UserOrganization>) -> (String, i64) {
         // If there is no refresh token, we create one
         if self.refresh_token.is_empty() {
-            use crate::crypto;
-            use data_encoding::BASE64URL;
-
-            self.refresh_token = BASE64URL.encode(&crypto::get_random_64());
+            self.new_refresh_family();
         }
 
+        self.access_token(
+            user,
+            orgs,
+            vec!["api".into(), "offline_access".into()],
+            vec!["Application".into()],
+        )
+    }
+
+    /// Access token of a login with the API key, identified by its `amr` claim. It only has the "api" scope,
+    /// no refresh token is created for it, the clients use the API key again once it expires
+    pub fn api_key_tokens(&mut self, user: &super::User, orgs: Vec<super::UserOrganization>) -> (String, i64) {
+        use crate::auth::AMR_API_KEY;
+
+        self.access_token(user, orgs, vec!["api".into()], vec![AMR_API_KEY.into()])
+    }
+
+    fn access_token(
+        &mut self,
+        user: &super::User,
+        orgs: Vec<super::UserOrganization>,
+        scope: Vec<String>,
+        amr: Vec<String>,
+    ) -> (String, i64) {
         // Update the expiration of the device and the last update date
         let time_now = Utc::now().naive_utc();
         self.updated_at = time_now;
@@ -77,10 +422,17 @@ impl Device {
 
 
         // Create the JWT claims struct, to send to the client
-        use crate::auth::{encode_jwt, LoginJWTClaims, DEFAULT_VALIDITY, JWT_LOGIN_ISSUER};
+        use crate::auth::{access_token_validity, encode_jwt, LoginJWTClaims, AMR_API_KEY, JWT_LOGIN_ISSUER};
+        // The same validity for the claim and the response, read once in case the config changes in between
+        let validity = access_token_validity();
+        let api_key_id = if amr.iter().any(|m| m == AMR_API_KEY) {
+            user.api_key_id()
+        } else {
+            None
+        };
         let claims = LoginJWTClaims {
             nbf: time_now.timestamp(),
-            exp: (time_now + *DEFAULT_VALIDITY).timestamp(),
+            exp: (time_now + validity).timestamp(),
             iss: JWT_LOGIN_ISSUER.to_string(),
             sub: user.uuid.to_string(),
 
@@ -96,11 +448,12 @@ impl Device {
 
             sstamp: user.security_stamp.to_string(),
             device: self.uuid.to_string(),
-            scope: vec!["api".into(), "offline_access".into()],
-            amr: vec!["Application".into()],
+            scope,
+            amr,
+            api_key_id,
         };
 
-        (encode_jwt(&claims), DEFAULT_VALIDITY.num_seconds())
+        (encode_jwt(&claims), validity.num_seconds())
     }
 }
 
@@ -137,6 +490,22 @@ impl Device {
         Ok(())
     }
 
+    /// Deletes the devices of the user except one, which revokes their refresh tokens right away.
+    /// Returns the number of devices deleted
+    pub fn delete_all_by_user_except(
+        user_uuid: &str,
+        device_uuid: &str,
+        conn: &DbConn,
+    ) -> Result<usize, crate::error::Error> {
+        diesel::delete(
+            devices::table
+                .filter(devices::user_uuid.eq(user_uuid))
+                .filter(devices::uuid.ne(device_uuid)),
+        )
+        .execute(&**conn)
+        .map_res("Error removing devices")
+    }
+
     pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
         devices::table
             .filter(devices::uuid.eq(uuid))
@@ -144,9 +513,21 @@ impl Device {
             .ok()
     }
 
+    /// Finds the device by its current refresh token, or by the one replaced by the last rotation
     pub fn find_by_refresh_token(refresh_token: &str, conn: &DbConn) -> Option<Self> {
         devices::table
-            .filter(devices::refresh_token.eq(refresh_token))
+            .filter(
+                devices::refresh_token
+                    .eq(refresh_token)
+                    .or(devices::previous_refresh_token.eq(refresh_token)),
+            )
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    pub fn find_by_refresh_family(refresh_family: &str, conn: &DbConn) -> Option<Self> {
+        devices::table
+            .filter(devices::refresh_family.eq(refresh_family))
             .first::<Self>(&**conn)
             .ok()
     }
@@ -157,4 +538,231 @@ impl Device {
             .load::<Self>(&**conn)
             .expect("Error loading devices")
     }
+
+    /// Loads the devices of all the users, most recently used first
+    #[cfg(feature = "admin")]
+    pub fn find_all(limit: i64, offset: i64, conn: &DbConn) -> Vec<Self> {
+        devices::table
+            .order((devices::updated_at.desc(), devices::uuid.asc()))
+            .limit(limit)
+            .offset(offset)
+            .load::<Self>(&**conn)
+            .expect("Error loading devices")
+    }
+
+    #[cfg(feature = "admin")]
+    pub fn count_all(conn: &DbConn) -> i64 {
+        devices::table.count().first::<i64>(&**conn).ok().unwrap_or(0)
+    }
+
+    /// The last time a device of the user logged in or refreshed its session
+    pub fn find_last_activity_by_user(user_uuid: &str, conn: &DbConn) -> Option<NaiveDateTime> {
+        devices::table
+            .filter(devices::user_uuid.eq(user_uuid))
+            .select(diesel::dsl::max(devices::updated_at))
+            .first::<Option<NaiveDateTime>>(&**conn)
+            .ok()
+            .and_then(|date| date)
+    }
+
+    /// Same as `find_last_activity_by_user`, for all the users at once. The users without devices are left out
+    pub fn find_last_activity_by_users(user_uuids: &[String], conn: &DbConn) -> Vec<(String, Option<NaiveDateTime>)> {
+        devices::table
+            .filter(devices::user_uuid.eq_any(user_uuids))
+            .group_by(devices::user_uuid)
+            .select((devices::user_uuid, diesel::dsl::max(devices::updated_at)))
+            .load::<(String, Option<NaiveDateTime>)>(&**conn)
+            .unwrap_or_default()
+    }
+
+    /// Loads the approved devices with a session, least recently used first. The API key logins don't have one
+    pub fn find_active_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        devices::table
+            .filter(devices::user_uuid.eq(user_uuid))
+            .filter(devices::approved.eq(true))
+            .filter(devices::refresh_token.ne(""))
+            .order(devices::updated_at.asc())
+            .load::<Self>(&**conn)
+            .expect("Error loading devices")
+    }
+
+    pub fn find_pending_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        devices::table
+            .filter(devices::user_uuid.eq(user_uuid))
+            .filter(devices::approved.eq(false))
+            .filter(devices::created_at.ge(Self::pending_expiration_date()))
+            .load::<Self>(&**conn)
+            .expect("Error loading devices")
+    }
+
+    pub fn find_pending_by_approval_token(token: &str, conn: &DbConn) -> Option<Self> {
+        devices::table
+            .filter(devices::approval_token.eq(token))
+            .filter(devices::approved.eq(false))
+            .filter(devices::created_at.ge(Self::pending_expiration_date()))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    /// Deletes the pending devices that weren't approved in time, returning how many there were
+    pub fn delete_expired_pending(conn: &DbConn) -> Result<usize, crate::error::Error> {
+        diesel::delete(
+            devices::table
+                .filter(devices::approved.eq(false))
+                .filter(devices::created_at.lt(Self::pending_expiration_date())),
+        )
+        .execute(&**conn)
+        .map_res("Error deleting expired pending devices")
+    }
+
+    /// Forgets the trusted device keys of all the devices of the user, which are no longer valid once the user key is rotated
+    pub fn clear_trust_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::update(devices::table.filter(devices::user_uuid.eq(user_uuid)))
+            .set((
+                devices::encrypted_user_key.eq(None::<String>),
+                devices::encrypted_public_key.eq(None::<String>),
+                devices::encrypted_private_key.eq(None::<String>),
+            ))
+            .execute(&**conn)
+            .map_res("Error clearing the trusted device keys")
+    }
+
+    /// Removes the remembered second factor of the devices where it expired, returns how many were removed
+    pub fn clear_expired_twofactor_remember(conn: &DbConn) -> Result<usize, crate::error::Error> {
+        if crate::CONFIG.twofactor_remember_days() <= 0 {
+            return Ok(0);
+        }
+
+        // Same as `is_twofactor_remember_expired`
+        diesel::update(
+            devices::table.filter(devices::twofactor_remember.is_not_null()).filter(
+                devices::twofactor_remember_expires_at
+                    .lt(Utc::now().naive_utc())
+                    .or(devices::twofactor_remember_expires_at.is_null()),
+            ),
+        )
+        .set((
+            devices::twofactor_remember.eq(None::<String>),
+            devices::twofactor_remember_expires_at.eq(None::<NaiveDateTime>),
+        ))
+        .execute(&**conn)
+        .map_res("Error clearing expired remembered devices")
+    }
+
+    /// Forgets the remembered second factor of all the devices of the user, returns how many there were
+    pub fn clear_twofactor_remember_by_user(user_uuid: &str, conn: &DbConn) -> Result<usize, crate::error::Error> {
+        diesel::update(
+            devices::table
+                .filter(devices::user_uuid.eq(user_uuid))
+                .filter(devices::twofactor_remember.is_not_null()),
+        )
+        .set((
+            devices::twofactor_remember.eq(None::<String>),
+            devices::twofactor_remember_expires_at.eq(None::<NaiveDateTime>),
+        ))
+        .execute(&**conn)
+        .map_res("Error clearing the remembered devices")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged_in_device() -> Device {
+        let mut device = Device::new("device".into(), "user".into(), "Firefox".into(), DeviceType::FirefoxBrowser as i32);
+        device.new_refresh_family();
+        device
+    }
+
+    #[test]
+    fn rotation_keeps_the_family() {
+        let mut device = logged_in_device();
+        let first = device.refresh_token.clone();
+
+        device.rotate_refresh_token();
+
+        assert_ne!(device.refresh_token, first);
+        assert_eq!(device.refresh_generation, 1);
+        assert_eq!(device.previous_refresh_token.as_ref(), Some(&first));
+        assert_eq!(Device::refresh_token_family(&first), device.refresh_family.as_ref().map(String::as_str));
+        assert_eq!(
+            Device::refresh_token_family(&device.refresh_token),
+            device.refresh_family.as_ref().map(String::as_str)
+        );
+    }
+
+    #[test]
+    fn rotated_out_tokens_are_traced_to_their_device() {
+        let mut device = logged_in_device();
+        let first = device.refresh_token.clone();
+        device.rotate_refresh_token();
+        device.rotate_refresh_token();
+
+        // Older than the previous token, so only the family can find the device
+        assert_ne!(device.previous_refresh_token.as_ref(), Some(&first));
+        assert_eq!(Device::refresh_token_family(&first), device.refresh_family.as_ref().map(String::as_str));
+    }
+
+    #[test]
+    fn previous_token_is_only_accepted_during_the_grace() {
+        let mut device = logged_in_device();
+        assert!(!device.in_rotation_grace());
+
+        device.rotate_refresh_token();
+        assert!(device.in_rotation_grace());
+
+        device.refresh_rotated_at = Some(Utc::now().naive_utc() - chrono::Duration::hours(1));
+        assert!(!device.in_rotation_grace());
+    }
+
+    #[test]
+    fn revocation_invalidates_the_family() {
+        let mut device = logged_in_device();
+        device.rotate_refresh_token();
+
+        device.revoke_refresh_tokens();
+
+        assert!(device.refresh_token.is_empty());
+        assert!(device.refresh_family.is_none());
+        assert!(device.previous_refresh_token.is_none());
+        assert!(!device.in_rotation_grace());
+    }
+
+    #[test]
+    fn tokens_from_before_the_rotation_start_a_family() {
+        assert_eq!(Device::refresh_token_family("legacytoken"), None);
+        assert_eq!(Device::refresh_token_family(".token"), None);
+
+        let mut device = Device::new("device".into(), "user".into(), "Firefox".into(), 0);
+        device.refresh_token = "legacytoken".into();
+        device.rotate_refresh_token();
+
+        assert!(device.refresh_family.is_some());
+        assert_eq!(device.refresh_generation, 0);
+        assert!(device.previous_refresh_token.is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn revoked_sessions_reject_their_refresh_tokens() {
+        use crate::db::models::User;
+
+        let conn = crate::migrations::test_connection();
+        let mut user = User::new("user@example.com".into());
+        user.save(&conn).unwrap();
+
+        let mut current = Device::new("current".into(), user.uuid.clone(), "Firefox".into(), 0);
+        let mut other = Device::new("other".into(), user.uuid.clone(), "Android".into(), 0);
+        current.new_refresh_family();
+        other.new_refresh_family();
+        current.save(&conn).unwrap();
+        other.save(&conn).unwrap();
+
+        assert_eq!(Device::delete_all_by_user_except(&user.uuid, &current.uuid, &conn).unwrap(), 1);
+
+        assert!(Device::find_by_refresh_token(&other.refresh_token, &conn).is_none());
+        let found = Device::find_by_refresh_token(&current.refresh_token, &conn).unwrap();
+        assert_eq!(found.uuid, current.uuid);
+    }
 }
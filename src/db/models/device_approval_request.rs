@@ -0,0 +1,135 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::{Device, Organization, User};
+use crate::CONFIG;
+
+/// A new device of a member waiting for an admin of the organization, with the `AdminDeviceApproval` policy.
+/// There is one per organization with the policy, approving any of them approves the device
+#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[table_name = "device_approval_requests"]
+#[belongs_to(User, foreign_key = "user_uuid")]
+#[belongs_to(Organization, foreign_key = "org_uuid")]
+#[primary_key(uuid)]
+pub struct DeviceApprovalRequest {
+    pub uuid: String,
+    pub user_uuid: String,
+    pub org_uuid: String,
+    pub device_uuid: String,
+    pub ip_address: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Local methods
+impl DeviceApprovalRequest {
+    pub fn new(user_uuid: String, org_uuid: String, device_uuid: String, ip_address: String) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid,
+            org_uuid,
+            device_uuid,
+            ip_address,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    /// The requests older than this are expired, and deleted by the cleanup
+    fn expiration_date() -> NaiveDateTime {
+        Utc::now().naive_utc() - Duration::hours(CONFIG.admin_device_approval_expiration_hours())
+    }
+
+    pub fn to_json(&self, user: &User, device: &Device) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "OrganizationId": self.org_uuid,
+            "UserId": self.user_uuid,
+            "Email": user.email,
+            "Name": user.name,
+            "DeviceId": self.device_uuid,
+            "DeviceName": device.name,
+            "DeviceType": device.atype,
+            "IpAddress": self.ip_address,
+            "CreationDate": format_date(&self.created_at),
+            "Object": "deviceApprovalRequest",
+        })
+    }
+}
+
+use crate::db::schema::device_approval_requests;
+use crate::db::DbConn;
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl DeviceApprovalRequest {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        diesel::replace_into(device_approval_requests::table)
+            .values(self)
+            .execute(&**conn)
+            .map_res("Error saving device approval request")
+    }
+
+    pub fn delete_all_by_device(device_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::delete(device_approval_requests::table.filter(device_approval_requests::device_uuid.eq(device_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting device approval requests")
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::delete(device_approval_requests::table.filter(device_approval_requests::user_uuid.eq(user_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting device approval requests")
+    }
+
+    pub fn delete_all_by_organization(org_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::delete(device_approval_requests::table.filter(device_approval_requests::org_uuid.eq(org_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting device approval requests")
+    }
+
+    /// Deletes the expired requests, returning how many there were
+    pub fn delete_expired(conn: &DbConn) -> Result<usize, crate::error::Error> {
+        diesel::delete(
+            device_approval_requests::table.filter(device_approval_requests::created_at.lt(Self::expiration_date())),
+        )
+        .execute(&**conn)
+        .map_res("Error deleting expired device approval requests")
+    }
+
+    pub fn find_by_uuid_and_org(uuid: &str, org_uuid: &str, conn: &DbConn) -> Option<Self> {
+        device_approval_requests::table
+            .filter(device_approval_requests::uuid.eq(uuid))
+            .filter(device_approval_requests::org_uuid.eq(org_uuid))
+            .filter(device_approval_requests::created_at.ge(Self::expiration_date()))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    /// Loads the pending requests of the organization, oldest first
+    pub fn find_by_org(org_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        device_approval_requests::table
+            .filter(device_approval_requests::org_uuid.eq(org_uuid))
+            .filter(device_approval_requests::created_at.ge(Self::expiration_date()))
+            .order(device_approval_requests::created_at.asc())
+            .load::<Self>(&**conn)
+            .expect("Error loading device approval requests")
+    }
+
+    /// Whether the device has a pending request in the organization, an expired one doesn't count
+    pub fn exists_by_device_and_org(device_uuid: &str, org_uuid: &str, conn: &DbConn) -> bool {
+        device_approval_requests::table
+            .filter(device_approval_requests::device_uuid.eq(device_uuid))
+            .filter(device_approval_requests::org_uuid.eq(org_uuid))
+            .filter(device_approval_requests::created_at.ge(Self::expiration_date()))
+            .count()
+            .first::<i64>(&**conn)
+            .ok()
+            .unwrap_or(0)
+            != 0
+    }
+}
@@ -0,0 +1,220 @@
+use chrono::NaiveDateTime;
+#[cfg(feature = "emergency_access")]
+use chrono::{Duration, Utc};
+#[cfg(feature = "emergency_access")]
+use serde_json::Value;
+
+#[cfg(feature = "emergency_access")]
+use super::User;
+
+/// Access to the vault of the grantor given to the grantee, which they can request
+/// and get once the grantor approves it or the wait time passes
+#[derive(Debug, Identifiable, Queryable, Insertable)]
+#[table_name = "emergency_access"]
+#[primary_key(uuid)]
+pub struct EmergencyAccess {
+    pub uuid: String,
+    pub grantor_uuid: String,
+    pub grantee_uuid: String,
+    /// The key of the grantor encrypted with the public key of the grantee, set when the grantor confirms the access
+    pub key_encrypted: Option<String>,
+    pub atype: i32,
+    pub status: i32,
+    pub wait_time_days: i32,
+    pub recovery_initiated_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+/// https://github.com/bitwarden/server/blob/master/src/Core/Enums/EmergencyAccessType.cs
+#[cfg(feature = "emergency_access")]
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+pub enum EmergencyAccessType {
+    View = 0,
+    Takeover = 1,
+}
+
+/// https://github.com/bitwarden/server/blob/master/src/Core/Enums/EmergencyAccessStatusType.cs
+#[cfg(feature = "emergency_access")]
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+pub enum EmergencyAccessStatus {
+    Invited = 0,
+    Accepted = 1,
+    Confirmed = 2,
+    RecoveryInitiated = 3,
+    RecoveryApproved = 4,
+}
+
+/// Local methods
+impl EmergencyAccess {
+    #[cfg(feature = "emergency_access")]
+    pub fn new(grantor_uuid: String, grantee_uuid: String, atype: EmergencyAccessType, wait_time_days: i32) -> Self {
+        let now = Utc::now().naive_utc();
+
+        Self {
+            uuid: crate::util::get_uuid(),
+            grantor_uuid,
+            grantee_uuid,
+            key_encrypted: None,
+            atype: atype as i32,
+            status: EmergencyAccessStatus::Invited as i32,
+            wait_time_days,
+            recovery_initiated_at: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// The moment an initiated recovery gets approved automatically, if the grantor doesn't approve or reject it before
+    #[cfg(feature = "emergency_access")]
+    pub fn approval_date(&self) -> Option<NaiveDateTime> {
+        if self.status != EmergencyAccessStatus::RecoveryInitiated as i32 {
+            return None;
+        }
+
+        self.recovery_initiated_at
+            .map(|initiated| initiated + Duration::days(i64::from(self.wait_time_days)))
+    }
+
+    /// The status, taking into account the recoveries approved because the wait time passed
+    #[cfg(feature = "emergency_access")]
+    pub fn current_status(&self) -> i32 {
+        match self.approval_date() {
+            Some(date) if date <= Utc::now().naive_utc() => EmergencyAccessStatus::RecoveryApproved as i32,
+            _ => self.status,
+        }
+    }
+
+    #[cfg(feature = "emergency_access")]
+    pub fn is_involved(&self, user_uuid: &str) -> bool {
+        self.grantor_uuid == user_uuid || self.grantee_uuid == user_uuid
+    }
+
+    #[cfg(feature = "emergency_access")]
+    pub fn to_json(&self, conn: &DbConn) -> Value {
+        use crate::util::format_date;
+
+        let name_and_email = |uuid: &str| match User::find_by_uuid(uuid, conn) {
+            Some(user) => (Value::String(user.name), Value::String(user.email)),
+            None => (Value::Null, Value::Null),
+        };
+        let (grantor_name, grantor_email) = name_and_email(&self.grantor_uuid);
+        let (grantee_name, grantee_email) = name_and_email(&self.grantee_uuid);
+
+        json!({
+            "Id": self.uuid,
+            "GrantorId": self.grantor_uuid,
+            "GrantorName": grantor_name,
+            "GrantorEmail": grantor_email,
+            "GranteeId": self.grantee_uuid,
+            "GranteeName": grantee_name,
+            "GranteeEmail": grantee_email,
+            "Type": self.atype,
+            "Status": self.current_status(),
+            "WaitTimeDays": self.wait_time_days,
+            "CreationDate": format_date(&self.created_at),
+            "Object": "emergencyAccess",
+        })
+    }
+
+    /// The state of the access, with the time left before an initiated recovery is approved automatically
+    #[cfg(feature = "emergency_access")]
+    pub fn to_json_status(&self) -> Value {
+        use crate::util::format_date;
+
+        let now = Utc::now().naive_utc();
+        let remaining = self
+            .approval_date()
+            .map(|date| std::cmp::max(date.signed_duration_since(now).num_seconds(), 0));
+
+        json!({
+            "Id": self.uuid,
+            "Type": self.atype,
+            "Status": self.current_status(),
+            "WaitTimeDays": self.wait_time_days,
+            "RecoveryInitiatedDate": self.recovery_initiated_at.as_ref().map(format_date),
+            "ApprovalDate": self.approval_date().as_ref().map(format_date),
+            "RemainingWaitSeconds": remaining,
+            "Object": "emergencyAccessStatus",
+        })
+    }
+}
+
+use crate::db::schema::emergency_access;
+use crate::db::DbConn;
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl EmergencyAccess {
+    #[cfg(feature = "emergency_access")]
+    pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
+        User::update_uuid_revision(&self.grantor_uuid, conn);
+        User::update_uuid_revision(&self.grantee_uuid, conn);
+        self.updated_at = Utc::now().naive_utc();
+
+        diesel::replace_into(emergency_access::table)
+            .values(&*self)
+            .execute(&**conn)
+            .map_res("Error saving emergency access")
+    }
+
+    #[cfg(feature = "emergency_access")]
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        User::update_uuid_revision(&self.grantor_uuid, conn);
+        User::update_uuid_revision(&self.grantee_uuid, conn);
+
+        diesel::delete(emergency_access::table.filter(emergency_access::uuid.eq(self.uuid)))
+            .execute(&**conn)
+            .map_res("Error removing emergency access")
+    }
+
+    /// Removes the accesses where the user is either the grantor or the grantee
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::delete(
+            emergency_access::table.filter(
+                emergency_access::grantor_uuid
+                    .eq(user_uuid)
+                    .or(emergency_access::grantee_uuid.eq(user_uuid)),
+            ),
+        )
+        .execute(&**conn)
+        .map_res("Error removing emergency access")
+    }
+
+    #[cfg(feature = "emergency_access")]
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        emergency_access::table
+            .filter(emergency_access::uuid.eq(uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    #[cfg(feature = "emergency_access")]
+    pub fn find_by_grantor_and_grantee(grantor_uuid: &str, grantee_uuid: &str, conn: &DbConn) -> Option<Self> {
+        emergency_access::table
+            .filter(emergency_access::grantor_uuid.eq(grantor_uuid))
+            .filter(emergency_access::grantee_uuid.eq(grantee_uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    #[cfg(feature = "emergency_access")]
+    pub fn find_by_grantor(grantor_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        emergency_access::table
+            .filter(emergency_access::grantor_uuid.eq(grantor_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading emergency access")
+    }
+
+    #[cfg(feature = "emergency_access")]
+    pub fn find_by_grantee(grantee_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        emergency_access::table
+            .filter(emergency_access::grantee_uuid.eq(grantee_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading emergency access")
+    }
+}
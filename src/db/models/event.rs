@@ -0,0 +1,190 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+#[derive(Debug, Identifiable, Queryable, Insertable)]
+#[table_name = "event"]
+#[primary_key(uuid)]
+pub struct Event {
+    pub uuid: String,
+    pub event_type: i32,
+
+    pub user_uuid: Option<String>,
+    pub org_uuid: Option<String>,
+    pub cipher_uuid: Option<String>,
+
+    pub device_uuid: Option<String>,
+    pub device_type: Option<i32>,
+    pub ip_address: Option<String>,
+
+    pub event_date: NaiveDateTime,
+
+    /// The user that made the change, when it's not the one the event is about, like the admin confirming a member
+    pub act_user_uuid: Option<String>,
+}
+
+/// https://github.com/bitwarden/server/blob/master/src/Core/Enums/EventType.cs
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+pub enum EventType {
+    // User
+    UserLoggedIn = 1000,
+    UserChangedPassword = 1001,
+    UserUpdated2fa = 1002,
+    UserDisabled2fa = 1003,
+    UserRecovered2fa = 1004,
+    UserFailedLogIn = 1005,
+    UserFailedLogIn2fa = 1006,
+    UserClientExportedVault = 1007,
+
+    // Cipher
+    CipherCreated = 1100,
+    CipherUpdated = 1101,
+    CipherDeleted = 1102,
+    CipherAttachmentCreated = 1103,
+    CipherAttachmentDeleted = 1104,
+    CipherShared = 1105,
+    CipherUpdatedCollections = 1106,
+
+    // Collection
+    CollectionCreated = 1300,
+    CollectionUpdated = 1301,
+    CollectionDeleted = 1302,
+
+    // Organization user
+    OrganizationUserInvited = 1500,
+    OrganizationUserConfirmed = 1501,
+    OrganizationUserUpdated = 1502,
+    OrganizationUserRemoved = 1503,
+
+    // Organization
+    OrganizationUpdated = 1600,
+    OrganizationClientExportedVault = 1602,
+
+    // Admin, not part of the official event types
+    AdminClearedUserLockout = 9000,
+    AdminClearedIpLockout = 9001,
+    AdminDisabledUser = 9002,
+    AdminEnabledUser = 9003,
+    AdminTerminatedSession = 9004,
+
+    // Server, not part of the official event types
+    UserDisabledInactive = 9100,
+}
+
+/// Local methods
+impl Event {
+    pub fn new(event_type: EventType, user_uuid: Option<String>) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            event_type: event_type as i32,
+
+            user_uuid,
+            org_uuid: None,
+            cipher_uuid: None,
+
+            device_uuid: None,
+            device_type: None,
+            ip_address: None,
+
+            event_date: Utc::now().naive_utc(),
+
+            act_user_uuid: None,
+        }
+    }
+
+    /// The event as the user sees it in their activity, without the internal ids. The name is `None` for the
+    /// devices that were deleted since
+    pub fn to_json(&self, device_name: Option<&str>) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Type": self.event_type,
+            "DeviceName": device_name,
+            "DeviceType": self.device_type,
+            "IpAddress": self.ip_address,
+            "Date": format_date(&self.event_date),
+            "Object": "event",
+        })
+    }
+}
+
+use crate::db::schema::event;
+use crate::db::DbConn;
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl Event {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        diesel::replace_into(event::table)
+            .values(self)
+            .execute(&**conn)
+            .map_res("Error saving event")
+    }
+
+    /// Saves the event, only logging the error if it fails, so that it never interrupts the request
+    pub fn save_or_log(&self, conn: &DbConn) {
+        if let Err(e) = self.save(conn) {
+            error!("Error saving event of type {}: {:#?}", self.event_type, e);
+        }
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::delete(event::table.filter(event::user_uuid.eq(user_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting events")
+    }
+
+    /// Removes the events older than `EVENTS_DAYS_RETAIN`, when it's set
+    pub fn delete_expired(conn: &DbConn) -> Result<usize, crate::error::Error> {
+        let days = crate::CONFIG.events_days_retain();
+        if days <= 0 {
+            return Ok(0);
+        }
+
+        let limit = Utc::now().naive_utc() - chrono::Duration::days(days);
+        diesel::delete(event::table.filter(event::event_date.lt(limit)))
+            .execute(&**conn)
+            .map_res("Error deleting expired events")
+    }
+
+    pub fn find_by_user_and_types(user_uuid: &str, types: &[i32], limit: i64, offset: i64, conn: &DbConn) -> Vec<Self> {
+        event::table
+            .filter(event::user_uuid.eq(user_uuid))
+            .filter(event::event_type.eq_any(types))
+            .order(event::event_date.desc())
+            .limit(limit)
+            .offset(offset)
+            .load::<Self>(&**conn)
+            .expect("Error loading events")
+    }
+
+    /// Loads the events between the dates, oldest first, from all the organizations and users when `org_uuid` is None
+    pub fn find_by_date_range(
+        org_uuid: Option<&str>,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+        limit: i64,
+        offset: i64,
+        conn: &DbConn,
+    ) -> Result<Vec<Self>, crate::error::Error> {
+        let mut query = event::table
+            .filter(event::event_date.ge(start))
+            .filter(event::event_date.le(end))
+            .into_boxed();
+
+        if let Some(org_uuid) = org_uuid {
+            query = query.filter(event::org_uuid.eq(org_uuid));
+        }
+
+        query
+            .order((event::event_date.asc(), event::uuid.asc()))
+            .limit(limit)
+            .offset(offset)
+            .load::<Self>(&**conn)
+            .map_res("Error loading events")
+    }
+}
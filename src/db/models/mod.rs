@@ -1,19 +1,37 @@
 mod attachment;
 mod cipher;
+mod cipher_revision;
+mod cipher_transfer;
 mod device;
+mod device_approval_request;
+mod emergency_access;
+mod event;
 mod folder;
+mod security_notice;
+mod send;
 mod user;
 
 mod collection;
+mod org_policy;
 mod organization;
 mod two_factor;
 
 pub use self::attachment::Attachment;
-pub use self::cipher::Cipher;
+pub use self::cipher::{Cipher, UriMatchType};
+pub use self::cipher_revision::CipherRevision;
+pub use self::cipher_transfer::CipherTransfer;
 pub use self::collection::{Collection, CollectionCipher, CollectionUser};
-pub use self::device::Device;
+pub use self::device::{Device, DeviceType};
+pub use self::device_approval_request::DeviceApprovalRequest;
+pub use self::emergency_access::EmergencyAccess;
+#[cfg(feature = "emergency_access")]
+pub use self::emergency_access::{EmergencyAccessStatus, EmergencyAccessType};
+pub use self::event::{Event, EventType};
 pub use self::folder::{Folder, FolderCipher};
+pub use self::org_policy::{MasterPasswordRequirements, OrgPolicy, OrgPolicyType};
 pub use self::organization::Organization;
 pub use self::organization::{UserOrgStatus, UserOrgType, UserOrganization};
+pub use self::security_notice::{SecurityNotice, SecurityNoticeType};
+pub use self::send::{Send, SendType};
 pub use self::two_factor::{TwoFactor, TwoFactorType};
-pub use self::user::{Invitation, User};
+pub use self::user::{Invitation, PasswordAlgorithm, User};
@@ -0,0 +1,357 @@
+use chrono::NaiveDateTime;
+use serde_json::Value;
+
+use super::{Organization, User, UserOrgStatus, UserOrgType};
+
+#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[table_name = "org_policies"]
+#[belongs_to(Organization, foreign_key = "org_uuid")]
+#[primary_key(uuid)]
+pub struct OrgPolicy {
+    pub uuid: String,
+    pub org_uuid: String,
+    pub atype: i32,
+    pub enabled: bool,
+    pub data: String,
+    pub updated_at: Option<NaiveDateTime>,
+}
+
+/// https://github.com/bitwarden/server/blob/master/src/Core/Enums/PolicyType.cs
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+pub enum OrgPolicyType {
+    TwoFactorAuthentication = 0,
+    MasterPassword = 1,
+    PasswordGenerator = 2,
+    SingleOrg = 3,
+    RequireSso = 4,
+    DisableSend = 6,
+    ResetPassword = 8,
+    MaximumVaultTimeout = 9,
+
+    // Not part of the official policy types, only managed through the API
+    MembershipDomains = 100,
+    RestrictItemDeletion = 101,
+    DefaultCollections = 102,
+    /// The members can't skip the two-step login from their trusted IP ranges
+    DisableTrustedIpRanges = 103,
+    /// The new devices of the members can't log in until an admin of the organization approves them
+    AdminDeviceApproval = 104,
+    /// Only the owners can export the data of the organization
+    DisableExport = 105,
+    /// The exports of the data of the organization need to be protected with a password by the clients
+    PasswordProtectedExport = 106,
+}
+
+/// A collection the members get access to when they are confirmed, with the `DefaultCollections` policy
+#[derive(Debug)]
+pub struct DefaultCollection {
+    pub uuid: String,
+    pub read_only: bool,
+    pub can_delete: bool,
+}
+
+/// The requirements of the `MasterPassword` policies, like `{"minComplexity": 3, "minLength": 12, "requireUpper": true,
+/// "requireLower": true, "requireNumbers": true, "requireSpecial": true, "enforceOnLogin": true}`.
+/// The complexity is the zxcvbn score computed by the clients, from 0 to 4
+#[derive(Debug, Default, PartialEq)]
+pub struct MasterPasswordRequirements {
+    pub min_complexity: u64,
+    pub min_length: u64,
+    pub require_upper: bool,
+    pub require_lower: bool,
+    pub require_numbers: bool,
+    pub require_special: bool,
+    /// The users whose password was set before the policy changed need to update it when they log in
+    pub enforce_on_login: bool,
+}
+
+impl MasterPasswordRequirements {
+    /// Combines the requirements of several policies, keeping the strictest of each
+    pub fn combine(policies: &[OrgPolicy]) -> Self {
+        policies
+            .iter()
+            .map(OrgPolicy::master_password_requirements)
+            .fold(Self::default(), |acc, r| Self {
+                min_complexity: std::cmp::max(acc.min_complexity, r.min_complexity),
+                min_length: std::cmp::max(acc.min_length, r.min_length),
+                require_upper: acc.require_upper || r.require_upper,
+                require_lower: acc.require_lower || r.require_lower,
+                require_numbers: acc.require_numbers || r.require_numbers,
+                require_special: acc.require_special || r.require_special,
+                enforce_on_login: acc.enforce_on_login || r.enforce_on_login,
+            })
+    }
+
+    /// Whether there is something to check in the passwords, `enforce_on_login` alone isn't a requirement
+    pub fn is_empty(&self) -> bool {
+        self.min_complexity == 0
+            && self.min_length == 0
+            && !self.require_upper
+            && !self.require_lower
+            && !self.require_numbers
+            && !self.require_special
+    }
+}
+
+/// Local methods
+impl OrgPolicy {
+    pub fn new(org_uuid: String, atype: OrgPolicyType, data: String) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            org_uuid,
+            atype: atype as i32,
+            enabled: false,
+            data,
+            updated_at: None,
+        }
+    }
+
+    /// With the `MembershipDomains` policy, the email domains the members need to have, like `{"domains": ["example.com"]}`
+    pub fn membership_domains(&self) -> Vec<String> {
+        let data: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
+
+        match data["domains"].as_array() {
+            Some(domains) => domains
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|d| d.trim().to_lowercase())
+                .filter(|d| !d.is_empty())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// With the `RestrictItemDeletion` policy, whether the managers can still delete the ciphers, like `{"managersCanDelete": false}`.
+    /// They can unless it's disabled, the members with the user role never can
+    pub fn managers_can_delete(&self) -> bool {
+        let data: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
+        data["managersCanDelete"].as_bool().unwrap_or(true)
+    }
+
+    /// With the `DefaultCollections` policy, the collections given to the new members,
+    /// like `{"collections": [{"id": "...", "readOnly": false, "canDelete": false}]}`
+    pub fn default_collections(&self) -> Vec<DefaultCollection> {
+        let data: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
+
+        match data["collections"].as_array() {
+            Some(collections) => collections
+                .iter()
+                .filter_map(|c| {
+                    Some(DefaultCollection {
+                        uuid: c["id"].as_str()?.to_string(),
+                        read_only: c["readOnly"].as_bool().unwrap_or(false),
+                        can_delete: c["canDelete"].as_bool().unwrap_or(false),
+                    })
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// With the `MasterPassword` policy, the number of previous passwords that can't be reused, like `{"preventReuse": 5}`
+    pub fn prevent_reuse(&self) -> usize {
+        let data: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
+        data["preventReuse"].as_u64().unwrap_or(0) as usize
+    }
+
+    /// With the `MasterPassword` policy, the requirements of the new passwords. The missing values don't require anything
+    pub fn master_password_requirements(&self) -> MasterPasswordRequirements {
+        let data: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
+
+        MasterPasswordRequirements {
+            min_complexity: data["minComplexity"].as_u64().unwrap_or(0),
+            min_length: data["minLength"].as_u64().unwrap_or(0),
+            require_upper: data["requireUpper"].as_bool().unwrap_or(false),
+            require_lower: data["requireLower"].as_bool().unwrap_or(false),
+            require_numbers: data["requireNumbers"].as_bool().unwrap_or(false),
+            require_special: data["requireSpecial"].as_bool().unwrap_or(false),
+            enforce_on_login: data["enforceOnLogin"].as_bool().unwrap_or(false),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let data: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
+
+        json!({
+            "Id": self.uuid,
+            "OrganizationId": self.org_uuid,
+            "Type": self.atype,
+            "Data": data,
+            "Enabled": self.enabled,
+            "Object": "policy",
+        })
+    }
+}
+
+use crate::db::schema::{org_policies, users_organizations};
+use crate::db::DbConn;
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl OrgPolicy {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        diesel::replace_into(org_policies::table)
+            .values(self)
+            .execute(&**conn)
+            .map_res("Error saving org_policy")
+    }
+
+    pub fn delete_all_by_organization(org_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::delete(org_policies::table.filter(org_policies::org_uuid.eq(org_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting org_policy")
+    }
+
+    pub fn find_by_org(org_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        org_policies::table
+            .filter(org_policies::org_uuid.eq(org_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading org_policy")
+    }
+
+    pub fn find_by_org_and_type(org_uuid: &str, atype: i32, conn: &DbConn) -> Option<Self> {
+        org_policies::table
+            .filter(org_policies::org_uuid.eq(org_uuid))
+            .filter(org_policies::atype.eq(atype))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    /// Returns the enabled policies of this type that apply to the user in their organizations.
+    /// Owners and admins are exempt from the policies, and so are the users that haven't accepted the invitation yet
+    pub fn find_applicable_to_user(user_uuid: &str, atype: OrgPolicyType, conn: &DbConn) -> Vec<Self> {
+        org_policies::table
+            .inner_join(users_organizations::table.on(users_organizations::org_uuid.eq(org_policies::org_uuid)))
+            .filter(users_organizations::user_uuid.eq(user_uuid))
+            .filter(users_organizations::status.ne(UserOrgStatus::Invited as i32))
+            .filter(users_organizations::atype.gt(UserOrgType::Admin as i32))
+            .filter(org_policies::atype.eq(atype as i32))
+            .filter(org_policies::enabled.eq(true))
+            .select(org_policies::all_columns)
+            .load::<Self>(&**conn)
+            .expect("Error loading org_policy")
+    }
+
+    /// Returns whether an enabled policy of this type applies to the user in any of their organizations
+    pub fn is_applicable_to_user(user_uuid: &str, atype: OrgPolicyType, conn: &DbConn) -> bool {
+        !Self::find_applicable_to_user(user_uuid, atype, conn).is_empty()
+    }
+
+    /// The number of previous passwords the user can't reuse, the highest of their master password policies
+    pub fn password_history_count(user_uuid: &str, conn: &DbConn) -> usize {
+        Self::find_applicable_to_user(user_uuid, OrgPolicyType::MasterPassword, conn)
+            .iter()
+            .map(Self::prevent_reuse)
+            .max()
+            .map_or(0, |count| std::cmp::min(count, User::PASSWORD_HISTORY_MAX))
+    }
+
+    /// Whether the user needs to update the master password when logging in, because a `MasterPassword` policy
+    /// enforced on login changed after the password was set. The policies changed before they were dated don't count
+    pub fn is_password_reset_forced(user: &User, conn: &DbConn) -> bool {
+        let policies = Self::find_applicable_to_user(&user.uuid, OrgPolicyType::MasterPassword, conn);
+        Self::forces_password_reset(&policies, user.password_changed_at)
+    }
+
+    /// Whether this `MasterPassword` policy would make the user update the master password when logging in once it's
+    /// enabled. A disabled policy would be dated when enabled, so only the requirements it keeps matter
+    pub fn would_force_password_reset(&self, user: &User) -> bool {
+        if self.enabled {
+            return Self::forces_password_reset(std::slice::from_ref(self), user.password_changed_at);
+        }
+
+        self.master_password_requirements().enforce_on_login && !user.password_hash.is_empty()
+    }
+
+    fn forces_password_reset(policies: &[Self], password_changed_at: Option<NaiveDateTime>) -> bool {
+        policies
+            .iter()
+            .filter(|policy| policy.master_password_requirements().enforce_on_login)
+            .filter_map(|policy| policy.updated_at)
+            .any(|updated_at| password_changed_at.map_or(true, |changed_at| changed_at < updated_at))
+    }
+
+    /// The enabled policies of the organizations the user is a confirmed member of, which the clients enforce themselves
+    pub fn find_confirmed_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        org_policies::table
+            .inner_join(users_organizations::table.on(users_organizations::org_uuid.eq(org_policies::org_uuid)))
+            .filter(users_organizations::user_uuid.eq(user_uuid))
+            .filter(users_organizations::status.eq(UserOrgStatus::Confirmed as i32))
+            .filter(org_policies::enabled.eq(true))
+            .select(org_policies::all_columns)
+            .load::<Self>(&**conn)
+            .expect("Error loading org_policy")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    fn master_password_policy(data: &str, updated_at: Option<NaiveDateTime>) -> OrgPolicy {
+        let mut policy = OrgPolicy::new("org".into(), OrgPolicyType::MasterPassword, data.into());
+        policy.enabled = true;
+        policy.updated_at = updated_at;
+        policy
+    }
+
+    #[test]
+    fn forces_the_reset_when_the_policy_changed_after_the_password() {
+        let now = Utc::now().naive_utc();
+        let policies = vec![master_password_policy(r#"{"minLength": 12, "enforceOnLogin": true}"#, Some(now))];
+
+        assert!(OrgPolicy::forces_password_reset(&policies, Some(now - Duration::days(1))));
+        assert!(OrgPolicy::forces_password_reset(&policies, None));
+        assert!(!OrgPolicy::forces_password_reset(&policies, Some(now + Duration::seconds(1))));
+    }
+
+    #[test]
+    fn only_forces_the_reset_when_enforced_on_login() {
+        let now = Utc::now().naive_utc();
+        let before = Some(now - Duration::days(1));
+
+        let policies = vec![master_password_policy(r#"{"minLength": 12}"#, Some(now))];
+        assert!(!OrgPolicy::forces_password_reset(&policies, before));
+
+        // The policies changed before they were dated don't count
+        let policies = vec![master_password_policy(r#"{"minLength": 12, "enforceOnLogin": true}"#, None)];
+        assert!(!OrgPolicy::forces_password_reset(&policies, before));
+    }
+
+    #[test]
+    fn previews_the_password_reset_of_a_disabled_policy() {
+        let now = Utc::now().naive_utc();
+        let mut user = User::new("user@example.com".into());
+        user.set_password("password");
+
+        let mut policy = master_password_policy(r#"{"minLength": 12, "enforceOnLogin": true}"#, Some(now));
+        policy.enabled = false;
+        assert!(policy.would_force_password_reset(&user));
+
+        // Once enabled, the members who changed the password after it are fine
+        policy.enabled = true;
+        assert!(!policy.would_force_password_reset(&user));
+        user.password_changed_at = Some(now - Duration::days(1));
+        assert!(policy.would_force_password_reset(&user));
+
+        let policy = master_password_policy(r#"{"minLength": 12}"#, None);
+        assert!(!policy.would_force_password_reset(&user));
+    }
+
+    #[test]
+    fn parses_the_requirements() {
+        let policy = master_password_policy(r#"{"minComplexity": 3, "minLength": 12, "requireUpper": true}"#, None);
+        let requirements = policy.master_password_requirements();
+
+        assert_eq!(requirements.min_complexity, 3);
+        assert_eq!(requirements.min_length, 12);
+        assert!(requirements.require_upper && !requirements.require_lower);
+        assert!(!requirements.enforce_on_login);
+        assert!(master_password_policy("{}", None).master_password_requirements().is_empty());
+    }
+}
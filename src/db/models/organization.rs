@@ -1,7 +1,8 @@
+use chrono::NaiveDateTime;
 use serde_json::Value;
 use std::cmp::Ordering;
 
-use super::{CollectionUser, User};
+use super::{CollectionUser, TwoFactor, TwoFactorType, User};
 
 #[derive(Debug, Identifiable, Queryable, Insertable)]
 #[table_name = "organizations"]
@@ -10,6 +11,8 @@ pub struct Organization {
     pub uuid: String,
     pub name: String,
     pub billing_email: String,
+    /// Entered by the members to log in with single sign-on and to use the Key Connector, instead of the uuid
+    pub identifier: Option<String>,
 }
 
 #[derive(Debug, Identifiable, Queryable, Insertable)]
@@ -155,6 +158,7 @@ impl Organization {
 
             name,
             billing_email,
+            identifier: None,
         }
     }
 
@@ -162,6 +166,7 @@ impl Organization {
         json!({
             "Id": self.uuid,
             "Name": self.name,
+            "Identifier": self.identifier,
             "Seats": 10,
             "MaxCollections": 10,
             "MaxStorageGb": 10, // The value doesn't matter, we don't check server-side
@@ -203,7 +208,7 @@ impl UserOrganization {
     }
 }
 
-use crate::db::schema::{ciphers_collections, organizations, users_collections, users_organizations};
+use crate::db::schema::{ciphers_collections, organizations, twofactor, users, users_collections, users_organizations};
 use crate::db::DbConn;
 use diesel;
 use diesel::prelude::*;
@@ -227,11 +232,13 @@ impl Organization {
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
-        use super::{Cipher, Collection};
+        use super::{Cipher, Collection, DeviceApprovalRequest, OrgPolicy};
 
         Cipher::delete_all_by_organization(&self.uuid, &conn)?;
         Collection::delete_all_by_organization(&self.uuid, &conn)?;
         UserOrganization::delete_all_by_organization(&self.uuid, &conn)?;
+        OrgPolicy::delete_all_by_organization(&self.uuid, &conn)?;
+        DeviceApprovalRequest::delete_all_by_organization(&self.uuid, &conn)?;
 
         diesel::delete(organizations::table.filter(organizations::uuid.eq(self.uuid)))
             .execute(&**conn)
@@ -244,6 +251,13 @@ impl Organization {
             .first::<Self>(&**conn)
             .ok()
     }
+
+    pub fn find_by_identifier(identifier: &str, conn: &DbConn) -> Option<Self> {
+        organizations::table
+            .filter(organizations::identifier.eq(identifier))
+            .first::<Self>(&**conn)
+            .ok()
+    }
 }
 
 impl UserOrganization {
@@ -253,6 +267,7 @@ impl UserOrganization {
         json!({
             "Id": self.org_uuid,
             "Name": org.name,
+            "Identifier": org.identifier,
             "Seats": 10,
             "MaxCollections": 10,
             "UsersGetPremium": true,
@@ -292,10 +307,43 @@ impl UserOrganization {
         })
     }
 
-    pub fn to_json_collection_user_details(&self, read_only: bool) -> Value {
+    /// Member details for the organization admins, with the enabled two-factor methods (never their data) and the last access
+    pub fn to_json_member_report(
+        &self,
+        user: &User,
+        twofactors: &[TwoFactor],
+        last_access: Option<NaiveDateTime>,
+    ) -> Value {
+        use crate::util::format_date;
+
+        let twofactor_methods: Vec<&str> = twofactors
+            .iter()
+            .filter(|tf| tf.user_uuid == user.uuid && tf.enabled)
+            .filter_map(TwoFactor::type_name)
+            .collect();
+
+        json!({
+            "Id": self.uuid,
+            "UserId": self.user_uuid,
+            "Name": user.name,
+            "Email": user.email,
+
+            "Status": self.status,
+            "Type": self.atype,
+
+            "TwoFactorEnabled": !twofactor_methods.is_empty(),
+            "TwoFactorProviders": twofactor_methods,
+            "LastAccessDate": last_access.as_ref().map(format_date),
+
+            "Object": "organizationMemberReport",
+        })
+    }
+
+    pub fn to_json_collection_user_details(&self, read_only: bool, can_delete: bool) -> Value {
         json!({
             "Id": self.uuid,
-            "ReadOnly": read_only
+            "ReadOnly": read_only,
+            "CanDelete": can_delete,
         })
     }
 
@@ -306,7 +354,7 @@ impl UserOrganization {
             let collections = CollectionUser::find_by_organization_and_user_uuid(&self.org_uuid, &self.user_uuid, conn);
             collections
                 .iter()
-                .map(|c| json!({"Id": c.collection_uuid, "ReadOnly": c.read_only}))
+                .map(|c| json!({"Id": c.collection_uuid, "ReadOnly": c.read_only, "CanDelete": c.can_delete}))
                 .collect()
         };
 
@@ -335,7 +383,8 @@ impl UserOrganization {
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
         User::update_uuid_revision(&self.user_uuid, conn);
 
-        CollectionUser::delete_all_by_user(&self.user_uuid, &conn)?;
+        // Only the collections of this organization, the user keeps the access to the ones of the others
+        CollectionUser::delete_all_by_user_and_org(&self.user_uuid, &self.org_uuid, &conn)?;
 
         diesel::delete(users_organizations::table.filter(users_organizations::uuid.eq(self.uuid)))
             .execute(&**conn)
@@ -405,6 +454,66 @@ impl UserOrganization {
             .expect("Error loading user organizations")
     }
 
+    /// A page of the members of the organization with their users, ordered by email, and the number of members of all
+    /// the pages. Only the ones with the status when it's provided, and without an enabled two-factor method when asked
+    pub fn find_report_page(
+        org_uuid: &str,
+        status: Option<i32>,
+        without_2fa: bool,
+        limit: i64,
+        offset: i64,
+        conn: &DbConn,
+    ) -> (Vec<(Self, User)>, i64) {
+        // The count and the page need the same filters, and a boxed query can only be used once
+        let query = || {
+            let mut query = users_organizations::table
+                .inner_join(users::table.on(users::uuid.eq(users_organizations::user_uuid)))
+                .filter(users_organizations::org_uuid.eq(org_uuid))
+                .into_boxed();
+
+            if let Some(status) = status {
+                query = query.filter(users_organizations::status.eq(status));
+            }
+
+            if without_2fa {
+                // Same methods as `TwoFactor::type_name`, the remembered devices and the implementation details don't count
+                let with_2fa = twofactor::table
+                    .filter(twofactor::enabled.eq(true))
+                    .filter(twofactor::atype.lt(1000))
+                    .filter(twofactor::atype.ne(TwoFactorType::Remember as i32))
+                    .select(twofactor::user_uuid);
+                query = query.filter(diesel::dsl::not(users::uuid.eq_any(with_2fa)));
+            }
+
+            query
+        };
+
+        let total = query().count().first::<i64>(&**conn).unwrap_or(0);
+        let members = query()
+            .order(users::email.asc())
+            .limit(limit)
+            .offset(offset)
+            .load::<(Self, User)>(&**conn)
+            .expect("Error loading the members report");
+
+        (members, total)
+    }
+
+    /// Counts the memberships of the organization, only the ones with the status when it's provided
+    pub fn count_by_org(org_uuid: &str, status: Option<UserOrgStatus>, conn: &DbConn) -> i64 {
+        let query = users_organizations::table.filter(users_organizations::org_uuid.eq(org_uuid));
+
+        match status {
+            Some(status) => query
+                .filter(users_organizations::status.eq(status as i32))
+                .count()
+                .first::<i64>(&**conn),
+            None => query.count().first::<i64>(&**conn),
+        }
+        .ok()
+        .unwrap_or(0)
+    }
+
     pub fn find_by_org_and_type(org_uuid: &str, atype: i32, conn: &DbConn) -> Vec<Self> {
         users_organizations::table
             .filter(users_organizations::org_uuid.eq(org_uuid))
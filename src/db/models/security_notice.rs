@@ -0,0 +1,128 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::User;
+
+/// Security related notice shown to the user inside the clients, until they acknowledge it.
+/// They are stored even when the same notice is sent by email, so they are available to the users without email
+#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[table_name = "security_notices"]
+#[belongs_to(User, foreign_key = "user_uuid")]
+#[primary_key(uuid)]
+pub struct SecurityNotice {
+    pub uuid: String,
+    pub user_uuid: String,
+    pub atype: i32,
+    /// JSON object with the details of the notice, which depend on the type
+    pub data: String,
+    pub created_at: NaiveDateTime,
+    pub acknowledged_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+pub enum SecurityNoticeType {
+    NewDevice = 0,
+    TwoFactorChanged = 1,
+    SessionTerminated = 2,
+    TwoFactorLockout = 3,
+}
+
+/// Local methods
+impl SecurityNotice {
+    pub fn new(user_uuid: String, atype: SecurityNoticeType, data: Value) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid,
+            atype: atype as i32,
+            data: data.to_string(),
+            created_at: Utc::now().naive_utc(),
+            acknowledged_at: None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        let data: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
+
+        json!({
+            "Id": self.uuid,
+            "Type": self.atype,
+            "Data": data,
+            "CreationDate": format_date(&self.created_at),
+            "Acknowledged": self.acknowledged_at.is_some(),
+            "AcknowledgedDate": self.acknowledged_at.as_ref().map(format_date),
+            "Object": "securityNotice",
+        })
+    }
+}
+
+use crate::db::schema::security_notices;
+use crate::db::DbConn;
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl SecurityNotice {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        diesel::replace_into(security_notices::table)
+            .values(self)
+            .execute(&**conn)
+            .map_res("Error saving security notice")
+    }
+
+    /// Saves the notice, only logging the error if it fails, so that it never interrupts the request
+    pub fn save_or_log(&self, conn: &DbConn) {
+        if let Err(e) = self.save(conn) {
+            error!("Error saving security notice of type {}: {:#?}", self.atype, e);
+        }
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::delete(security_notices::table.filter(security_notices::user_uuid.eq(user_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting security notices")
+    }
+
+    pub fn find_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &DbConn) -> Option<Self> {
+        security_notices::table
+            .filter(security_notices::uuid.eq(uuid))
+            .filter(security_notices::user_uuid.eq(user_uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    /// Loads the notices not acknowledged yet, newest first
+    pub fn find_unacknowledged_by_user(user_uuid: &str, limit: i64, offset: i64, conn: &DbConn) -> Vec<Self> {
+        security_notices::table
+            .filter(security_notices::user_uuid.eq(user_uuid))
+            .filter(security_notices::acknowledged_at.is_null())
+            .order((security_notices::created_at.desc(), security_notices::uuid.asc()))
+            .limit(limit)
+            .offset(offset)
+            .load::<Self>(&**conn)
+            .expect("Error loading security notices")
+    }
+
+    pub fn acknowledge(&mut self, conn: &DbConn) -> EmptyResult {
+        if self.acknowledged_at.is_none() {
+            self.acknowledged_at = Some(Utc::now().naive_utc());
+            self.save(conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn acknowledge_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::update(
+            security_notices::table
+                .filter(security_notices::user_uuid.eq(user_uuid))
+                .filter(security_notices::acknowledged_at.is_null()),
+        )
+        .set(security_notices::acknowledged_at.eq(Utc::now().naive_utc()))
+        .execute(&**conn)
+        .map_res("Error acknowledging security notices")
+    }
+}
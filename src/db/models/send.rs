@@ -0,0 +1,306 @@
+use chrono::NaiveDateTime;
+#[cfg(feature = "sends")]
+use chrono::Utc;
+use serde_json::Value;
+
+#[cfg(feature = "sends")]
+use crate::crypto;
+#[cfg(feature = "sends")]
+use crate::CONFIG;
+
+use super::User;
+
+#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[table_name = "sends"]
+#[belongs_to(User, foreign_key = "user_uuid")]
+#[primary_key(uuid)]
+pub struct Send {
+    pub uuid: String,
+    pub user_uuid: String,
+
+    pub name: String,
+    pub notes: Option<String>,
+    pub atype: i32,
+    /// Encrypted contents of the Send, opaque to the server
+    pub data: String,
+    pub akey: String,
+
+    pub password_hash: Option<Vec<u8>>,
+    pub password_salt: Option<Vec<u8>>,
+    pub password_iter: Option<i32>,
+
+    pub max_access_count: Option<i32>,
+    pub access_count: i32,
+
+    pub creation_date: NaiveDateTime,
+    pub revision_date: NaiveDateTime,
+    pub expiration_date: Option<NaiveDateTime>,
+    pub deletion_date: NaiveDateTime,
+
+    pub disabled: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+pub enum SendType {
+    Text = 0,
+    File = 1,
+}
+
+/// Local methods
+impl Send {
+    #[cfg(feature = "sends")]
+    pub fn new(
+        user_uuid: String,
+        atype: i32,
+        name: String,
+        data: String,
+        akey: String,
+        deletion_date: NaiveDateTime,
+    ) -> Self {
+        let now = Utc::now().naive_utc();
+
+        Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid,
+
+            name,
+            notes: None,
+            atype,
+            data,
+            akey,
+
+            password_hash: None,
+            password_salt: None,
+            password_iter: None,
+
+            max_access_count: None,
+            access_count: 0,
+
+            creation_date: now,
+            revision_date: now,
+            expiration_date: None,
+            deletion_date,
+
+            disabled: false,
+        }
+    }
+
+    #[cfg(feature = "sends")]
+    pub fn set_password(&mut self, password: Option<&str>) {
+        if let Some(password) = password {
+            let salt = crypto::get_random_64();
+            let iterations = CONFIG.password_iterations();
+
+            self.password_hash = Some(crypto::hash_password(password.as_bytes(), &salt, iterations as u32));
+            self.password_salt = Some(salt);
+            self.password_iter = Some(iterations);
+        } else {
+            self.password_hash = None;
+            self.password_salt = None;
+            self.password_iter = None;
+        }
+    }
+
+    #[cfg(feature = "sends")]
+    pub fn has_password(&self) -> bool {
+        self.password_hash.is_some()
+    }
+
+    #[cfg(feature = "sends")]
+    pub fn check_password(&self, password: &str) -> bool {
+        match (&self.password_hash, &self.password_salt, self.password_iter) {
+            (Some(hash), Some(salt), Some(iter)) => {
+                crypto::verify_password_hash(password.as_bytes(), salt, hash, iter as u32)
+            }
+            _ => false,
+        }
+    }
+
+    /// The identifier used in the public Send links, the url-safe base64 encoding of the uuid bytes
+    pub fn access_id(&self) -> String {
+        use data_encoding::BASE64URL_NOPAD;
+
+        match uuid::Uuid::parse_str(&self.uuid) {
+            Ok(uuid) => BASE64URL_NOPAD.encode(uuid.as_bytes()),
+            Err(_) => self.uuid.clone(),
+        }
+    }
+
+    /// Returns whether the Send can't be accessed anymore, regardless of the access count
+    #[cfg(feature = "sends")]
+    pub fn is_unavailable(&self) -> bool {
+        let now = Utc::now().naive_utc();
+
+        self.disabled || self.deletion_date <= now || self.expiration_date.map_or(false, |exp| exp <= now)
+    }
+
+    #[cfg(feature = "sends")]
+    pub fn is_exhausted(&self) -> bool {
+        self.max_access_count.map_or(false, |max| self.access_count >= max)
+    }
+
+    /// Splits the stored data into the "Text" and "File" values, depending on the Send type
+    fn text_and_file(&self) -> (Value, Value) {
+        use num_traits::FromPrimitive;
+
+        let data = serde_json::from_str(&self.data).unwrap_or(Value::Null);
+        match SendType::from_i32(self.atype) {
+            Some(SendType::File) => (Value::Null, data),
+            _ => (data, Value::Null),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        let (text, file) = self.text_and_file();
+
+        json!({
+            "Id": self.uuid,
+            "AccessId": self.access_id(),
+            "Type": self.atype,
+
+            "Name": self.name,
+            "Notes": self.notes,
+            "Text": text,
+            "File": file,
+            "Key": self.akey,
+
+            "MaxAccessCount": self.max_access_count,
+            "AccessCount": self.access_count,
+            "Password": self.password_hash.as_ref().map(|h| data_encoding::BASE64.encode(h)),
+            "Disabled": self.disabled,
+
+            "RevisionDate": format_date(&self.revision_date),
+            "ExpirationDate": self.expiration_date.as_ref().map(format_date),
+            "DeletionDate": format_date(&self.deletion_date),
+            "Object": "send",
+        })
+    }
+
+    /// The link to share the Send. The clients append the key of the Send to it, which the server never sees
+    #[cfg(feature = "sends")]
+    pub fn access_url(&self, host: &str) -> String {
+        format!("{}/#/send/{}", host.trim_end_matches('/'), self.access_id())
+    }
+
+    /// The link along with a summary of the restrictions of the Send, for the clients to show before sharing it
+    #[cfg(feature = "sends")]
+    pub fn to_json_link(&self, host: &str) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Url": self.access_url(host),
+            "HasPassword": self.has_password(),
+            "MaxAccessCount": self.max_access_count,
+            "AccessCount": self.access_count,
+            "Disabled": self.disabled,
+            "ExpirationDate": self.expiration_date.as_ref().map(format_date),
+            "DeletionDate": format_date(&self.deletion_date),
+            "Object": "send-link",
+        })
+    }
+
+    /// The view of the Send given to the recipients accessing it, without any owner information
+    #[cfg(feature = "sends")]
+    pub fn to_json_access(&self) -> Value {
+        use crate::util::format_date;
+
+        let (text, file) = self.text_and_file();
+
+        json!({
+            "Id": self.access_id(),
+            "Type": self.atype,
+
+            "Name": self.name,
+            "Text": text,
+            "File": file,
+            "Key": self.akey,
+
+            "ExpirationDate": self.expiration_date.as_ref().map(format_date),
+            "Object": "send-access",
+        })
+    }
+}
+
+use crate::db::schema::sends;
+use crate::db::DbConn;
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl Send {
+    #[cfg(feature = "sends")]
+    pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
+        User::update_uuid_revision(&self.user_uuid, conn);
+        self.revision_date = Utc::now().naive_utc();
+
+        diesel::replace_into(sends::table)
+            .values(&*self)
+            .execute(&**conn)
+            .map_res("Error saving send")
+    }
+
+    #[cfg(feature = "sends")]
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        User::update_uuid_revision(&self.user_uuid, conn);
+
+        diesel::delete(sends::table.filter(sends::uuid.eq(self.uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting send")
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        diesel::delete(sends::table.filter(sends::user_uuid.eq(user_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting sends")
+    }
+
+    /// Counts one access to the Send, returning false when the maximum access count was already reached.
+    /// The check and the increment happen in a single statement, so concurrent accesses can't exceed the limit.
+    #[cfg(feature = "sends")]
+    pub fn increment_access_count(&mut self, conn: &DbConn) -> Result<bool, crate::error::Error> {
+        use diesel::dsl::sql;
+        use diesel::sql_types::Bool;
+
+        let updated = diesel::update(sends::table.filter(sends::uuid.eq(&self.uuid)).filter(sql::<Bool>(
+            "(max_access_count IS NULL OR access_count < max_access_count)",
+        )))
+        .set(sends::access_count.eq(sends::access_count + 1))
+        .execute(&**conn)
+        .map_res("Error updating send access count")?;
+
+        if updated == 0 {
+            return Ok(false);
+        }
+
+        User::update_uuid_revision(&self.user_uuid, conn);
+        self.access_count += 1;
+        Ok(true)
+    }
+
+    #[cfg(feature = "sends")]
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        sends::table.filter(sends::uuid.eq(uuid)).first::<Self>(&**conn).ok()
+    }
+
+    #[cfg(feature = "sends")]
+    pub fn find_by_access_id(access_id: &str, conn: &DbConn) -> Option<Self> {
+        use data_encoding::BASE64URL_NOPAD;
+
+        let bytes = BASE64URL_NOPAD.decode(access_id.as_bytes()).ok()?;
+        let uuid = uuid::Uuid::from_slice(&bytes).ok()?.to_string();
+
+        Self::find_by_uuid(&uuid, conn)
+    }
+
+    pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        sends::table
+            .filter(sends::user_uuid.eq(user_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading sends")
+    }
+}
@@ -1,3 +1,4 @@
+use chrono::{NaiveDateTime, Utc};
 use serde_json::Value;
 
 use super::User;
@@ -12,6 +13,10 @@ pub struct TwoFactor {
     pub atype: i32,
     pub enabled: bool,
     pub data: String,
+    /// Time step of the last accepted TOTP code, the codes of that step and the previous ones are rejected
+    pub last_used: i32,
+    /// When the row was created, the challenges expire from it. Unknown for the rows from before it was stored
+    pub created_at: Option<NaiveDateTime>,
 }
 
 #[allow(dead_code)]
@@ -24,14 +29,68 @@ pub enum TwoFactorType {
     U2f = 4,
     Remember = 5,
     OrganizationDuo = 6,
+    Webauthn = 7,
 
     // These are implementation details
     U2fRegisterChallenge = 1000,
     U2fLoginChallenge = 1001,
+    WebauthnRegisterChallenge = 1002,
+    WebauthnLoginChallenge = 1003,
+    EmailVerificationChallenge = 1004,
+    /// The data is the type the user prefers to be asked for when the client doesn't select one
+    PreferredProvider = 1005,
+    /// The data is a JSON list of the IP ranges where the user can skip the two-step login
+    TrustedIpRanges = 1006,
 }
 
 /// Local methods
 impl TwoFactor {
+    /// Seconds the U2F and WebAuthn challenges are kept, longer than the keys are given to answer them
+    pub const CHALLENGE_LIFETIME_SECONDS: i64 = 300;
+
+    /// Name of the two-factor method, `None` for the remembered devices and the implementation details
+    pub fn type_name(&self) -> Option<&'static str> {
+        Self::type_name_of(self.atype)
+    }
+
+    pub fn type_name_of(atype: i32) -> Option<&'static str> {
+        use num_traits::FromPrimitive;
+
+        match TwoFactorType::from_i32(atype)? {
+            TwoFactorType::Authenticator => Some("authenticator"),
+            TwoFactorType::Email => Some("email"),
+            TwoFactorType::Duo => Some("duo"),
+            TwoFactorType::YubiKey => Some("yubikey"),
+            TwoFactorType::U2f => Some("u2f"),
+            TwoFactorType::OrganizationDuo => Some("organizationDuo"),
+            TwoFactorType::Webauthn => Some("webauthn"),
+            _ => None,
+        }
+    }
+
+    /// Order in which the providers are offered when the user has no preference, the hardware keys first,
+    /// then the authenticator apps and the email last
+    pub fn provider_priority(atype: i32) -> i32 {
+        use num_traits::FromPrimitive;
+
+        match TwoFactorType::from_i32(atype) {
+            Some(TwoFactorType::Webauthn) => 0,
+            Some(TwoFactorType::U2f) => 1,
+            Some(TwoFactorType::YubiKey) => 2,
+            Some(TwoFactorType::Authenticator) => 3,
+            Some(TwoFactorType::Duo) => 4,
+            Some(TwoFactorType::OrganizationDuo) => 5,
+            Some(TwoFactorType::Email) => 6,
+            _ => 7,
+        }
+    }
+
+    /// Sorts the provider types by priority, keeping the preferred one first when it's in the list
+    pub fn sort_providers(providers: &mut Vec<i32>, preferred: Option<i32>) {
+        providers.sort_by_key(|&atype| (Some(atype) != preferred, Self::provider_priority(atype), atype));
+        providers.dedup();
+    }
+
     pub fn new(user_uuid: String, atype: TwoFactorType, data: String) -> Self {
         Self {
             uuid: crate::util::get_uuid(),
@@ -39,6 +98,8 @@ impl TwoFactor {
             atype: atype as i32,
             enabled: true,
             data,
+            last_used: 0,
+            created_at: Some(Utc::now().naive_utc()),
         }
     }
 
@@ -90,6 +151,22 @@ impl TwoFactor {
             .expect("Error loading twofactor")
     }
 
+    /// Same as `find_by_user`, for all the users at once
+    pub fn find_by_users(user_uuids: &[String], conn: &DbConn) -> Vec<Self> {
+        twofactor::table
+            .filter(twofactor::user_uuid.eq_any(user_uuids))
+            .filter(twofactor::atype.lt(1000)) // Filter implementation types
+            .load::<Self>(&**conn)
+            .expect("Error loading twofactor")
+    }
+
+    pub fn find_by_type(atype: i32, conn: &DbConn) -> Vec<Self> {
+        twofactor::table
+            .filter(twofactor::atype.eq(atype))
+            .load::<Self>(&**conn)
+            .expect("Error loading twofactor")
+    }
+
     pub fn find_by_user_and_type(user_uuid: &str, atype: i32, conn: &DbConn) -> Option<Self> {
         twofactor::table
             .filter(twofactor::user_uuid.eq(user_uuid))
@@ -98,6 +175,88 @@ impl TwoFactor {
             .ok()
     }
 
+    pub fn find_preferred_provider(user_uuid: &str, conn: &DbConn) -> Option<i32> {
+        Self::find_by_user_and_type(user_uuid, TwoFactorType::PreferredProvider as i32, conn)
+            .and_then(|tf| tf.data.parse().ok())
+    }
+
+    /// Stores the preferred provider of the user, or removes the preference with `None`
+    pub fn set_preferred_provider(user_uuid: &str, atype: Option<i32>, conn: &DbConn) -> EmptyResult {
+        let current = Self::find_by_user_and_type(user_uuid, TwoFactorType::PreferredProvider as i32, conn);
+
+        match (current, atype) {
+            (Some(mut tf), Some(atype)) => {
+                tf.data = atype.to_string();
+                tf.save(conn)
+            }
+            (None, Some(atype)) => Self::new(
+                user_uuid.to_string(),
+                TwoFactorType::PreferredProvider,
+                atype.to_string(),
+            )
+            .save(conn),
+            (Some(tf), None) => tf.delete(conn),
+            (None, None) => Ok(()),
+        }
+    }
+
+    pub fn find_trusted_ip_ranges(user_uuid: &str, conn: &DbConn) -> Vec<String> {
+        Self::find_by_user_and_type(user_uuid, TwoFactorType::TrustedIpRanges as i32, conn)
+            .and_then(|tf| serde_json::from_str(&tf.data).ok())
+            .unwrap_or_default()
+    }
+
+    /// Stores the trusted IP ranges of the user, an empty list removes them
+    pub fn set_trusted_ip_ranges(user_uuid: &str, ranges: &[String], conn: &DbConn) -> EmptyResult {
+        let current = Self::find_by_user_and_type(user_uuid, TwoFactorType::TrustedIpRanges as i32, conn);
+        if ranges.is_empty() {
+            return match current {
+                Some(tf) => tf.delete(conn),
+                None => Ok(()),
+            };
+        }
+
+        let data = serde_json::to_string(ranges)?;
+        match current {
+            Some(mut tf) => {
+                tf.data = data;
+                tf.save(conn)
+            }
+            None => Self::new(user_uuid.to_string(), TwoFactorType::TrustedIpRanges, data).save(conn),
+        }
+    }
+
+    /// Removes the U2F and WebAuthn challenges older than `CHALLENGE_LIFETIME_SECONDS`, and the email verification
+    /// challenges older than `EMAIL_2FA_CODE_MINUTES`, whose codes expired
+    pub fn delete_expired_challenges(conn: &DbConn) -> Result<usize, crate::error::Error> {
+        let now = Utc::now().naive_utc();
+        let challenge_limit = now - chrono::Duration::seconds(Self::CHALLENGE_LIFETIME_SECONDS);
+        let email_limit = now - chrono::Duration::minutes(crate::CONFIG.email_2fa_code_minutes());
+
+        let challenges = diesel::delete(
+            twofactor::table
+                .filter(twofactor::atype.eq_any(vec![
+                    TwoFactorType::U2fRegisterChallenge as i32,
+                    TwoFactorType::U2fLoginChallenge as i32,
+                    TwoFactorType::WebauthnRegisterChallenge as i32,
+                    TwoFactorType::WebauthnLoginChallenge as i32,
+                ]))
+                .filter(twofactor::created_at.lt(challenge_limit).or(twofactor::created_at.is_null())),
+        )
+        .execute(&**conn)
+        .map_res("Error deleting expired challenges")?;
+
+        let email_challenges = diesel::delete(
+            twofactor::table
+                .filter(twofactor::atype.eq(TwoFactorType::EmailVerificationChallenge as i32))
+                .filter(twofactor::created_at.lt(email_limit).or(twofactor::created_at.is_null())),
+        )
+        .execute(&**conn)
+        .map_res("Error deleting expired email verification challenges")?;
+
+        Ok(challenges + email_challenges)
+    }
+
     pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
         diesel::delete(twofactor::table.filter(twofactor::user_uuid.eq(user_uuid)))
             .execute(&**conn)
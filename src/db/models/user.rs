@@ -35,6 +35,64 @@ pub struct User {
 
     pub client_kdf_type: i32,
     pub client_kdf_iter: i32,
+
+    /// Date after which the login is blocked until a second factor is enrolled, used by `REQUIRE_2FA`
+    pub twofactor_enroll_deadline: Option<NaiveDateTime>,
+
+    /// Preferred locale, used for the emails. When unset, the one requested by the client is used
+    pub culture: Option<String>,
+
+    /// Nonce of the last requested magic link, cleared once it's used so that it only works once
+    pub magic_link_nonce: Option<String>,
+    pub magic_link_sent_at: Option<NaiveDateTime>,
+
+    /// The key of the user is kept by the Key Connector of their organization, instead of being derived from a master password
+    pub uses_key_connector: bool,
+
+    /// Consecutive wrong second factors, the login is blocked until `twofactor_locked_until` once they reach `TWOFACTOR_LOCKOUT_ATTEMPTS`
+    pub twofactor_failures: i32,
+    pub twofactor_locked_until: Option<NaiveDateTime>,
+
+    /// Secret of the personal API key, used with the `client_credentials` grant and the client id from `api_client_id`
+    pub api_key: Option<String>,
+
+    /// JSON list of the recent login locations from the GeoIP database, with the last time they were seen
+    pub login_locations: Option<String>,
+
+    /// The disabled users can't log in, and their sessions stop working. Only an admin can enable them again
+    pub enabled: bool,
+    /// When the user was warned that the account would be disabled for being inactive
+    pub inactivity_warned_at: Option<NaiveDateTime>,
+    /// When an admin last enabled the user, it counts as activity so the user isn't disabled again right away
+    pub enabled_at: Option<NaiveDateTime>,
+
+    /// JSON list of the previous password hashes with their iterations, kept when a master password policy prevents the reuse
+    pub password_history: Option<String>,
+    /// When the master password was last set, to know if it predates a change of the master password policies
+    pub password_changed_at: Option<NaiveDateTime>,
+
+    /// The `PasswordAlgorithm` of `password_hash`. With Argon2id, `password_iterations` is its time cost
+    pub password_algorithm: i32,
+
+    /// The `TWOFACTOR_ENROLL_*` requirements that applied when `twofactor_enroll_deadline` was last checked
+    pub twofactor_enroll_reasons: i32,
+}
+
+/// The server-side hash of the password hash sent by the clients
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+pub enum PasswordAlgorithm {
+    Pbkdf2 = 0,
+    Argon2id = 1,
+}
+
+impl PasswordAlgorithm {
+    /// The one used for the new and changed passwords, with `PASSWORD_ALGORITHM`
+    pub fn configured() -> Self {
+        match CONFIG.password_algorithm().as_str() {
+            "argon2id" => PasswordAlgorithm::Argon2id,
+            _ => PasswordAlgorithm::Pbkdf2,
+        }
+    }
 }
 
 enum UserStatus {
@@ -47,6 +105,20 @@ enum UserStatus {
 impl User {
     pub const CLIENT_KDF_TYPE_DEFAULT: i32 = 0; // PBKDF2: 0
     pub const CLIENT_KDF_ITER_DEFAULT: i32 = 5_000;
+    /// The iterations the current clients register the new accounts with
+    pub const CLIENT_KDF_ITER_REGISTER: i32 = 100_000;
+
+    /// The requirements of a second factor, in `twofactor_enroll_reasons`
+    pub const TWOFACTOR_ENROLL_SERVER: i32 = 1; // REQUIRE_2FA
+    pub const TWOFACTOR_ENROLL_ORG_ADMINS: i32 = 2; // REQUIRE_2FA_ORG_ADMINS
+
+    /// The login locations are forgotten after these days, or once there are too many newer ones
+    const LOGIN_LOCATION_DAYS: i64 = 90;
+    const LOGIN_LOCATION_MAX: usize = 10;
+
+    /// Maximum number of previous passwords that can be remembered to prevent their reuse.
+    /// Each one is hashed again to compare it on every password change, so the list is kept short
+    pub const PASSWORD_HISTORY_MAX: usize = 5;
 
     pub fn new(mail: String) -> Self {
         let now = Utc::now().naive_utc();
@@ -78,16 +150,70 @@ impl User {
 
             client_kdf_type: Self::CLIENT_KDF_TYPE_DEFAULT,
             client_kdf_iter: Self::CLIENT_KDF_ITER_DEFAULT,
+
+            twofactor_enroll_deadline: None,
+
+            culture: None,
+
+            magic_link_nonce: None,
+            magic_link_sent_at: None,
+
+            uses_key_connector: false,
+
+            twofactor_failures: 0,
+            twofactor_locked_until: None,
+
+            api_key: None,
+
+            login_locations: None,
+
+            enabled: true,
+            inactivity_warned_at: None,
+            enabled_at: None,
+
+            password_history: None,
+            password_changed_at: None,
+
+            password_algorithm: PasswordAlgorithm::Pbkdf2 as i32,
+
+            twofactor_enroll_reasons: 0,
         }
     }
 
     pub fn check_valid_password(&self, password: &str) -> bool {
-        crypto::verify_password_hash(
-            password.as_bytes(),
-            &self.salt,
-            &self.password_hash,
-            self.password_iterations as u32,
-        )
+        use num_traits::FromPrimitive;
+
+        match PasswordAlgorithm::from_i32(self.password_algorithm) {
+            Some(PasswordAlgorithm::Argon2id) => {
+                crypto::verify_password_argon2id(password.as_bytes(), &self.password_hash)
+            }
+            _ => crypto::verify_password_hash(
+                password.as_bytes(),
+                &self.salt,
+                &self.password_hash,
+                self.password_iterations as u32,
+            ),
+        }
+    }
+
+    /// Sets the enrollment deadline for the requirements of a second factor that apply now. The grace period starts
+    /// again when one of them didn't apply before, so joining an organization with the policy doesn't block the login
+    /// right away, and it's never shortened. Returns whether the user needs to be saved
+    pub fn update_twofactor_enroll_deadline(&mut self, reasons: i32, now: NaiveDateTime) -> bool {
+        let started = match self.twofactor_enroll_deadline {
+            None => true,
+            // Set before the requirements were remembered
+            Some(_) if self.twofactor_enroll_reasons == 0 => false,
+            Some(_) => reasons & !self.twofactor_enroll_reasons != 0,
+        };
+        let changed = started || reasons != self.twofactor_enroll_reasons;
+
+        if started {
+            let deadline = now + chrono::Duration::days(CONFIG.require_2fa_grace_days());
+            self.twofactor_enroll_deadline = Some(self.twofactor_enroll_deadline.map_or(deadline, |d| d.max(deadline)));
+        }
+        self.twofactor_enroll_reasons = reasons;
+        changed
     }
 
     pub fn check_valid_recovery_code(&self, recovery_code: &str) -> bool {
@@ -98,22 +224,238 @@ impl User {
         }
     }
 
+    fn password_history(&self) -> Vec<(String, i32)> {
+        self.password_history
+            .as_ref()
+            .and_then(|h| serde_json::from_str(h).ok())
+            .unwrap_or_default()
+    }
+
+    /// Whether the password is the current one or one of the `count` most recent previous ones.
+    /// The hashes derived with other KDF settings can't be compared, so those passwords aren't found
+    pub fn is_password_reused(&self, password: &str, count: usize) -> bool {
+        use data_encoding::BASE64;
+
+        let count = std::cmp::min(count, Self::PASSWORD_HISTORY_MAX);
+        self.check_valid_password(password)
+            || self.password_history().iter().take(count).any(|(hash, iterations)| {
+                let hash = match BASE64.decode(hash.as_bytes()) {
+                    Ok(hash) => hash,
+                    Err(_) => return false,
+                };
+                if crypto::is_argon2id_hash(&hash) {
+                    crypto::verify_password_argon2id(password.as_bytes(), &hash)
+                } else {
+                    *iterations > 0
+                        && crypto::verify_password_hash(password.as_bytes(), &self.salt, &hash, *iterations as u32)
+                }
+            })
+    }
+
+    /// Remembers the current password before it's replaced, keeping the `count` most recent ones. Nothing is kept with 0
+    pub fn update_password_history(&mut self, count: usize) {
+        use data_encoding::BASE64;
+
+        let count = std::cmp::min(count, Self::PASSWORD_HISTORY_MAX);
+        if count == 0 {
+            self.password_history = None;
+            return;
+        }
+
+        let mut history = self.password_history();
+        history.insert(0, (BASE64.encode(&self.password_hash), self.password_iterations));
+        history.truncate(count);
+        self.password_history = serde_json::to_string(&history).ok();
+    }
+
+    /// The new password is hashed with the currently configured server-side algorithm and parameters
     pub fn set_password(&mut self, password: &str) {
-        self.password_hash = crypto::hash_password(password.as_bytes(), &self.salt, self.password_iterations as u32);
+        self.hash_password(password);
+        self.password_changed_at = Some(Utc::now().naive_utc());
+    }
+
+    fn hash_password(&mut self, password: &str) {
+        let algorithm = PasswordAlgorithm::configured();
+        self.password_algorithm = algorithm as i32;
+
+        self.password_hash = match algorithm {
+            PasswordAlgorithm::Pbkdf2 => {
+                self.password_iterations = CONFIG.password_iterations();
+                crypto::hash_password(password.as_bytes(), &self.salt, self.password_iterations as u32)
+            }
+            PasswordAlgorithm::Argon2id => {
+                self.password_iterations = CONFIG.argon2_iterations() as i32;
+                crypto::hash_password_argon2id(
+                    password.as_bytes(),
+                    &self.salt,
+                    CONFIG.argon2_memory_kib(),
+                    CONFIG.argon2_iterations(),
+                    CONFIG.argon2_parallelism(),
+                )
+            }
+        };
+    }
+
+    /// With `PASSWORD_ALGORITHM_UPGRADE`, hashes the password again when its algorithm is weaker than the configured.
+    /// It's still the same password, so it doesn't count as a change. Returns whether it was upgraded
+    pub fn upgrade_password_hash(&mut self, password: &str) -> bool {
+        if !CONFIG.password_algorithm_upgrade() || !self.is_password_hash_upgradable(PasswordAlgorithm::configured()) {
+            return false;
+        }
+
+        self.hash_password(password);
+        true
+    }
+
+    /// Only PBKDF2 is replaced by Argon2id, going back to PBKDF2 would weaken the hash
+    fn is_password_hash_upgradable(&self, configured: PasswordAlgorithm) -> bool {
+        self.password_algorithm == PasswordAlgorithm::Pbkdf2 as i32 && configured == PasswordAlgorithm::Argon2id
+    }
+
+    /// Whether the KDF parameters of the user are weaker than the current configuration, they are upgraded when the client
+    /// changes the KDF settings. The accounts created before the client iterations were stored use the default ones
+    pub fn kdf_upgrade_needed(&self) -> bool {
+        let client_iter = if self.client_kdf_iter > 0 {
+            self.client_kdf_iter
+        } else {
+            Self::CLIENT_KDF_ITER_DEFAULT
+        };
+
+        // Only PBKDF2 iterations can be compared
+        let client_weaker =
+            self.client_kdf_type == Self::CLIENT_KDF_TYPE_DEFAULT && client_iter < CONFIG.client_kdf_iter_minimum();
+        // The Argon2id hashes don't have comparable iterations, and the PBKDF2 ones are upgraded on login with it
+        let server_weaker = self.password_algorithm == PasswordAlgorithm::Pbkdf2 as i32
+            && PasswordAlgorithm::configured() == PasswordAlgorithm::Pbkdf2
+            && self.password_iterations < CONFIG.password_iterations();
+
+        client_weaker || server_weaker
+    }
+
+    /// Returns the preferred locale of the user, or the provided fallback when it's not set
+    pub fn locale<'a>(&'a self, fallback: &'a str) -> &'a str {
+        self.culture.as_ref().map(String::as_str).unwrap_or(fallback)
+    }
+
+    /// Number of seconds until the user can try the second factor again, if they are locked out
+    pub fn twofactor_lockout_seconds(&self) -> Option<i64> {
+        let now = Utc::now().naive_utc();
+        match self.twofactor_locked_until {
+            Some(until) if until > now => Some(until.signed_duration_since(now).num_seconds() + 1),
+            _ => None,
+        }
+    }
+
+    /// Counts a wrong second factor, returning whether it locked the user out. The count starts over after a lockout
+    pub fn register_twofactor_failure(&mut self) -> bool {
+        let attempts = CONFIG.twofactor_lockout_attempts();
+        if attempts == 0 {
+            return false;
+        }
+
+        self.twofactor_failures += 1;
+        if (self.twofactor_failures as u64) < attempts {
+            return false;
+        }
+
+        let duration = chrono::Duration::seconds(CONFIG.twofactor_lockout_seconds() as i64);
+        self.twofactor_locked_until = Some(Utc::now().naive_utc() + duration);
+        self.twofactor_failures = 0;
+        true
+    }
+
+    /// Forgets the wrong second factors after a successful one, returning whether there were any
+    pub fn reset_twofactor_failures(&mut self) -> bool {
+        let changed = self.twofactor_failures != 0 || self.twofactor_locked_until.is_some();
+        self.twofactor_failures = 0;
+        self.twofactor_locked_until = None;
+        changed
     }
 
     pub fn reset_security_stamp(&mut self) {
         self.security_stamp = crate::util::get_uuid();
     }
+
+    /// Starts a new magic link, invalidating the previous one, and returns its nonce
+    pub fn new_magic_link_nonce(&mut self) -> String {
+        use data_encoding::HEXLOWER;
+
+        let nonce = HEXLOWER.encode(&crypto::get_random(vec![0u8; 16]));
+        self.magic_link_nonce = Some(nonce.clone());
+        self.magic_link_sent_at = Some(Utc::now().naive_utc());
+        nonce
+    }
+
+    pub fn check_magic_link_nonce(&self, nonce: &str) -> bool {
+        match self.magic_link_nonce {
+            Some(ref saved) => crypto::ct_eq(saved, nonce),
+            None => false,
+        }
+    }
+
+    /// The client id of the API key, in the same format as the official server
+    pub fn api_client_id(&self) -> String {
+        format!("user.{}", self.uuid)
+    }
+
+    /// Generates a new API key secret, replacing the previous one, and returns it
+    pub fn new_api_key(&mut self) -> String {
+        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+        let key: String = crypto::get_random(vec![0u8; 30])
+            .iter()
+            .map(|b| CHARSET[*b as usize % CHARSET.len()] as char)
+            .collect();
+        self.api_key = Some(key.clone());
+        key
+    }
+
+    /// Identifies the current API key in its access tokens, so that they stop working once it's rotated
+    pub fn api_key_id(&self) -> Option<String> {
+        self.api_key.as_ref().map(|key| crypto::sha256_hex(key.as_bytes())[..16].to_string())
+    }
+
+    pub fn check_api_key(&self, secret: &str) -> bool {
+        match self.api_key {
+            Some(ref key) => crypto::ct_eq(key, secret),
+            None => false,
+        }
+    }
+
+    /// Remembers a login from the location, returning whether it's unusual: the recent logins were all from other locations.
+    /// Without recent logins there is nothing to compare with, so it isn't
+    pub fn register_login_location(&mut self, location: &str) -> bool {
+        let now = Utc::now().naive_utc().timestamp();
+        let forget_before = now - Self::LOGIN_LOCATION_DAYS * 86_400;
+
+        let mut locations: Vec<(String, i64)> = self
+            .login_locations
+            .as_ref()
+            .and_then(|l| serde_json::from_str(l).ok())
+            .unwrap_or_default();
+        locations.retain(|(_, seen)| *seen > forget_before);
+
+        let unusual = !locations.is_empty() && locations.iter().all(|(l, _)| l != location);
+
+        locations.retain(|(l, _)| l != location);
+        locations.insert(0, (location.to_string(), now));
+        locations.truncate(Self::LOGIN_LOCATION_MAX);
+        self.login_locations = serde_json::to_string(&locations).ok();
+
+        unusual
+    }
 }
 
-use super::{Cipher, Device, Folder, TwoFactor, UserOrgType, UserOrganization};
+use super::{
+    Cipher, CipherTransfer, Device, DeviceApprovalRequest, EmergencyAccess, Event, Folder, SecurityNotice, Send,
+    TwoFactor, UserOrgType, UserOrganization,
+};
 use crate::db::schema::{invitations, users};
 use crate::db::DbConn;
 use diesel;
 use diesel::prelude::*;
 
-use crate::api::EmptyResult;
+use crate::api::{ApiResult, EmptyResult};
 use crate::error::MapResult;
 
 /// Database methods
@@ -132,17 +474,19 @@ impl User {
 
         json!({
             "_Status": status as i32,
+            "_Enabled": self.enabled,
             "Id": self.uuid,
             "Name": self.name,
             "Email": self.email,
             "EmailVerified": true,
             "Premium": true,
             "MasterPasswordHint": self.password_hint,
-            "Culture": "en-US",
+            "Culture": self.locale(crate::util::DEFAULT_LOCALE),
             "TwoFactorEnabled": twofactor_enabled,
             "Key": self.akey,
             "PrivateKey": self.private_key,
             "SecurityStamp": self.security_stamp,
+            "UsesKeyConnector": self.uses_key_connector,
             "Organizations": orgs_json,
             "Object": "profile"
         })
@@ -161,6 +505,14 @@ impl User {
             .map_res("Error saving user")
     }
 
+    /// Only saves the login locations, a login doesn't change the revision of the user
+    pub fn save_login_locations(&self, conn: &DbConn) -> EmptyResult {
+        diesel::update(users::table.filter(users::uuid.eq(&self.uuid)))
+            .set(users::login_locations.eq(&self.login_locations))
+            .execute(&**conn)
+            .map_res("Error saving the login locations")
+    }
+
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
         for user_org in UserOrganization::find_by_user(&self.uuid, &*conn) {
             if user_org.atype == UserOrgType::Owner {
@@ -172,10 +524,16 @@ impl User {
         }
 
         UserOrganization::delete_all_by_user(&self.uuid, &*conn)?;
+        CipherTransfer::delete_all_by_user(&self.uuid, &*conn)?;
         Cipher::delete_all_by_user(&self.uuid, &*conn)?;
         Folder::delete_all_by_user(&self.uuid, &*conn)?;
+        Send::delete_all_by_user(&self.uuid, &*conn)?;
+        EmergencyAccess::delete_all_by_user(&self.uuid, &*conn)?;
+        DeviceApprovalRequest::delete_all_by_user(&self.uuid, &*conn)?;
         Device::delete_all_by_user(&self.uuid, &*conn)?;
         TwoFactor::delete_all_by_user(&self.uuid, &*conn)?;
+        Event::delete_all_by_user(&self.uuid, &*conn)?;
+        SecurityNotice::delete_all_by_user(&self.uuid, &*conn)?;
         Invitation::take(&self.email, &*conn); // Delete invitation if any
 
         diesel::delete(users::table.filter(users::uuid.eq(self.uuid)))
@@ -189,6 +547,7 @@ impl User {
         }
     }
 
+    #[cfg(feature = "admin")]
     pub fn update_all_revisions(conn: &DbConn) -> EmptyResult {
         let updated_at = Utc::now().naive_utc();
 
@@ -221,6 +580,31 @@ impl User {
         .map_res("Error updating user revision")
     }
 
+    /// Records the inactivity warning without changing the revision of the user, it's not visible to the clients
+    pub fn set_inactivity_warned_at(&mut self, date: NaiveDateTime, conn: &DbConn) -> EmptyResult {
+        diesel::update(users::table.filter(users::uuid.eq(&self.uuid)))
+            .set(users::inactivity_warned_at.eq(Some(date)))
+            .execute(&**conn)
+            .map_res("Error saving the inactivity warning")?;
+
+        self.inactivity_warned_at = Some(date);
+        Ok(())
+    }
+
+    /// Clears the nonce of the magic link if it's still the same, returning false when it was already used.
+    /// It's done in a single statement, so that the link can't be used twice even with concurrent requests
+    pub fn take_magic_link_nonce(&self, nonce: &str, conn: &DbConn) -> bool {
+        diesel::update(
+            users::table
+                .filter(users::uuid.eq(&self.uuid))
+                .filter(users::magic_link_nonce.eq(nonce)),
+        )
+        .set(users::magic_link_nonce.eq(None::<String>))
+        .execute(&**conn)
+        .map(|updated| updated == 1)
+        .unwrap_or(false)
+    }
+
     pub fn find_by_mail(mail: &str, conn: &DbConn) -> Option<Self> {
         let lower_mail = mail.to_lowercase();
         users::table
@@ -233,9 +617,36 @@ impl User {
         users::table.filter(users::uuid.eq(uuid)).first::<Self>(&**conn).ok()
     }
 
+    /// Finds the user of an API key client id, without checking the secret
+    pub fn find_by_api_client_id(client_id: &str, conn: &DbConn) -> Option<Self> {
+        if !client_id.starts_with("user.") {
+            return None;
+        }
+        Self::find_by_uuid(&client_id[5..], conn).filter(|u| u.api_key.is_some())
+    }
+
     pub fn get_all(conn: &DbConn) -> Vec<Self> {
         users::table.load::<Self>(&**conn).expect("Error loading users")
     }
+
+    /// The enabled users created before the date, the candidates to be disabled for being inactive.
+    /// The invited users who haven't registered yet don't have a password, unless they use Key Connector
+    pub fn find_enabled_created_before(date: NaiveDateTime, conn: &DbConn) -> Vec<Self> {
+        users::table
+            .filter(users::enabled.eq(true))
+            .filter(users::created_at.lt(date))
+            .filter(
+                users::password_hash
+                    .ne(Vec::<u8>::new())
+                    .or(users::uses_key_connector.eq(true)),
+            )
+            .load::<Self>(&**conn)
+            .expect("Error loading users")
+    }
+
+    pub fn count(conn: &DbConn) -> ApiResult<i64> {
+        users::table.count().first::<i64>(&**conn).map_res("Error counting users")
+    }
 }
 
 #[derive(Debug, Identifiable, Queryable, Insertable)]
@@ -283,3 +694,80 @@ impl Invitation {
             }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_with_hash(algorithm: PasswordAlgorithm) -> User {
+        let mut user = User::new("user@example.com".into());
+        user.password_algorithm = algorithm as i32;
+        user.password_iterations = 1_000;
+        user.password_hash = match algorithm {
+            PasswordAlgorithm::Pbkdf2 => crypto::hash_password(b"password", &user.salt, 1_000),
+            PasswordAlgorithm::Argon2id => crypto::hash_password_argon2id(b"password", &user.salt, 8, 1, 1),
+        };
+        user
+    }
+
+    #[test]
+    fn passwords_are_checked_with_their_own_algorithm() {
+        for algorithm in &[PasswordAlgorithm::Pbkdf2, PasswordAlgorithm::Argon2id] {
+            let user = user_with_hash(*algorithm);
+            assert!(user.check_valid_password("password"));
+            assert!(!user.check_valid_password("other password"));
+        }
+    }
+
+    #[test]
+    fn hashes_with_the_wrong_algorithm_are_rejected() {
+        let mut user = user_with_hash(PasswordAlgorithm::Argon2id);
+        user.password_algorithm = PasswordAlgorithm::Pbkdf2 as i32;
+        assert!(!user.check_valid_password("password"));
+
+        let mut user = user_with_hash(PasswordAlgorithm::Pbkdf2);
+        user.password_algorithm = PasswordAlgorithm::Argon2id as i32;
+        assert!(!user.check_valid_password("password"));
+    }
+
+    #[test]
+    fn twofactor_enroll_deadline_restarts_for_new_requirements() {
+        let now = Utc::now().naive_utc();
+        let mut user = User::new("user@example.com".into());
+
+        assert!(user.update_twofactor_enroll_deadline(User::TWOFACTOR_ENROLL_ORG_ADMINS, now));
+        let first = user.twofactor_enroll_deadline.unwrap();
+        assert!(first > now);
+
+        // The same requirements keep the deadline
+        let later = now + chrono::Duration::days(30);
+        assert!(!user.update_twofactor_enroll_deadline(User::TWOFACTOR_ENROLL_ORG_ADMINS, later));
+        assert_eq!(user.twofactor_enroll_deadline, Some(first));
+
+        let reasons = User::TWOFACTOR_ENROLL_ORG_ADMINS | User::TWOFACTOR_ENROLL_SERVER;
+        assert!(user.update_twofactor_enroll_deadline(reasons, later));
+        assert!(user.twofactor_enroll_deadline.unwrap() > later);
+        assert_eq!(user.twofactor_enroll_reasons, reasons);
+    }
+
+    #[test]
+    fn twofactor_enroll_deadline_is_kept_when_set_before_the_reasons() {
+        let now = Utc::now().naive_utc();
+        let mut user = User::new("user@example.com".into());
+        user.twofactor_enroll_deadline = Some(now);
+
+        assert!(user.update_twofactor_enroll_deadline(User::TWOFACTOR_ENROLL_SERVER, now));
+        assert_eq!(user.twofactor_enroll_deadline, Some(now));
+    }
+
+    #[test]
+    fn only_pbkdf2_hashes_are_upgraded_to_argon2id() {
+        let pbkdf2 = user_with_hash(PasswordAlgorithm::Pbkdf2);
+        assert!(pbkdf2.is_password_hash_upgradable(PasswordAlgorithm::Argon2id));
+        assert!(!pbkdf2.is_password_hash_upgradable(PasswordAlgorithm::Pbkdf2));
+
+        let argon2id = user_with_hash(PasswordAlgorithm::Argon2id);
+        assert!(!argon2id.is_password_hash_upgradable(PasswordAlgorithm::Pbkdf2));
+        assert!(!argon2id.is_password_hash_upgradable(PasswordAlgorithm::Argon2id));
+    }
+}
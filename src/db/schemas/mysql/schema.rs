@@ -8,6 +8,33 @@ table! {
     }
 }
 
+table! {
+    cipher_revisions (uuid) {
+        uuid -> Varchar,
+        cipher_uuid -> Varchar,
+        organization_uuid -> Nullable<Varchar>,
+        atype -> Integer,
+        name -> Text,
+        notes -> Nullable<Text>,
+        fields -> Nullable<Text>,
+        data -> Text,
+        password_history -> Nullable<Text>,
+        revision_date -> Datetime,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    cipher_transfers (uuid) {
+        uuid -> Varchar,
+        cipher_uuid -> Varchar,
+        sender_uuid -> Varchar,
+        recipient_uuid -> Varchar,
+        data -> Text,
+        created_at -> Datetime,
+    }
+}
+
 table! {
     ciphers (uuid) {
         uuid -> Varchar,
@@ -40,6 +67,17 @@ table! {
     }
 }
 
+table! {
+    device_approval_requests (uuid) {
+        uuid -> Varchar,
+        user_uuid -> Varchar,
+        org_uuid -> Varchar,
+        device_uuid -> Varchar,
+        ip_address -> Text,
+        created_at -> Datetime,
+    }
+}
+
 table! {
     devices (uuid) {
         uuid -> Varchar,
@@ -51,6 +89,48 @@ table! {
         push_token -> Nullable<Text>,
         refresh_token -> Text,
         twofactor_remember -> Nullable<Text>,
+        refresh_ip -> Nullable<Text>,
+        encrypted_user_key -> Nullable<Text>,
+        encrypted_public_key -> Nullable<Text>,
+        encrypted_private_key -> Nullable<Text>,
+        approved -> Bool,
+        refresh_family -> Nullable<Text>,
+        refresh_generation -> Integer,
+        previous_refresh_token -> Nullable<Text>,
+        refresh_rotated_at -> Nullable<Datetime>,
+        last_used_ip -> Nullable<Text>,
+        twofactor_remember_expires_at -> Nullable<Datetime>,
+        approval_token -> Nullable<Text>,
+    }
+}
+
+table! {
+    emergency_access (uuid) {
+        uuid -> Varchar,
+        grantor_uuid -> Varchar,
+        grantee_uuid -> Varchar,
+        key_encrypted -> Nullable<Text>,
+        atype -> Integer,
+        status -> Integer,
+        wait_time_days -> Integer,
+        recovery_initiated_at -> Nullable<Datetime>,
+        created_at -> Datetime,
+        updated_at -> Datetime,
+    }
+}
+
+table! {
+    event (uuid) {
+        uuid -> Varchar,
+        event_type -> Integer,
+        user_uuid -> Nullable<Varchar>,
+        org_uuid -> Nullable<Varchar>,
+        cipher_uuid -> Nullable<Varchar>,
+        device_uuid -> Nullable<Varchar>,
+        device_type -> Nullable<Integer>,
+        ip_address -> Nullable<Text>,
+        event_date -> Datetime,
+        act_user_uuid -> Nullable<Varchar>,
     }
 }
 
@@ -77,11 +157,56 @@ table! {
     }
 }
 
+table! {
+    org_policies (uuid) {
+        uuid -> Varchar,
+        org_uuid -> Varchar,
+        atype -> Integer,
+        enabled -> Bool,
+        data -> Text,
+        updated_at -> Nullable<Datetime>,
+    }
+}
+
 table! {
     organizations (uuid) {
         uuid -> Varchar,
         name -> Text,
         billing_email -> Text,
+        identifier -> Nullable<Text>,
+    }
+}
+
+table! {
+    security_notices (uuid) {
+        uuid -> Varchar,
+        user_uuid -> Varchar,
+        atype -> Integer,
+        data -> Text,
+        created_at -> Datetime,
+        acknowledged_at -> Nullable<Datetime>,
+    }
+}
+
+table! {
+    sends (uuid) {
+        uuid -> Varchar,
+        user_uuid -> Varchar,
+        name -> Text,
+        notes -> Nullable<Text>,
+        atype -> Integer,
+        data -> Text,
+        akey -> Text,
+        password_hash -> Nullable<Blob>,
+        password_salt -> Nullable<Blob>,
+        password_iter -> Nullable<Integer>,
+        max_access_count -> Nullable<Integer>,
+        access_count -> Integer,
+        creation_date -> Datetime,
+        revision_date -> Datetime,
+        expiration_date -> Nullable<Datetime>,
+        deletion_date -> Datetime,
+        disabled -> Bool,
     }
 }
 
@@ -92,6 +217,8 @@ table! {
         atype -> Integer,
         enabled -> Bool,
         data -> Text,
+        last_used -> Integer,
+        created_at -> Nullable<Datetime>,
     }
 }
 
@@ -116,6 +243,22 @@ table! {
         excluded_globals -> Text,
         client_kdf_type -> Integer,
         client_kdf_iter -> Integer,
+        twofactor_enroll_deadline -> Nullable<Datetime>,
+        culture -> Nullable<Text>,
+        magic_link_nonce -> Nullable<Text>,
+        magic_link_sent_at -> Nullable<Datetime>,
+        uses_key_connector -> Bool,
+        twofactor_failures -> Integer,
+        twofactor_locked_until -> Nullable<Datetime>,
+        api_key -> Nullable<Text>,
+        login_locations -> Nullable<Text>,
+        enabled -> Bool,
+        inactivity_warned_at -> Nullable<Datetime>,
+        enabled_at -> Nullable<Datetime>,
+        password_history -> Nullable<Text>,
+        password_changed_at -> Nullable<Datetime>,
+        password_algorithm -> Integer,
+        twofactor_enroll_reasons -> Integer,
     }
 }
 
@@ -124,6 +267,7 @@ table! {
         user_uuid -> Varchar,
         collection_uuid -> Varchar,
         read_only -> Bool,
+        can_delete -> Bool,
     }
 }
 
@@ -140,15 +284,22 @@ table! {
 }
 
 joinable!(attachments -> ciphers (cipher_uuid));
+joinable!(cipher_revisions -> ciphers (cipher_uuid));
+joinable!(cipher_transfers -> ciphers (cipher_uuid));
 joinable!(ciphers -> organizations (organization_uuid));
 joinable!(ciphers -> users (user_uuid));
 joinable!(ciphers_collections -> ciphers (cipher_uuid));
 joinable!(ciphers_collections -> collections (collection_uuid));
 joinable!(collections -> organizations (org_uuid));
+joinable!(device_approval_requests -> organizations (org_uuid));
+joinable!(device_approval_requests -> users (user_uuid));
 joinable!(devices -> users (user_uuid));
 joinable!(folders -> users (user_uuid));
 joinable!(folders_ciphers -> ciphers (cipher_uuid));
 joinable!(folders_ciphers -> folders (folder_uuid));
+joinable!(org_policies -> organizations (org_uuid));
+joinable!(security_notices -> users (user_uuid));
+joinable!(sends -> users (user_uuid));
 joinable!(twofactor -> users (user_uuid));
 joinable!(users_collections -> collections (collection_uuid));
 joinable!(users_collections -> users (user_uuid));
@@ -157,14 +308,22 @@ joinable!(users_organizations -> users (user_uuid));
 
 allow_tables_to_appear_in_same_query!(
     attachments,
+    cipher_revisions,
+    cipher_transfers,
     ciphers,
     ciphers_collections,
     collections,
+    device_approval_requests,
     devices,
+    emergency_access,
+    event,
     folders,
     folders_ciphers,
     invitations,
+    org_policies,
     organizations,
+    security_notices,
+    sends,
     twofactor,
     users,
     users_collections,
@@ -9,14 +9,14 @@ macro_rules! make_error {
 
         #[derive(Display)]
         pub enum ErrorKind { $($name( $ty )),+ }
-        pub struct Error { message: String, error: ErrorKind, error_code: u16 }
+        pub struct Error { message: String, error: ErrorKind, error_code: u16, retry_after: Option<i64>, api_code: Option<ErrorCode> }
 
         $(impl From<$ty> for Error {
             fn from(err: $ty) -> Self { Error::from((stringify!($name), err)) }
         })+
         $(impl<S: Into<String>> From<(S, $ty)> for Error {
             fn from(val: (S, $ty)) -> Self {
-                Error { message: val.0.into(), error: ErrorKind::$name(val.1), error_code: BAD_REQUEST }
+                Error { message: val.0.into(), error: ErrorKind::$name(val.1), error_code: BAD_REQUEST, retry_after: None, api_code: None }
             }
         })+
         impl StdError for Error {
@@ -50,6 +50,39 @@ use yubico::yubicoerror::YubicoError as YubiErr;
 #[derive(Display, Serialize)]
 pub struct Empty {}
 
+/// Stable identifiers of the errors for the programs using the API, sent as `error_code` in the body.
+/// The messages can change, these don't
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ErrorCode {
+    InvalidCredentials,
+    TwoFactorRequired,
+    InvalidTwoFactorCode,
+    TwoFactorEnrollmentRequired,
+    AccountLocked,
+    AccountDisabled,
+    RateLimited,
+    CaptchaRequired,
+    DeviceApprovalRequired,
+    InvalidRefreshToken,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidCredentials => "INVALID_CREDENTIALS",
+            ErrorCode::TwoFactorRequired => "TWO_FACTOR_REQUIRED",
+            ErrorCode::InvalidTwoFactorCode => "INVALID_TWO_FACTOR_CODE",
+            ErrorCode::TwoFactorEnrollmentRequired => "TWO_FACTOR_ENROLLMENT_REQUIRED",
+            ErrorCode::AccountLocked => "ACCOUNT_LOCKED",
+            ErrorCode::AccountDisabled => "ACCOUNT_DISABLED",
+            ErrorCode::RateLimited => "RATE_LIMITED",
+            ErrorCode::CaptchaRequired => "CAPTCHA_REQUIRED",
+            ErrorCode::DeviceApprovalRequired => "DEVICE_APPROVAL_REQUIRED",
+            ErrorCode::InvalidRefreshToken => "INVALID_REFRESH_TOKEN",
+        }
+    }
+}
+
 // Error struct
 // Contains a String error message, meant for the user and an enum variant, with an error of different types.
 //
@@ -109,6 +142,31 @@ impl Error {
         self.error_code = code;
         self
     }
+
+    /// Sends the `Retry-After` header with the error, with the number of seconds the client should wait
+    pub fn with_retry_after(mut self, seconds: i64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    /// Adds the `error_code` to the JSON body, the message stays the same
+    pub fn with_api_code(mut self, code: ErrorCode) -> Self {
+        self.api_code = Some(code);
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn api_code(&self) -> Option<ErrorCode> {
+        self.api_code
+    }
+
+    #[cfg(test)]
+    pub fn status_code(&self) -> u16 {
+        self.error_code
+    }
 }
 
 pub trait MapResult<S> {
@@ -164,22 +222,46 @@ fn _api_error(_: &impl std::any::Any, msg: &str) -> String {
 //
 use std::io::Cursor;
 
-use rocket::http::{ContentType, Status};
+use rocket::http::{ContentType, Header, Status};
 use rocket::request::Request;
 use rocket::response::{self, Responder, Response};
 
-impl<'r> Responder<'r> for Error {
-    fn respond_to(self, _: &Request) -> response::Result<'r> {
+impl Error {
+    /// The JSON sent to the client, with the `error_code` of the `ErrorCode` when there is one
+    fn body(&self) -> String {
         let usr_msg = format!("{}", self);
+
+        if let Some(api_code) = self.api_code {
+            if let Ok(Value::Object(mut body)) = serde_json::from_str::<Value>(&usr_msg) {
+                body.insert("error_code".to_string(), Value::String(api_code.as_str().to_string()));
+                return Value::Object(body).to_string();
+            }
+        }
+
+        usr_msg
+    }
+}
+
+impl<'r> Responder<'r> for Error {
+    fn respond_to(mut self, request: &Request) -> response::Result<'r> {
         error!("{:#?}", self);
 
+        // Only the messages meant for the user are translated, the logs stay in English
+        if let Some(message) = crate::util::translate_error(crate::auth::request_locale(request), &self.message) {
+            self.message = message;
+        }
+        let usr_msg = self.body();
+
         let code = Status::from_code(self.error_code).unwrap_or(Status::BadRequest);
 
-        Response::build()
-            .status(code)
-            .header(ContentType::JSON)
-            .sized_body(Cursor::new(usr_msg))
-            .ok()
+        let mut response = Response::build();
+        response.status(code).header(ContentType::JSON);
+
+        if let Some(seconds) = self.retry_after {
+            response.header(Header::new("Retry-After", seconds.to_string()));
+        }
+
+        response.sized_body(Cursor::new(usr_msg)).ok()
     }
 }
 
@@ -196,11 +278,25 @@ macro_rules! err {
     }};
 }
 
+/// Like `err!`, with the `ErrorCode` of the error
+#[macro_export]
+macro_rules! err_code {
+    ($code:expr, $msg:expr) => {{
+        return Err(crate::error::Error::new($msg, $msg).with_api_code($code));
+    }};
+    ($code:expr, $usr_msg:expr, $log_value:expr) => {{
+        return Err(crate::error::Error::new($usr_msg, $log_value).with_api_code($code));
+    }};
+}
+
 #[macro_export]
 macro_rules! err_json {
     ($expr:expr) => {{
         return Err(crate::error::Error::from($expr));
     }};
+    ($expr:expr, $code:expr) => {{
+        return Err(crate::error::Error::from($expr).with_api_code($code));
+    }};
 }
 
 #[macro_export]
@@ -214,3 +310,59 @@ macro_rules! err_handler {
         return rocket::Outcome::Failure((rocket::http::Status::Unauthorized, $usr_msg));
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body_json(error: &Error) -> Value {
+        serde_json::from_str(&error.body()).unwrap()
+    }
+
+    fn code_error() -> Result<(), Error> {
+        err_code!(ErrorCode::InvalidCredentials, "Username or password is incorrect. Try again")
+    }
+
+    fn json_error() -> Result<(), Error> {
+        err_json!(json!({"error": "invalid_grant"}), ErrorCode::TwoFactorRequired)
+    }
+
+    #[test]
+    fn api_codes_are_added_to_the_body() {
+        let error = code_error().unwrap_err();
+        assert_eq!(error.api_code(), Some(ErrorCode::InvalidCredentials));
+        assert_eq!(error.status_code(), 400);
+
+        let body = body_json(&error);
+        assert_eq!(body["error_code"], "INVALID_CREDENTIALS");
+        assert_eq!(body["ErrorModel"]["Message"], "Username or password is incorrect. Try again");
+    }
+
+    #[test]
+    fn errors_without_api_code_keep_their_body() {
+        let error = Error::new("Invalid refresh token", "");
+        assert_eq!(error.api_code(), None);
+        assert!(body_json(&error).get("error_code").is_none());
+    }
+
+    #[test]
+    fn lockout_errors_have_their_api_code() {
+        let locked = crate::lockout::locked_out_error(60, String::new());
+        assert_eq!(locked.api_code(), Some(ErrorCode::AccountLocked));
+        assert_eq!(locked.status_code(), 429);
+        assert_eq!(body_json(&locked)["error_code"], "ACCOUNT_LOCKED");
+
+        let limited = crate::lockout::ratelimited_error(60, String::new());
+        assert_eq!(limited.api_code(), Some(ErrorCode::RateLimited));
+        assert_eq!(body_json(&limited)["error_code"], "RATE_LIMITED");
+    }
+
+    #[test]
+    fn json_errors_keep_their_body_with_the_api_code() {
+        let error = json_error().unwrap_err();
+
+        let body = body_json(&error);
+        assert_eq!(body["error"], "invalid_grant");
+        assert_eq!(body["error_code"], "TWO_FACTOR_REQUIRED");
+    }
+}
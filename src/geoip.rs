@@ -0,0 +1,405 @@
+//
+// GeoIP lookups in MaxMind DB files, like the GeoLite2 Country and ASN databases. Only what's needed to find
+// the country and the autonomous system of an IP is supported, the database is read in memory at startup
+//
+use std::net::{IpAddr, Ipv4Addr};
+
+use serde_json::{Map, Number, Value};
+
+use crate::CONFIG;
+
+/// Separates the data section from the metadata, at the end of the file
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+/// Limit to the nesting of the values and the pointers, the records only have a few levels
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// ISO 3166-1 code of the country, with the country and city databases
+    pub country: Option<String>,
+    /// Number of the autonomous system, with the ASN databases
+    pub asn: Option<u64>,
+}
+
+impl Location {
+    /// Identifies the location: the country when the database has them, the autonomous system otherwise
+    pub fn key(&self) -> String {
+        match (&self.country, self.asn) {
+            (Some(country), _) => country.clone(),
+            (None, Some(asn)) => format!("AS{}", asn),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+struct Database {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    /// The data section is between the search tree and the metadata
+    data_start: usize,
+    data_end: usize,
+    /// Node where the IPv4 addresses start, in the IPv6 databases they are the ones starting with 96 zeros
+    ipv4_start: usize,
+    ipv6: bool,
+}
+
+lazy_static! {
+    static ref DATABASE: Option<Database> = load_database();
+}
+
+/// Reads the database, so that it's not done by the first login that needs it
+pub fn load() {
+    lazy_static::initialize(&DATABASE);
+}
+
+fn load_database() -> Option<Database> {
+    let path = CONFIG.geoip_database_path()?;
+
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Error reading the GeoIP database {}: {}", path, e);
+            return None;
+        }
+    };
+
+    match Database::new(data) {
+        Some(database) => {
+            info!("Loaded the GeoIP database {}", path);
+            Some(database)
+        }
+        None => {
+            error!("Invalid GeoIP database {}", path);
+            None
+        }
+    }
+}
+
+/// Looks up the country and the autonomous system of the IP.
+/// Returns None without a database, or when the IP isn't in it like the private addresses
+pub fn lookup(ip: &IpAddr) -> Option<Location> {
+    DATABASE.as_ref()?.locate(ip)
+}
+
+/// The IPv4 address of the IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`), which the dual-stack sockets report
+fn unmap_ipv4(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => {
+                IpAddr::V4(Ipv4Addr::new((high >> 8) as u8, high as u8, (low >> 8) as u8, low as u8))
+            }
+            _ => *ip,
+        },
+        IpAddr::V4(_) => *ip,
+    }
+}
+
+impl Database {
+    fn new(data: Vec<u8>) -> Option<Self> {
+        // The marker could appear in the data too, the metadata is after the last one
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)?;
+
+        let (metadata, _) = decode(&data[marker + METADATA_MARKER.len()..], 0, 0)?;
+        let node_count = metadata["node_count"].as_u64()? as usize;
+        let record_size = metadata["record_size"].as_u64()? as usize;
+        let ipv6 = match metadata["ip_version"].as_u64()? {
+            4 => false,
+            6 => true,
+            _ => return None,
+        };
+
+        if record_size != 24 && record_size != 28 && record_size != 32 {
+            return None;
+        }
+
+        let data_start = node_count.checked_mul(record_size / 4)?.checked_add(16)?;
+        if data_start > marker {
+            return None;
+        }
+
+        let mut database = Self {
+            data,
+            node_count,
+            record_size,
+            data_start,
+            data_end: marker,
+            ipv4_start: 0,
+            ipv6,
+        };
+
+        if ipv6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = database.read_record(node, 0)?;
+            }
+            database.ipv4_start = node;
+        }
+
+        Some(database)
+    }
+
+    /// Reads the left (bit 0) or right (bit 1) record of a node of the search tree
+    fn read_record(&self, node: usize, bit: u8) -> Option<usize> {
+        let node_bytes = self.record_size / 4;
+        let b = self.data.get(node * node_bytes..(node + 1) * node_bytes)?;
+        let read = |bytes: &[u8]| bytes.iter().fold(0, |acc, &b| (acc << 8) | usize::from(b));
+
+        Some(match (self.record_size, bit) {
+            (24, 0) => read(&b[0..3]),
+            (24, _) => read(&b[3..6]),
+            // The middle byte has the high bits of both records
+            (28, 0) => (usize::from(b[3] & 0xf0) << 20) | read(&b[0..3]),
+            (28, _) => (usize::from(b[3] & 0x0f) << 24) | read(&b[4..7]),
+            (_, 0) => read(&b[0..4]),
+            (_, _) => read(&b[4..8]),
+        })
+    }
+
+    fn locate(&self, ip: &IpAddr) -> Option<Location> {
+        let record = self.lookup(ip)?;
+
+        let country = record["country"]["iso_code"]
+            .as_str()
+            .or_else(|| record["registered_country"]["iso_code"].as_str())
+            .map(String::from);
+        let asn = record["autonomous_system_number"].as_u64();
+
+        if country.is_none() && asn.is_none() {
+            return None;
+        }
+        Some(Location { country, asn })
+    }
+
+    fn lookup(&self, ip: &IpAddr) -> Option<Value> {
+        let (octets, mut node) = match unmap_ipv4(ip) {
+            IpAddr::V4(ip) => (ip.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(_) if !self.ipv6 => return None,
+            IpAddr::V6(ip) => (ip.octets().to_vec(), 0),
+        };
+
+        for i in 0..octets.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (octets[i / 8] >> (7 - i % 8)) & 1;
+            node = self.read_record(node, bit)?;
+        }
+
+        // The node count itself means there is no data for the IP, the bigger values point to the data section
+        let offset = node.checked_sub(self.node_count + 16)?;
+        let (record, _) = decode(&self.data[self.data_start..self.data_end], offset, 0)?;
+        Some(record)
+    }
+}
+
+fn read_uint(section: &[u8], offset: usize, len: usize) -> Option<u64> {
+    let bytes = section.get(offset..offset + len)?;
+    Some(bytes.iter().fold(0, |acc, &b| (acc << 8) | u64::from(b)))
+}
+
+/// Decodes the value at the offset of the section, returning it and the offset after it.
+/// The pointers are relative to the start of the section
+fn decode(section: &[u8], offset: usize, depth: usize) -> Option<(Value, usize)> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+
+    let control = *section.get(offset)?;
+    let mut offset = offset + 1;
+    let mut data_type = control >> 5;
+
+    if data_type == 1 {
+        // The size bits of the pointers are part of the address
+        let len = usize::from((control >> 3) & 0x3) + 1;
+        let high = u64::from(control & 0x7);
+        let value = read_uint(section, offset, len)?;
+        let pointer = match len {
+            1 => (high << 8) | value,
+            2 => ((high << 16) | value) + 2048,
+            3 => ((high << 24) | value) + 526_336,
+            _ => value,
+        };
+
+        let (pointed, _) = decode(section, pointer as usize, depth + 1)?;
+        return Some((pointed, offset + len));
+    }
+
+    if data_type == 0 {
+        data_type = section.get(offset)?.checked_add(7)?;
+        offset += 1;
+    }
+
+    // The bigger sizes are in the following bytes
+    let (size, size_len) = match control & 0x1f {
+        29 => (29 + read_uint(section, offset, 1)?, 1),
+        30 => (285 + read_uint(section, offset, 2)?, 2),
+        31 => (65_821 + read_uint(section, offset, 3)?, 3),
+        size => (u64::from(size), 0),
+    };
+    let size = size as usize;
+    offset += size_len;
+
+    match data_type {
+        2 => {
+            let bytes = section.get(offset..offset + size)?;
+            let text = String::from_utf8(bytes.to_vec()).ok()?;
+            Some((Value::String(text), offset + size))
+        }
+        3 if size == 8 => {
+            let double = f64::from_bits(read_uint(section, offset, 8)?);
+            Some((Number::from_f64(double).map_or(Value::Null, Value::Number), offset + 8))
+        }
+        // The bytes aren't needed for the lookups
+        4 => Some((Value::Null, offset + size)),
+        5 | 6 | 9 if size <= 8 => Some((Value::from(read_uint(section, offset, size)?), offset + size)),
+        10 if size <= 16 => {
+            let bytes = section.get(offset..offset + size)?;
+            let value = bytes.iter().fold(0u128, |acc, &b| (acc << 8) | u128::from(b));
+            Some((Value::String(value.to_string()), offset + size))
+        }
+        7 => {
+            let mut map = Map::new();
+            for _ in 0..size {
+                let (key, next) = decode(section, offset, depth + 1)?;
+                let (value, next) = decode(section, next, depth + 1)?;
+                map.insert(key.as_str()?.to_string(), value);
+                offset = next;
+            }
+            Some((Value::Object(map), offset))
+        }
+        // The int32 shorter than 4 bytes are positive
+        8 if size <= 4 => {
+            let value = read_uint(section, offset, size)? as u32 as i32;
+            Some((Value::from(value), offset + size))
+        }
+        11 => {
+            let mut items = Vec::with_capacity(std::cmp::min(size, section.len()));
+            for _ in 0..size {
+                let (item, next) = decode(section, offset, depth + 1)?;
+                items.push(item);
+                offset = next;
+            }
+            Some((Value::Array(items), offset))
+        }
+        14 => Some((Value::Bool(size != 0), offset)),
+        15 if size == 4 => {
+            let float = f32::from_bits(read_uint(section, offset, 4)? as u32);
+            Some((
+                Number::from_f64(f64::from(float)).map_or(Value::Null, Value::Number),
+                offset + 4,
+            ))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(text: &str) -> Vec<u8> {
+        let mut bytes = vec![0x40 | text.len() as u8];
+        bytes.extend_from_slice(text.as_bytes());
+        bytes
+    }
+
+    fn uint32(value: u32) -> Vec<u8> {
+        let mut bytes = vec![0xc4];
+        bytes.extend_from_slice(&value.to_be_bytes());
+        bytes
+    }
+
+    fn map(entries: Vec<(&str, Vec<u8>)>) -> Vec<u8> {
+        let mut bytes = vec![0xe0 | entries.len() as u8];
+        for (key, value) in entries {
+            bytes.extend(string(key));
+            bytes.extend(value);
+        }
+        bytes
+    }
+
+    /// A database with 24 bits records, where the IPv4 addresses under 128.0.0.0 are in France
+    /// and the others in the AS64500. The IPv6 one only has the IPv4 addresses
+    fn fixture(ipv6: bool) -> Vec<u8> {
+        let france = map(vec![("country", map(vec![("iso_code", string("FR"))]))]);
+        let asn = map(vec![("autonomous_system_number", uint32(64_500))]);
+
+        let node_count = if ipv6 { 97 } else { 1 };
+        let no_data = node_count;
+        let france_record = node_count + 16;
+        let asn_record = france_record + france.len();
+
+        // The first 96 bits of the IPv4 addresses are zeros in the IPv6 databases
+        let mut nodes: Vec<(usize, usize)> = (1..node_count).map(|next| (next, no_data)).collect();
+        nodes.push((france_record, asn_record));
+
+        let mut data = Vec::new();
+        for (left, right) in nodes {
+            data.extend_from_slice(&(left as u32).to_be_bytes()[1..]);
+            data.extend_from_slice(&(right as u32).to_be_bytes()[1..]);
+        }
+        data.extend_from_slice(&[0; 16]);
+        data.extend(france);
+        data.extend(asn);
+        data.extend_from_slice(METADATA_MARKER);
+        data.extend(map(vec![
+            ("node_count", uint32(node_count as u32)),
+            ("record_size", uint32(24)),
+            ("ip_version", uint32(if ipv6 { 6 } else { 4 })),
+        ]));
+        data
+    }
+
+    fn locate(database: &Database, ip: &str) -> Option<String> {
+        database.locate(&ip.parse().unwrap()).map(|location| location.key())
+    }
+
+    #[test]
+    fn test_lookup_ipv4_database() {
+        let database = Database::new(fixture(false)).unwrap();
+
+        assert_eq!(locate(&database, "10.0.0.1"), Some("FR".to_string()));
+        assert_eq!(locate(&database, "200.0.0.1"), Some("AS64500".to_string()));
+        assert_eq!(locate(&database, "2001:db8::1"), None);
+    }
+
+    #[test]
+    fn test_lookup_ipv6_database() {
+        let database = Database::new(fixture(true)).unwrap();
+
+        assert_eq!(database.ipv4_start, 96);
+        assert_eq!(locate(&database, "10.0.0.1"), Some("FR".to_string()));
+        assert_eq!(locate(&database, "200.0.0.1"), Some("AS64500".to_string()));
+        assert_eq!(locate(&database, "2001:db8::1"), None);
+    }
+
+    #[test]
+    fn test_lookup_ipv4_mapped_addresses() {
+        for ipv6 in &[false, true] {
+            let database = Database::new(fixture(*ipv6)).unwrap();
+
+            assert_eq!(locate(&database, "::ffff:10.0.0.1"), Some("FR".to_string()));
+            assert_eq!(locate(&database, "::ffff:200.0.0.1"), Some("AS64500".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_invalid_database() {
+        let mut data = fixture(false);
+        assert!(Database::new(data[..20].to_vec()).is_none());
+
+        // The tree can't be bigger than the file
+        let mut node_count = string("node_count");
+        node_count.extend(uint32(1));
+        let position = data.windows(node_count.len()).position(|w| w == &node_count[..]).unwrap();
+        data[position + node_count.len() - 2..position + node_count.len()].copy_from_slice(&[0x03, 0xe8]);
+        assert!(Database::new(data).is_none());
+    }
+}
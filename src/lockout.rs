@@ -0,0 +1,384 @@
+//
+// Failed login tracking, used to lock out the users and IPs with too many failed attempts
+// The admin page logins are tracked by IP separately, so they don't lock the IP out of the vault
+// Independently of the lockouts, the failed logins of each IP are rate limited in a sliding window,
+// and the authenticated API requests of each user in a fixed window.
+// The IPs with a few failed logins also need a captcha, before they get anywhere near the lockout
+//
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde_json::Value;
+
+use crate::error::{Error, ErrorCode};
+use crate::CONFIG;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum LockoutKind {
+    User,
+    Ip,
+    /// The logins to the admin page, tracked by IP
+    #[cfg(feature = "admin")]
+    Admin,
+}
+
+impl LockoutKind {
+    #[cfg(feature = "admin")]
+    pub fn from_str(kind: &str) -> Option<Self> {
+        match kind {
+            "user" => Some(LockoutKind::User),
+            "ip" => Some(LockoutKind::Ip),
+            "admin" => Some(LockoutKind::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LockoutKind::User => "user",
+            LockoutKind::Ip => "ip",
+            #[cfg(feature = "admin")]
+            LockoutKind::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Attempts {
+    pub failures: u64,
+    pub last_failure: NaiveDateTime,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+impl Attempts {
+    /// The counters are forgotten once the lockout ends, or when there were no failures for the lockout duration
+    fn is_stale(&self, now: NaiveDateTime) -> bool {
+        match self.locked_until {
+            Some(until) => until <= now,
+            None => self.last_failure + lockout_duration() <= now,
+        }
+    }
+
+    fn is_locked(&self, now: NaiveDateTime) -> bool {
+        self.locked_until.map_or(false, |until| until > now)
+    }
+}
+
+lazy_static! {
+    static ref ATTEMPTS: Mutex<HashMap<(LockoutKind, String), Attempts>> = Mutex::new(HashMap::new());
+    static ref RATELIMIT: Mutex<HashMap<String, VecDeque<NaiveDateTime>>> = Mutex::new(HashMap::new());
+    /// The start of the current window of each user, and the number of requests in it
+    static ref API_RATELIMIT: Mutex<HashMap<String, (NaiveDateTime, u64)>> = Mutex::new(HashMap::new());
+    /// The failed logins of each IP counted for the captcha, and the last one
+    static ref CAPTCHA_FAILURES: Mutex<HashMap<String, (u64, NaiveDateTime)>> = Mutex::new(HashMap::new());
+}
+
+fn lockout_duration() -> Duration {
+    Duration::seconds(CONFIG.login_lockout_seconds() as i64)
+}
+
+/// The users are tracked by email, which is what's available before they are authenticated
+fn normalize(kind: LockoutKind, key: &str) -> (LockoutKind, String) {
+    match kind {
+        LockoutKind::User => (kind, key.trim().to_lowercase()),
+        _ => (kind, key.to_string()),
+    }
+}
+
+/// Returns the moment the lockout ends, if the user or IP is currently locked out
+pub fn locked_until(kind: LockoutKind, key: &str) -> Option<NaiveDateTime> {
+    let now = Utc::now().naive_utc();
+    let attempts = ATTEMPTS.lock().unwrap();
+
+    attempts
+        .get(&normalize(kind, key))
+        .filter(|a| a.is_locked(now))
+        .and_then(|a| a.locked_until)
+}
+
+/// Returns the number of seconds until the lockout ends, rounded up, if the user or IP is currently locked out
+pub fn retry_after(kind: LockoutKind, key: &str) -> Option<i64> {
+    let now = Utc::now().naive_utc();
+
+    locked_until(kind, key).map(|until| seconds_until(until, now))
+}
+
+fn seconds_until(date: NaiveDateTime, now: NaiveDateTime) -> i64 {
+    let millis = date.signed_duration_since(now).num_milliseconds();
+    std::cmp::max((millis + 999) / 1000, 1)
+}
+
+/// A `429 Too Many Requests` error telling the client when it can retry
+fn too_many_requests(usr_msg: &str, retry_after: i64, log_msg: String) -> Error {
+    Error::new(usr_msg, log_msg)
+        .with_code(429)
+        .with_retry_after(retry_after)
+}
+
+/// The error returned while locked out
+pub fn locked_out_error(retry_after: i64, log_msg: String) -> Error {
+    too_many_requests("Too many failed login attempts. Try again later", retry_after, log_msg)
+        .with_api_code(ErrorCode::AccountLocked)
+}
+
+/// Counts a failed login attempt, locking the user or IP out once `LOGIN_LOCKOUT_ATTEMPTS` is reached.
+/// Returns whether this attempt locked it out, does nothing when the lockout is disabled
+pub fn register_failure(kind: LockoutKind, key: &str) -> bool {
+    let max_attempts = CONFIG.login_lockout_attempts();
+    if max_attempts == 0 {
+        return false;
+    }
+
+    let now = Utc::now().naive_utc();
+    let mut attempts = ATTEMPTS.lock().unwrap();
+    // The unknown emails are counted too, so the stale entries can't pile up
+    attempts.retain(|_, a| !a.is_stale(now));
+
+    let entry = attempts.entry(normalize(kind, key)).or_insert(Attempts {
+        failures: 0,
+        last_failure: now,
+        locked_until: None,
+    });
+
+    entry.failures += 1;
+    entry.last_failure = now;
+
+    if entry.failures >= max_attempts && entry.locked_until.is_none() {
+        entry.locked_until = Some(now + lockout_duration());
+        warn!(
+            "Locked out {} {} after {} failed login attempts",
+            kind.as_str(),
+            key,
+            entry.failures
+        );
+        return true;
+    }
+
+    false
+}
+
+/// Forgets the failed attempts of the user or IP, returning whether there were any
+pub fn clear(kind: LockoutKind, key: &str) -> bool {
+    ATTEMPTS.lock().unwrap().remove(&normalize(kind, key)).is_some()
+}
+
+/// Lists the users and IPs with recent failed attempts, locked out or not, removing the stale ones
+#[cfg(feature = "admin")]
+pub fn list_json() -> Vec<Value> {
+    use crate::util::format_date;
+
+    let now = Utc::now().naive_utc();
+    let mut attempts = ATTEMPTS.lock().unwrap();
+    attempts.retain(|_, a| !a.is_stale(now));
+
+    attempts
+        .iter()
+        .map(|((kind, key), a)| {
+            json!({
+                "Type": kind.as_str(),
+                "Key": key,
+                "Failures": a.failures,
+                "LastFailure": format_date(&a.last_failure),
+                "Locked": a.is_locked(now),
+                "LockedUntil": a.locked_until.as_ref().map(format_date),
+            })
+        })
+        .collect()
+}
+
+fn ratelimit_window() -> Duration {
+    Duration::seconds(CONFIG.login_ratelimit_seconds() as i64)
+}
+
+/// Returns the number of seconds until the IP can try again, if it reached `LOGIN_RATELIMIT_MAX_BURST` failed
+/// logins in the last `LOGIN_RATELIMIT_SECONDS`
+pub fn ratelimit_retry_after(ip: &str) -> Option<i64> {
+    let max_burst = CONFIG.login_ratelimit_max_burst();
+    if max_burst == 0 {
+        return None;
+    }
+
+    let now = Utc::now().naive_utc();
+    let window_start = now - ratelimit_window();
+    let mut ratelimit = RATELIMIT.lock().unwrap();
+
+    let failures = ratelimit.get_mut(ip)?;
+    while failures.front().map_or(false, |f| *f <= window_start) {
+        failures.pop_front();
+    }
+
+    if (failures.len() as u64) < max_burst {
+        return None;
+    }
+
+    // The oldest failure in the window is the next one to leave it
+    failures
+        .front()
+        .map(|oldest| seconds_until(*oldest + ratelimit_window(), now))
+}
+
+/// Counts a failed login of the IP for the rate limit. Only the last `LOGIN_RATELIMIT_MAX_BURST` are kept,
+/// as the older ones don't change when the IP can try again
+pub fn register_ratelimit_failure(ip: &str) {
+    let max_burst = CONFIG.login_ratelimit_max_burst();
+    if max_burst == 0 {
+        return;
+    }
+
+    let now = Utc::now().naive_utc();
+    let window_start = now - ratelimit_window();
+    let mut ratelimit = RATELIMIT.lock().unwrap();
+    ratelimit.retain(|_, f| f.back().map_or(false, |last| *last > window_start));
+
+    let failures = ratelimit.entry(ip.to_string()).or_insert_with(VecDeque::new);
+    failures.push_back(now);
+    while failures.len() as u64 > max_burst {
+        failures.pop_front();
+    }
+}
+
+/// Counts a magic link request of the IP, with the same limits as the failed logins but separately from them.
+/// Returns the number of seconds until the IP can request another one once it reached them
+pub fn register_magic_link_request(ip: &str) -> Option<i64> {
+    let key = format!("magic_link {}", ip);
+    if let Some(seconds) = ratelimit_retry_after(&key) {
+        return Some(seconds);
+    }
+
+    register_ratelimit_failure(&key);
+    None
+}
+
+pub fn clear_ratelimit(ip: &str) {
+    RATELIMIT.lock().unwrap().remove(ip);
+}
+
+/// The error returned while rate limited
+pub fn ratelimited_error(retry_after: i64, log_msg: String) -> Error {
+    too_many_requests("Too many login attempts, try again later", retry_after, log_msg)
+        .with_api_code(ErrorCode::RateLimited)
+}
+
+/// Counts a failed login of the IP for `CAPTCHA_FAILURE_THRESHOLD`. Does nothing when the captcha isn't configured
+pub fn register_captcha_failure(ip: &str) {
+    if !CONFIG.captcha_enabled() {
+        return;
+    }
+
+    let now = Utc::now().naive_utc();
+    let mut failures = CAPTCHA_FAILURES.lock().unwrap();
+    failures.retain(|_, (_, last)| *last + lockout_duration() > now);
+
+    let entry = failures.entry(ip.to_string()).or_insert((0, now));
+    entry.0 += 1;
+    entry.1 = now;
+}
+
+/// Whether the logins from the IP need a captcha, after `CAPTCHA_FAILURE_THRESHOLD` recent failed logins
+pub fn captcha_required(ip: &str) -> bool {
+    if !CONFIG.captcha_enabled() {
+        return false;
+    }
+
+    let now = Utc::now().naive_utc();
+    match CAPTCHA_FAILURES.lock().unwrap().get(ip) {
+        Some((count, last)) => *count >= CONFIG.captcha_failure_threshold() && *last + lockout_duration() > now,
+        None => false,
+    }
+}
+
+/// The API request budget of a user in the current `API_RATELIMIT_SECONDS` window
+#[derive(Debug, Copy, Clone)]
+pub struct ApiRateLimit {
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset: NaiveDateTime,
+    /// Whether the request was rejected because the budget was already used
+    pub exceeded: bool,
+}
+
+impl ApiRateLimit {
+    /// Number of seconds until the window ends and the budget is restored, rounded up
+    pub fn reset_seconds(&self) -> i64 {
+        seconds_until(self.reset, Utc::now().naive_utc())
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "Enabled": true,
+            "Limit": self.limit,
+            "Remaining": self.remaining,
+            "ResetDate": crate::util::format_date(&self.reset),
+            "ResetSeconds": self.reset_seconds(),
+            "Object": "rateLimit",
+        })
+    }
+}
+
+/// Stored in the cache of the requests authenticated by the `Headers` guard, so the response headers don't
+/// need to look up the shared store again
+pub struct ApiRateLimitStatus(pub Option<ApiRateLimit>);
+
+fn api_ratelimit_window() -> Duration {
+    Duration::seconds(CONFIG.api_ratelimit_seconds() as i64)
+}
+
+/// Counts an API request of the user, returning the remaining budget. Once it's used, the requests aren't
+/// counted anymore and are returned as exceeded until the window ends. Returns None when the limit is disabled
+pub fn register_api_request(user_uuid: &str) -> Option<ApiRateLimit> {
+    let limit = CONFIG.api_ratelimit_max_requests();
+    if limit == 0 {
+        return None;
+    }
+
+    let now = Utc::now().naive_utc();
+    let window = api_ratelimit_window();
+    let mut ratelimit = API_RATELIMIT.lock().unwrap();
+
+    // The windows that ended are only removed when a new one starts, so most requests don't go through all of them
+    let expired = ratelimit
+        .get(user_uuid)
+        .map_or(true, |(start, _)| *start + window <= now);
+    if expired {
+        ratelimit.retain(|_, (start, _)| *start + window > now);
+        ratelimit.insert(user_uuid.to_string(), (now, 0));
+    }
+
+    let (start, count) = ratelimit.get_mut(user_uuid)?;
+    let exceeded = *count >= limit;
+    if !exceeded {
+        *count += 1;
+    }
+
+    Some(ApiRateLimit {
+        limit,
+        remaining: limit.saturating_sub(*count),
+        reset: *start + window,
+        exceeded,
+    })
+}
+
+/// The current budget of the user, without counting a request
+pub fn api_ratelimit_status(user_uuid: &str) -> Option<ApiRateLimit> {
+    let limit = CONFIG.api_ratelimit_max_requests();
+    if limit == 0 {
+        return None;
+    }
+
+    let now = Utc::now().naive_utc();
+    let window = api_ratelimit_window();
+    let ratelimit = API_RATELIMIT.lock().unwrap();
+
+    let (reset, count) = match ratelimit.get(user_uuid) {
+        Some((start, count)) if *start + window > now => (*start + window, *count),
+        _ => (now + window, 0),
+    };
+
+    Some(ApiRateLimit {
+        limit,
+        remaining: limit.saturating_sub(count),
+        reset,
+        exceeded: false,
+    })
+}
@@ -2,24 +2,41 @@ use lettre::smtp::authentication::Credentials;
 use lettre::smtp::ConnectionReuseParameters;
 use lettre::{ClientSecurity, ClientTlsParameters, SmtpClient, SmtpTransport, Transport};
 use lettre_email::{EmailBuilder, MimeMultipartType, PartBuilder};
-use native_tls::{Protocol, TlsConnector};
+use native_tls::{Certificate, Protocol, TlsConnector};
 use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
 use quoted_printable::encode_to_str;
 
 use crate::api::EmptyResult;
-use crate::auth::{encode_jwt, generate_invite_claims};
+use crate::auth::{encode_jwt, generate_invite_claims, generate_magic_link_claims};
 use crate::error::Error;
 use crate::CONFIG;
 use chrono::NaiveDateTime;
 
-fn mailer() -> SmtpTransport {
+/// Reads the PEM encoded CA certificate used to validate the SMTP server
+pub fn load_ca_cert(path: &str) -> Result<Certificate, String> {
+    let pem = std::fs::read(path).map_err(|e| e.to_string())?;
+    Certificate::from_pem(&pem).map_err(|e| e.to_string())
+}
+
+fn mailer() -> Result<SmtpTransport, String> {
     let host = CONFIG.smtp_host().unwrap();
 
     let client_security = if CONFIG.smtp_ssl() {
-        let tls = TlsConnector::builder()
-            .min_protocol_version(Some(Protocol::Tlsv11))
-            .build()
-            .unwrap();
+        let mut tls_builder = TlsConnector::builder();
+        tls_builder.min_protocol_version(Some(Protocol::Tlsv11));
+
+        if CONFIG.smtp_accept_invalid_certs() {
+            tls_builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+
+        if let Some(path) = CONFIG.smtp_ca_cert() {
+            let cert = load_ca_cert(&path).map_err(|e| format!("Can't load the CA certificate: {}", e))?;
+            tls_builder.add_root_certificate(cert);
+        }
+
+        let tls = tls_builder.build().map_err(|e| e.to_string())?;
 
         let params = ClientTlsParameters::new(host.clone(), tls);
 
@@ -32,25 +49,42 @@ fn mailer() -> SmtpTransport {
         ClientSecurity::None
     };
 
-    let smtp_client = SmtpClient::new((host.as_str(), CONFIG.smtp_port()), client_security).unwrap();
+    let smtp_client =
+        SmtpClient::new((host.as_str(), CONFIG.smtp_port()), client_security).map_err(|e| e.to_string())?;
 
     let smtp_client = match (&CONFIG.smtp_username(), &CONFIG.smtp_password()) {
         (Some(user), Some(pass)) => smtp_client.credentials(Credentials::new(user.clone(), pass.clone())),
         _ => smtp_client,
     };
 
-    smtp_client
+    Ok(smtp_client
         .smtp_utf8(true)
         .connection_reuse(ConnectionReuseParameters::NoReuse)
-        .transport()
+        .transport())
 }
 
-fn get_text(template_name: &'static str, data: serde_json::Value) -> Result<(String, String, String), Error> {
+fn get_text(
+    template_name: &'static str,
+    locale: &str,
+    data: serde_json::Value,
+) -> Result<(String, String, String), Error> {
+    let template_name = localized_template(template_name, locale);
     let (subject_html, body_html) = get_template(&format!("{}.html", template_name), &data)?;
-    let (_subject_text, body_text) = get_template(template_name, &data)?;
+    let (_subject_text, body_text) = get_template(&template_name, &data)?;
     Ok((subject_html, body_html, body_text))
 }
 
+/// Uses the translated templates for the locale if both are available in the templates folder, otherwise the default ones
+fn localized_template(template_name: &str, locale: &str) -> String {
+    let localized = format!("{}.{}", template_name, locale);
+
+    if CONFIG.has_template(&localized) && CONFIG.has_template(&format!("{}.html", localized)) {
+        localized
+    } else {
+        template_name.to_string()
+    }
+}
+
 fn get_template(template_name: &str, data: &serde_json::Value) -> Result<(String, String), Error> {
     let text = CONFIG.render_template(template_name, data)?;
     let mut text_split = text.split("<!---------------->");
@@ -68,14 +102,15 @@ fn get_template(template_name: &str, data: &serde_json::Value) -> Result<(String
     Ok((subject, body))
 }
 
-pub fn send_password_hint(address: &str, hint: Option<String>) -> EmptyResult {
+pub fn send_password_hint(address: &str, hint: Option<String>, locale: &str) -> EmptyResult {
     let template_name = if hint.is_some() {
         "email/pw_hint_some"
     } else {
         "email/pw_hint_none"
     };
 
-    let (subject, body_html, body_text) = get_text(template_name, json!({ "hint": hint, "url": CONFIG.domain() }))?;
+    let (subject, body_html, body_text) =
+        get_text(template_name, locale, json!({ "hint": hint, "url": CONFIG.domain() }))?;
 
     send_email(&address, &subject, &body_html, &body_text)
 }
@@ -87,6 +122,7 @@ pub fn send_invite(
     org_user_id: Option<String>,
     org_name: &str,
     invited_by_email: Option<String>,
+    locale: &str,
 ) -> EmptyResult {
     let claims = generate_invite_claims(
         uuid.to_string(),
@@ -99,6 +135,7 @@ pub fn send_invite(
 
     let (subject, body_html, body_text) = get_text(
         "email/send_org_invite",
+        locale,
         json!({
             "url": CONFIG.domain(),
             "org_id": org_id.unwrap_or_else(|| "_".to_string()),
@@ -112,9 +149,10 @@ pub fn send_invite(
     send_email(&address, &subject, &body_html, &body_text)
 }
 
-pub fn send_invite_accepted(new_user_email: &str, address: &str, org_name: &str) -> EmptyResult {
+pub fn send_invite_accepted(new_user_email: &str, address: &str, org_name: &str, locale: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
         "email/invite_accepted",
+        locale,
         json!({
             "url": CONFIG.domain(),
             "email": new_user_email,
@@ -125,9 +163,23 @@ pub fn send_invite_accepted(new_user_email: &str, address: &str, org_name: &str)
     send_email(&address, &subject, &body_html, &body_text)
 }
 
-pub fn send_invite_confirmed(address: &str, org_name: &str) -> EmptyResult {
+pub fn send_invite_confirmed(address: &str, org_name: &str, locale: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
         "email/invite_confirmed",
+        locale,
+        json!({
+            "url": CONFIG.domain(),
+            "org_name": org_name,
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
+pub fn send_membership_revoked(address: &str, org_name: &str, locale: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/membership_revoked",
+        locale,
         json!({
             "url": CONFIG.domain(),
             "org_name": org_name,
@@ -137,7 +189,61 @@ pub fn send_invite_confirmed(address: &str, org_name: &str) -> EmptyResult {
     send_email(&address, &subject, &body_html, &body_text)
 }
 
-pub fn send_new_device_logged_in(address: &str, ip: &str, dt: &NaiveDateTime, device: &str) -> EmptyResult {
+pub fn send_magic_link(address: &str, uuid: &str, nonce: &str, ip: &str, locale: &str) -> EmptyResult {
+    let claims = generate_magic_link_claims(uuid.to_string(), nonce.to_string(), ip.to_string());
+    let token = encode_jwt(&claims);
+
+    let (subject, body_html, body_text) = get_text(
+        "email/magic_link",
+        locale,
+        json!({
+            "url": CONFIG.domain(),
+            "email": percent_encode(address.as_bytes(), NON_ALPHANUMERIC).to_string(),
+            "token": token,
+            "ttl": CONFIG.magic_link_ttl_minutes(),
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
+pub fn send_twofactor_email_token(address: &str, token: &str, locale: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/twofactor_email",
+        locale,
+        json!({
+            "url": CONFIG.domain(),
+            "token": token,
+            "ttl": CONFIG.email_2fa_code_minutes(),
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
+pub fn send_device_approval(address: &str, token: &str, ip: &str, device: &str, locale: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/device_approval",
+        locale,
+        json!({
+            "url": CONFIG.domain(),
+            "token": token,
+            "ip": ip,
+            "device": device,
+            "hours": CONFIG.admin_device_approval_expiration_hours(),
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
+pub fn send_new_device_logged_in(
+    address: &str,
+    ip: &str,
+    dt: &NaiveDateTime,
+    device: &str,
+    locale: &str,
+) -> EmptyResult {
     use crate::util::upcase_first;
     let device = upcase_first(device);
 
@@ -145,9 +251,39 @@ pub fn send_new_device_logged_in(address: &str, ip: &str, dt: &NaiveDateTime, de
 
     let (subject, body_html, body_text) = get_text(
         "email/new_device_logged_in",
+        locale,
+        json!({
+            "url": CONFIG.domain(),
+            "ip": ip,
+            "device": device,
+            "datetime": datetime,
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
+/// Sent instead of the new device email when the login also comes from an unusual location
+pub fn send_new_location_logged_in(
+    address: &str,
+    ip: &str,
+    location: &str,
+    dt: &NaiveDateTime,
+    device: &str,
+    locale: &str,
+) -> EmptyResult {
+    use crate::util::upcase_first;
+    let device = upcase_first(device);
+
+    let datetime = dt.format("%A, %B %_d, %Y at %H:%M").to_string();
+
+    let (subject, body_html, body_text) = get_text(
+        "email/new_location_logged_in",
+        locale,
         json!({
             "url": CONFIG.domain(),
             "ip": ip,
+            "location": location,
             "device": device,
             "datetime": datetime,
         }),
@@ -156,7 +292,56 @@ pub fn send_new_device_logged_in(address: &str, ip: &str, dt: &NaiveDateTime, de
     send_email(&address, &subject, &body_html, &body_text)
 }
 
+pub fn send_session_terminated(
+    address: &str,
+    ip: &str,
+    dt: &NaiveDateTime,
+    device: &str,
+    reason: &str,
+    locale: &str,
+) -> EmptyResult {
+    use crate::util::upcase_first;
+    let device = upcase_first(device);
+
+    let datetime = dt.format("%A, %B %_d, %Y at %H:%M").to_string();
+
+    let (subject, body_html, body_text) = get_text(
+        "email/session_terminated",
+        locale,
+        json!({
+            "url": CONFIG.domain(),
+            "ip": ip,
+            "device": device,
+            "datetime": datetime,
+            "reason": reason,
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
+/// Sends a test email from the admin page. Unlike the other emails, the cause of the errors is returned,
+/// so that connection and certificate validation problems are clear
+#[cfg(feature = "admin")]
+pub fn send_test(address: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/smtp_test",
+        crate::util::DEFAULT_LOCALE,
+        json!({
+            "url": CONFIG.domain(),
+        }),
+    )?;
+
+    _send_email(address, &subject, &body_html, &body_text)
+        .map_err(|e| Error::new(format!("Error sending email: {}", e), e))
+}
+
 fn send_email(address: &str, subject: &str, body_html: &str, body_text: &str) -> EmptyResult {
+    _send_email(address, subject, body_html, body_text).map_err(|e| Error::new("Error sending email", e))
+}
+
+/// Sends the email, returning the cause of the error, which isn't shown to the users unless it's a test email
+fn _send_email(address: &str, subject: &str, body_html: &str, body_text: &str) -> Result<(), String> {
     let html = PartBuilder::new()
         .body(encode_to_str(body_html))
         .header(("Content-Type", "text/html; charset=utf-8"))
@@ -180,14 +365,11 @@ fn send_email(address: &str, subject: &str, body_html: &str, body_text: &str) ->
         .subject(subject)
         .child(alternative.build())
         .build()
-        .map_err(|e| Error::new("Error building email", e.to_string()))?;
+        .map_err(|e| format!("Error building email: {}", e))?;
 
-    let mut transport = mailer();
+    let mut transport = mailer()?;
 
-    let result = transport
-        .send(email.into())
-        .map_err(|e| Error::new("Error sending email", e.to_string()))
-        .and(Ok(()));
+    let result = transport.send(email.into()).map_err(|e| e.to_string()).and(Ok(()));
 
     // Explicitly close the connection, in case of error
     transport.close();
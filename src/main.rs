@@ -23,22 +23,36 @@ extern crate num_derive;
 use std::{
     path::Path,
     process::{exit, Command},
+    thread,
+    time::Duration,
 };
 
 #[macro_use]
 mod error;
 mod api;
 mod auth;
+mod captcha;
 mod config;
 mod crypto;
 mod db;
+mod geoip;
+mod lockout;
 mod mail;
+mod metrics;
+mod sso;
 mod util;
+mod webauthn;
+mod webhook;
 
 pub use config::CONFIG;
 pub use error::{Error, MapResult};
 
 fn main() {
+    if std::env::args().any(|arg| arg == "--benchmark-kdf") {
+        benchmark_kdf();
+        exit(0);
+    }
+
     launch_info();
 
     if CONFIG.extended_logging() {
@@ -47,8 +61,13 @@ fn main() {
 
     check_db();
     check_rsa_keys();
+    check_server_secret_key();
     check_web_vault();
+    check_smtp_tls();
     migrations::run_migrations();
+    migrate_u2f_registrations();
+    geoip::load();
+    schedule_cleanup();
 
     launch_rocket();
 }
@@ -68,10 +87,71 @@ fn launch_info() {
     println!("\\--------------------------------------------------------------------/\n");
 }
 
+const BENCHMARK_ROUNDS: u32 = 5;
+// Time a single login should spend hashing the password
+const BENCHMARK_TARGET_MS: u128 = 500;
+
+/// Measures the server-side password hashing with the configured algorithm and suggests a value for this host
+fn benchmark_kdf() {
+    if CONFIG.password_algorithm() == "argon2id" {
+        benchmark_kdf_argon2id();
+        return;
+    }
+
+    let iterations = CONFIG.password_iterations();
+    if iterations <= 0 {
+        println!("PASSWORD_ITERATIONS needs to be a positive number");
+        exit(1);
+    }
+
+    println!(
+        "Benchmarking PBKDF2-SHA256 with {} iterations ({} rounds)...",
+        iterations, BENCHMARK_ROUNDS
+    );
+    let elapsed = crypto::benchmark_password_hash(iterations as u32, BENCHMARK_ROUNDS);
+    let elapsed_ms = elapsed.as_millis().max(1);
+    println!("Average time per password hash: {} ms", elapsed_ms);
+
+    // Round to the nearest 10000 iterations, and don't suggest going below the default
+    let suggested = (iterations as u128 * BENCHMARK_TARGET_MS / elapsed_ms) / 10_000 * 10_000;
+    let suggested = suggested.max(100_000);
+    println!(
+        "To spend around {} ms per login on this host, set PASSWORD_ITERATIONS={}",
+        BENCHMARK_TARGET_MS, suggested
+    );
+    println!("Note that the change only applies to users when they change their password");
+}
+
+/// The memory of Argon2id is kept as configured, only the iterations are suggested
+fn benchmark_kdf_argon2id() {
+    let (memory_kib, iterations, parallelism) =
+        (CONFIG.argon2_memory_kib(), CONFIG.argon2_iterations(), CONFIG.argon2_parallelism());
+
+    println!(
+        "Benchmarking Argon2id with {} KiB, {} iterations and {} lanes ({} rounds)...",
+        memory_kib, iterations, parallelism, BENCHMARK_ROUNDS
+    );
+    let elapsed = crypto::benchmark_password_hash_argon2id(memory_kib, iterations, parallelism, BENCHMARK_ROUNDS);
+    let elapsed_ms = elapsed.as_millis().max(1);
+    println!("Average time per password hash: {} ms", elapsed_ms);
+
+    let suggested = (iterations as u128 * BENCHMARK_TARGET_MS / elapsed_ms).max(1);
+    println!(
+        "To spend around {} ms per login on this host, set ARGON2_ITERATIONS={}, or change ARGON2_MEMORY_KIB and try again",
+        BENCHMARK_TARGET_MS, suggested
+    );
+    println!("Note that the change only applies to users when they change their password");
+}
+
 fn init_logging() -> Result<(), fern::InitError> {
     use std::str::FromStr;
     let mut logger = fern::Dispatch::new()
         .format(|out, message, record| {
+            // The JSON login logs are kept as-is, so every line is a valid JSON object
+            if record.target() == api::LOGIN_LOG_TARGET {
+                return out.finish(format_args!("{}", message));
+            }
+
             out.finish(format_args!(
                 "{}[{}][{}] {}",
                 chrono::Local::now().format("[%Y-%m-%d %H:%M:%S]"),
@@ -192,6 +272,20 @@ fn check_rsa_keys() {
     }
 }
 
+fn check_server_secret_key() {
+    let path = CONFIG.server_secret_key_filename();
+    if util::file_exists(&path) {
+        return;
+    }
+
+    info!("Server secret key doesn't exist, creating it...");
+    let key = crypto::get_random(vec![0u8; 32]);
+    if let Err(e) = util::write_file(&path, &key) {
+        error!("Error creating the server secret key: {}", e);
+        exit(1);
+    }
+}
+
 fn check_web_vault() {
     if !CONFIG.web_vault_enabled() {
         return;
@@ -205,6 +299,12 @@ fn check_web_vault() {
     }
 }
 
+fn check_smtp_tls() {
+    if CONFIG.mail_enabled() && CONFIG.smtp_ssl() && CONFIG.smtp_accept_invalid_certs() {
+        warn!("SMTP_ACCEPT_INVALID_CERTS is enabled, the certificate of the SMTP server won't be validated. Consider using SMTP_CA_CERT instead");
+    }
+}
+
 // Embed the migrations from the migrations folder into the application
 // This way, the program automatically migrates the database to the latest version
 // https://docs.rs/diesel_migrations/*/diesel_migrations/macro.embed_migrations.html
@@ -223,6 +323,161 @@ mod migrations {
         use std::io::stdout;
         embedded_migrations::run_with_output(&connection, &mut stdout()).expect("Can't run migrations");
     }
+
+    /// An in-memory database with all the migrations, for the tests that need one
+    #[cfg(all(test, feature = "sqlite"))]
+    pub fn test_connection() -> crate::db::DbConn {
+        use diesel::r2d2::{ConnectionManager, Pool};
+        use diesel::sqlite::SqliteConnection;
+
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(ConnectionManager::<SqliteConnection>::new(":memory:"))
+            .expect("Can't create the test database");
+        let connection = pool.get().expect("Can't connect to the test database");
+        embedded_migrations::run(&*connection).expect("Can't run migrations");
+
+        crate::db::DbConn(connection)
+    }
+}
+
+fn migrate_u2f_registrations() {
+    let conn = match db::init_pool().get() {
+        Ok(conn) => db::DbConn(conn),
+        Err(e) => {
+            error!("Error getting a connection to migrate the U2F keys: {:#?}", e);
+            exit(1);
+        }
+    };
+
+    if let Err(e) = api::core::two_factor::migrate_all_u2f_to_webauthn(&conn) {
+        error!("Error migrating the U2F keys to WebAuthn: {:#?}", e);
+        exit(1);
+    }
+}
+
+/// Periodically removes the short-lived data that expired, so it doesn't accumulate in the database
+fn schedule_cleanup() {
+    let interval = CONFIG.cleanup_interval_seconds();
+    if interval == 0 {
+        return;
+    }
+
+    let pool = db::init_pool();
+    thread::Builder::new()
+        .name("cleanup".to_string())
+        .spawn(move || loop {
+            thread::sleep(Duration::from_secs(interval));
+
+            let conn = match pool.get() {
+                Ok(conn) => db::DbConn(conn),
+                Err(e) => {
+                    error!("Error getting a connection for the cleanup: {:#?}", e);
+                    continue;
+                }
+            };
+
+            match db::models::Device::clear_expired_twofactor_remember(&conn) {
+                Ok(0) => {}
+                Ok(n) => info!("Removed {} expired remembered two-factor devices", n),
+                Err(e) => error!("Error removing the expired remembered devices: {:#?}", e),
+            }
+
+            match db::models::Device::delete_expired_pending(&conn) {
+                Ok(0) => {}
+                Ok(n) => info!("Removed {} expired pending devices", n),
+                Err(e) => error!("Error removing the expired pending devices: {:#?}", e),
+            }
+
+            match db::models::DeviceApprovalRequest::delete_expired(&conn) {
+                Ok(0) => {}
+                Ok(n) => info!("Removed {} expired device approval requests", n),
+                Err(e) => error!("Error removing the expired device approval requests: {:#?}", e),
+            }
+
+            match db::models::TwoFactor::delete_expired_challenges(&conn) {
+                Ok(0) => {}
+                Ok(n) => info!("Removed {} expired two-step login challenges", n),
+                Err(e) => error!("Error removing the expired two-step login challenges: {:#?}", e),
+            }
+
+            match api::core::two_factor::clear_expired_email_tokens(&conn) {
+                Ok(0) => {}
+                Ok(n) => info!("Removed {} expired email two-step login codes", n),
+                Err(e) => error!("Error removing the expired email codes: {:#?}", e),
+            }
+
+            match db::models::Event::delete_expired(&conn) {
+                Ok(0) => {}
+                Ok(n) => info!("Removed {} expired events", n),
+                Err(e) => error!("Error removing the expired events: {:#?}", e),
+            }
+
+            disable_inactive_users(&conn);
+        })
+        .expect("Error starting the cleanup thread");
+}
+
+/// Disables the users without logins in `INACTIVE_ACCOUNT_DISABLE_DAYS`. With email, they are warned first
+/// and always get `INACTIVE_ACCOUNT_WARNING_DAYS` to log in after the warning
+fn disable_inactive_users(conn: &db::DbConn) {
+    use chrono::{Duration, Utc};
+    use db::models::{Device, Event, EventType, User};
+
+    let days = CONFIG.inactive_account_disable_days();
+    if days <= 0 {
+        return;
+    }
+
+    let now = Utc::now().naive_utc();
+    let warning_days = std::cmp::max(CONFIG.inactive_account_warning_days(), 0);
+    let warn = CONFIG.mail_enabled() && warning_days > 0;
+    let warn_before = now - Duration::days(days - warning_days);
+
+    for mut user in User::find_enabled_created_before(warn_before, conn) {
+        let last_activity = Device::find_last_activity_by_user(&user.uuid, conn)
+            .into_iter()
+            .chain(user.enabled_at)
+            .fold(user.created_at, std::cmp::max);
+        if last_activity >= warn_before {
+            continue;
+        }
+
+        // A warning sent before the last login is from a previous period of inactivity
+        let warned_at = user.inactivity_warned_at.filter(|warned| *warned > last_activity);
+        let mut disable_at = last_activity + Duration::days(days);
+
+        match warned_at {
+            None if warn => {
+                let disable_date = std::cmp::max(disable_at, now + Duration::days(warning_days));
+                let locale = user.locale(util::DEFAULT_LOCALE);
+                if let Err(e) = mail::send_inactive_account_warning(&user.email, &disable_date, locale) {
+                    // Retried on the next cleanup, the account isn't disabled without the warning
+                    error!("Error sending the inactive account warning to {}: {:#?}", user.email, e);
+                    continue;
+                }
+                if let Err(e) = user.set_inactivity_warned_at(now, conn) {
+                    error!("Error saving the inactive account warning of {}: {:#?}", user.email, e);
+                }
+                continue;
+            }
+            Some(warned) => disable_at = std::cmp::max(disable_at, warned + Duration::days(warning_days)),
+            None => {}
+        }
+
+        if disable_at > now {
+            continue;
+        }
+
+        user.enabled = false;
+        if let Err(e) = user.save(conn) {
+            error!("Error disabling the inactive user {}: {:#?}", user.email, e);
+            continue;
+        }
+
+        Event::new(EventType::UserDisabledInactive, Some(user.uuid.clone())).save_or_log(conn);
+        info!("Disabled the user {}, inactive since {}", user.email, last_activity);
+    }
 }
 
 fn launch_rocket() {
@@ -237,11 +492,13 @@ fn launch_rocket() {
     let rocket = rocket
         .mount("/", api::web_routes())
         .mount("/api", api::core_routes())
-        .mount("/admin", api::admin_routes())
         .mount("/identity", api::identity_routes())
         .mount("/icons", api::icons_routes())
         .mount("/notifications", api::notifications_routes());
 
+    #[cfg(feature = "admin")]
+    let rocket = rocket.mount("/admin", api::admin_routes());
+
     // Force the level up for the fairings, managed state and lauch
     if !CONFIG.log_mounts() {
         log::set_max_level(log::LevelFilter::max());
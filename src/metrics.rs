@@ -0,0 +1,223 @@
+//
+// Prometheus metrics of the login flows, in the text exposition format. The counters and histograms are kept in memory,
+// and nothing is recorded while the metrics are disabled
+//
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::CONFIG;
+
+pub const LOGIN_ATTEMPTS: &str = "bitwarden_rs_login_attempts_total";
+pub const LOGIN_FAILURES: &str = "bitwarden_rs_login_failures_total";
+pub const LOGIN_DURATION: &str = "bitwarden_rs_login_duration_seconds";
+pub const TWOFACTOR_CHALLENGES: &str = "bitwarden_rs_twofactor_challenges_total";
+pub const TWOFACTOR_FAILURES: &str = "bitwarden_rs_twofactor_failures_total";
+pub const NEW_DEVICES: &str = "bitwarden_rs_new_devices_total";
+pub const REFRESH_TOKEN_USES: &str = "bitwarden_rs_refresh_token_uses_total";
+
+/// The name, type and help of the metrics, in the order they are listed
+const METRICS: &[(&str, &str, &str)] = &[
+    (LOGIN_ATTEMPTS, "counter", "Token requests of the logins, by grant type"),
+    (
+        LOGIN_FAILURES,
+        "counter",
+        "Failed token requests of the logins, by reason",
+    ),
+    (
+        LOGIN_DURATION,
+        "histogram",
+        "Time to handle the token requests, by grant type",
+    ),
+    (TWOFACTOR_CHALLENGES, "counter", "Logins asked for a second factor"),
+    (TWOFACTOR_FAILURES, "counter", "Wrong second factors, by provider"),
+    (NEW_DEVICES, "counter", "Devices created by the logins"),
+    (REFRESH_TOKEN_USES, "counter", "Uses of the refresh tokens, by result"),
+];
+
+/// Upper bounds of the buckets of the histograms, in seconds. The password hashing takes most of a login
+const BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+struct Histogram {
+    /// Observations in each bucket alone, they are added up when rendered
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+lazy_static! {
+    /// The values by metric name and labels, already formatted like `{grant_type="password"}`
+    static ref COUNTERS: Mutex<BTreeMap<(&'static str, String), u64>> = Mutex::new(BTreeMap::new());
+    static ref HISTOGRAMS: Mutex<BTreeMap<(&'static str, String), Histogram>> = Mutex::new(BTreeMap::new());
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Adds one to the counter with these labels
+pub fn inc(name: &'static str, labels: &[(&str, &str)]) {
+    if !CONFIG.metrics_enabled() {
+        return;
+    }
+
+    *COUNTERS
+        .lock()
+        .unwrap()
+        .entry((name, format_labels(labels)))
+        .or_insert(0) += 1;
+}
+
+/// Records a duration in the histogram with these labels
+pub fn observe(name: &'static str, labels: &[(&str, &str)], duration: Duration) {
+    if !CONFIG.metrics_enabled() {
+        return;
+    }
+
+    let seconds = duration.as_secs_f64();
+    let mut histograms = HISTOGRAMS.lock().unwrap();
+    let histogram = histograms
+        .entry((name, format_labels(labels)))
+        .or_insert_with(|| Histogram {
+            buckets: vec![0; BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        });
+
+    // The ones over the last bound are only in the count, which is the +Inf bucket
+    if let Some(i) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+        histogram.buckets[i] += 1;
+    }
+    histogram.sum += seconds;
+    histogram.count += 1;
+}
+
+/// Adds the bucket label to the other labels of a histogram
+fn with_bucket(labels: &str, bound: &str) -> String {
+    if labels.is_empty() {
+        format!("{{le=\"{}\"}}", bound)
+    } else {
+        format!("{},le=\"{}\"}}", &labels[..labels.len() - 1], bound)
+    }
+}
+
+/// All the metrics in the text format of Prometheus, the ones without values only have their help and type
+pub fn render() -> String {
+    let counters = COUNTERS.lock().unwrap();
+    let histograms = HISTOGRAMS.lock().unwrap();
+    let mut out = String::new();
+
+    for (name, kind, help) in METRICS {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+
+        for ((_, labels), value) in counters
+            .range((*name, String::new())..)
+            .take_while(|((n, _), _)| n == name)
+        {
+            writeln!(out, "{}{} {}", name, labels, value).unwrap();
+        }
+
+        for ((_, labels), histogram) in histograms
+            .range((*name, String::new())..)
+            .take_while(|((n, _), _)| n == name)
+        {
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    name,
+                    with_bucket(labels, &bound.to_string()),
+                    cumulative
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "{}_bucket{} {}",
+                name,
+                with_bucket(labels, "+Inf"),
+                histogram.count
+            )
+            .unwrap();
+            writeln!(out, "{}_sum{} {}", name, labels, histogram.sum).unwrap();
+            writeln!(out, "{}_count{} {}", name, labels, histogram.count).unwrap();
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_label_is_added_to_the_others() {
+        assert_eq!(with_bucket("", "0.5"), "{le=\"0.5\"}");
+        assert_eq!(
+            with_bucket("{grant_type=\"password\"}", "+Inf"),
+            "{grant_type=\"password\",le=\"+Inf\"}"
+        );
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(format_labels(&[]), "");
+        assert_eq!(
+            format_labels(&[("reason", "a\"b\\c\nd"), ("grant_type", "password")]),
+            "{reason=\"a\\\"b\\\\c\\nd\",grant_type=\"password\"}"
+        );
+    }
+
+    #[test]
+    fn every_metric_is_rendered() {
+        let out = render();
+        for (name, kind, help) in METRICS {
+            assert!(out.contains(&format!("# HELP {} {}\n", name, help)));
+            assert!(out.contains(&format!("# TYPE {} {}\n", name, kind)));
+        }
+    }
+
+    #[test]
+    fn values_are_rendered_with_their_labels() {
+        let labels = format_labels(&[("grant_type", "render_test")]);
+        COUNTERS.lock().unwrap().insert((LOGIN_ATTEMPTS, labels.clone()), 3);
+        HISTOGRAMS.lock().unwrap().insert(
+            (LOGIN_DURATION, labels),
+            Histogram {
+                // One observation in the first bucket, and one over the last bound
+                buckets: vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                sum: 20.004,
+                count: 2,
+            },
+        );
+
+        let out = render();
+        let lines: Vec<&str> = out.lines().collect();
+        for expected in &[
+            "bitwarden_rs_login_attempts_total{grant_type=\"render_test\"} 3",
+            "bitwarden_rs_login_duration_seconds_bucket{grant_type=\"render_test\",le=\"0.005\"} 1",
+            "bitwarden_rs_login_duration_seconds_bucket{grant_type=\"render_test\",le=\"10\"} 1",
+            "bitwarden_rs_login_duration_seconds_bucket{grant_type=\"render_test\",le=\"+Inf\"} 2",
+            "bitwarden_rs_login_duration_seconds_sum{grant_type=\"render_test\"} 20.004",
+            "bitwarden_rs_login_duration_seconds_count{grant_type=\"render_test\"} 2",
+        ] {
+            assert!(lines.contains(expected), "missing line: {}", expected);
+        }
+    }
+}
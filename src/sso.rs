@@ -0,0 +1,259 @@
+//
+// OpenID Connect login with the authorization code flow. The server is the client of the identity provider,
+// and then gives its own authorization code to the Bitwarden client, which exchanges it at `/identity/connect/token`
+//
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Utc;
+use data_encoding::BASE64URL_NOPAD;
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use reqwest::Client;
+use serde_json::Value;
+
+use crate::crypto;
+use crate::error::Error;
+use crate::CONFIG;
+
+lazy_static! {
+    static ref CLIENT: Client = Client::builder()
+        .use_sys_proxy()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+    /// The configuration of the provider, discovered on first use
+    static ref METADATA: Mutex<Option<Value>> = Mutex::new(None);
+    /// The nonces of the authorization codes already used, with their expiration
+    static ref USED_CODES: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+}
+
+/// Where the provider sends the users back, it needs to be allowed in the client configured there
+pub fn redirect_uri() -> String {
+    format!("{}/identity/connect/oidc-signin", CONFIG.domain())
+}
+
+fn encode(value: &str) -> String {
+    percent_encode(value.as_bytes(), NON_ALPHANUMERIC).to_string()
+}
+
+/// Appends the parameters to the query of the URL
+pub fn url_with_params(url: &str, params: &[(&str, &str)]) -> String {
+    let query: Vec<String> = params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, encode(value)))
+        .collect();
+
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", url, separator, query.join("&"))
+}
+
+fn issuer_url() -> Result<String, Error> {
+    match CONFIG.sso_issuer_url() {
+        Some(url) => Ok(url.trim_end_matches('/').to_string()),
+        None => err!("SSO is not configured"),
+    }
+}
+
+/// The discovery document of the provider, it's only fetched again after a failed request
+fn metadata() -> Result<Value, Error> {
+    if let Some(ref metadata) = *METADATA.lock().unwrap() {
+        return Ok(metadata.clone());
+    }
+
+    let issuer = issuer_url()?;
+    let metadata: Value = CLIENT
+        .get(&format!("{}/.well-known/openid-configuration", issuer))
+        .send()?
+        .error_for_status()?
+        .json()?;
+
+    // The tokens are checked against the issuer in the document, so it has to be the configured one
+    if metadata["issuer"].as_str().map(|i| i.trim_end_matches('/')) != Some(issuer.as_str()) {
+        err!("The issuer of the SSO provider doesn't match SSO_ISSUER_URL")
+    }
+    for endpoint in &["authorization_endpoint", "token_endpoint", "jwks_uri"] {
+        if !metadata[endpoint].is_string() {
+            err!(format!(
+                "The configuration of the SSO provider doesn't have the {}",
+                endpoint
+            ))
+        }
+    }
+
+    *METADATA.lock().unwrap() = Some(metadata.clone());
+    Ok(metadata)
+}
+
+fn forget_metadata() {
+    *METADATA.lock().unwrap() = None;
+}
+
+/// The URL of the login page of the provider, it sends back the state along with the code
+pub fn authorize_url(state: &str, nonce: &str) -> Result<String, Error> {
+    let metadata = metadata()?;
+    let client_id = CONFIG.sso_client_id().unwrap_or_default();
+    let scope = format!("openid {}", CONFIG.sso_scopes());
+    let redirect_uri = redirect_uri();
+
+    Ok(url_with_params(
+        metadata["authorization_endpoint"].as_str().unwrap_or_default(),
+        &[
+            ("response_type", "code"),
+            ("client_id", client_id.as_str()),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("scope", scope.trim()),
+            ("state", state),
+            ("nonce", nonce),
+        ],
+    ))
+}
+
+/// The user identified by the provider
+pub struct SsoUser {
+    pub email: String,
+    pub name: Option<String>,
+}
+
+/// Exchanges the code given by the provider, returning the user of its validated ID token
+pub fn exchange_code(code: &str, nonce: &str) -> Result<SsoUser, Error> {
+    let metadata = metadata()?;
+    let client_id = CONFIG.sso_client_id().unwrap_or_default();
+    let client_secret = CONFIG.sso_client_secret().unwrap_or_default();
+    let redirect_uri = redirect_uri();
+
+    let response = CLIENT
+        .post(metadata["token_endpoint"].as_str().unwrap_or_default())
+        .basic_auth(encode(&client_id), Some(encode(&client_secret)))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("client_id", client_id.as_str()),
+        ])
+        .send()
+        .and_then(|r| r.error_for_status());
+
+    let token: Value = match response.and_then(|mut r| r.json()) {
+        Ok(token) => token,
+        Err(e) => {
+            forget_metadata();
+            err!("Error exchanging the SSO code", e.to_string())
+        }
+    };
+
+    let id_token = match token["id_token"].as_str() {
+        Some(id_token) => id_token,
+        None => err!("The SSO provider didn't return an ID token"),
+    };
+    let claims = validate_id_token(id_token, nonce, &metadata)?;
+
+    // The email is what matches the accounts, so an unverified one could take over any of them.
+    // Some providers don't include the claim at all, they can only be trusted with `SSO_REQUIRE_EMAIL_VERIFIED=false`
+    match claims["email_verified"].as_bool() {
+        Some(true) => {}
+        None if !CONFIG.sso_require_email_verified() => {}
+        _ => err!("The email of the SSO user is not verified"),
+    }
+    let email = match claims["email"].as_str() {
+        Some(email) if !email.trim().is_empty() => email.trim().to_lowercase(),
+        _ => err!("The ID token doesn't have an email, check SSO_SCOPES"),
+    };
+
+    Ok(SsoUser {
+        email,
+        name: claims["name"].as_str().map(String::from),
+    })
+}
+
+/// Checks the RS256 signature of the ID token with the keys of the provider, and its claims
+fn validate_id_token(id_token: &str, nonce: &str, metadata: &Value) -> Result<Value, Error> {
+    let parts: Vec<&str> = id_token.split('.').collect();
+    if parts.len() != 3 {
+        err!("Invalid SSO ID token")
+    }
+
+    let decode_part =
+        |part: &str| -> Option<Vec<u8>> { BASE64URL_NOPAD.decode(part.trim_end_matches('=').as_bytes()).ok() };
+    let (header, payload, signature) = match (decode_part(parts[0]), decode_part(parts[1]), decode_part(parts[2])) {
+        (Some(header), Some(payload), Some(signature)) => (header, payload, signature),
+        _ => err!("Invalid SSO ID token"),
+    };
+    let header: Value = serde_json::from_slice(&header)?;
+    let claims: Value = serde_json::from_slice(&payload)?;
+
+    if header["alg"].as_str() != Some("RS256") {
+        err!("Only the RS256 signed ID tokens are supported")
+    }
+
+    let jwks: Value = CLIENT
+        .get(metadata["jwks_uri"].as_str().unwrap_or_default())
+        .send()?
+        .error_for_status()?
+        .json()?;
+
+    // Without a key id in the token, any of the RSA signing keys can match
+    let kid = header["kid"].as_str();
+    let signing_input = format!("{}.{}", parts[0], parts[1]);
+    let valid = jwks["keys"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter(|key| key["kty"].as_str() == Some("RSA") && key["use"].as_str().unwrap_or("sig") == "sig")
+        .filter(|key| kid.is_none() || key["kid"].as_str() == kid)
+        .any(|key| {
+            match (
+                key["n"].as_str().and_then(decode_part),
+                key["e"].as_str().and_then(decode_part),
+            ) {
+                (Some(n), Some(e)) => crypto::verify_rs256(&n, &e, signing_input.as_bytes(), &signature),
+                _ => false,
+            }
+        });
+    if !valid {
+        err!("Invalid signature of the SSO ID token")
+    }
+
+    if claims["iss"] != metadata["issuer"] {
+        err!("The SSO ID token is from another issuer")
+    }
+
+    let client_id = CONFIG.sso_client_id().unwrap_or_default();
+    let audience_valid = match claims["aud"] {
+        Value::String(ref aud) => *aud == client_id,
+        Value::Array(ref auds) => auds.iter().any(|aud| aud.as_str() == Some(client_id.as_str())),
+        _ => false,
+    };
+    if !audience_valid {
+        err!("The SSO ID token is for another client")
+    }
+
+    let leeway = CONFIG.token_clock_skew_seconds() as i64;
+    if claims["exp"]
+        .as_i64()
+        .map_or(true, |exp| exp + leeway < Utc::now().timestamp())
+    {
+        err!("The SSO ID token has expired")
+    }
+
+    if !crypto::ct_eq(claims["nonce"].as_str().unwrap_or_default(), nonce) {
+        err!("The nonce of the SSO ID token doesn't match")
+    }
+
+    Ok(claims)
+}
+
+/// Whether the authorization code with this nonce was already used to log in
+pub fn is_code_used(nonce: &str) -> bool {
+    USED_CODES.lock().unwrap().contains_key(nonce)
+}
+
+/// Marks the authorization code as used, returning false if it already was. They are forgotten once expired
+pub fn take_code(nonce: &str, exp: i64) -> bool {
+    let now = Utc::now().timestamp();
+    let mut used = USED_CODES.lock().unwrap();
+    used.retain(|_, code_exp| *code_exp >= now);
+
+    used.insert(nonce.to_string(), exp).is_none()
+}
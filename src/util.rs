@@ -15,7 +15,7 @@ impl Fairing for AppHeaders {
         }
     }
 
-    fn on_response(&self, _req: &Request, res: &mut Response) {
+    fn on_response(&self, req: &Request, res: &mut Response) {
         res.set_raw_header("Feature-Policy", "accelerometer 'none'; ambient-light-sensor 'none'; autoplay 'none'; camera 'none'; encrypted-media 'none'; fullscreen 'none'; geolocation 'none'; gyroscope 'none'; magnetometer 'none'; microphone 'none'; midi 'none'; payment 'none'; picture-in-picture 'none'; sync-xhr 'self' https://haveibeenpwned.com https://twofactorauth.org; usb 'none'; vr 'none'");
         res.set_raw_header("Referrer-Policy", "same-origin");
         res.set_raw_header("X-Frame-Options", "SAMEORIGIN");
@@ -24,6 +24,17 @@ impl Fairing for AppHeaders {
         let csp = "frame-ancestors 'self' chrome-extension://nngceckbapebfimnlniiiahkandclblb moz-extension://*;";
         res.set_raw_header("Content-Security-Policy", csp);
 
+        // The budget of the requests authenticated with the `Headers` guard, when the API rate limit is enabled
+        if let Some(ref ratelimit) = req.local_cache(|| crate::lockout::ApiRateLimitStatus(None)).0 {
+            let reset = ratelimit.reset_seconds().to_string();
+            res.set_raw_header("X-RateLimit-Limit", ratelimit.limit.to_string());
+            res.set_raw_header("X-RateLimit-Remaining", ratelimit.remaining.to_string());
+            if ratelimit.exceeded {
+                res.set_raw_header("Retry-After", reset.clone());
+            }
+            res.set_raw_header("X-RateLimit-Reset", reset);
+        }
+
         // Disable cache unless otherwise specified
         if !res.headers().contains("cache-control") {
             res.set_raw_header("Cache-Control", "no-cache, no-store, max-age=0");
@@ -86,6 +97,15 @@ pub fn read_file_string(path: &str) -> IOResult<String> {
     Ok(contents)
 }
 
+pub fn write_file(path: &str, content: &[u8]) -> IOResult<()> {
+    use std::io::Write;
+
+    let mut file = File::create(Path::new(path))?;
+    file.write_all(content)?;
+
+    Ok(())
+}
+
 pub fn delete_file(path: &str) -> IOResult<()> {
     let res = fs::remove_file(path);
 
@@ -137,6 +157,22 @@ pub fn upcase_first(s: &str) -> String {
     }
 }
 
+/// Converts a PascalCase or camelCase key to snake_case, keys that are already lowercase are left as they are
+pub fn snake_case(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 4);
+    for (i, c) in s.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                result.push('_');
+            }
+            result.extend(c.to_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
 pub fn try_parse_string<S, T, U>(string: impl Try<Ok = S, Error = U>) -> Option<T>
 where
     S: AsRef<str>,
@@ -174,6 +210,116 @@ pub fn format_date(date: &NaiveDateTime) -> String {
     date.format(DATETIME_FORMAT).to_string()
 }
 
+//
+// IP address methods
+//
+
+use std::net::IpAddr;
+
+/// Checks if both addresses are in the same network, using a /24 prefix for IPv4 and a /64 prefix for IPv6
+pub fn same_subnet(a: &IpAddr, b: &IpAddr) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..3] == b.octets()[..3],
+        (IpAddr::V6(a), IpAddr::V6(b)) => a.segments()[..4] == b.segments()[..4],
+        _ => false,
+    }
+}
+
+/// Parses an IP range in CIDR notation, like `192.168.1.0/24`. An address without a prefix is a range with only itself
+pub fn parse_ip_range(range: &str) -> Option<(IpAddr, u8)> {
+    let mut parts = range.trim().splitn(2, '/');
+    let addr: IpAddr = parts.next()?.parse().ok()?;
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+
+    let prefix = match parts.next() {
+        Some(prefix) => prefix.parse().ok().filter(|&p| p <= max_prefix)?,
+        None => max_prefix,
+    };
+    Some((addr, prefix))
+}
+
+/// Checks if the address is in the range, the ranges that can't be parsed contain no addresses
+pub fn ip_in_range(ip: &IpAddr, range: &str) -> bool {
+    let prefix_matches = |a: u128, b: u128, bits: u8, len: u8| {
+        let shift = u32::from(bits - len);
+        len == 0 || (a >> shift) == (b >> shift)
+    };
+
+    match (ip, parse_ip_range(range)) {
+        (IpAddr::V4(ip), Some((IpAddr::V4(net), len))) => {
+            prefix_matches(u128::from(u32::from(*ip)), u128::from(u32::from(net)), 32, len)
+        }
+        (IpAddr::V6(ip), Some((IpAddr::V6(net), len))) => prefix_matches(u128::from(*ip), u128::from(net), 128, len),
+        _ => false,
+    }
+}
+
+//
+// Locale methods
+//
+
+pub const DEFAULT_LOCALE: &str = "en-US";
+
+const SUPPORTED_LOCALES: &[&str] = &[
+    "en-US", "en-GB", "cs-CZ", "da-DK", "de-DE", "es-ES", "fi-FI", "fr-FR", "it-IT", "ja-JP", "ko-KR", "nb-NO",
+    "nl-NL", "pl-PL", "pt-BR", "pt-PT", "ru-RU", "sv-SE", "tr-TR", "uk-UA", "zh-CN", "zh-TW",
+];
+
+/// Returns the supported locale matching the provided one, either exactly or by its language, ignoring case
+pub fn find_locale(locale: &str) -> Option<&'static str> {
+    let locale = locale.trim().replace('_', "-");
+    let language = locale.split('-').next().unwrap_or_default();
+
+    SUPPORTED_LOCALES
+        .iter()
+        .find(|l| l.eq_ignore_ascii_case(&locale))
+        .or_else(|| {
+            SUPPORTED_LOCALES
+                .iter()
+                .find(|l| l.split('-').next().map_or(false, |l| l.eq_ignore_ascii_case(language)))
+        })
+        .cloned()
+}
+
+lazy_static! {
+    /// The translations of the error messages by locale, keyed by the English message without its final period
+    static ref ERROR_TRANSLATIONS: std::collections::HashMap<String, std::collections::HashMap<String, String>> =
+        serde_json::from_str(include_str!("static/error_messages.json")).expect("Invalid error message translations");
+}
+
+/// The translation of an error message to the locale, `None` when it doesn't have one
+pub fn translate_error(locale: &str, message: &str) -> Option<String> {
+    ERROR_TRANSLATIONS
+        .get(locale)?
+        .get(message.trim_end_matches('.'))
+        .cloned()
+}
+
+/// Returns the preferred supported locale from an Accept-Language header value
+pub fn locale_from_accept_language(header: &str) -> Option<&'static str> {
+    let mut languages: Vec<(&str, f32)> = header
+        .split(',')
+        .filter_map(|part| {
+            let mut parts = part.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .filter_map(|p| p.trim().trim_start_matches("q=").parse::<f32>().ok())
+                .next()
+                .unwrap_or(1.0);
+
+            if tag.is_empty() || tag == "*" {
+                None
+            } else {
+                Some((tag, quality))
+            }
+        })
+        .collect();
+
+    // The sort is stable, so the order of the header is kept for the same quality
+    languages.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    languages.into_iter().filter_map(|(tag, _)| find_locale(tag)).next()
+}
+
 //
 // Deserialization methods
 //
@@ -0,0 +1,413 @@
+//
+// WebAuthn support, limited to what the security keys need: the registrations use the "none" attestation,
+// so only the credential is extracted from them, and the assertions are checked with their ES256 key
+//
+use data_encoding::BASE64URL_NOPAD;
+use serde_json::Value;
+
+use crate::crypto;
+use crate::error::Error;
+
+/// Flag of the authenticator data set when the user was present
+const FLAG_USER_PRESENT: u8 = 0x01;
+/// Flag of the authenticator data set when it includes the attested credential
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// COSE algorithm identifier of ECDSA P-256 with SHA-256
+pub const COSE_ALG_ES256: i64 = -7;
+
+/// The clients send the binary values in base64url, some with padding or in plain base64, so all of them are accepted
+pub fn decode_base64(data: &str) -> Result<Vec<u8>, Error> {
+    let normalized: String = data
+        .trim_end_matches('=')
+        .chars()
+        .map(|c| match c {
+            '+' => '-',
+            '/' => '_',
+            c => c,
+        })
+        .collect();
+
+    match BASE64URL_NOPAD.decode(normalized.as_bytes()) {
+        Ok(decoded) => Ok(decoded),
+        Err(_) => err!("Invalid WebAuthn data encoding"),
+    }
+}
+
+pub fn encode_base64(data: &[u8]) -> String {
+    BASE64URL_NOPAD.encode(data)
+}
+
+pub fn new_challenge() -> String {
+    encode_base64(&crypto::get_random(vec![0u8; 32]))
+}
+
+//
+// CBOR
+//
+
+/// The CBOR values that appear in the attestations, tags and floats are not supported
+#[derive(Debug, PartialEq)]
+enum Cbor {
+    Int(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(Cbor, Cbor)>),
+    Bool(bool),
+    Null,
+}
+
+impl Cbor {
+    fn get(&self, key: &Cbor) -> Option<&Cbor> {
+        match self {
+            Cbor::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+/// Limit to the nesting of the values, the attestations only have a few levels
+const CBOR_MAX_DEPTH: usize = 16;
+
+/// Decodes the first CBOR value of the data, returning it and the remaining data after it
+fn decode_cbor(data: &[u8], depth: usize) -> Option<(Cbor, &[u8])> {
+    if depth > CBOR_MAX_DEPTH {
+        return None;
+    }
+
+    let (&initial, mut data) = data.split_first()?;
+    let major = initial >> 5;
+    let info = initial & 0x1f;
+
+    let arg = match info {
+        0..=23 => u64::from(info),
+        24..=27 => {
+            let len = 1 << (info - 24);
+            if data.len() < len {
+                return None;
+            }
+            let (bytes, rest) = data.split_at(len);
+            data = rest;
+            bytes.iter().fold(0, |acc, &b| (acc << 8) | u64::from(b))
+        }
+        _ => return None, // Indefinite lengths are not used by the authenticators
+    };
+
+    // The lengths can't be bigger than the data, which also prevents huge allocations
+    let len = arg as usize;
+    if (major == 2 || major == 3 || major == 4 || major == 5) && (arg > data.len() as u64) {
+        return None;
+    }
+
+    match major {
+        0 if arg <= i64::max_value() as u64 => Some((Cbor::Int(arg as i64), data)),
+        1 if arg <= i64::max_value() as u64 => Some((Cbor::Int(-1 - arg as i64), data)),
+        2 => {
+            let (bytes, rest) = data.split_at(len);
+            Some((Cbor::Bytes(bytes.to_vec()), rest))
+        }
+        3 => {
+            let (bytes, rest) = data.split_at(len);
+            let text = String::from_utf8(bytes.to_vec()).ok()?;
+            Some((Cbor::Text(text), rest))
+        }
+        4 => {
+            let mut items = Vec::with_capacity(len);
+            for _ in 0..len {
+                let (item, rest) = decode_cbor(data, depth + 1)?;
+                items.push(item);
+                data = rest;
+            }
+            Some((Cbor::Array(items), data))
+        }
+        5 => {
+            let mut entries = Vec::with_capacity(len);
+            for _ in 0..len {
+                let (key, rest) = decode_cbor(data, depth + 1)?;
+                let (value, rest) = decode_cbor(rest, depth + 1)?;
+                entries.push((key, value));
+                data = rest;
+            }
+            Some((Cbor::Map(entries), data))
+        }
+        7 => match info {
+            20 => Some((Cbor::Bool(false), data)),
+            21 => Some((Cbor::Bool(true), data)),
+            22 | 23 => Some((Cbor::Null, data)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+//
+// Authenticator data
+//
+
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    counter: u32,
+    /// The rest of the data, with the attested credential and the extensions
+    rest: &'a [u8],
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, Error> {
+    if data.len() < 37 {
+        err!("Invalid WebAuthn authenticator data")
+    }
+
+    let (rp_id_hash, data) = data.split_at(32);
+    let flags = data[0];
+    let counter = data[1..5].iter().fold(0, |acc, &b| (acc << 8) | u32::from(b));
+
+    Ok(AuthenticatorData {
+        rp_id_hash,
+        flags,
+        counter,
+        rest: &data[5..],
+    })
+}
+
+/// Checks the client data of a registration or an assertion, `expected_type` is "webauthn.create" or "webauthn.get"
+fn check_client_data(client_data_json: &[u8], expected_type: &str, challenge: &str, origin: &str) -> Result<(), Error> {
+    let client_data: Value = match serde_json::from_slice(client_data_json) {
+        Ok(data) => data,
+        Err(_) => err!("Invalid WebAuthn client data"),
+    };
+
+    if client_data["type"].as_str() != Some(expected_type) {
+        err!("Invalid WebAuthn client data type")
+    }
+
+    // Compare the decoded values, the encoding of the challenge depends on the client
+    let received = client_data["challenge"].as_str().map(decode_base64);
+    match (received, decode_base64(challenge)) {
+        (Some(Ok(ref received)), Ok(ref expected)) if crypto::ct_eq(received, expected) => (),
+        _ => err!("WebAuthn challenge doesn't match"),
+    }
+
+    if client_data["origin"].as_str() != Some(origin) {
+        err!("WebAuthn origin doesn't match")
+    }
+
+    Ok(())
+}
+
+/// A credential extracted from a registration
+pub struct Credential {
+    pub id: Vec<u8>,
+    /// ES256 public key, as an uncompressed point
+    pub public_key: Vec<u8>,
+    pub counter: u32,
+}
+
+/// Converts an EC2 P-256 COSE key to an uncompressed point, the format used by U2F and by `crypto::verify_es256`
+fn cose_key_to_point(key: &Cbor) -> Result<Vec<u8>, Error> {
+    let int = |value: &Cbor| match value {
+        Cbor::Int(i) => Some(*i),
+        _ => None,
+    };
+    let kty = key.get(&Cbor::Int(1)).and_then(int);
+    let alg = key.get(&Cbor::Int(3)).and_then(int);
+    let crv = key.get(&Cbor::Int(-1)).and_then(int);
+
+    if kty != Some(2) || alg != Some(COSE_ALG_ES256) || crv != Some(1) {
+        err!("Only ES256 security keys are supported")
+    }
+
+    match (key.get(&Cbor::Int(-2)), key.get(&Cbor::Int(-3))) {
+        (Some(Cbor::Bytes(x)), Some(Cbor::Bytes(y))) if x.len() == 32 && y.len() == 32 => {
+            let mut point = Vec::with_capacity(65);
+            point.push(0x04);
+            point.extend_from_slice(x);
+            point.extend_from_slice(y);
+            Ok(point)
+        }
+        _ => err!("Invalid WebAuthn public key"),
+    }
+}
+
+/// Extracts the credential of a registration response. The attestation statement is not verified,
+/// the registrations are requested with the "none" attestation
+pub fn parse_registration(
+    attestation_object: &[u8],
+    client_data_json: &[u8],
+    challenge: &str,
+    origin: &str,
+    rp_id: &str,
+) -> Result<Credential, Error> {
+    check_client_data(client_data_json, "webauthn.create", challenge, origin)?;
+
+    let attestation = match decode_cbor(attestation_object, 0) {
+        Some((attestation, _)) => attestation,
+        None => err!("Invalid WebAuthn attestation"),
+    };
+
+    let auth_data = match attestation.get(&Cbor::Text("authData".into())) {
+        Some(Cbor::Bytes(data)) => parse_authenticator_data(data)?,
+        _ => err!("Invalid WebAuthn attestation"),
+    };
+
+    if !crypto::ct_eq(auth_data.rp_id_hash, crypto::sha256(rp_id.as_bytes())) {
+        err!("WebAuthn relying party doesn't match")
+    }
+
+    if auth_data.flags & FLAG_USER_PRESENT == 0 || auth_data.flags & FLAG_ATTESTED_CREDENTIAL == 0 {
+        err!("Invalid WebAuthn authenticator flags")
+    }
+
+    // The attested credential data is the AAGUID, the credential id length and id, and the COSE key
+    let rest = auth_data.rest;
+    if rest.len() < 18 {
+        err!("Invalid WebAuthn attested credential")
+    }
+    let id_len = (usize::from(rest[16]) << 8) | usize::from(rest[17]);
+    if rest.len() < 18 + id_len {
+        err!("Invalid WebAuthn attested credential")
+    }
+    let (id, rest) = rest[18..].split_at(id_len);
+
+    let public_key = match decode_cbor(rest, 0) {
+        Some((key, _)) => cose_key_to_point(&key)?,
+        None => err!("Invalid WebAuthn public key"),
+    };
+
+    Ok(Credential {
+        id: id.to_vec(),
+        public_key,
+        counter: auth_data.counter,
+    })
+}
+
+/// Checks an assertion with the public key of the credential, returning the counter of the authenticator.
+/// `rp_ids` are the relying party ids the assertion can be for, the U2F app id is one of them for migrated keys
+pub fn verify_assertion(
+    authenticator_data: &[u8],
+    client_data_json: &[u8],
+    signature: &[u8],
+    challenge: &str,
+    origin: &str,
+    rp_ids: &[&str],
+    public_key: &[u8],
+) -> Result<u32, Error> {
+    check_client_data(client_data_json, "webauthn.get", challenge, origin)?;
+
+    let auth_data = parse_authenticator_data(authenticator_data)?;
+
+    let rp_id_hash = auth_data.rp_id_hash;
+    if !rp_ids
+        .iter()
+        .any(|rp_id| crypto::ct_eq(rp_id_hash, crypto::sha256(rp_id.as_bytes())))
+    {
+        err!("WebAuthn relying party doesn't match")
+    }
+
+    if auth_data.flags & FLAG_USER_PRESENT == 0 {
+        err!("Invalid WebAuthn authenticator flags")
+    }
+
+    // The signature is of the authenticator data followed by the hash of the client data
+    let mut signed = authenticator_data.to_vec();
+    signed.extend_from_slice(&crypto::sha256(client_data_json));
+
+    if !crypto::verify_es256(public_key, &signed, signature) {
+        err!("Invalid WebAuthn signature")
+    }
+
+    Ok(auth_data.counter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Made with a fixed P-256 key, for the relying party `vault.example.com`
+    const ORIGIN: &str = "https://vault.example.com";
+    const RP_ID: &str = "vault.example.com";
+    const CHALLENGE: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8";
+
+    const ATTESTATION: &str = "o2NmbXRkbm9uZWdhdHRTdG10oGhhdXRoRGF0YViUQJm-liBkZdUY5aI1Z5_X2Rz2JfA_XBKxfj-Fy2VMxAFBAAAAAAAAAAAAAAAAAAAAAAAAAAAAEGRlZmdoaWprbG1ub3BxcnOlAQIDJiABIVggTv7YmbD26cOPu7BA21GTVYCVffCkgSVaKweIr8zuPnkiWCAaU5klUWNBoo9ALGXw8kt2c7m5cYvYuVAHqG5y9tUaoA";
+    const CLIENT_DATA_CREATE: &str = r#"{"type":"webauthn.create","challenge":"AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8","origin":"https://vault.example.com"}"#;
+
+    const AUTHENTICATOR_DATA: &str = "QJm-liBkZdUY5aI1Z5_X2Rz2JfA_XBKxfj-Fy2VMxAEBAAAABQ";
+    const CLIENT_DATA_GET: &str = r#"{"type":"webauthn.get","challenge":"AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8","origin":"https://vault.example.com"}"#;
+    const SIGNATURE: &str = "MEUCIBPtw56-S_KpOXLcuDP70gVL1te_ItsI0sZ8UYIH4hJZAiEAzAtAvtEBwSDMQjbAkIzxCkj5fBWM-gFszXrtaMm5tQY";
+
+    const CREDENTIAL_ID: &str = "ZGVmZ2hpamtsbW5vcHFycw";
+    const PUBLIC_KEY: &str = "BE7-2Jmw9unDj7uwQNtRk1WAlX3wpIElWisHiK_M7j55GlOZJVFjQaKPQCxl8PJLdnO5uXGL2LlQB6hucvbVGqA";
+
+    fn decode(data: &str) -> Vec<u8> {
+        decode_base64(data).unwrap()
+    }
+
+    fn register(challenge: &str, origin: &str, rp_id: &str) -> Result<Credential, Error> {
+        parse_registration(
+            &decode(ATTESTATION),
+            CLIENT_DATA_CREATE.as_bytes(),
+            challenge,
+            origin,
+            rp_id,
+        )
+    }
+
+    fn check_assertion(signature: &[u8], rp_ids: &[&str]) -> Result<u32, Error> {
+        verify_assertion(
+            &decode(AUTHENTICATOR_DATA),
+            CLIENT_DATA_GET.as_bytes(),
+            signature,
+            CHALLENGE,
+            ORIGIN,
+            rp_ids,
+            &decode(PUBLIC_KEY),
+        )
+    }
+
+    #[test]
+    fn registration_gives_the_credential() {
+        let credential = register(CHALLENGE, ORIGIN, RP_ID).unwrap();
+
+        assert_eq!(credential.id, decode(CREDENTIAL_ID));
+        assert_eq!(credential.public_key, decode(PUBLIC_KEY));
+        assert_eq!(credential.counter, 0);
+    }
+
+    #[test]
+    fn registration_checks_the_challenge_the_origin_and_the_relying_party() {
+        assert!(register(&new_challenge(), ORIGIN, RP_ID).is_err());
+        assert!(register(CHALLENGE, "https://evil.example.com", RP_ID).is_err());
+        assert!(register(CHALLENGE, ORIGIN, "evil.example.com").is_err());
+    }
+
+    #[test]
+    fn assertion_gives_the_counter() {
+        assert_eq!(check_assertion(&decode(SIGNATURE), &[RP_ID]).unwrap(), 5);
+        // The U2F app id of the migrated keys is checked along the relying party
+        let rp_ids = ["https://vault.example.com/app-id.json", RP_ID];
+        assert_eq!(check_assertion(&decode(SIGNATURE), &rp_ids).unwrap(), 5);
+    }
+
+    #[test]
+    fn assertion_checks_the_signature_and_the_relying_party() {
+        let mut signature = decode(SIGNATURE);
+        let last = signature.len() - 1;
+        signature[last] ^= 1;
+
+        assert!(check_assertion(&signature, &[RP_ID]).is_err());
+        assert!(check_assertion(&decode(SIGNATURE), &["evil.example.com"]).is_err());
+    }
+
+    #[test]
+    fn cbor_rejects_truncated_and_too_deep_values() {
+        // A byte string of 4 bytes with only 2 of them
+        assert_eq!(decode_cbor(&[0x44, 0x01, 0x02], 0), None);
+        // Arrays of a single value, nested deeper than the limit
+        let mut nested = vec![0x81; CBOR_MAX_DEPTH + 2];
+        nested.push(0x01);
+        assert_eq!(decode_cbor(&nested, 0), None);
+
+        let expected = Cbor::Array(vec![Cbor::Int(1), Cbor::Int(-1), Cbor::Null]);
+        assert_eq!(decode_cbor(&[0x83, 0x01, 0x20, 0xf6], 0).map(|(v, _)| v), Some(expected));
+    }
+}
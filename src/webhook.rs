@@ -0,0 +1,189 @@
+//
+// Delivery of the security events to the webhook configured by the admin
+//
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use reqwest::{header::CONTENT_TYPE, Client};
+use serde_json::Value;
+
+use crate::crypto;
+use crate::error::Error;
+use crate::CONFIG;
+
+/// Header with the HMAC-SHA256 of the body, as "sha256=<hex>", when a secret is configured
+const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// Attempts to deliver the background events, the first retry waits a second and every other one twice as long
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Events waiting for the delivery thread, the new ones are dropped while it's full
+const QUEUE_SIZE: usize = 100;
+
+lazy_static! {
+    static ref CLIENT: Client = Client::builder()
+        .use_sys_proxy()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+
+    /// A single thread delivers the background events one after the other
+    static ref QUEUE: Mutex<SyncSender<Value>> = {
+        let (sender, receiver) = sync_channel::<Value>(QUEUE_SIZE);
+        std::thread::spawn(move || {
+            for payload in receiver {
+                deliver(&payload);
+            }
+        });
+        Mutex::new(sender)
+    };
+}
+
+/// Keeps the first character of the local part, with `EVENT_WEBHOOK_MASK_USER`
+fn mask_user(email: &str) -> String {
+    match email.find('@') {
+        Some(at) => {
+            let first: String = email[..at].chars().take(1).collect();
+            format!("{}***{}", first, &email[at..])
+        }
+        None => String::from("***"),
+    }
+}
+
+/// The JSON sent for every event, the values that don't apply to it are null
+pub fn event_payload(event_type: &str, user: Option<&str>, ip: Option<&str>, device: Option<&str>) -> Value {
+    let user = user.map(|user| {
+        if CONFIG.event_webhook_mask_user() {
+            mask_user(user)
+        } else {
+            user.to_string()
+        }
+    });
+
+    json!({
+        "event_type": event_type,
+        "user": user,
+        "ip": ip,
+        "device": device,
+        "timestamp": crate::util::format_date(&Utc::now().naive_utc()),
+    })
+}
+
+/// How a delivery went, `status` is None when the receiver didn't respond
+pub struct Delivery {
+    pub status: Option<u16>,
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+impl Delivery {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+
+    #[cfg(feature = "admin")]
+    pub fn to_json(&self) -> Value {
+        json!({
+            "Success": self.is_success(),
+            "Status": self.status,
+            "LatencyMs": self.latency_ms,
+            "Error": self.error,
+        })
+    }
+}
+
+/// Sends the payload to the webhook, signed when a secret is configured. Only fails when the webhook is not configured,
+/// the delivery errors and the non-2xx responses are reported in the result
+pub fn send(payload: &Value) -> Result<Delivery, Error> {
+    let url = match CONFIG.event_webhook_url() {
+        Some(url) if CONFIG.webhook_enabled() => url,
+        _ => err!("The event webhook is not configured"),
+    };
+
+    let body = payload.to_string();
+    let mut request = CLIENT.post(&url).header(CONTENT_TYPE, "application/json");
+    if let Some(secret) = CONFIG.event_webhook_secret() {
+        let signature = crypto::hmac_sign_sha256(&secret, &body);
+        request = request.header(SIGNATURE_HEADER, format!("sha256={}", signature));
+    }
+
+    let start = Instant::now();
+    let result = request.body(body).send();
+    let latency_ms = start.elapsed().as_millis() as u64;
+
+    Ok(match result {
+        Ok(response) => {
+            let status = response.status();
+            Delivery {
+                status: Some(status.as_u16()),
+                latency_ms,
+                error: if status.is_success() {
+                    None
+                } else {
+                    Some(format!("The webhook responded with {}", status))
+                },
+            }
+        }
+        Err(e) => Delivery {
+            status: e.status().map(|s| s.as_u16()),
+            latency_ms,
+            error: Some(e.to_string()),
+        },
+    })
+}
+
+/// Whether the delivery could work later, the receiver didn't respond or had a temporary error
+fn is_retryable(delivery: &Delivery) -> bool {
+    match delivery.status {
+        Some(status) => status == 429 || status >= 500,
+        None => true,
+    }
+}
+
+/// Queues the payload for the delivery thread when the webhook is enabled, so that the request doesn't wait for it.
+/// The events that don't fit in the queue are dropped, so a flood of failed logins can't pile them up
+pub fn send_in_background(payload: Value) {
+    if !CONFIG.webhook_enabled() {
+        return;
+    }
+
+    match QUEUE.lock().unwrap().try_send(payload) {
+        Ok(()) => (),
+        Err(TrySendError::Full(payload)) => warn!(
+            "Too many webhook events waiting, dropping the {} event",
+            payload["event_type"].as_str().unwrap_or_default()
+        ),
+        Err(TrySendError::Disconnected(_)) => error!("The webhook delivery thread stopped, the event can't be sent"),
+    }
+}
+
+/// The failed deliveries are retried a few times and then only logged
+fn deliver(payload: &Value) {
+    let event_type = payload["event_type"].as_str().unwrap_or_default();
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let delivery = match send(payload) {
+            Ok(delivery) => delivery,
+            Err(e) => {
+                error!("Error sending the {} event: {:#?}", event_type, e);
+                return;
+            }
+        };
+
+        if delivery.is_success() {
+            return;
+        }
+        if attempt == MAX_ATTEMPTS || !is_retryable(&delivery) {
+            error!(
+                "Error sending the {} event, after {} attempts: {:?}",
+                event_type, attempt, delivery.error
+            );
+            return;
+        }
+
+        std::thread::sleep(Duration::from_secs(1 << (attempt - 1)));
+    }
+}